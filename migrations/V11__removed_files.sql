@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS removed_files (
+    branch TEXT NOT NULL,
+    path TEXT NOT NULL,
+    keyword_mode TEXT NOT NULL,
+    PRIMARY KEY (branch, path)
+);
@@ -0,0 +1,3 @@
+-- The commit time used for the export, which may differ from commits.time if
+-- it was clamped to keep the branch's history monotonic.
+ALTER TABLE commit_branches ADD COLUMN commit_time INTEGER;
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS skipped_commits (
+    oid TEXT NOT NULL PRIMARY KEY,
+    reason TEXT,
+    created_at INTEGER NOT NULL
+);
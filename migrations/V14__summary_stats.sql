@@ -0,0 +1,8 @@
+-- Lines changed relative to each commit's first parent, for summary reports.
+ALTER TABLE commit_branches ADD COLUMN lines_added INTEGER;
+ALTER TABLE commit_branches ADD COLUMN lines_removed INTEGER;
+
+-- The size of each file revision as written to CVS, and whether it was added
+-- as binary.
+ALTER TABLE file_revisions ADD COLUMN size INTEGER;
+ALTER TABLE file_revisions ADD COLUMN binary INTEGER;
@@ -0,0 +1 @@
+ALTER TABLE branch_mappings ADD COLUMN tagged INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+ALTER TABLE branch_mappings ADD COLUMN root_branch TEXT;
+ALTER TABLE branch_mappings ADD COLUMN root_oid TEXT;
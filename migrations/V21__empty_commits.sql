@@ -0,0 +1 @@
+ALTER TABLE commit_branches ADD COLUMN empty INTEGER NOT NULL DEFAULT 0;
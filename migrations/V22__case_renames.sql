@@ -0,0 +1 @@
+ALTER TABLE branch_mappings ADD COLUMN case_renames TEXT;
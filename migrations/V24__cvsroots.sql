@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS cvsroots (
+    cvsroot TEXT NOT NULL,
+    module TEXT NOT NULL DEFAULT '',
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (cvsroot, module)
+);
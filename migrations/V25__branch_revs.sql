@@ -0,0 +1 @@
+ALTER TABLE branch_mappings ADD COLUMN rev_oid TEXT;
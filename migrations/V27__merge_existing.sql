@@ -0,0 +1 @@
+ALTER TABLE branch_mappings ADD COLUMN merge_existing INTEGER NOT NULL DEFAULT 0;
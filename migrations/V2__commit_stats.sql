@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS commits (
+    oid TEXT NOT NULL PRIMARY KEY,
+    author_name TEXT NOT NULL,
+    author_email TEXT NOT NULL,
+    time INTEGER NOT NULL
+);
+
+ALTER TABLE commit_branches ADD COLUMN exported_at INTEGER;
+ALTER TABLE commit_branches ADD COLUMN duration_ms INTEGER;
+ALTER TABLE commit_branches ADD COLUMN files_added INTEGER;
+ALTER TABLE commit_branches ADD COLUMN files_modified INTEGER;
+ALTER TABLE commit_branches ADD COLUMN files_removed INTEGER;
+ALTER TABLE commit_branches ADD COLUMN bytes_written INTEGER;
@@ -0,0 +1,11 @@
+ALTER TABLE commits ADD COLUMN summary TEXT;
+
+CREATE TABLE IF NOT EXISTS file_revisions (
+    branch TEXT NOT NULL,
+    oid TEXT NOT NULL,
+    path TEXT NOT NULL,
+    revision TEXT NOT NULL,
+    PRIMARY KEY (path, revision)
+);
+
+CREATE INDEX IF NOT EXISTS file_revisions_oid ON file_revisions (oid);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS file_state (
+    branch TEXT NOT NULL,
+    path TEXT NOT NULL,
+    oid TEXT NOT NULL,
+    PRIMARY KEY (branch, path)
+);
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS commit_branches_branch_oid ON commit_branches (branch, oid);
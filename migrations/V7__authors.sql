@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS authors (
+    git_email TEXT NOT NULL PRIMARY KEY COLLATE NOCASE,
+    cvs_user TEXT NOT NULL,
+    display_name TEXT
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS message_overrides (
+    oid TEXT NOT NULL PRIMARY KEY,
+    message TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+ALTER TABLE branch_mappings ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at INTEGER NOT NULL,
+    command TEXT NOT NULL,
+    branch TEXT NOT NULL,
+    action TEXT NOT NULL,
+    detail TEXT
+);
@@ -0,0 +1,237 @@
+use std::{ffi::OsString, path::PathBuf, rc::Rc};
+
+use anyhow::Context;
+use git2::Oid;
+use structopt::StructOpt;
+
+use crate::{
+    cvs, cvswrappers,
+    database::Database,
+    git::Repository,
+    push,
+    verify::{self, Options},
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    #[structopt(short, long, help = "the branch that was exported")]
+    branch: String,
+
+    #[structopt(long, help = "the last git commit that was exported")]
+    commit: String,
+
+    #[structopt(long, help = "an existing checkout of the CVS module")]
+    checkout: PathBuf,
+
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(short, long, default_value = ".", help = "git repository")]
+    git: OsString,
+
+    #[structopt(short, long, help = "use a remote branch")]
+    remote: bool,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "src",
+        help = "the target directory within the cvs checkout; can be . to use the top level"
+    )]
+    target: PathBuf,
+
+    #[structopt(
+        long,
+        help = "ignore differences in expanded RCS keywords such as $Id$"
+    )]
+    tolerate_keyword_diffs: bool,
+
+    #[structopt(
+        long,
+        help = "the permissions manifest within the target, which isn't in the git tree"
+    )]
+    permissions_manifest: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "the target contains a .cvswrappers file written by push --write-cvswrappers"
+    )]
+    write_cvswrappers: bool,
+
+    #[structopt(
+        long,
+        default_value = "escape",
+        possible_values = cvs::Sanitise::VARIANTS,
+        help = "how characters that CVS tags can't contain are handled in the branch name"
+    )]
+    sanitise: cvs::Sanitise,
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    let mut db = Database::open(&opt.database)?;
+    let repo = Repository::discover(&opt.git, 0)?;
+
+    let branch = repo.require_branch(&opt.branch, opt.remote)?;
+    if db.get_cvs_branch(&opt.branch)?.is_some() {
+        anyhow::bail!("branch {} is already in the database", &opt.branch);
+    }
+
+    let last = Oid::from_str(&opt.commit)?;
+    let history = branch.linear_history()?;
+    let index = match history.iter().position(|oid| *oid == last) {
+        Some(index) => index,
+        None => anyhow::bail!(
+            "commit {} is not in the first parent history of {}",
+            last,
+            &opt.branch
+        ),
+    };
+
+    // Make sure that the checkout actually matches the commit before we claim
+    // that it was exported.
+    let commit = repo.commit(&last)?;
+    let mut files = Vec::new();
+    let discrepancies = verify::compare(
+        &repo,
+        &commit.tree_id(),
+        &opt.checkout.join(&opt.target),
+        &Options {
+            tolerate_keyword_diffs: opt.tolerate_keyword_diffs,
+            generated: opt
+                .permissions_manifest
+                .iter()
+                .cloned()
+                .chain(opt.write_cvswrappers.then(|| cvswrappers::PATH.into()))
+                .collect(),
+            ..Options::default()
+        },
+        |path, oid| files.push((path.to_path_buf(), *oid)),
+    )?;
+    if !discrepancies.is_empty() {
+        for discrepancy in &discrepancies {
+            eprintln!("{}", discrepancy);
+        }
+        anyhow::bail!(
+            "checkout does not match commit {}: {} discrepancies",
+            last,
+            discrepancies.len()
+        );
+    }
+
+    let commits = history
+        .iter()
+        .take(index + 1)
+        .map(|oid| {
+            repo.commit(oid)
+                .with_context(|| format!("cannot read commit {}", oid))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    db.write_branch(
+        &opt.branch,
+        &push::cvs_branch_name(&db, &opt.branch, None, opt.sanitise)?,
+        commits.iter().map(Rc::as_ref),
+    )?;
+    db.set_sanitise_scheme(&opt.branch, opt.sanitise.as_str())?;
+    db.mark_exported_through(&opt.branch, index)?;
+    db.write_file_state(&opt.branch, files.into_iter())?;
+
+    println!(
+        "adopted {} commits on {} up to {}",
+        commits.len(),
+        &opt.branch,
+        last
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::test_support::GitBuilder;
+
+    fn adopt(
+        git: &GitBuilder,
+        database: &Path,
+        checkout: &Path,
+        commit: &Oid,
+    ) -> anyhow::Result<()> {
+        let mut opt = Opt::from_iter_safe(vec![
+            OsString::from("adopt"),
+            "-b".into(),
+            "main".into(),
+            "--commit".into(),
+            commit.to_string().into(),
+            "--checkout".into(),
+            checkout.into(),
+            "-g".into(),
+            git.path().into(),
+        ])
+        .unwrap();
+        opt.database = database.into();
+        run(opt)
+    }
+
+    #[test]
+    fn test_adopt() {
+        let mut git = GitBuilder::new();
+        git.checkout("main");
+        let first = git.file("a.txt", "a\n").commit("first");
+        let second = git.file("b.txt", "b\n").commit("second");
+        let third = git.file("a.txt", "changed\n").commit("third");
+
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("git2cvs.db");
+        let checkout = dir.path().join("checkout");
+        fs::create_dir_all(checkout.join("src/CVS")).unwrap();
+        fs::write(checkout.join("src/a.txt"), "a\n").unwrap();
+        fs::write(checkout.join("src/b.txt"), "b\n").unwrap();
+
+        // A checkout that doesn't match the commit isn't adopted.
+        let err = adopt(&git, &database, &checkout, &third).unwrap_err();
+        assert_eq!(
+            format!("checkout does not match commit {}: 1 discrepancies", third),
+            err.to_string()
+        );
+        let err = adopt(&git, &database, &checkout, &first).unwrap_err();
+        assert_eq!(
+            format!("checkout does not match commit {}: 1 discrepancies", first),
+            err.to_string()
+        );
+        assert_eq!(
+            None,
+            Database::open(&database)
+                .unwrap()
+                .get_cvs_branch("main")
+                .unwrap()
+        );
+
+        // Nor is a commit from somewhere else.
+        let mut other = GitBuilder::new();
+        let stranger = other.file("a.txt", "a\n").commit("stranger");
+        let err = adopt(&git, &database, &checkout, &stranger).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("is not in the first parent history of main"),
+            "{}",
+            err
+        );
+
+        adopt(&git, &database, &checkout, &second).unwrap();
+        let db = Database::open(&database).unwrap();
+        assert_eq!(Some("main".into()), db.get_cvs_branch("main").unwrap());
+        assert_eq!(Some(second), db.last_exported_commit("main").unwrap());
+        assert_eq!(Some(second), db.branch_tip("main").unwrap());
+        let mut files: Vec<_> = db.file_state("main").unwrap().into_keys().collect();
+        files.sort();
+        assert_eq!(vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")], files);
+        drop(db);
+
+        // A branch can only be adopted once.
+        let err = adopt(&git, &database, &checkout, &second).unwrap_err();
+        assert_eq!("branch main is already in the database", err.to_string());
+    }
+}
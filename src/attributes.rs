@@ -0,0 +1,410 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+};
+
+use git2::Oid;
+
+use crate::git::Repository;
+
+/// Reads gitattributes from the .gitattributes files in a tree, so that each
+/// commit is exported with its own attributes rather than whatever is checked
+/// out. Only the attributes that decide between binary and text are
+/// understood.
+#[derive(Debug, Clone, Default)]
+pub struct TreeAttributes {
+    /// Parsed .gitattributes files by blob ID, which rarely change from one
+    /// commit to the next.
+    parsed: RefCell<HashMap<Oid, Rc<Vec<Rule>>>>,
+}
+
+/// A pattern in the syntax of .gitattributes. Patterns containing a / are
+/// matched against the whole path, and others against the file name alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    pattern: Vec<u8>,
+    anchored: bool,
+}
+
+impl Glob {
+    fn new(pattern: &[u8]) -> Self {
+        match pattern.strip_prefix(b"/") {
+            Some(pattern) => Self {
+                pattern: pattern.to_vec(),
+                anchored: true,
+            },
+            None => Self {
+                pattern: pattern.to_vec(),
+                anchored: pattern.contains(&b'/'),
+            },
+        }
+    }
+
+    /// Returns the pattern if it matches file names in any directory.
+    pub fn file_name_pattern(&self) -> Option<&str> {
+        if self.anchored {
+            None
+        } else {
+            std::str::from_utf8(&self.pattern).ok()
+        }
+    }
+
+    /// Matches a relative path. Any . components are ignored.
+    pub fn matches(&self, path: &Path) -> bool {
+        let path: PathBuf = path
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect();
+        if self.anchored {
+            wildmatch(&self.pattern, path.as_os_str().as_bytes())
+        } else {
+            let name = path.file_name().map_or(&b""[..], |name| name.as_bytes());
+            wildmatch(&self.pattern, name)
+        }
+    }
+}
+
+impl std::str::FromStr for Glob {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.ends_with('/') {
+            anyhow::bail!("glob {:?} cannot match a file", s);
+        }
+        Ok(Self::new(s.as_bytes()))
+    }
+}
+
+impl fmt::Display for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let slash = if self.anchored && !self.pattern.contains(&b'/') {
+            "/"
+        } else {
+            ""
+        };
+        write!(f, "{}{}", slash, String::from_utf8_lossy(&self.pattern))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    glob: Glob,
+    /// Some(true) for binary or -text, Some(false) for text, and None where an
+    /// earlier match is undone by !text or text=auto.
+    binary: Option<bool>,
+}
+
+impl TreeAttributes {
+    /// Returns whether the attributes in the tree make the file at the given
+    /// path within it binary or text, or None if they don't say.
+    pub fn binary(
+        &self,
+        repo: &Repository,
+        tree: &Oid,
+        path: &Path,
+    ) -> anyhow::Result<Option<bool>> {
+        let mut binary = None;
+
+        // Deeper files take precedence, as do later lines within a file.
+        let mut dirs: Vec<_> = path.ancestors().skip(1).collect();
+        dirs.reverse();
+        for dir in dirs {
+            let oid = match repo.blob_at(tree, &dir.join(".gitattributes"))? {
+                Some(oid) => oid,
+                None => continue,
+            };
+            let relative = path.strip_prefix(dir)?;
+            for rule in self.rules(repo, &oid)?.iter() {
+                if rule.glob.matches(relative) {
+                    binary = rule.binary;
+                }
+            }
+        }
+
+        Ok(binary)
+    }
+
+    /// Returns the patterns in the .gitattributes file at the top of the tree
+    /// that make files binary in any directory, leaving out any that a later
+    /// line undoes.
+    pub fn binary_patterns(&self, repo: &Repository, tree: &Oid) -> anyhow::Result<Vec<Glob>> {
+        let oid = match repo.blob_at(tree, Path::new(".gitattributes"))? {
+            Some(oid) => oid,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut patterns: Vec<&Glob> = Vec::new();
+        let rules = self.rules(repo, &oid)?;
+        for rule in rules.iter() {
+            patterns.retain(|glob| **glob != rule.glob);
+            if rule.binary == Some(true) && !rule.glob.anchored {
+                patterns.push(&rule.glob);
+            }
+        }
+
+        Ok(patterns.into_iter().cloned().collect())
+    }
+
+    fn rules(&self, repo: &Repository, oid: &Oid) -> anyhow::Result<Rc<Vec<Rule>>> {
+        if let Some(rules) = self.parsed.borrow().get(oid) {
+            return Ok(rules.clone());
+        }
+
+        let rules = Rc::new(parse(repo.blob(oid)?.content()));
+        self.parsed.borrow_mut().insert(*oid, rules.clone());
+        Ok(rules)
+    }
+}
+
+/// Parses the lines of a .gitattributes file that set binary or text.
+fn parse(content: &[u8]) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for line in content.split(|b| *b == b'\n') {
+        let mut fields = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|field| !field.is_empty());
+        let pattern = match fields.next() {
+            Some(pattern) if !pattern.starts_with(b"#") => pattern,
+            _ => continue,
+        };
+        // Attributes never apply to directories, so neither do these
+        // patterns.
+        if pattern.ends_with(b"/") {
+            continue;
+        }
+
+        let mut binary = None;
+        let mut matched = false;
+        for attribute in fields {
+            binary = match attribute {
+                b"binary" | b"-text" => Some(true),
+                b"text" => Some(false),
+                b"!text" | b"text=auto" => None,
+                _ if attribute.starts_with(b"text=") => Some(false),
+                _ => continue,
+            };
+            matched = true;
+        }
+        if !matched {
+            continue;
+        }
+
+        rules.push(Rule {
+            glob: Glob::new(pattern),
+            binary,
+        });
+    }
+
+    rules
+}
+
+/// Matches a path against a gitattributes pattern: * and ? don't match /,
+/// but ** does when it's a whole path component.
+pub fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) if rest.first() == Some(&b'*') => {
+            let rest = &rest[1..];
+            match rest.strip_prefix(b"/") {
+                // **/ matches any number of whole directories, including none.
+                Some(rest) => {
+                    wildmatch(rest, text)
+                        || text
+                            .iter()
+                            .enumerate()
+                            .any(|(i, b)| *b == b'/' && wildmatch(rest, &text[i + 1..]))
+                }
+                None => (0..=text.len()).any(|i| wildmatch(rest, &text[i..])),
+            }
+        }
+        Some((b'*', rest)) => (0..=text.len())
+            .take_while(|i| *i == 0 || text[i - 1] != b'/')
+            .any(|i| wildmatch(rest, &text[i..])),
+        Some((b'?', rest)) => match text.split_first() {
+            Some((c, text)) if *c != b'/' => wildmatch(rest, text),
+            _ => false,
+        },
+        Some((b'[', rest)) => match text.split_first() {
+            Some((c, text)) if *c != b'/' => match class(rest, *c) {
+                Some((matched, rest)) => matched && wildmatch(rest, text),
+                // An unclosed [ is literal.
+                None => *c == b'[' && wildmatch(rest, text),
+            },
+            _ => false,
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => match text.split_first() {
+            Some((c, text)) if *c == rest[0] => wildmatch(&rest[1..], text),
+            _ => false,
+        },
+        Some((p, rest)) => match text.split_first() {
+            Some((c, text)) if c == p => wildmatch(rest, text),
+            _ => false,
+        },
+    }
+}
+
+/// Matches a character against a character class after its opening [,
+/// returning whether it matched and the rest of the pattern, or None if the
+/// class isn't closed.
+fn class(pattern: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    let (negated, body) = match pattern.first() {
+        Some(b'!') | Some(b'^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+    // A ] straight after the [ is part of the class.
+    let end = body.iter().skip(1).position(|b| *b == b']')? + 1;
+    let members = &body[..end];
+
+    let mut found = false;
+    let mut i = 0;
+    while i < members.len() {
+        if i + 2 < members.len() && members[i + 1] == b'-' {
+            found |= members[i] <= c && c <= members[i + 2];
+            i += 3;
+        } else {
+            found |= members[i] == c;
+            i += 1;
+        }
+    }
+
+    Some((found != negated, &body[end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::GitBuilder;
+
+    #[test]
+    fn test_wildmatch() {
+        for (pattern, text) in [
+            ("*.pdf", "a.pdf"),
+            ("a?c", "abc"),
+            ("[a-c]x", "bx"),
+            ("[!a-c]x", "dx"),
+            ("[]]", "]"),
+            ("docs/*.txt", "docs/a.txt"),
+            ("**/x.dat", "x.dat"),
+            ("**/x.dat", "a/b/x.dat"),
+            ("a/**/b", "a/b"),
+            ("a/**/b", "a/x/y/b"),
+            ("a/**", "a/x/y"),
+            ("\\*", "*"),
+        ] {
+            assert!(
+                wildmatch(pattern.as_bytes(), text.as_bytes()),
+                "{} {}",
+                pattern,
+                text
+            );
+        }
+
+        for (pattern, text) in [
+            ("*.pdf", "a.pdfx"),
+            ("a?c", "a/c"),
+            ("[a-c]x", "dx"),
+            ("docs/*.txt", "docs/a/b.txt"),
+            ("a/**/b", "a/xb"),
+            ("\\*", "a"),
+        ] {
+            assert!(
+                !wildmatch(pattern.as_bytes(), text.as_bytes()),
+                "{} {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            vec![
+                Rule {
+                    glob: Glob {
+                        pattern: b"*.pdf".to_vec(),
+                        anchored: false,
+                    },
+                    binary: Some(true),
+                },
+                Rule {
+                    glob: Glob {
+                        pattern: b"data/*.dat".to_vec(),
+                        anchored: true,
+                    },
+                    binary: Some(true),
+                },
+                Rule {
+                    glob: Glob {
+                        pattern: b"top.txt".to_vec(),
+                        anchored: true,
+                    },
+                    binary: Some(false),
+                },
+                Rule {
+                    glob: Glob {
+                        pattern: b"*.c".to_vec(),
+                        anchored: false,
+                    },
+                    binary: None,
+                },
+            ],
+            parse(
+                b"# comment\n*.pdf binary\ndata/*.dat -diff -text\n/top.txt text eol=lf\n\
+                  *.c text=auto\n*.h eol=crlf\ndir/ binary\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_glob() {
+        let glob = |pattern: &str| pattern.parse::<Glob>().unwrap();
+        assert!(glob("*.dat").matches(Path::new("./src/a/x.dat")));
+        assert!(glob("src/*.dat").matches(Path::new("./src/x.dat")));
+        assert!(!glob("src/*.dat").matches(Path::new("src/a/x.dat")));
+        assert!(glob("/x.dat").matches(Path::new("x.dat")));
+        assert!(!glob("/x.dat").matches(Path::new("a/x.dat")));
+        assert!(glob("firmware/**").matches(Path::new("firmware/a/b.bin")));
+        assert_eq!("/x.dat", glob("/x.dat").to_string());
+        assert_eq!("firmware/**", glob("firmware/**").to_string());
+
+        assert!("".parse::<Glob>().is_err());
+        assert!("dir/".parse::<Glob>().is_err());
+    }
+
+    #[test]
+    fn test_binary() {
+        let mut git = GitBuilder::new();
+        let oid = git
+            .file(
+                ".gitattributes",
+                "*.pdf binary\n*.dat -text\nforce.bin text\n",
+            )
+            .file("sub/.gitattributes", "*.dat text\n")
+            .commit("attributes");
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let tree = repo.commit(&oid).unwrap().tree_id();
+        let attributes = TreeAttributes::default();
+        let binary = |path: &str| attributes.binary(&repo, &tree, Path::new(path)).unwrap();
+
+        assert_eq!(Some(true), binary("doc.pdf"));
+        assert_eq!(Some(true), binary("a/b/doc.pdf"));
+        assert_eq!(Some(true), binary("x.dat"));
+        assert_eq!(Some(false), binary("sub/x.dat"));
+        assert_eq!(Some(false), binary("force.bin"));
+        assert_eq!(None, binary("README"));
+
+        let patterns = attributes.binary_patterns(&repo, &tree).unwrap();
+        assert_eq!(
+            vec![Some("*.pdf"), Some("*.dat")],
+            patterns
+                .iter()
+                .map(Glob::file_name_pattern)
+                .collect::<Vec<_>>()
+        );
+    }
+}
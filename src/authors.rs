@@ -0,0 +1,306 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::OsString,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use structopt::StructOpt;
+
+use crate::{
+    database::{Author, Database},
+    git::Commit,
+    json::Value,
+    output::{self, Format},
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(subcommand)]
+    action: Action,
+}
+
+#[derive(Debug, StructOpt)]
+enum Action {
+    /// Maps a git author email to a CVS user.
+    Add {
+        email: String,
+        cvs_user: String,
+
+        #[structopt(long, help = "the name to use when annotating commits")]
+        name: Option<String>,
+    },
+
+    /// Lists the mapped authors.
+    List {
+        #[structopt(
+            long,
+            default_value = "table",
+            possible_values = Format::VARIANTS,
+            help = "output format"
+        )]
+        format: Format,
+    },
+
+    /// Removes the mapping for a git author email.
+    Remove { email: String },
+
+    /// Imports an author file, replacing any existing mappings for the same
+    /// emails.
+    Import { file: PathBuf },
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    let mut db = match opt.action {
+        Action::List { .. } => Database::open_readonly(&opt.database)?,
+        _ => Database::open(&opt.database)?,
+    };
+
+    match opt.action {
+        Action::Add {
+            email,
+            cvs_user,
+            name,
+        } => {
+            db.put_authors(&[Author {
+                git_email: email,
+                cvs_user,
+                display_name: name,
+            }])?;
+        }
+        Action::List { format } => {
+            let authors = db.authors()?;
+            match format {
+                Format::Table => print!(
+                    "{}",
+                    output::table(
+                        &["git email", "cvs user", "name"],
+                        &authors
+                            .into_iter()
+                            .map(|author| {
+                                vec![
+                                    author.git_email,
+                                    author.cvs_user,
+                                    author.display_name.unwrap_or_default(),
+                                ]
+                            })
+                            .collect::<Vec<_>>(),
+                    )
+                ),
+                Format::Json => println!(
+                    "{}",
+                    Value::Array(
+                        authors
+                            .into_iter()
+                            .map(|author| Value::object(vec![
+                                ("git_email", author.git_email.into()),
+                                ("cvs_user", author.cvs_user.into()),
+                                ("display_name", author.display_name.into()),
+                            ]))
+                            .collect()
+                    )
+                ),
+            }
+        }
+        Action::Remove { email } => {
+            if !db.remove_author(&email)? {
+                anyhow::bail!("author {} is not mapped", email);
+            }
+        }
+        Action::Import { file } => {
+            let count = db.put_authors(&read_file(&file)?)?;
+            println!("imported {} authors from {}", count, file.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// The authors known for an export: those in the database, plus any only
+/// found in an author file.
+#[derive(Debug, Default)]
+pub struct AuthorMap {
+    authors: HashMap<String, Author>,
+}
+
+impl AuthorMap {
+    pub fn load(db: &Database, file: Option<&Path>) -> anyhow::Result<Self> {
+        let mut authors = HashMap::new();
+        let from_file = match file {
+            Some(file) => read_file(file)?,
+            None => Vec::new(),
+        };
+
+        // Database entries win over the file.
+        for author in from_file.into_iter().chain(db.authors()?) {
+            authors.insert(author.git_email.to_lowercase(), author);
+        }
+
+        Ok(Self { authors })
+    }
+
+    pub fn get(&self, email: &str) -> Option<&Author> {
+        self.authors.get(&email.to_lowercase())
+    }
+
+    /// Returns the author emails in the given commits that aren't mapped.
+    pub fn unmapped<'a, I>(&self, commits: I) -> Vec<String>
+    where
+        I: IntoIterator<Item = &'a Commit>,
+    {
+        commits
+            .into_iter()
+            .map(|commit| commit.author_email())
+            .filter(|email| self.get(email).is_none())
+            .map(String::from)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Returns the given message with a line identifying the git author of the
+    /// commit appended.
+    pub fn annotate(&self, commit: &Commit, mut message: Vec<u8>) -> Vec<u8> {
+        let name = self
+            .get(commit.author_email())
+            .and_then(|author| author.display_name.as_deref())
+            .unwrap_or_else(|| commit.author_name());
+
+        while message.last() == Some(&b'\n') {
+            message.pop();
+        }
+        message.extend_from_slice(
+            format!("\n\nGit-Author: {} <{}>\n", name, commit.author_email()).as_bytes(),
+        );
+        message
+    }
+}
+
+fn read_file(path: &Path) -> anyhow::Result<Vec<Author>> {
+    let file = File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+    parse(BufReader::new(file)).with_context(|| format!("cannot parse {}", path.display()))
+}
+
+/// Parses an author file, in the same format as `git cvsimport -A`:
+///
+/// ```text
+/// cvs_user=Display Name <git email>
+/// ```
+///
+/// The reverse, `Display Name <git email>=cvs_user`, is also accepted. Blank
+/// lines and lines starting with # are ignored.
+fn parse<R: BufRead>(reader: R) -> anyhow::Result<Vec<Author>> {
+    let mut authors = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parsed = line.split_once('=').and_then(|(left, right)| {
+            let (cvs_user, rest) = if left.trim().ends_with('>') {
+                (right, left)
+            } else {
+                (left, right)
+            };
+            let (name, email) = rest.trim().strip_suffix('>')?.rsplit_once('<')?;
+            Some(Author {
+                git_email: email.trim().into(),
+                cvs_user: cvs_user.trim().into(),
+                display_name: Some(name.trim())
+                    .filter(|name| !name.is_empty())
+                    .map(String::from),
+            })
+        });
+        match parsed {
+            Some(author) if !author.cvs_user.is_empty() && !author.git_email.is_empty() => {
+                authors.push(author)
+            }
+            _ => anyhow::bail!("line {}: expected cvs_user=Name <email>", i + 1),
+        }
+    }
+
+    Ok(authors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            vec![
+                Author {
+                    git_email: "alice@example.com".into(),
+                    cvs_user: "alice".into(),
+                    display_name: Some("Alice Smith".into()),
+                },
+                Author {
+                    git_email: "bob@example.com".into(),
+                    cvs_user: "bob".into(),
+                    display_name: None,
+                },
+            ],
+            parse(
+                &b"# comment\nalice=Alice Smith <alice@example.com>\n\n bob = <bob@example.com>\n"
+                    [..]
+            )
+            .unwrap()
+        );
+
+        assert_eq!(
+            vec![Author {
+                git_email: "carol@example.com".into(),
+                cvs_user: "carol".into(),
+                display_name: Some("Carol Jones".into()),
+            }],
+            parse(&b"Carol Jones <carol@example.com> = carol\n"[..]).unwrap()
+        );
+
+        assert!(parse(&b"alice\n"[..]).is_err());
+        assert!(parse(&b"=Alice <alice@example.com>\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_database_overrides_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("authors");
+        std::fs::write(
+            &file,
+            "alice=Alice <alice@example.com>\nbob=Bob <bob@example.com>\n",
+        )
+        .unwrap();
+
+        let mut db = Database::open(":memory:").unwrap();
+        db.put_authors(&[Author {
+            git_email: "Alice@Example.com".into(),
+            cvs_user: "asmith".into(),
+            display_name: None,
+        }])
+        .unwrap();
+
+        let map = AuthorMap::load(&db, Some(&file)).unwrap();
+        assert_eq!("asmith", map.get("alice@example.com").unwrap().cvs_user);
+        assert_eq!("bob", map.get("BOB@example.com").unwrap().cvs_user);
+        assert!(map.get("carol@example.com").is_none());
+
+        assert!(db.remove_author("alice@example.com").unwrap());
+        assert!(!db.remove_author("alice@example.com").unwrap());
+        assert_eq!(
+            "alice",
+            AuthorMap::load(&db, Some(&file))
+                .unwrap()
+                .get("alice@example.com")
+                .unwrap()
+                .cvs_user
+        );
+    }
+}
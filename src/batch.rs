@@ -0,0 +1,303 @@
+use std::{ffi::OsString, fs, path::PathBuf};
+
+use anyhow::Context;
+use structopt::StructOpt;
+
+use crate::{
+    config, output,
+    push::{self, Outcome, PushOpt},
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(long, help = "TOML file describing the exports to run")]
+    jobs: PathBuf,
+
+    #[structopt(long, help = "don't run any more jobs once one has failed")]
+    fail_fast: bool,
+
+    #[structopt(
+        short,
+        long,
+        parse(from_occurrences),
+        help = "log more detail about each commit; may be given twice"
+    )]
+    pub verbose: u8,
+}
+
+/// A push described by the job file.
+#[derive(Debug)]
+struct Job {
+    name: String,
+    retry: bool,
+    opt: PushOpt,
+}
+
+/// How a job ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Status {
+    Finished,
+    Stopped,
+    Failed(String),
+    NotRun,
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<Outcome> {
+    let content = fs::read_to_string(&opt.jobs)
+        .with_context(|| format!("cannot read job file {}", opt.jobs.display()))?;
+    let jobs = parse_jobs(&content, &opt.database)
+        .with_context(|| format!("invalid job file {}", opt.jobs.display()))?;
+
+    let results = run_jobs(jobs, opt.fail_fast);
+    print!(
+        "{}",
+        output::table(
+            &["job", "status", "detail"],
+            &results
+                .iter()
+                .map(|(name, status)| {
+                    let (status, detail) = match status {
+                        Status::Finished => ("finished", ""),
+                        Status::Stopped => ("stopped", "run again with retry = true to continue"),
+                        Status::Failed(error) => ("failed", error.as_str()),
+                        Status::NotRun => ("not run", ""),
+                    };
+                    vec![name.clone(), status.to_string(), detail.to_string()]
+                })
+                .collect::<Vec<_>>(),
+        )
+    );
+
+    let failed = results
+        .iter()
+        .filter(|(_, status)| matches!(status, Status::Failed(_)))
+        .count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} jobs failed", failed, results.len());
+    }
+    if results.iter().any(|(_, status)| *status == Status::Stopped) {
+        return Ok(Outcome::Stopped);
+    }
+    Ok(Outcome::Finished)
+}
+
+/// Runs each job in turn, returning the name and status of every job.
+fn run_jobs(jobs: Vec<Job>, fail_fast: bool) -> Vec<(String, Status)> {
+    let mut results = Vec::new();
+    let mut failed = false;
+    for job in jobs {
+        if failed && fail_fast {
+            results.push((job.name, Status::NotRun));
+            continue;
+        }
+
+        log::info!("running job {}", job.name);
+        let status = match push::run(job.opt, job.retry) {
+            Ok(Outcome::Finished) => Status::Finished,
+            Ok(Outcome::Stopped) => Status::Stopped,
+            Err(e) => {
+                log::error!("job {} failed: {:#}", job.name, e);
+                failed = true;
+                Status::Failed(format!("{:#}", e))
+            }
+        };
+        results.push((job.name, status));
+    }
+
+    results
+}
+
+/// Parses a job file.
+///
+/// Each `[[job]]` table is a push: its keys are the long names of the push
+/// options, with string, integer, boolean, or array values. Keys in an
+/// optional `[defaults]` table apply to every job unless the job sets them
+/// itself. `name` labels the job in the summary, and `retry = true` runs it as
+/// retry-failures.
+fn parse_jobs(content: &str, database: &OsString) -> anyhow::Result<Vec<Job>> {
+    let mut file: toml::value::Table = toml::from_str(content)?;
+    let defaults = match file.remove("defaults") {
+        Some(toml::Value::Table(defaults)) => defaults,
+        Some(_) => anyhow::bail!("defaults must be a table"),
+        None => toml::value::Table::new(),
+    };
+    let jobs = match file.remove("job") {
+        Some(toml::Value::Array(jobs)) if !jobs.is_empty() => jobs,
+        Some(_) | None => anyhow::bail!("no [[job]] tables"),
+    };
+    if let Some(key) = file.keys().next() {
+        anyhow::bail!("unknown top level key {}", key);
+    }
+
+    jobs.into_iter()
+        .enumerate()
+        .map(|(i, job)| {
+            let job = match job {
+                toml::Value::Table(job) => job,
+                _ => anyhow::bail!("job {} is not a table", i + 1),
+            };
+            let mut options = defaults.clone();
+            options.extend(job);
+            parse_job(options, database).with_context(|| format!("job {}", i + 1))
+        })
+        .collect()
+}
+
+fn parse_job(mut options: toml::value::Table, database: &OsString) -> anyhow::Result<Job> {
+    let name = match options.remove("name") {
+        Some(toml::Value::String(name)) => Some(name),
+        Some(_) => anyhow::bail!("name must be a string"),
+        None => None,
+    };
+    let retry = match options.remove("retry") {
+        Some(toml::Value::Boolean(retry)) => retry,
+        Some(_) => anyhow::bail!("retry must be a boolean"),
+        None => false,
+    };
+
+    let mut args: Vec<OsString> = vec!["git2cvs".into(), "push".into()];
+    for (key, value) in options.iter() {
+        let flag = format!("--{}", key.replace('_', "-"));
+        if flag == "--database" {
+            anyhow::bail!("the database is shared by every job; pass it to batch instead");
+        }
+        if flag == "--config" || flag == "--print-config" {
+            anyhow::bail!("{} cannot be set in a job", key);
+        }
+        args.extend(
+            config::option_args(&flag, value).map_err(|e| anyhow::anyhow!("{} {}", key, e))?,
+        );
+    }
+    args.push("--database".into());
+    args.push(database.clone());
+
+    let opt = match crate::parse_args(args) {
+        Ok(crate::Opt::Push(opt)) => opt,
+        Ok(_) => unreachable!(),
+        Err(e) => anyhow::bail!(
+            "{}",
+            e.message
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .trim_start_matches("error: ")
+        ),
+    };
+    let name = name.unwrap_or_else(|| match opt.branches.as_slice() {
+        [] => opt.git.to_string_lossy().into_owned(),
+        branches => format!("{}:{}", opt.git.to_string_lossy(), branches.join(",")),
+    });
+
+    Ok(Job { name, retry, opt })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_jobs() {
+        let database = OsString::from("/tmp/git2cvs.db");
+        let jobs = parse_jobs(
+            r#"
+            [defaults]
+            cvsroot = ":local:/srv/cvs"
+            annotate-authors = true
+
+            [[job]]
+            name = "library"
+            git = "/srv/git/lib.git"
+            branch = "main"
+            module = "lib"
+            include = ["src", "docs"]
+            object_cache_size = 16
+
+            [[job]]
+            git = "/srv/git/tools.git"
+            cvsroot = ":local:/srv/other"
+            annotate-authors = false
+            retry = true
+            "#,
+            &database,
+        )
+        .unwrap();
+
+        assert_eq!(2, jobs.len());
+        let lib = &jobs[0];
+        assert_eq!("library", lib.name);
+        assert!(!lib.retry);
+        assert_eq!("/srv/cvs", lib.opt.cvsroot().path());
+        assert!(lib.opt.annotate_authors);
+        assert_eq!("lib", lib.opt.module);
+        assert_eq!(
+            vec![PathBuf::from("src"), PathBuf::from("docs")],
+            lib.opt.include
+        );
+        assert_eq!(16, lib.opt.object_cache_size);
+        assert_eq!(database, lib.opt.database);
+
+        let tools = &jobs[1];
+        assert_eq!("/srv/git/tools.git", tools.name);
+        assert!(tools.retry);
+        assert_eq!("/srv/other", tools.opt.cvsroot().path());
+        assert!(!tools.opt.annotate_authors);
+        assert_eq!(Path::new("src"), tools.opt.target);
+    }
+
+    #[test]
+    fn test_parse_jobs_invalid() {
+        let database = OsString::from("/tmp/git2cvs.db");
+        let error = |content: &str| format!("{:#}", parse_jobs(content, &database).unwrap_err());
+
+        assert!(error("").contains("no [[job]] tables"));
+        assert!(error("[[job]]\ngit = \"a\"\ncvsroot = \"/cvs\"\n[other]\n").contains("other"));
+        assert!(
+            error("[[job]]\ngit = \"a\"\ncvsroot = \"/cvs\"\ndatabase = \"b\"\n")
+                .contains("shared by every job")
+        );
+        assert!(
+            error("[[job]]\ngit = \"a\"\ncvsroot = \"/cvs\"\nmodule = 1.5\n")
+                .contains("job 1: module must be")
+        );
+        assert!(
+            error("[[job]]\ngit = \"a\"\ncvsroot = \"/cvs\"\n[[job]]\ncvsroot = \"/cvs\"\nnonsense = true\n")
+                .starts_with("job 2: ")
+        );
+    }
+
+    #[test]
+    fn test_run_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("git2cvs.db").into_os_string();
+        let content = format!(
+            "[defaults]\ncvsroot = \":local:{}\"\n[[job]]\nname = \"one\"\ngit = \"{}\"\n[[job]]\nname = \"two\"\ngit = \"{}\"\n",
+            dir.path().join("cvs").display(),
+            dir.path().join("missing-one").display(),
+            dir.path().join("missing-two").display(),
+        );
+
+        // Neither job has a git repository, so both fail, unless the first
+        // failure stops the batch.
+        let results = run_jobs(parse_jobs(&content, &database).unwrap(), false);
+        assert_eq!(
+            vec!["one", "two"],
+            results
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(results
+            .iter()
+            .all(|(_, status)| matches!(status, Status::Failed(_))));
+
+        let results = run_jobs(parse_jobs(&content, &database).unwrap(), true);
+        assert!(matches!(results[0].1, Status::Failed(_)));
+        assert_eq!(Status::NotRun, results[1].1);
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How many of the most recent commits are used to estimate how long the next
+/// one will take.
+const WINDOW: usize = 10;
+
+/// Keeps track of how much of a --max-runtime budget is left.
+#[derive(Debug)]
+pub struct Budget {
+    start: Instant,
+    max: Duration,
+    recent: VecDeque<Duration>,
+}
+
+impl Budget {
+    pub fn new(max: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            max,
+            recent: VecDeque::with_capacity(WINDOW),
+        }
+    }
+
+    /// Records how long a commit took.
+    pub fn record(&mut self, duration: Duration) {
+        if self.recent.len() == WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(duration);
+    }
+
+    /// The average time taken by the most recent commits.
+    pub fn estimate(&self) -> Option<Duration> {
+        if self.recent.is_empty() {
+            None
+        } else {
+            Some(self.recent.iter().sum::<Duration>() / self.recent.len() as u32)
+        }
+    }
+
+    /// Returns true if the next commit is likely to finish within the budget.
+    pub fn allows_next(&self) -> bool {
+        self.allows_next_at(self.start.elapsed())
+    }
+
+    fn allows_next_at(&self, elapsed: Duration) -> bool {
+        elapsed + self.estimate().unwrap_or_default() < self.max
+    }
+}
+
+/// Parses a duration such as 90, 90s, 15m, or 4h.
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number: u64 = match number.parse() {
+        Ok(number) => number,
+        Err(_) => anyhow::bail!("invalid duration {}", s),
+    };
+
+    let seconds = match unit {
+        "s" => number,
+        "m" => number * 60,
+        "h" => number * 60 * 60,
+        _ => anyhow::bail!("invalid duration {}: the unit must be s, m, or h", s),
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Duration::from_secs(90), parse_duration("90").unwrap());
+        assert_eq!(Duration::from_secs(90), parse_duration("90s").unwrap());
+        assert_eq!(Duration::from_secs(900), parse_duration("15m").unwrap());
+        assert_eq!(Duration::from_secs(4 * 3600), parse_duration("4h").unwrap());
+
+        for invalid in &["", "h", "4d", "1.5h", "-1m"] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_allows_next() {
+        let mut budget = Budget::new(Duration::from_secs(100));
+        assert!(budget.allows_next_at(Duration::from_secs(99)));
+        assert!(!budget.allows_next_at(Duration::from_secs(100)));
+
+        budget.record(Duration::from_secs(30));
+        budget.record(Duration::from_secs(10));
+        assert_eq!(Some(Duration::from_secs(20)), budget.estimate());
+        assert!(budget.allows_next_at(Duration::from_secs(79)));
+        assert!(!budget.allows_next_at(Duration::from_secs(80)));
+
+        // Only the most recent commits count.
+        for _ in 0..WINDOW {
+            budget.record(Duration::from_secs(1));
+        }
+        assert_eq!(Some(Duration::from_secs(1)), budget.estimate());
+    }
+}
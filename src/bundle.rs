@@ -0,0 +1,177 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use git2::Oid;
+use subprocess::{Exec, ExitStatus};
+
+const SIGNATURES: &[&str] = &["# v2 git bundle", "# v3 git bundle"];
+
+/// A git bundle file, as produced by `git bundle create`.
+#[derive(Debug)]
+pub struct Bundle {
+    path: PathBuf,
+    prerequisites: Vec<Oid>,
+}
+
+impl Bundle {
+    /// Opens the given path as a bundle, returning None if it isn't one.
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Option<Self>> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let reader = BufReader::new(
+            File::open(path).with_context(|| format!("cannot open {}", path.display()))?,
+        );
+        let prerequisites = match parse_header(reader)? {
+            Some(prerequisites) => prerequisites,
+            None => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            path: path.into(),
+            prerequisites,
+        }))
+    }
+
+    /// The commits that must already exist for a thin bundle to be usable.
+    pub fn prerequisites(&self) -> &[Oid] {
+        &self.prerequisites
+    }
+
+    /// Creates a new bare repository at the given path, and fetches every ref
+    /// in the bundle into it.
+    ///
+    /// libgit2 doesn't understand bundles, so we have to shell out to git.
+    pub fn unbundle<P: AsRef<Path>>(&self, git: &OsStr, target: P) -> anyhow::Result<()> {
+        let target = target.as_ref();
+        git2::Repository::init_bare(target)
+            .with_context(|| format!("cannot create repository at {}", target.display()))?;
+
+        let status = Exec::cmd(git)
+            .arg("fetch")
+            .arg("--quiet")
+            .arg(&self.path)
+            .arg("+refs/*:refs/*")
+            .cwd(target)
+            .join()?;
+        if status != ExitStatus::Exited(0) {
+            anyhow::bail!(
+                "cannot fetch from bundle {}: git exited with {:?}",
+                self.path.display(),
+                status
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_header<R: BufRead>(reader: R) -> anyhow::Result<Option<Vec<Oid>>> {
+    let mut lines = reader.split(b'\n');
+
+    let signature = match lines.next() {
+        Some(line) => line?,
+        None => return Ok(None),
+    };
+    if !SIGNATURES.iter().any(|sig| sig.as_bytes() == signature) {
+        return Ok(None);
+    }
+
+    // After the signature come optional capabilities (v3 only), then
+    // prerequisites, then the refs, and finally a blank line before the pack.
+    let mut prerequisites = Vec::new();
+    for line in lines {
+        let line = line?;
+        if line.is_empty() {
+            return Ok(Some(prerequisites));
+        }
+
+        if let Some(prerequisite) = line.strip_prefix(b"-") {
+            let hex = prerequisite
+                .split(|c| *c == b' ')
+                .next()
+                .unwrap_or_default();
+            prerequisites.push(Oid::from_str(&String::from_utf8_lossy(hex))?);
+        }
+    }
+
+    anyhow::bail!("truncated bundle header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(None, parse_header(&b"PACK\0\0\0\x02"[..]).unwrap());
+
+        assert_eq!(
+            Some(vec![]),
+            parse_header(
+                &b"# v2 git bundle\n0123456789012345678901234567890123456789 refs/heads/main\n\nPACK"[..]
+            )
+            .unwrap()
+        );
+
+        assert_eq!(
+            Some(vec![
+                Oid::from_str("1111111111111111111111111111111111111111").unwrap()
+            ]),
+            parse_header(
+                &b"# v3 git bundle\n@object-format=sha1\n-1111111111111111111111111111111111111111 parent commit\n0123456789012345678901234567890123456789 refs/heads/main\n\nPACK"[..]
+            )
+            .unwrap()
+        );
+
+        assert!(parse_header(&b"# v2 git bundle\n-1111"[..]).is_err());
+    }
+
+    #[test]
+    fn test_unbundle() {
+        let source = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(source.path()).unwrap();
+        let tree = raw
+            .find_tree(raw.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+        let oid = raw
+            .commit(Some("refs/heads/main"), &sig, &sig, "initial", &tree, &[])
+            .unwrap();
+
+        let bundle_path = source.path().join("test.bundle");
+        Exec::cmd("git")
+            .arg("bundle")
+            .arg("create")
+            .arg(&bundle_path)
+            .arg("main")
+            .cwd(source.path())
+            .join()
+            .unwrap();
+
+        let bundle = Bundle::open(&bundle_path).unwrap().unwrap();
+        assert!(bundle.prerequisites().is_empty());
+        assert!(Bundle::open(source.path()).unwrap().is_none());
+
+        let target = tempfile::tempdir().unwrap();
+        bundle.unbundle(OsStr::new("git"), target.path()).unwrap();
+
+        let unbundled = git2::Repository::open_bare(target.path()).unwrap();
+        assert_eq!(
+            oid,
+            unbundled
+                .find_branch("main", git2::BranchType::Local)
+                .unwrap()
+                .get()
+                .target()
+                .unwrap()
+        );
+    }
+}
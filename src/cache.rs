@@ -0,0 +1,111 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::Hash,
+};
+
+/// A simple least recently used cache with a fixed entry budget.
+///
+/// A budget of zero disables the cache entirely, which is mostly useful for
+/// checking that the cache isn't changing behaviour.
+#[derive(Debug)]
+pub struct Lru<K, V> {
+    budget: usize,
+    entries: HashMap<K, (V, u64)>,
+    recency: BTreeMap<u64, K>,
+    tick: u64,
+    stats: Stats,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} hits, {} misses", self.hits, self.misses)
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Lru<K, V> {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some((value, tick)) => {
+                self.recency.remove(tick);
+                self.recency.insert(self.tick, key.clone());
+                *tick = self.tick;
+
+                self.stats.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.budget == 0 {
+            return;
+        }
+
+        self.tick += 1;
+        if let Some((_, tick)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.recency.remove(&tick);
+        }
+        self.recency.insert(self.tick, key);
+
+        while self.entries.len() > self.budget {
+            let oldest = *self.recency.keys().next().unwrap();
+            let key = self.recency.remove(&oldest).unwrap();
+            self.entries.remove(&key);
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(2);
+        lru.insert(1, "one");
+        lru.insert(2, "two");
+        assert_eq!(Some("one"), lru.get(&1));
+
+        // 2 is now the least recently used entry, so it should be evicted.
+        lru.insert(3, "three");
+        assert_eq!(None, lru.get(&2));
+        assert_eq!(Some("one"), lru.get(&1));
+        assert_eq!(Some("three"), lru.get(&3));
+
+        assert_eq!(Stats { hits: 3, misses: 1 }, lru.stats());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut lru = Lru::new(0);
+        lru.insert(1, "one");
+        assert_eq!(None, lru.get(&1));
+        assert_eq!(Stats { hits: 0, misses: 1 }, lru.stats());
+    }
+}
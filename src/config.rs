@@ -0,0 +1,523 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use structopt::StructOpt;
+
+use crate::cvs;
+
+/// The options that are handled here, rather than being options that can be
+/// set in the file.
+const CONFIG: &str = "config";
+const PRINT_CONFIG: &str = "print-config";
+
+/// An option that a command takes, as the config file sees it.
+#[derive(Debug)]
+struct Spec {
+    /// The name clap knows the option by, which is usually its long name.
+    name: &'static str,
+    long: &'static str,
+    short: Option<char>,
+    env: Option<&'static str>,
+    takes_value: bool,
+    multiple: bool,
+}
+
+impl Spec {
+    const fn flag(long: &'static str) -> Self {
+        Spec {
+            name: long,
+            long,
+            short: None,
+            env: None,
+            takes_value: false,
+            multiple: false,
+        }
+    }
+
+    const fn value(long: &'static str) -> Self {
+        Spec {
+            takes_value: true,
+            ..Spec::flag(long)
+        }
+    }
+
+    const fn named(self, name: &'static str) -> Self {
+        Spec { name, ..self }
+    }
+
+    const fn short(self, short: char) -> Self {
+        Spec {
+            short: Some(short),
+            ..self
+        }
+    }
+
+    const fn env(self, env: &'static str) -> Self {
+        Spec {
+            env: Some(env),
+            ..self
+        }
+    }
+
+    const fn multiple(self) -> Self {
+        Spec {
+            multiple: true,
+            ..self
+        }
+    }
+}
+
+/// The options of PushOpt. test_options checks this against the options
+/// clap knows about, so add new push options here too.
+const PUSH_OPTIONS: &[Spec] = &[
+    Spec::flag("keep-workdir"),
+    Spec::flag("dry-run"),
+    Spec::flag("quiet").short('q'),
+    Spec::flag("remote").short('r'),
+    Spec::flag("continue-on-error"),
+    Spec::flag("resume"),
+    Spec::flag("write-cvswrappers"),
+    Spec::flag("allow-existing-content"),
+    Spec::flag("merge-existing"),
+    Spec::flag("transcode-utf16"),
+    Spec::flag("clamp-timestamps"),
+    Spec::flag("allow-empty"),
+    Spec::flag("paranoid"),
+    Spec::flag("annotate-authors"),
+    Spec::flag("no-mailmap"),
+    Spec::flag("sha-trailer"),
+    Spec::flag("no-sha-trailer"),
+    Spec::flag("preserve-authors"),
+    Spec::flag("strict-authors"),
+    Spec::flag("verbose").short('v').multiple(),
+    Spec::flag("print-config"),
+    Spec::flag("allow-shallow"),
+    Spec::flag("fetch"),
+    Spec::flag("recurse-submodules"),
+    Spec::flag("rename-cvs-dirs"),
+    Spec::flag("cvs-dirs-ignore-case"),
+    Spec::flag("clean-strays"),
+    Spec::value("branch")
+        .named("branches")
+        .short('b')
+        .multiple(),
+    Spec::value("rev"),
+    Spec::value("cvs"),
+    Spec::value("allow-cvs-exit").multiple(),
+    Spec::value("cvs-output"),
+    Spec::value("cvs-retries"),
+    Spec::value("cvs-retry-delay"),
+    Spec::value("cvsroot").short('c').env("CVSROOT"),
+    Spec::value("database").short('d'),
+    Spec::value("git").short('g'),
+    Spec::value("git-binary"),
+    Spec::value("object-cache-size"),
+    Spec::value("module").short('m'),
+    Spec::value("target").short('t'),
+    Spec::value("include").multiple(),
+    Spec::value("exclude").multiple(),
+    Spec::value("prefix"),
+    Spec::value("path-map"),
+    Spec::value("permissions-manifest"),
+    Spec::value("skip-commit").multiple(),
+    Spec::value("binary-glob").multiple(),
+    Spec::value("text-glob").multiple(),
+    Spec::value("keyword-mode"),
+    Spec::value("keyword-glob").multiple(),
+    Spec::value("cvs-branch"),
+    Spec::value("trunk"),
+    Spec::value("arg-max"),
+    Spec::value("sanitise"),
+    Spec::value("verify-sample"),
+    Spec::value("dirty-workdir"),
+    Spec::value("authors"),
+    Spec::value("message-template"),
+    Spec::value("default-author"),
+    Spec::value("config"),
+    Spec::value("max-runtime"),
+    Spec::value("max-commits"),
+    Spec::value("since-commit"),
+    Spec::value("report-junit"),
+    Spec::value("watch"),
+    Spec::value("watch-max-failures"),
+    Spec::value("stop-file"),
+    Spec::value("symlinks"),
+    Spec::value("submodules"),
+    Spec::value("case-collisions"),
+    Spec::value("cvs-dir-substitute"),
+    Spec::value("keep-stray").multiple(),
+    Spec::value("max-component-length"),
+    Spec::value("max-path-length"),
+    Spec::value("max-path-depth"),
+];
+
+/// The options hook takes on top of the push options.
+const HOOK_OPTIONS: &[Spec] = &[Spec::value("lock-timeout")];
+
+/// Returns the command the arguments run, if clap can make sense of them.
+fn command_name(args: &[OsString]) -> Option<String> {
+    let matches = crate::Args::clap().get_matches_from_safe(args).ok()?;
+    matches.subcommand_name().map(str::to_string)
+}
+
+/// Returns the options of a command that takes push options.
+fn command_specs(command: &str) -> Option<Vec<&'static Spec>> {
+    match command {
+        "push" | "retry-failures" => Some(PUSH_OPTIONS.iter().collect()),
+        "hook" => Some(PUSH_OPTIONS.iter().chain(HOOK_OPTIONS).collect()),
+        _ => None,
+    }
+}
+
+/// Adds the options in the file given with --config to a command's
+/// arguments. Options given on the command line take precedence over the
+/// file, as do options with environment variables that are set.
+pub fn apply(args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let specs = match command_name(&args).as_deref().and_then(command_specs) {
+        Some(specs) => specs,
+        None => return Ok(args),
+    };
+    let given = given_options(&specs, &args[1..]);
+    let path = match given
+        .iter()
+        .find(|(long, _)| long == CONFIG)
+        .and_then(|(_, value)| value.clone())
+    {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(args),
+    };
+
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("cannot read config file {}", path.display()))?;
+    let table: toml::value::Table = toml::from_str(&content)
+        .with_context(|| format!("invalid config file {}", path.display()))?;
+
+    let mut expanded = Vec::new();
+    for (key, value) in &table {
+        let long = key.replace('_', "-");
+        let spec = match specs.iter().find(|spec| spec.long == long) {
+            Some(spec) if long != CONFIG && long != PRINT_CONFIG => spec,
+            _ => anyhow::bail!("{}: unknown option {}", path.display(), key),
+        };
+        if given.iter().any(|(given, _)| *given == long) {
+            continue;
+        }
+        if let Some(env) = &spec.env {
+            if std::env::var_os(env).is_some() {
+                continue;
+            }
+        }
+
+        expanded.extend(
+            option_args(&format!("--{}", long), value)
+                .with_context(|| format!("{}: invalid option {}", path.display(), key))?,
+        );
+    }
+    // The file's options go after the command's, but before any --.
+    let end = args
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len());
+    Ok(args[..end]
+        .iter()
+        .cloned()
+        .chain(expanded)
+        .chain(args[end..].iter().cloned())
+        .collect())
+}
+
+/// Turns a TOML value into command line arguments for the given flag. Arrays
+/// repeat the flag for each element, and booleans give the flag alone if
+/// they're true.
+pub fn option_args(flag: &str, value: &toml::Value) -> anyhow::Result<Vec<OsString>> {
+    let values = match value {
+        toml::Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+    };
+
+    let mut args: Vec<OsString> = Vec::new();
+    for value in values {
+        match value {
+            toml::Value::Boolean(true) => args.push(flag.into()),
+            toml::Value::Boolean(false) => {}
+            toml::Value::String(s) => {
+                args.push(flag.into());
+                args.push(s.into());
+            }
+            toml::Value::Integer(n) => {
+                args.push(flag.into());
+                args.push(n.to_string().into());
+            }
+            _ => anyhow::bail!("must be a string, integer, or boolean"),
+        }
+    }
+
+    Ok(args)
+}
+
+/// Returns the long name of each option given in the arguments, along with
+/// its value if it takes one.
+fn given_options(specs: &[&Spec], args: &[OsString]) -> Vec<(String, Option<OsString>)> {
+    let mut given = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+
+        if let Some(long) = arg.strip_prefix("--") {
+            let (long, inline) = match long.split_once('=') {
+                Some((long, value)) => (long, Some(OsString::from(value))),
+                None => (long, None),
+            };
+            if let Some(spec) = specs.iter().find(|spec| spec.long == long) {
+                let value = match inline {
+                    Some(value) => Some(value),
+                    None if spec.takes_value => args.next().cloned(),
+                    None => None,
+                };
+                given.push((spec.long.to_string(), value));
+            }
+        } else if let Some(shorts) = arg.strip_prefix('-') {
+            // Short flags can be run together, and the last can be an option
+            // with its value attached.
+            for (i, short) in shorts.char_indices() {
+                let spec = match specs.iter().find(|spec| spec.short == Some(short)) {
+                    Some(spec) => spec,
+                    None => break,
+                };
+                if spec.takes_value {
+                    let rest = &shorts[i + short.len_utf8()..];
+                    let value = match rest.strip_prefix('=').unwrap_or(rest) {
+                        "" => args.next().cloned(),
+                        rest => Some(rest.into()),
+                    };
+                    given.push((spec.long.to_string(), value));
+                    break;
+                }
+                given.push((spec.long.to_string(), None));
+            }
+        }
+    }
+
+    given
+}
+
+/// Prints the options a command would run with, after merging the config
+/// file, the environment, and the defaults, in the config file's format.
+pub fn print(args: &[OsString], config: Option<&Path>) -> anyhow::Result<()> {
+    let matches = crate::Args::clap().get_matches_from_safe(args)?;
+    let (command, matches) = matches.subcommand();
+    let (specs, matches) = match (command_specs(command), matches) {
+        (Some(specs), Some(matches)) => (specs, matches),
+        _ => anyhow::bail!("--print-config can only be given to push, retry-failures, or hook"),
+    };
+
+    let mut table = toml::value::Table::new();
+    for spec in &specs {
+        if matches!(spec.long, CONFIG | PRINT_CONFIG | "help" | "version") {
+            continue;
+        }
+
+        let value = if spec.takes_value {
+            let values: Vec<_> = match matches.values_of_os(spec.name) {
+                Some(values) => values.map(|value| printable(spec, value)).collect(),
+                None => continue,
+            };
+            if spec.multiple {
+                toml::Value::Array(values)
+            } else {
+                match values.into_iter().next() {
+                    Some(value) => value,
+                    None => continue,
+                }
+            }
+        } else {
+            match matches.occurrences_of(spec.name) {
+                count if spec.multiple && count > 1 => toml::Value::Integer(count as i64),
+                count => toml::Value::Boolean(count > 0),
+            }
+        };
+        table.insert(spec.long.to_string(), value);
+    }
+
+    if let Some(config) = config {
+        println!("# merged with {}", config.display());
+    }
+    print!("{}", toml::Value::Table(table));
+    Ok(())
+}
+
+fn printable(spec: &Spec, value: &OsStr) -> toml::Value {
+    let value = value.to_string_lossy();
+    // A pserver password shouldn't end up in a terminal or a log.
+    if spec.long == "cvsroot" {
+        if let Ok(cvsroot) = value.parse::<cvs::CvsRoot>() {
+            return toml::Value::String(cvsroot.to_string());
+        }
+    }
+    toml::Value::String(value.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_options() {
+        use structopt::clap::ErrorKind;
+
+        for command in ["push", "hook"] {
+            let specs = command_specs(command).unwrap();
+
+            // Every option in the table is one clap knows, and takes a value
+            // if the table says it does: a flag followed by a value leaves the
+            // value as an argument clap doesn't expect.
+            let kind = |args: &[&str]| {
+                crate::Args::clap()
+                    .get_matches_from_safe(["git2cvs", command].iter().chain(args))
+                    .map_err(|err| err.kind)
+                    .err()
+            };
+            for spec in &specs {
+                let long = format!("--{}", spec.long);
+                let short = spec.short.map(|short| format!("-{}", short));
+                for arg in std::iter::once(&long).chain(&short) {
+                    assert_ne!(Some(ErrorKind::UnknownArgument), kind(&[arg]), "{}", arg);
+                    assert_eq!(
+                        spec.takes_value,
+                        kind(&[arg, "x"]) != Some(ErrorKind::UnknownArgument),
+                        "{}",
+                        arg
+                    );
+                }
+            }
+
+            // And every option clap knows is in the table.
+            let help = crate::Args::clap()
+                .get_matches_from_safe(["git2cvs", command, "--help"])
+                .unwrap_err();
+            assert_eq!(ErrorKind::HelpDisplayed, help.kind);
+            for word in help.message.split_whitespace() {
+                let long = match word.strip_prefix("--") {
+                    Some(long) => long.trim_end_matches(|c: char| !c.is_ascii_alphanumeric()),
+                    None => continue,
+                };
+                assert!(
+                    matches!(long, "help" | "version")
+                        || specs.iter().any(|spec| spec.long == long),
+                    "{} isn't in the option table",
+                    long
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_given_options() {
+        let given_longs = |args: &[&str]| -> HashSet<String> {
+            let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+            given_options(&command_specs("push").unwrap(), &args)
+                .into_iter()
+                .map(|(long, _)| long)
+                .collect()
+        };
+
+        assert_eq!(
+            ["branch", "cvsroot", "quiet", "module", "exclude"]
+                .iter()
+                .map(|long| long.to_string())
+                .collect::<HashSet<_>>(),
+            given_longs(&[
+                "-b",
+                "main",
+                "--cvsroot=/cvs",
+                "-qm",
+                "lib",
+                "--exclude",
+                "*.o",
+                "--",
+                "--target",
+            ])
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("git2cvs.toml");
+        fs::write(
+            &config,
+            r#"
+            git = "/srv/git/lib.git"
+            module = "lib"
+            target = "from-file"
+            exclude = ["*.o", "*.a"]
+            annotate_authors = true
+            quiet = false
+            "#,
+        )
+        .unwrap();
+
+        let args = |args: &[&str]| -> Vec<OsString> {
+            ["git2cvs", "push"]
+                .iter()
+                .chain(args)
+                .map(OsString::from)
+                .collect()
+        };
+        let expanded = apply(args(&[
+            "--config",
+            config.to_str().unwrap(),
+            "-c",
+            "/cvs",
+            "-d",
+            "db",
+            "-t",
+            "from-cli",
+        ]))
+        .unwrap();
+        let opt = match crate::parse_args(expanded).unwrap() {
+            crate::Opt::Push(opt) => opt,
+            _ => unreachable!(),
+        };
+        assert_eq!(OsString::from("/srv/git/lib.git"), opt.git);
+        assert_eq!("lib", opt.module);
+        assert_eq!(PathBuf::from("from-cli"), opt.target);
+        assert_eq!(2, opt.exclude.len());
+        assert!(opt.annotate_authors);
+        assert!(!opt.quiet);
+
+        // Other commands, and pushes without a config file, are left alone.
+        let status: Vec<OsString> = ["git2cvs", "status", "--config", "x"]
+            .iter()
+            .map(OsString::from)
+            .collect();
+        assert_eq!(status, apply(status.clone()).unwrap());
+        assert_eq!(args(&["-d", "db"]), apply(args(&["-d", "db"])).unwrap());
+
+        fs::write(&config, "modul = \"lib\"\n").unwrap();
+        let message = format!(
+            "{:#}",
+            apply(args(&["--config", config.to_str().unwrap()])).unwrap_err()
+        );
+        assert!(message.ends_with("unknown option modul"), "{}", message);
+
+        fs::write(&config, "config = \"other.toml\"\n").unwrap();
+        assert!(apply(args(&["--config", config.to_str().unwrap()])).is_err());
+
+        fs::write(&config, "module = 1.5\n").unwrap();
+        let message = format!(
+            "{:#}",
+            apply(args(&["--config", config.to_str().unwrap()])).unwrap_err()
+        );
+        assert!(message.contains("invalid option module"), "{}", message);
+    }
+}
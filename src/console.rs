@@ -0,0 +1,203 @@
+use std::io::{self, IsTerminal, Write};
+
+use crate::{
+    events::{Event, Listener, Progress},
+    output,
+};
+
+/// The longest commit subject shown before it's truncated.
+const SUBJECT_WIDTH: usize = 50;
+
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const RED: &str = "31";
+const DIM: &str = "2";
+
+/// Writes one line per commit to standard error, in colour if standard error
+/// is a terminal. Nothing is written unless info level logging is enabled.
+pub struct Console {
+    enabled: bool,
+    color: bool,
+}
+
+impl Console {
+    pub fn stderr() -> Self {
+        Self {
+            enabled: log::log_enabled!(log::Level::Info),
+            color: io::stderr().is_terminal(),
+        }
+    }
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn render(&self, progress: Progress, event: &Event<'_>) -> String {
+        let commit = event.commit();
+        let width = progress.total.to_string().len();
+        let oid = commit.id().to_string();
+
+        let outcome = match event {
+            Event::Exported { stats, .. } => format!(
+                "{} {} {}",
+                self.paint(GREEN, &format!("+{}", stats.files_added)),
+                self.paint(YELLOW, &format!("~{}", stats.files_modified)),
+                self.paint(RED, &format!("-{}", stats.files_removed)),
+            ),
+            Event::Skipped { reason, .. } => self.paint(DIM, &format!("skipped: {}", reason)),
+            Event::Failed { error, .. } => self.paint(RED, &format!("failed: {}", error)),
+        };
+
+        format!(
+            "[{:>width$}/{}] {} {} {}: {} {}",
+            progress.index,
+            progress.total,
+            self.paint(YELLOW, &oid[..8]),
+            output::date(commit.time().seconds()),
+            commit.author_name(),
+            truncate(&commit.summary(), SUBJECT_WIDTH),
+            outcome,
+            width = width
+        )
+    }
+}
+
+impl Listener for Console {
+    fn event(&mut self, progress: Progress, event: &Event<'_>) {
+        if !self.enabled {
+            return;
+        }
+
+        // Losing a progress line isn't worth failing the push over.
+        let _ = writeln!(io::stderr().lock(), "{}", self.render(progress, event));
+    }
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        text.to_string()
+    } else {
+        let mut truncated: String = text.chars().take(width - 3).collect();
+        truncated.push_str("...");
+        truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database::ExportStats, git::Repository};
+
+    #[test]
+    fn test_render() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(dir.path()).unwrap();
+        let tree = raw
+            .find_tree(raw.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let sig = git2::Signature::new(
+            "Alice Smith",
+            "alice@example.com",
+            &git2::Time::new(1_600_000_000, 0),
+        )
+        .unwrap();
+        let first = raw
+            .commit(
+                Some("HEAD"),
+                &sig,
+                &sig,
+                "Add the frobnicator\n\nIt frobs.\n",
+                &tree,
+                &[],
+            )
+            .unwrap();
+        let second = raw
+            .commit(
+                Some("HEAD"),
+                &sig,
+                &sig,
+                "Make the frobnicator considerably faster on very large inputs",
+                &tree,
+                &[&raw.find_commit(first).unwrap()],
+            )
+            .unwrap();
+
+        let repo = Repository::open(dir.path(), 0).unwrap();
+        let first = repo.commit(&first).unwrap();
+        let second = repo.commit(&second).unwrap();
+        let short = |commit: &crate::git::Commit| commit.id().to_string()[..8].to_string();
+        let console = Console {
+            enabled: true,
+            color: false,
+        };
+        let progress = |index| Progress { index, total: 12 };
+
+        let stats = ExportStats {
+            files_added: 2,
+            files_modified: 1,
+            ..ExportStats::default()
+        };
+        assert_eq!(
+            format!(
+                "[ 1/12] {} 2020-09-13 Alice Smith: Add the frobnicator +2 ~1 -0",
+                short(&first)
+            ),
+            console.render(
+                progress(1),
+                &Event::Exported {
+                    commit: &first,
+                    stats: &stats,
+                }
+            )
+        );
+        assert_eq!(
+            format!(
+                "[10/12] {} 2020-09-13 Alice Smith: Make the frobnicator considerably faster on ver... skipped: dataset",
+                short(&second)
+            ),
+            console.render(
+                progress(10),
+                &Event::Skipped {
+                    commit: &second,
+                    reason: "dataset",
+                }
+            )
+        );
+        assert_eq!(
+            format!(
+                "[12/12] {} 2020-09-13 Alice Smith: Add the frobnicator failed: cvs broke",
+                short(&first)
+            ),
+            console.render(
+                progress(12),
+                &Event::Failed {
+                    commit: &first,
+                    error: &anyhow::anyhow!("cvs broke"),
+                    duration: Default::default(),
+                }
+            )
+        );
+
+        let console = Console {
+            enabled: true,
+            color: true,
+        };
+        assert_eq!(
+            format!(
+                "[ 1/12] \x1b[33m{}\x1b[0m 2020-09-13 Alice Smith: Add the frobnicator \x1b[32m+2\x1b[0m \x1b[33m~1\x1b[0m \x1b[31m-0\x1b[0m",
+                short(&first)
+            ),
+            console.render(
+                progress(1),
+                &Event::Exported {
+                    commit: &first,
+                    stats: &stats,
+                }
+            )
+        );
+    }
+}
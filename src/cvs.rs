@@ -1,59 +1,768 @@
 use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     convert::TryFrom,
+    env,
     ffi::{OsStr, OsString},
-    io::Write,
-    path::{Path, PathBuf},
+    fs,
+    io::{self, BufRead, Write},
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
 };
 
-use subprocess::Exec;
+use subprocess::{Exec, ExitStatus, NullFile, Redirection};
 use sysconf::SysconfVariable;
 use tempfile::NamedTempFile;
 
+use crate::error::{Error, Result};
+
 trait ExecExt {
     fn log(self) -> Self;
+
+    /// Runs the command, failing unless it succeeds or exits with one of the
+    /// allowed codes. Errors describe the command as cmdline, which needn't
+    /// show all of its arguments.
+    fn run_as(
+        self,
+        cmdline: &str,
+        operation: Operation,
+        allowed: &[u32],
+        output: Output,
+    ) -> Result<()>;
+
+    /// Runs the command without checking its exit status, returning the last
+    /// lines of its output if that was captured.
+    fn execute(self, operation: Operation, output: Output) -> Result<(ExitStatus, Vec<String>)>;
 }
 
 impl ExecExt for Exec {
     fn log(self) -> Self {
-        log::trace!("{:?}", self.to_cmdline_lossy());
+        log::trace!("{:?}", cmdline(&self));
         self
     }
+
+    fn run_as(
+        self,
+        cmdline: &str,
+        operation: Operation,
+        allowed: &[u32],
+        output: Output,
+    ) -> Result<()> {
+        let (status, tail) = self.execute(operation, output)?;
+        check_status(status, cmdline, allowed, tail)
+    }
+
+    fn execute(self, operation: Operation, output: Output) -> Result<(ExitStatus, Vec<String>)> {
+        if output == Output::Inherit {
+            return Ok((self.join()?, Vec::new()));
+        }
+
+        let mut popen = self
+            .stdout(Redirection::Pipe)
+            .stderr(Redirection::Pipe)
+            .popen()?;
+        let (stdout, stderr) = (popen.stdout.take(), popen.stderr.take());
+        let tail = Mutex::new(VecDeque::new());
+        let forwarded = thread::scope(|scope| {
+            let stderr = scope.spawn(|| forward_output(stderr, operation, log::Level::Warn, &tail));
+            forward_output(stdout, operation, log::Level::Debug, &tail)
+                .and(stderr.join().expect("stderr forwarding panicked"))
+        });
+        let status = popen.wait()?;
+        forwarded?;
+
+        let tail = tail.into_inner().expect("output tail poisoned");
+        Ok((status, tail.into()))
+    }
+}
+
+/// Where a directory that has replaced a file goes while the file is removed.
+fn set_aside_name(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".git2cvs-replaced");
+    PathBuf::from(name)
+}
+
+/// Whether cvs commands are started with SIGINT and SIGTERM ignored.
+static SHIELDED: AtomicBool = AtomicBool::new(false);
+
+/// Starts cvs with SIGINT and SIGTERM ignored. The subprocess crate resets
+/// the signal mask of its children, but an ignored signal stays ignored
+/// across exec.
+const SHIELD_SCRIPT: &str = "trap '' INT TERM; exec \"$0\" \"$@\"";
+
+/// Makes every cvs command started from now on ignore SIGINT and SIGTERM.
+/// Ctrl-C on the terminal signals cvs as well as git2cvs, and a long-running
+/// session that stops cleanly on a signal needs the commit in progress to
+/// finish rather than be cut off.
+pub fn shield_from_stop_signals() {
+    SHIELDED.store(true, Ordering::SeqCst);
+}
+
+fn shielded(cvs: &OsStr) -> Exec {
+    Exec::cmd("/bin/sh").arg("-c").arg(SHIELD_SCRIPT).arg(cvs)
+}
+
+/// Describes a command for logs and errors, leaving out the shell that
+/// shields cvs from signals.
+fn cmdline(exec: &Exec) -> String {
+    let cmdline = exec.to_cmdline_lossy();
+    let shell = shielded(OsStr::new("cvs")).to_cmdline_lossy();
+    match shell
+        .strip_suffix("cvs")
+        .and_then(|shell| cmdline.strip_prefix(shell))
+    {
+        Some(cvs) => cvs.to_string(),
+        None => cmdline,
+    }
+}
+
+/// Starts a cvs command that can't wait for input: stdin is /dev/null, so a
+/// prompt reads end of file rather than hanging an unattended export, and
+/// anything that would start an editor fails instead.
+///
+/// ssh, which ext roots use by default, asks for passwords and host key
+/// confirmation on the terminal rather than stdin. CVS_RSH can only name a
+/// program, not pass it -o BatchMode=yes, so ssh is told to ask a program
+/// that always fails instead, unless the environment already says how it
+/// should ask. CVS_PASSFILE is left alone: cvs only reads it, and never
+/// prompts for a pserver password outside `cvs login`.
+fn command(cvs: &OsStr) -> Exec {
+    let mut exec = if SHIELDED.load(Ordering::SeqCst) {
+        shielded(cvs)
+    } else {
+        Exec::cmd(cvs)
+    };
+    exec = exec.stdin(NullFile).env("CVSEDITOR", "false");
+    if env::var_os("SSH_ASKPASS_REQUIRE").is_none() {
+        exec = exec
+            .env("SSH_ASKPASS", "false")
+            .env("SSH_ASKPASS_REQUIRE", "force");
+    }
+    exec
+}
+
+/// How many lines of a failed command's output are kept for the error.
+const OUTPUT_TAIL_LINES: usize = 50;
+
+/// Logs each line of a command's output, keeping the last few in tail.
+fn forward_output(
+    pipe: Option<fs::File>,
+    operation: Operation,
+    level: log::Level,
+    tail: &Mutex<VecDeque<String>>,
+) -> io::Result<()> {
+    let mut pipe = match pipe {
+        Some(pipe) => io::BufReader::new(pipe),
+        None => return Ok(()),
+    };
+
+    let mut line = Vec::new();
+    while pipe.read_until(b'\n', &mut line)? > 0 {
+        let text = String::from_utf8_lossy(&line).trim_end().to_string();
+        line.clear();
+        log::log!(level, "cvs {}: {}", operation.as_str(), text);
+
+        let mut tail = tail.lock().expect("output tail poisoned");
+        if tail.len() == OUTPUT_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(text);
+    }
+    Ok(())
+}
+
+/// Fails unless the command succeeded or exited with one of the allowed
+/// codes, keeping the last lines of its output in the error.
+fn check_status(
+    status: ExitStatus,
+    cmdline: &str,
+    allowed: &[u32],
+    output: Vec<String>,
+) -> Result<()> {
+    match status {
+        ExitStatus::Exited(0) => Ok(()),
+        ExitStatus::Exited(code) if allowed.contains(&code) => {
+            log::debug!("{} exited with allowed code {}", cmdline, code);
+            Ok(())
+        }
+        status => Err(Error::CvsCommandFailed {
+            command: cmdline.into(),
+            status,
+            output,
+            attempts: 1,
+        }),
+    }
+}
+
+/// A cvs command whose exit code is checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Checkout,
+    Add,
+    Commit,
+    Remove,
+    Admin,
+    Tag,
+    Update,
+    Init,
+    Import,
+}
+
+impl Operation {
+    /// The operations that can be given to --allow-cvs-exit.
+    pub const VARIANTS: &'static [&'static str] = &[
+        "checkout", "add", "commit", "remove", "admin", "tag", "update",
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operation::Checkout => "checkout",
+            Operation::Add => "add",
+            Operation::Commit => "commit",
+            Operation::Remove => "remove",
+            Operation::Admin => "admin",
+            Operation::Tag => "tag",
+            Operation::Update => "update",
+            Operation::Init => "init",
+            Operation::Import => "import",
+        }
+    }
+}
+
+impl std::str::FromStr for Operation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "checkout" => Ok(Operation::Checkout),
+            "add" => Ok(Operation::Add),
+            "commit" => Ok(Operation::Commit),
+            "remove" => Ok(Operation::Remove),
+            "admin" => Ok(Operation::Admin),
+            "tag" => Ok(Operation::Tag),
+            "update" => Ok(Operation::Update),
+            _ => Err(Error::UnknownValue {
+                kind: "cvs operation",
+                value: s.into(),
+                expected: Self::VARIANTS,
+            }),
+        }
+    }
+}
+
+/// Where the output of cvs commands goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// Each line is logged, and the last lines are included in the error if
+    /// the command fails.
+    Capture,
+    /// The output goes straight to the terminal, which is handy when cvs
+    /// needs to ask for something.
+    Inherit,
+}
+
+impl Output {
+    pub const VARIANTS: &'static [&'static str] = &["capture", "inherit"];
+}
+
+impl std::str::FromStr for Output {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "capture" => Ok(Output::Capture),
+            "inherit" => Ok(Output::Inherit),
+            _ => Err(Error::UnknownValue {
+                kind: "cvs output mode",
+                value: s.into(),
+                expected: Self::VARIANTS,
+            }),
+        }
+    }
+}
+
+/// How often a cvs command that failed for a passing reason, such as another
+/// client holding a lock, is tried again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    pub retries: u32,
+    /// The wait before the first retry, which doubles for each one after.
+    pub delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Retry {
+    fn delay(&self, attempt: u32) -> Duration {
+        self.delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+    }
+}
+
+/// The operations that are retried, since they're the ones that take locks
+/// in the repository.
+const RETRIED_OPERATIONS: &[Operation] = &[Operation::Add, Operation::Commit, Operation::Remove];
+
+/// Output from cvs that means another client got in the way, rather than that
+/// something is wrong with what we asked for. A failed up-to-date check isn't
+/// one of them: someone else committed to the file, and trying again without
+/// updating would fail the same way, while updating would merge their change
+/// into what we export.
+const TRANSIENT_FAILURES: &[&str] = &["lock failed", "failed to obtain dir lock"];
+
+fn is_transient(output: &[String]) -> bool {
+    output.iter().any(|line| {
+        TRANSIENT_FAILURES
+            .iter()
+            .any(|pattern| line.contains(pattern))
+    })
+}
+
+/// A non-zero exit code that's harmless for one operation, given as
+/// OPERATION=CODE: for example, commit=1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllowedExit {
+    pub operation: Operation,
+    pub code: u32,
+}
+
+impl std::str::FromStr for AllowedExit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (operation, code) = match s.split_once('=') {
+            Some(split) => split,
+            None => return Err(Error::InvalidAllowedExit { spec: s.into() }),
+        };
+        Ok(Self {
+            operation: operation.parse()?,
+            code: code
+                .parse()
+                .map_err(|_| Error::InvalidAllowedExit { spec: s.into() })?,
+        })
+    }
+}
+
+/// What happens to the cvs commands that change the repository.
+#[derive(Debug, Clone)]
+enum Runner {
+    Execute,
+    /// Logs and records the commands instead of running them.
+    DryRun(Rc<RefCell<DryRun>>),
+}
+
+/// What a dry run would have done.
+#[derive(Debug, Default)]
+struct DryRun {
+    commands: Vec<String>,
+    /// Files that would have been added, relative to the working copy.
+    added: HashSet<PathBuf>,
+    /// Branch tags that would have been created.
+    branches: HashSet<String>,
+}
+
+impl DryRun {
+    fn record(&mut self, command: String) {
+        log::info!("dry run: {}", command);
+        self.commands.push(command);
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Context {
     cvs: OsString,
+    allowed: HashMap<Operation, Vec<u32>>,
+    runner: Runner,
+    arg_limits: ArgLimits,
+    output: Output,
+    retry: Retry,
 }
 
 impl Context {
     pub fn new(cvs: &OsStr) -> Self {
-        Self { cvs: cvs.into() }
+        Self {
+            cvs: cvs.into(),
+            allowed: HashMap::new(),
+            runner: Runner::Execute,
+            arg_limits: *ARG_LIMITS,
+            output: Output::Capture,
+            retry: Retry::default(),
+        }
+    }
+
+    /// Splits long command lines as though ARG_MAX were the given number of
+    /// bytes, here and in the working copies checked out from this context.
+    pub fn arg_max(mut self, arg_max: usize) -> Self {
+        self.arg_limits = ArgLimits::from_arg_max(arg_max);
+        self
+    }
+
+    /// Sends the output of cvs commands to the given place, here and in the
+    /// working copies checked out from this context.
+    pub fn output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Logs and records the commands that would change the repository, here
+    /// and in the working copies checked out from this context, instead of
+    /// running them. Commands that only read, such as checkouts, still run.
+    pub fn dry_run(mut self) -> Self {
+        self.runner = Runner::DryRun(Rc::new(RefCell::new(DryRun::default())));
+        self
+    }
+
+    /// Returns the commands that a dry run skipped, in order.
+    pub fn planned(&self) -> Vec<String> {
+        match &self.runner {
+            Runner::Execute => Vec::new(),
+            Runner::DryRun(dry_run) => dry_run.borrow().commands.clone(),
+        }
+    }
+
+    /// Retries commands that fail because another client got in the way,
+    /// here and in the working copies checked out from this context.
+    /// Output has to be captured to tell when that happened.
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Treats the given exit code from an operation as success, both here and
+    /// in the working copies checked out from this context.
+    pub fn allow_exit(mut self, allowed: AllowedExit) -> Self {
+        self.allowed
+            .entry(allowed.operation)
+            .or_default()
+            .push(allowed.code);
+        self
     }
 
+    /// Checks out a module, either from the trunk or from an existing branch.
     pub fn checkout<P: AsRef<Path>>(
         &self,
-        cvsroot: &OsStr,
+        cvsroot: &CvsRoot,
         module: &str,
         target: P,
-    ) -> anyhow::Result<Repository> {
-        Exec::cmd(&self.cvs)
+        branch: Option<&str>,
+    ) -> Result<Repository> {
+        let target = target.as_ref();
+        let branch = match (&self.runner, branch) {
+            (Runner::DryRun(dry_run), Some(name)) if dry_run.borrow().branches.contains(name) => {
+                log::warn!(
+                    "dry run: checking out the trunk in place of new branch {}, so the plan may be inexact",
+                    name
+                );
+                None
+            }
+            _ => branch,
+        };
+        let mut exec = command(&self.cvs)
             .arg("-d")
-            .arg(cvsroot)
+            .arg(cvsroot.to_command_arg(false))
             .arg("checkout")
             .arg("-d")
-            .arg(target.as_ref())
-            .arg("-R")
-            .arg(module)
-            .log()
-            .join()?;
+            .arg(target);
+        if let Some(branch) = branch {
+            exec = exec.arg("-r").arg(branch);
+        }
+        // Not logged with ExecExt, since the CVSROOT may include a password.
+        log::trace!(
+            "checking out {} from {} into {}",
+            module,
+            cvsroot,
+            target.display()
+        );
+        exec.arg("-R").arg(module).run_as(
+            &format!(
+                "cvs -d {} checkout {}",
+                cvsroot.to_command_arg(true),
+                module
+            ),
+            Operation::Checkout,
+            allowed(&self.allowed, Operation::Checkout),
+            self.output,
+        )?;
+
+        let cwd = locate_checkout(target, module)?;
+        log::trace!("checkout of {} is in {}", module, cwd.display());
+
+        Ok(self.repository(cwd, branch))
+    }
 
-        let mut cwd = PathBuf::new();
-        cwd.push(target);
+    /// Brings a working copy that an earlier checkout of a module left behind
+    /// up to date, rather than checking the module out again. The working
+    /// copy is moved onto the given branch, or the trunk without one, in case
+    /// it was last used for another.
+    pub fn update_checkout<P: AsRef<Path>>(
+        &self,
+        module: &str,
+        target: P,
+        branch: Option<&str>,
+    ) -> Result<Repository> {
+        let cwd = locate_checkout(target.as_ref(), module)?;
+        let repo = self.repository(cwd, branch);
+        let cmd = repo.cmd().arg("update").arg("-d");
+        let cmd = match branch {
+            Some(branch) => cmd.arg("-r").arg(branch),
+            None => cmd.arg("-A"),
+        };
+        repo.run(cmd, Operation::Update)?;
+        Ok(repo)
+    }
 
-        Ok(Repository {
+    fn repository(&self, cwd: PathBuf, branch: Option<&str>) -> Repository {
+        Repository {
             cvs: self.cvs.clone(),
             cwd,
-        })
+            branch: branch.map(String::from),
+            allowed: self.allowed.clone(),
+            runner: self.runner.clone(),
+            arg_limits: self.arg_limits,
+            output: self.output,
+            retry: self.retry,
+        }
+    }
+
+    /// Creates a branch tag on the given revisions of files in a module,
+    /// leaving every other file off the branch.
+    pub fn create_branch_at(
+        &self,
+        cvsroot: &CvsRoot,
+        module: &str,
+        name: &str,
+        revisions: &[(PathBuf, String)],
+    ) -> Result<()> {
+        let mut by_revision: BTreeMap<&str, Vec<PathBuf>> = BTreeMap::new();
+        for (path, revision) in revisions {
+            by_revision
+                .entry(revision)
+                .or_default()
+                .push(module_path(module, path));
+        }
+
+        for (revision, paths) in by_revision {
+            let mut chunker = ArgChunker::new(
+                Operation::Tag,
+                |chunk| self.do_rtag(cvsroot, name, revision, chunk),
+                self.arg_limits,
+            );
+            for path in paths {
+                chunker.push(path)?;
+            }
+            chunker.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn do_rtag(
+        &self,
+        cvsroot: &CvsRoot,
+        name: &str,
+        revision: &str,
+        paths: &[OsString],
+    ) -> Result<()> {
+        if let Runner::DryRun(dry_run) = &self.runner {
+            let mut dry_run = dry_run.borrow_mut();
+            dry_run.branches.insert(name.into());
+            dry_run.record(format!(
+                "cvs rtag -b -r {} {} {}",
+                revision,
+                name,
+                paths
+                    .iter()
+                    .map(|path| path.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ));
+            return Ok(());
+        }
+
+        // Not logged with ExecExt, since the CVSROOT may include a password.
+        log::trace!(
+            "tagging {} files at revision {} with branch {}",
+            paths.len(),
+            revision,
+            name
+        );
+        command(&self.cvs)
+            .arg("-d")
+            .arg(cvsroot.to_command_arg(false))
+            .arg("rtag")
+            .arg("-b")
+            .arg("-r")
+            .arg(revision)
+            .arg(name)
+            .args(paths)
+            .run_as(
+                &format!(
+                    "cvs -d {} rtag -b -r {} {}",
+                    cvsroot.to_command_arg(true),
+                    revision,
+                    name
+                ),
+                Operation::Tag,
+                allowed(&self.allowed, Operation::Tag),
+                self.output,
+            )
+    }
+
+    /// Checks whether any file in a module is on the given branch, which is
+    /// how a branch tag exists in CVS.
+    pub fn branch_exists(&self, cvsroot: &CvsRoot, module: &str, name: &str) -> Result<bool> {
+        if let Runner::DryRun(dry_run) = &self.runner {
+            if dry_run.borrow().branches.contains(name) {
+                return Ok(true);
+            }
+        }
+
+        // Not logged with ExecExt, since the CVSROOT may include a password.
+        log::trace!("looking for branch {} in {}", name, module);
+        let exec = command(&self.cvs)
+            .arg("-d")
+            .arg(cvsroot.to_command_arg(false))
+            .arg("rlog")
+            .arg("-h")
+            .arg(module)
+            .stdout(Redirection::Pipe);
+        let capture = match self.output {
+            Output::Capture => exec.stderr(Redirection::Pipe),
+            Output::Inherit => exec,
+        }
+        .capture()?;
+        let stderr = capture.stderr_str();
+        for line in stderr.lines() {
+            log::debug!("cvs rlog: {}", line);
+        }
+        if !capture.exit_status.success() {
+            let mut output: Vec<String> = stderr
+                .lines()
+                .rev()
+                .take(OUTPUT_TAIL_LINES)
+                .map(String::from)
+                .collect();
+            output.reverse();
+            return Err(Error::CvsCommandFailed {
+                command: format!("cvs -d {} rlog -h {}", cvsroot.to_command_arg(true), module),
+                status: capture.exit_status,
+                output,
+                attempts: 1,
+            });
+        }
+
+        Ok(parse_branch_tags(&capture.stdout_str()).contains(name))
+    }
+
+    /// Creates a repository at the CVSROOT, or adds anything missing from
+    /// the administrative files of an existing one.
+    pub fn init(&self, cvsroot: &CvsRoot) -> Result<()> {
+        command(&self.cvs)
+            .arg("-d")
+            .arg(cvsroot.to_command_arg(false))
+            .arg("init")
+            .run_as(
+                &format!("cvs -d {} init", cvsroot.to_command_arg(true)),
+                Operation::Init,
+                allowed(&self.allowed, Operation::Init),
+                self.output,
+            )
+    }
+
+    /// Creates an empty module in the repository by importing an empty
+    /// directory, so that it can be checked out.
+    pub fn import_module(&self, cvsroot: &CvsRoot, module: &str) -> Result<()> {
+        let empty = tempfile::tempdir()?;
+        command(&self.cvs)
+            .cwd(empty.path())
+            .arg("-d")
+            .arg(cvsroot.to_command_arg(false))
+            .arg("import")
+            .arg("-m")
+            .arg(format!("Create {}", module))
+            .arg(module)
+            .arg(IMPORT_VENDOR_TAG)
+            .arg(IMPORT_RELEASE_TAG)
+            .run_as(
+                &format!("cvs -d {} import {}", cvsroot.to_command_arg(true), module),
+                Operation::Import,
+                allowed(&self.allowed, Operation::Import),
+                self.output,
+            )
+    }
+}
+
+/// The vendor and release tags that `cvs import` insists on, for the empty
+/// imports that create modules.
+const IMPORT_VENDOR_TAG: &str = "git2cvs";
+const IMPORT_RELEASE_TAG: &str = "init";
+
+/// Returns the path of a file in the repository, given its path within a
+/// checkout of the module.
+fn module_path(module: &str, path: &Path) -> PathBuf {
+    match module.trim_matches('/') {
+        "" | "." => path.to_path_buf(),
+        module => Path::new(module).join(path),
+    }
+}
+
+fn allowed(allowed: &HashMap<Operation, Vec<u32>>, operation: Operation) -> &[u32] {
+    allowed
+        .get(&operation)
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+}
+
+/// Finds the directory that a module was checked out into.
+///
+/// Depending on the version of CVS, checking out a nested module such as
+/// foo/bar with -d puts its content either directly in the target or in a
+/// directory named after the last path component within the target, so we
+/// look for a CVS/Repository that names the module. If there's no
+/// CVS/Repository at all, the target is assumed.
+fn locate_checkout(target: &Path, module: &str) -> Result<PathBuf> {
+    let module = module.trim_matches('/');
+    if module.is_empty() || module == "." {
+        return Ok(target.to_path_buf());
+    }
+
+    let mut found = None;
+    let last = Path::new(module).file_name().unwrap_or_default();
+    for candidate in &[target.to_path_buf(), target.join(last), target.join(module)] {
+        let repository = match fs::read_to_string(candidate.join("CVS/Repository")) {
+            Ok(repository) => repository.trim_end().trim_end_matches('/').to_string(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        // Older versions of CVS record the absolute path of the directory in
+        // the repository.
+        if repository == module || repository.ends_with(&format!("/{}", module)) {
+            return Ok(candidate.clone());
+        }
+        found.get_or_insert(repository);
+    }
+
+    match found {
+        Some(repository) => Err(Error::CheckoutMismatch {
+            module: module.into(),
+            target: target.into(),
+            repository,
+        }),
+        None => Ok(target.to_path_buf()),
     }
 }
 
@@ -61,176 +770,2683 @@ impl Context {
 pub struct Repository {
     cvs: OsString,
     cwd: PathBuf,
+    branch: Option<String>,
+    allowed: HashMap<Operation, Vec<u32>>,
+    runner: Runner,
+    arg_limits: ArgLimits,
+    output: Output,
+    retry: Retry,
+}
+
+/// What `cvs status` says about a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStatus {
+    pub status: String,
+    /// Whether the file exists in the working copy.
+    pub working_file: bool,
+    /// The revision in the repository, if there's a revision control file.
+    pub repository_revision: Option<String>,
+}
+
+/// The sticky tag of a directory in a CVS working copy, as recorded in
+/// CVS/Tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StickyTag {
+    Branch(String),
+    Tag(String),
+    Date(String),
 }
 
 impl Repository {
-    pub fn add(&self, path: &OsStr, binary: bool) -> anyhow::Result<()> {
+    pub fn add(&self, path: &OsStr, mode: KeywordMode) -> Result<()> {
         let mut exec = self.cmd().arg("add");
 
-        if binary {
-            exec = exec.arg("-kb");
+        if let Some(option) = mode.add_option() {
+            exec = exec.arg(option);
         }
 
-        exec.arg(path).log().join()?;
-
-        Ok(())
+        self.note_added(std::iter::once(path));
+        self.run(exec.arg(path), Operation::Add)
     }
 
-    pub fn add_multiple<I, OS>(&self, paths: I, binary: bool) -> anyhow::Result<()>
+    pub fn add_multiple<I, OS>(&self, paths: I, mode: KeywordMode) -> Result<()>
     where
         I: Iterator<Item = OS>,
         OS: AsRef<OsStr>,
     {
-        let mut chunker =
-            ArgChunker::new(|chunk| self.do_add_multiple(chunk, binary), *ARG_MAX - 12);
+        // A path that can't fit on a command line at all would otherwise only
+        // fail once the paths before it had been added.
+        let paths: Vec<OS> = paths.collect();
+        for path in paths.iter() {
+            self.arg_limits.check(Operation::Add, path.as_ref())?;
+        }
+
+        let mut chunker = ArgChunker::new(
+            Operation::Add,
+            |chunk| self.do_add_multiple(chunk, mode),
+            self.arg_limits,
+        );
 
         for path in paths {
             chunker.push(path)?;
         }
 
-        Ok(())
+        chunker.flush()
     }
 
-    fn do_add_multiple(&self, paths: &Vec<OsString>, binary: bool) -> anyhow::Result<()> {
+    fn do_add_multiple(&self, paths: &Vec<OsString>, mode: KeywordMode) -> Result<()> {
         let mut exec = self.cmd().arg("add");
-        if binary {
-            exec = exec.arg("-kb");
+        if let Some(option) = mode.add_option() {
+            exec = exec.arg(option);
         }
 
         for path in paths {
             exec = exec.arg(path);
         }
 
-        exec.log().join()?;
-        Ok(())
+        self.note_added(paths.iter());
+        self.run(exec, Operation::Add)
     }
 
-    pub fn commit(&self, message: &[u8]) -> anyhow::Result<()> {
+    /// Commits the working copy. If an author is given, CVS is asked to record
+    /// the commit as that user; this only takes effect where CVS trusts the
+    /// environment for the username, such as a local CVSROOT written as root.
+    /// If a git commit is given, a trailer recording it is appended to the
+    /// message.
+    pub fn commit(
+        &self,
+        message: &[u8],
+        author: Option<&str>,
+        git_commit: Option<git2::Oid>,
+    ) -> Result<()> {
+        let message = match git_commit {
+            Some(oid) => with_git_commit(message, &oid),
+            None => message.to_vec(),
+        };
         let mut msgfile = NamedTempFile::new()?;
-        msgfile.write_all(message)?;
+        msgfile.write_all(&message)?;
         msgfile.flush()?;
 
-        self.cmd()
-            .arg("commit")
-            .arg("-F")
-            .arg(msgfile.path())
-            .log()
-            .join()?;
-
-        Ok(())
-    }
+        if let Runner::DryRun(dry_run) = &self.runner {
+            dry_run.borrow_mut().record(format!(
+                "cvs commit{}: {}",
+                author
+                    .map(|author| format!(" as {}", author))
+                    .unwrap_or_default(),
+                String::from_utf8_lossy(&message).trim_end()
+            ));
+            return Ok(());
+        }
 
-    pub fn remove(&self, path: &OsStr) -> anyhow::Result<()> {
-        self.cmd().arg("remove").arg(path).log().join()?;
+        let mut exec = self.cmd();
+        if let Some(author) = author {
+            exec = exec.env("LOGNAME", author).env("USER", author);
+        }
 
-        Ok(())
+        self.run(
+            exec.arg("commit").arg("-F").arg(msgfile.path()),
+            Operation::Commit,
+        )
     }
 
-    pub fn remove_multiple<I, OS>(&self, paths: I) -> anyhow::Result<()>
+    /// Schedules files for removal.
+    ///
+    /// Files that CVS doesn't know about on the checked out branch are
+    /// skipped and returned: on a branch, this includes files that only ever
+    /// existed on the trunk after the branch point, which CVS would otherwise
+    /// refuse to remove.
+    pub fn remove_multiple<I, P>(&self, paths: I) -> Result<Vec<PathBuf>>
     where
-        I: Iterator<Item = OS>,
-        OS: AsRef<OsStr>,
+        I: Iterator<Item = P>,
+        P: AsRef<Path>,
     {
-        let mut chunker = ArgChunker::new(|chunk| self.do_remove_multiple(chunk), *ARG_MAX - 12);
+        let paths: Vec<PathBuf> = paths.map(|path| path.as_ref().to_path_buf()).collect();
+        for path in paths.iter() {
+            self.arg_limits.check(Operation::Remove, path.as_os_str())?;
+        }
 
-        for path in paths {
-            chunker.push(path)?;
+        // A removal on the wrong branch would create dead revisions wherever
+        // the directory happens to be, so we check each directory first.
+        let mut checked = HashSet::new();
+        for dir in paths.iter().filter_map(|path| path.parent()) {
+            if checked.insert(dir) {
+                self.check_sticky_tag(dir)?;
+            }
         }
 
-        Ok(())
+        let known: HashSet<PathBuf> = self
+            .revisions(paths.iter())?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+
+        // Whatever has taken a removed file's place is moved out of the way
+        // while cvs removes it, since cvs would otherwise take the argument to
+        // be that: a directory the file became, or, on a case-insensitive
+        // file system, the file under a name that differs only in case.
+        let replaced: Vec<_> = paths
+            .iter()
+            .filter(|path| {
+                known.contains(*path) && fs::symlink_metadata(self.cwd.join(path)).is_ok()
+            })
+            .map(|path| (self.cwd.join(path), self.cwd.join(set_aside_name(path))))
+            .collect();
+        for (path, aside) in replaced.iter() {
+            fs::rename(path, aside).map_err(Error::file("move aside", path))?;
+        }
+        let result = self.remove_known(&paths, &known);
+        for (path, aside) in replaced.iter() {
+            fs::rename(aside, path).map_err(Error::file("move back", path))?;
+        }
+        result
     }
 
-    fn do_remove_multiple(&self, paths: &Vec<OsString>) -> anyhow::Result<()> {
-        let mut exec = self.cmd().arg("remove");
+    fn remove_known(&self, paths: &[PathBuf], known: &HashSet<PathBuf>) -> Result<Vec<PathBuf>> {
+        let mut chunker = ArgChunker::new(
+            Operation::Remove,
+            |chunk| self.do_remove_multiple(chunk),
+            self.arg_limits,
+        );
+        let mut skipped = Vec::new();
+        for path in paths.iter() {
+            if known.contains(path) || self.dry_run_added(path) {
+                chunker.push(path)?;
+            } else {
+                log::warn!(
+                    "not removing {}: it is not on CVS branch {}",
+                    path.display(),
+                    self.branch.as_deref().unwrap_or("HEAD")
+                );
+                skipped.push(path.clone());
+            }
+        }
+        chunker.flush()?;
 
-        for path in paths {
-            exec = exec.arg(path);
+        Ok(skipped)
+    }
+
+    /// Drops a directory that CVS no longer has any files in from the working
+    /// copy, along with its administrative files, so that something else
+    /// can take its place. CVS never removes directories from the
+    /// repository, so it stays there.
+    pub fn forget_directory<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        let (parent, name) = match (dir.parent(), dir.file_name()) {
+            (Some(parent), Some(name)) => (parent, name.to_string_lossy()),
+            _ => {
+                return Err(Error::Other(
+                    "cannot forget the top of the working copy".into(),
+                ))
+            }
+        };
+
+        let absolute = self.cwd.join(dir);
+        fs::remove_dir_all(&absolute).map_err(Error::file("delete", &absolute))?;
+
+        // The directory may be listed in Entries, or added in Entries.Log.
+        let listing = format!("D/{}/", name);
+        for admin in ["Entries", "Entries.Log"] {
+            let file = self.cwd.join(parent).join("CVS").join(admin);
+            let content = match fs::read_to_string(&file) {
+                Ok(content) => content,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(Error::file("read", &file)(e)),
+            };
+            let kept: String = content
+                .split_inclusive('\n')
+                .filter(|line| {
+                    !line.starts_with(&listing)
+                        && !line
+                            .strip_prefix("A ")
+                            .is_some_and(|line| line.starts_with(&listing))
+                })
+                .collect();
+            if kept != content {
+                fs::write(&file, kept).map_err(Error::file("write", &file))?;
+            }
         }
 
-        exec.log().join()?;
         Ok(())
     }
 
-    fn cmd(&self) -> Exec {
-        Exec::cmd(&self.cvs).cwd(&self.cwd)
-    }
-}
+    /// Returns the files that CVS tracks within a directory of the working
+    /// copy, relative to the top of the working copy. The search stops once
+    /// limit files have been found, so that checking whether a big module is
+    /// empty is cheap.
+    pub fn tracked_files<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        limit: Option<usize>,
+    ) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        let mut dirs = vec![dir
+            .as_ref()
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect::<PathBuf>()];
 
-struct ArgChunker<F: Fn(&Vec<OsString>) -> anyhow::Result<()>> {
-    acc: Vec<OsString>,
-    commit: F,
-    limit: usize,
-    size: usize,
-}
+        while let Some(dir) = dirs.pop() {
+            let absolute = self.cwd.join(&dir);
+            let mut names: Vec<_> = match fs::read(absolute.join("CVS").join("Entries")) {
+                Ok(content) => parse_entries(&String::from_utf8_lossy(&content))
+                    .into_keys()
+                    .collect(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(Error::file("read", &dir)(e)),
+            };
+            names.sort();
 
-impl<F: Fn(&Vec<OsString>) -> anyhow::Result<()>> ArgChunker<F> {
-    fn new(commit: F, limit: usize) -> Self {
-        Self {
-            acc: Vec::new(),
-            commit,
-            limit,
-            size: 0,
+            for name in names {
+                if matches!(limit, Some(limit) if files.len() >= limit) {
+                    return Ok(files);
+                }
+                files.push(dir.join(name));
+            }
+
+            let mut subdirs = Vec::new();
+            for entry in fs::read_dir(&absolute)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() && entry.file_name() != "CVS" {
+                    subdirs.push(dir.join(entry.file_name()));
+                }
+            }
+            subdirs.sort_by(|a, b| b.cmp(a));
+            dirs.extend(subdirs);
         }
+
+        Ok(files)
     }
 
-    fn do_commit(&mut self) -> anyhow::Result<()> {
-        (self.commit)(&self.acc)?;
+    /// Returns the sticky tag of a directory in the working copy, if any.
+    pub fn sticky_tag<P: AsRef<Path>>(&self, dir: P) -> Result<Option<StickyTag>> {
+        let file = self.cwd.join(dir).join("CVS").join("Tag");
+        match fs::read(&file) {
+            Ok(content) => Ok(parse_tag(&String::from_utf8_lossy(&content))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::file("read", &file)(e)),
+        }
+    }
 
-        self.size = 0;
-        self.acc.clear();
+    fn check_sticky_tag(&self, dir: &Path) -> Result<()> {
+        let expected = self.branch.clone().map(StickyTag::Branch);
+        let actual = self.sticky_tag(dir)?;
+        if actual != expected {
+            return Err(Error::StickyTagMismatch {
+                dir: self.cwd.join(dir),
+                actual,
+                expected,
+            });
+        }
 
         Ok(())
     }
 
-    fn push<OS: AsRef<OsStr>>(&mut self, path: OS) -> anyhow::Result<()> {
-        let owned = OsString::from(path.as_ref());
+    fn do_remove_multiple(&self, paths: &Vec<OsString>) -> Result<()> {
+        let mut exec = self.cmd().arg("remove");
 
-        if self.size + owned.len() > self.limit {
-            self.do_commit()?;
+        for path in paths {
+            exec = exec.arg(path);
         }
 
-        self.size += owned.len();
-        self.acc.push(owned);
-
-        Ok(())
+        self.run(exec, Operation::Remove)
     }
-}
 
-impl<F: Fn(&Vec<OsString>) -> anyhow::Result<()>> Drop for ArgChunker<F> {
-    fn drop(&mut self) {
-        if self.acc.len() > 0 {
-            self.do_commit().unwrap();
+    /// Asks the server about each of the given files. Files that CVS knows
+    /// nothing about are omitted.
+    pub fn status<I, P>(&self, paths: I) -> Result<HashMap<PathBuf, FileStatus>>
+    where
+        I: Iterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        // cvs status only prints file names, so we run it once per directory
+        // to be able to tell which file is which.
+        let mut dirs: HashMap<PathBuf, Vec<OsString>> = HashMap::new();
+        for path in paths {
+            let path = path.as_ref();
+            if let (Some(dir), Some(name)) = (path.parent(), path.file_name()) {
+                dirs.entry(dir.into()).or_default().push(name.into());
+            }
         }
-    }
-}
 
-pub fn sanitise_branch(name: &str) -> String {
-    let mut out = String::new();
+        let mut statuses = HashMap::new();
+        for (dir, names) in dirs {
+            let exec = command(&self.cvs)
+                .cwd(self.cwd.join(&dir))
+                .arg("status")
+                .args(&names)
+                .stdout(Redirection::Pipe);
+            let capture = match self.output {
+                Output::Capture => exec.stderr(Redirection::Pipe),
+                Output::Inherit => exec,
+            }
+            .log()
+            .capture()?;
+            let stderr = capture.stderr_str();
+            for line in stderr.lines() {
+                log::warn!("cvs status: {}", line);
+            }
+            if !capture.exit_status.success() {
+                let mut output: Vec<String> = stderr
+                    .lines()
+                    .rev()
+                    .take(OUTPUT_TAIL_LINES)
+                    .map(String::from)
+                    .collect();
+                output.reverse();
+                return Err(Error::CvsCommandFailed {
+                    command: format!("cvs status in {}", dir.display()),
+                    status: capture.exit_status,
+                    output,
+                    attempts: 1,
+                });
+            }
 
-    for c in name.chars() {
-        if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
-            out.push(c);
-        } else {
-            out.push_str(&format!("__u{:06x}", u32::from(c)));
+            for (name, status) in parse_status(&capture.stdout_str()) {
+                statuses.insert(dir.join(name), status);
+            }
         }
+
+        Ok(statuses)
     }
 
-    out
-}
+    /// Looks up the current revision of each of the given files from the
+    /// CVS/Entries file in its directory. Files that CVS doesn't know about are
+    /// omitted.
+    pub fn revisions<I, P>(&self, paths: I) -> Result<Vec<(PathBuf, String)>>
+    where
+        I: Iterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.entries(paths, |entry| entry.revision.clone())
+    }
 
-lazy_static! {
-    static ref ARG_MAX: usize =
-        usize::try_from(sysconf::raw::sysconf(SysconfVariable::ScArgMax).unwrap()).unwrap();
-}
+    /// Looks up the keyword substitution mode of each of the given files, in
+    /// the form taken by -k: for example, "b" for binary files. Files that CVS
+    /// doesn't know about are omitted.
+    pub fn keyword_modes<I, P>(&self, paths: I) -> Result<Vec<(PathBuf, String)>>
+    where
+        I: Iterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.entries(paths, |entry| {
+            entry
+                .options
+                .strip_prefix("-k")
+                .unwrap_or(DEFAULT_KEYWORD_MODE)
+                .to_string()
+        })
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Changes the default keyword substitution mode of a file in the
+    /// repository.
+    pub fn set_keyword_mode<P: AsRef<Path>>(&self, path: P, mode: &str) -> Result<()> {
+        let exec = self
+            .cmd()
+            .arg("admin")
+            .arg(format!("-k{}", mode))
+            .arg(path.as_ref());
+        self.run(exec, Operation::Admin)
+    }
 
-    #[test]
-    fn test_sanitise_branch() {
-        assert_eq!("foo", sanitise_branch("foo"));
-        assert_eq!("foo-Bar_quux0", sanitise_branch("foo-Bar_quux0"));
-        assert_eq!("__u000020", sanitise_branch(" "));
+    /// Creates a branch tag at the revisions in the working copy.
+    pub fn create_branch(&self, name: &str) -> Result<()> {
+        if let Runner::DryRun(dry_run) = &self.runner {
+            dry_run.borrow_mut().branches.insert(name.into());
+        }
+        self.run(self.cmd().arg("tag").arg("-b").arg(name), Operation::Tag)
+    }
+
+    /// Brings files in the working copy to the given revisions, which become
+    /// sticky, so this is only for looking at what CVS had, not committing.
+    pub fn update_to_revisions(&self, revisions: &[(PathBuf, String)]) -> Result<()> {
+        let mut by_revision: BTreeMap<&str, Vec<&Path>> = BTreeMap::new();
+        for (path, revision) in revisions {
+            by_revision.entry(revision).or_default().push(path);
+        }
+
+        for (revision, paths) in by_revision {
+            let mut chunker = ArgChunker::new(
+                Operation::Update,
+                |chunk| {
+                    self.run(
+                        self.cmd().arg("update").arg("-r").arg(revision).args(chunk),
+                        Operation::Update,
+                    )
+                },
+                self.arg_limits,
+            );
+            for path in paths {
+                chunker.push(path)?;
+            }
+            chunker.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the working copy onto a branch, so that later commits go there.
+    pub fn switch_branch(&mut self, name: &str) -> Result<()> {
+        self.run(
+            self.cmd().arg("update").arg("-r").arg(name),
+            Operation::Update,
+        )?;
+        // A dry run leaves the working copy where it was.
+        if let Runner::Execute = self.runner {
+            self.branch = Some(name.into());
+        }
+
+        Ok(())
+    }
+
+    fn entries<I, P, F>(&self, paths: I, field: F) -> Result<Vec<(PathBuf, String)>>
+    where
+        I: Iterator<Item = P>,
+        P: AsRef<Path>,
+        F: Fn(&Entry) -> String,
+    {
+        let mut entries: HashMap<PathBuf, HashMap<String, Entry>> = HashMap::new();
+        let mut values = Vec::new();
+
+        for path in paths {
+            let path = path.as_ref();
+            let (dir, name) = match (path.parent(), path.file_name()) {
+                (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+                _ => continue,
+            };
+
+            if !entries.contains_key(dir) {
+                // A directory that CVS doesn't know about has no Entries.
+                let file = self.cwd.join(dir).join("CVS").join("Entries");
+                let parsed = match fs::read(&file) {
+                    Ok(content) => parse_entries(&String::from_utf8_lossy(&content)),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+                    Err(e) => return Err(Error::file("read", &file)(e)),
+                };
+                entries.insert(dir.into(), parsed);
+            }
+
+            if let Some(entry) = entries[dir].get(name.as_ref()) {
+                values.push((path.into(), field(entry)));
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// The top of the working copy, which may be below the directory it was
+    /// checked out into if the module is nested.
+    pub fn cwd(&self) -> &Path {
+        &self.cwd
+    }
+
+    fn cmd(&self) -> Exec {
+        command(&self.cvs).cwd(&self.cwd)
+    }
+
+    /// Runs a command that changes the repository, unless this is a dry run.
+    fn run(&self, exec: Exec, operation: Operation) -> Result<()> {
+        match &self.runner {
+            Runner::Execute => self.execute(exec.log(), operation),
+            Runner::DryRun(dry_run) => {
+                dry_run.borrow_mut().record(cmdline(&exec));
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs a command, retrying it if it fails because of another client.
+    fn execute(&self, exec: Exec, operation: Operation) -> Result<()> {
+        let cmdline = cmdline(&exec);
+        let mut attempt = 1;
+        loop {
+            let (status, tail) = exec.clone().execute(operation, self.output)?;
+            let transient = is_transient(&tail);
+            let mut err = match check_status(status, &cmdline, self.allowed(operation), tail) {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if let Error::CvsCommandFailed { attempts, .. } = &mut err {
+                *attempts = attempt;
+            }
+
+            if !RETRIED_OPERATIONS.contains(&operation)
+                || !transient
+                || attempt > self.retry.retries
+            {
+                return Err(err);
+            }
+
+            let delay = self.retry.delay(attempt);
+            log::warn!(
+                "cvs {} failed on attempt {} of {} because of another client; retrying in {} seconds",
+                operation.as_str(),
+                attempt,
+                self.retry.retries + 1,
+                delay.as_secs()
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    fn note_added<I, OS>(&self, paths: I)
+    where
+        I: Iterator<Item = OS>,
+        OS: AsRef<OsStr>,
+    {
+        if let Runner::DryRun(dry_run) = &self.runner {
+            dry_run
+                .borrow_mut()
+                .added
+                .extend(paths.map(|path| PathBuf::from(path.as_ref())));
+        }
+    }
+
+    /// Returns whether a dry run would have added the file, so that it can be
+    /// removed again even though CVS doesn't know about it.
+    fn dry_run_added(&self, path: &Path) -> bool {
+        match &self.runner {
+            Runner::Execute => false,
+            Runner::DryRun(dry_run) => dry_run.borrow().added.contains(path),
+        }
+    }
+
+    fn allowed(&self, operation: Operation) -> &[u32] {
+        allowed(&self.allowed, operation)
+    }
+}
+
+/// How many variable arguments a single command can be given, and how much
+/// space they can take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ArgLimits {
+    bytes: usize,
+    count: usize,
+}
+
+/// Space kept back for the cvs binary and the fixed arguments before the
+/// paths, such as the CVSROOT, the subcommand and its options.
+const FIXED_ARG_BYTES: usize = 4096;
+
+/// Linux only limits the total size, but other systems cap the number of
+/// arguments too; staying under this costs a few more cvs runs at most.
+const MAX_ARG_COUNT: usize = 65536;
+
+/// The ARG_MAX used when sysconf can't say, which is small enough for
+/// anything POSIX.
+const DEFAULT_ARG_MAX: usize = 64 * 1024;
+
+/// ARG_MAX can be a quarter of the stack limit, which is enormous when the
+/// stack is unlimited, but there's nothing to gain from commands that long.
+const MAX_ARG_MAX: usize = 4 * 1024 * 1024;
+
+impl ArgLimits {
+    /// Works out the limits from ARG_MAX, less the environment that cvs is
+    /// run with and the fixed arguments.
+    fn from_arg_max(arg_max: usize) -> Self {
+        let environment: usize = std::env::vars_os()
+            .map(|(key, value)| arg_cost(&key) + value.len() + 1)
+            .sum();
+
+        Self {
+            bytes: arg_max.saturating_sub(environment + FIXED_ARG_BYTES),
+            count: MAX_ARG_COUNT,
+        }
+    }
+
+    /// Fails if an argument can't fit on a command line even by itself.
+    fn check(&self, operation: Operation, arg: &OsStr) -> Result<()> {
+        let cost = arg_cost(arg);
+        if cost > self.bytes {
+            return Err(Error::ArgumentTooLong {
+                operation,
+                arg: arg.into(),
+                cost,
+                available: self.bytes,
+            });
+        }
+        Ok(())
+    }
+
+    fn system() -> Self {
+        Self::from_arg_max(choose_arg_max(
+            sysconf::raw::sysconf(SysconfVariable::ScArgMax).ok(),
+        ))
+    }
+}
+
+/// Turns what sysconf said ARG_MAX is, if anything, into the ARG_MAX to use.
+fn choose_arg_max(reported: Option<isize>) -> usize {
+    let arg_max = match reported.map(usize::try_from) {
+        Some(Ok(arg_max)) if arg_max > 0 => arg_max.min(MAX_ARG_MAX),
+        _ => DEFAULT_ARG_MAX,
+    };
+    log::debug!(
+        "using an ARG_MAX of {} bytes (sysconf reported {:?})",
+        arg_max,
+        reported
+    );
+    arg_max
+}
+
+/// The space an argument takes when a command is run: the string, its NUL,
+/// and the pointer to it in argv.
+fn arg_cost(arg: &OsStr) -> usize {
+    arg.len() + 1 + std::mem::size_of::<*const std::os::raw::c_char>()
+}
+
+/// Splits arguments into chunks that fit within the limits and runs a command
+/// for each chunk. An argument that's too long on its own is an error, before
+/// anything is run for it. Callers have to call flush to run the last chunk;
+/// dropping a chunker with arguments left over, other than after an error,
+/// is a bug.
+struct ArgChunker<F: Fn(&Vec<OsString>) -> Result<()>> {
+    operation: Operation,
+    acc: Vec<OsString>,
+    commit: F,
+    limits: ArgLimits,
+    size: usize,
+    failed: bool,
+}
+
+impl<F: Fn(&Vec<OsString>) -> Result<()>> ArgChunker<F> {
+    fn new(operation: Operation, commit: F, limits: ArgLimits) -> Self {
+        Self {
+            operation,
+            acc: Vec::new(),
+            commit,
+            limits,
+            size: 0,
+            failed: false,
+        }
+    }
+
+    fn do_commit(&mut self) -> Result<()> {
+        let acc = std::mem::take(&mut self.acc);
+        self.size = 0;
+
+        let result = (self.commit)(&acc);
+        self.failed |= result.is_err();
+        result
+    }
+
+    fn push<OS: AsRef<OsStr>>(&mut self, path: OS) -> Result<()> {
+        let owned = OsString::from(path.as_ref());
+        if let Err(err) = self.limits.check(self.operation, &owned) {
+            self.failed = true;
+            return Err(err);
+        }
+        let cost = arg_cost(&owned);
+
+        if !self.acc.is_empty()
+            && (self.size + cost > self.limits.bytes || self.acc.len() >= self.limits.count)
+        {
+            self.do_commit()?;
+        }
+
+        self.size += cost;
+        self.acc.push(owned);
+
+        Ok(())
+    }
+
+    /// Runs the command for whatever's left over.
+    fn flush(&mut self) -> Result<()> {
+        if !self.acc.is_empty() {
+            self.do_commit()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<F: Fn(&Vec<OsString>) -> Result<()>> Drop for ArgChunker<F> {
+    fn drop(&mut self) {
+        // Once something has failed, the caller is returning the error and
+        // the rest of the arguments are meant to be abandoned.
+        if !self.acc.is_empty() && !self.failed {
+            log::error!(
+                "cvs {} was never run for {} arguments",
+                self.operation.as_str(),
+                self.acc.len()
+            );
+            debug_assert!(false, "ArgChunker dropped without being flushed");
+        }
+    }
+}
+
+/// The access method of a CVSROOT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Local,
+    Fork,
+    Ext,
+    Pserver,
+}
+
+impl Method {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Local => "local",
+            Method::Fork => "fork",
+            Method::Ext => "ext",
+            Method::Pserver => "pserver",
+        }
+    }
+}
+
+/// A parsed CVSROOT, as given to `cvs -d`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CvsRoot {
+    method: Method,
+    // Whether the root was a bare path, without :local:.
+    implicit: bool,
+    user: Option<String>,
+    password: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+    path: String,
+}
+
+impl CvsRoot {
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns true if the repository is accessed directly on this machine.
+    pub fn is_local(&self) -> bool {
+        matches!(self.method, Method::Local | Method::Fork)
+    }
+
+    /// Reconstructs the CVSROOT for `cvs -d`. With `strip_password`, any
+    /// pserver password is left out, which is what should be logged.
+    pub fn to_command_arg(&self, strip_password: bool) -> String {
+        if self.implicit {
+            return self.path.clone();
+        }
+
+        let mut out = format!(":{}:", self.method.as_str());
+        if let Some(host) = &self.host {
+            if let Some(user) = &self.user {
+                out.push_str(user);
+                if let (Some(password), false) = (&self.password, strip_password) {
+                    out.push(':');
+                    out.push_str(password);
+                }
+                out.push('@');
+            }
+            out.push_str(host);
+            out.push(':');
+            if let Some(port) = self.port {
+                out.push_str(&port.to_string());
+            }
+        }
+        out.push_str(&self.path);
+        out
+    }
+}
+
+impl std::fmt::Display for CvsRoot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_command_arg(true))
+    }
+}
+
+fn invalid_cvsroot<S: Into<String>>(root: &str, problem: S) -> Error {
+    Error::InvalidCvsRoot {
+        root: root.into(),
+        problem: problem.into(),
+    }
+}
+
+impl std::str::FromStr for CvsRoot {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(invalid_cvsroot(s, "is empty"));
+        }
+
+        let check_path = |path: &str| {
+            if path.starts_with('/') {
+                Ok(path.to_string())
+            } else {
+                Err(invalid_cvsroot(
+                    s,
+                    "does not have an absolute repository path",
+                ))
+            }
+        };
+
+        let rest = match s.strip_prefix(':') {
+            Some(rest) => rest,
+            None => {
+                return Ok(Self {
+                    method: Method::Local,
+                    implicit: true,
+                    user: None,
+                    password: None,
+                    host: None,
+                    port: None,
+                    path: check_path(s)?,
+                });
+            }
+        };
+
+        let (method, rest) = rest
+            .split_once(':')
+            .ok_or_else(|| invalid_cvsroot(s, "has an unterminated access method"))?;
+        let method = match method {
+            "local" => Method::Local,
+            "fork" => Method::Fork,
+            "ext" => Method::Ext,
+            "pserver" => Method::Pserver,
+            _ => {
+                return Err(invalid_cvsroot(
+                    s,
+                    format!("has unknown access method {}", method),
+                ))
+            }
+        };
+
+        if matches!(method, Method::Local | Method::Fork) {
+            return Ok(Self {
+                method,
+                implicit: false,
+                user: None,
+                password: None,
+                host: None,
+                port: None,
+                path: check_path(rest)?,
+            });
+        }
+
+        // [user[:password]@]host:[port]/path
+        let slash = rest
+            .find('/')
+            .ok_or_else(|| invalid_cvsroot(s, "has no repository path"))?;
+        let (authority, path) = rest.split_at(slash);
+        let (userinfo, hostport) = match authority.rsplit_once('@') {
+            Some((userinfo, hostport)) => (Some(userinfo), hostport),
+            None => (None, authority),
+        };
+
+        let (host, port) = match hostport.split_once(':') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => (
+                host,
+                Some(
+                    port.parse::<u16>()
+                        .ok()
+                        .filter(|port| *port != 0)
+                        .ok_or_else(|| invalid_cvsroot(s, format!("has invalid port {}", port)))?,
+                ),
+            ),
+            None => (hostport, None),
+        };
+        if host.is_empty() {
+            return Err(invalid_cvsroot(s, "has no host"));
+        }
+
+        let (user, password) = match userinfo {
+            Some(userinfo) => {
+                let (user, password) = match userinfo.split_once(':') {
+                    Some((user, password)) => (user, Some(password.to_string())),
+                    None => (userinfo, None),
+                };
+                if user.is_empty() {
+                    return Err(invalid_cvsroot(s, "has an empty user name"));
+                }
+                (Some(user.to_string()), password)
+            }
+            None => (None, None),
+        };
+        if password.is_some() && method != Method::Pserver {
+            return Err(invalid_cvsroot(
+                s,
+                "has a password, but only pserver accepts one",
+            ));
+        }
+
+        Ok(Self {
+            method,
+            implicit: false,
+            user,
+            password,
+            host: Some(host.to_string()),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// The keyword substitution mode CVS uses when none is given.
+pub const DEFAULT_KEYWORD_MODE: &str = "kv";
+
+/// A keyword substitution mode that files can be added with. The order is the
+/// order files are added in, so that directories, which are always added with
+/// the default mode, come before the files in them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum KeywordMode {
+    /// -kv, which expands keywords such as $Id$ on checkout.
+    #[default]
+    Default,
+    /// -ko, which leaves the content exactly as it was committed.
+    NoExpand,
+    /// -kk, which collapses keywords to their names.
+    KeywordOnly,
+    /// -kb, which also leaves line endings alone.
+    Binary,
+}
+
+impl KeywordMode {
+    pub const VARIANTS: &'static [&'static str] = &["kv", "ko", "kk", "kb"];
+
+    pub const ALL: &'static [KeywordMode] = &[
+        KeywordMode::Default,
+        KeywordMode::NoExpand,
+        KeywordMode::KeywordOnly,
+        KeywordMode::Binary,
+    ];
+
+    /// Returns the mode in the form taken by -k, as stored in CVS/Entries and
+    /// returned by Repository::keyword_modes.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            KeywordMode::Default => DEFAULT_KEYWORD_MODE,
+            KeywordMode::NoExpand => "o",
+            KeywordMode::KeywordOnly => "k",
+            KeywordMode::Binary => "b",
+        }
+    }
+
+    /// Returns whether CVS rewrites keywords such as $Id$ in files with this
+    /// mode when they're committed or checked out.
+    pub fn expands_keywords(self) -> bool {
+        matches!(self, KeywordMode::Default | KeywordMode::KeywordOnly)
+    }
+
+    /// Returns the option to pass to cvs add, if any.
+    fn add_option(self) -> Option<String> {
+        match self {
+            KeywordMode::Default => None,
+            mode => Some(mode.to_string()),
+        }
+    }
+}
+
+impl std::str::FromStr for KeywordMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('-').unwrap_or(s) {
+            "kv" => Ok(KeywordMode::Default),
+            "ko" => Ok(KeywordMode::NoExpand),
+            "kk" => Ok(KeywordMode::KeywordOnly),
+            "kb" => Ok(KeywordMode::Binary),
+            _ => Err(Error::UnknownValue {
+                kind: "keyword mode",
+                value: s.into(),
+                expected: Self::VARIANTS,
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for KeywordMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeywordMode::Default => write!(f, "-kv"),
+            mode => write!(f, "-k{}", mode.as_str()),
+        }
+    }
+}
+
+/// A file in CVS/Entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub revision: String,
+    /// The modification time of the file when CVS last wrote it, or a note
+    /// such as "Result of merge" or "dummy timestamp".
+    pub timestamp: String,
+    /// The sticky options, such as -kb.
+    pub options: String,
+}
+
+impl Entry {
+    /// Whether the file has been removed, but not yet committed.
+    pub fn is_removed(&self) -> bool {
+        self.revision.starts_with('-')
+    }
+
+    /// Whether the file has been added, but not yet committed.
+    pub fn is_added(&self) -> bool {
+        self.revision == "0"
+    }
+}
+
+/// The contents of a CVS administrative directory: CVS/Entries, with any
+/// changes in CVS/Entries.Log applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entries {
+    pub files: BTreeMap<String, Entry>,
+    pub dirs: BTreeSet<String>,
+    /// Whether the directory list is complete, which CVS marks with a line
+    /// that's just D. Otherwise, CVS looks for subdirectories on disk.
+    pub complete: bool,
+}
+
+impl Entries {
+    /// Reads the entries of a directory in a working copy, returning None if
+    /// CVS doesn't know about it.
+    pub fn read(dir: &Path) -> Result<Option<Self>> {
+        let read = |name: &str| -> Result<Option<String>> {
+            let file = dir.join("CVS").join(name);
+            match fs::read(&file) {
+                Ok(content) => Ok(Some(String::from_utf8_lossy(&content).into_owned())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(Error::file("read", &file)(e)),
+            }
+        };
+
+        let mut entries = match read("Entries")? {
+            Some(content) => Self::parse(&content),
+            None => return Ok(None),
+        };
+        if let Some(log) = read("Entries.Log")? {
+            entries.apply_log(&log);
+        }
+        Ok(Some(entries))
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let mut entries = Self::default();
+        for line in content.lines() {
+            entries.add(line);
+        }
+        entries
+    }
+
+    /// Applies the changes in an Entries.Log file, where each line is an
+    /// Entries line prefixed with A if it was added or R if it was removed.
+    pub fn apply_log(&mut self, content: &str) {
+        for line in content.lines() {
+            match line.split_once(' ') {
+                Some(("A", line)) => self.add(line),
+                Some(("R", line)) => self.remove(line),
+                _ => {}
+            }
+        }
+    }
+
+    fn add(&mut self, line: &str) {
+        // Each file entry looks like /name/revision/timestamp/options/tagdate,
+        // and each directory like D/name////.
+        if line == "D" {
+            self.complete = true;
+        } else if let Some(rest) = line.strip_prefix("D/") {
+            if let Some(name) = rest.split('/').next().filter(|name| !name.is_empty()) {
+                self.dirs.insert(name.to_string());
+            }
+        } else if let Some((name, entry)) = parse_entry(line) {
+            self.files.insert(name, entry);
+        }
+    }
+
+    fn remove(&mut self, line: &str) {
+        if let Some(rest) = line.strip_prefix("D/") {
+            if let Some(name) = rest.split('/').next() {
+                self.dirs.remove(name);
+            }
+        } else if let Some(name) = line
+            .strip_prefix('/')
+            .and_then(|rest| rest.split('/').next())
+        {
+            self.files.remove(name);
+        }
+    }
+}
+
+fn parse_entry(line: &str) -> Option<(String, Entry)> {
+    let mut fields = line.strip_prefix('/')?.split('/');
+    let name = fields.next()?;
+    let revision = fields.next()?;
+    let timestamp = fields.next().unwrap_or_default();
+    let options = fields.next().unwrap_or_default();
+    if name.is_empty() || revision.is_empty() {
+        return None;
+    }
+
+    Some((
+        name.to_string(),
+        Entry {
+            revision: revision.to_string(),
+            timestamp: timestamp.to_string(),
+            options: options.to_string(),
+        },
+    ))
+}
+
+/// Returns the files in CVS/Entries that are in the repository, leaving out
+/// those that are only added or removed in the working copy.
+fn parse_entries(content: &str) -> HashMap<String, Entry> {
+    Entries::parse(content)
+        .files
+        .into_iter()
+        .filter(|(_, entry)| !entry.is_added() && !entry.is_removed())
+        .collect()
+}
+
+/// Walks a working copy from the given directory, returning every file entry
+/// CVS has, including added and removed files, by path relative to dir.
+pub fn scan_entries(dir: &Path) -> Result<Vec<(PathBuf, Entry)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(relative) = dirs.pop() {
+        let absolute = dir.join(&relative);
+        let entries = match Entries::read(&absolute)? {
+            Some(entries) => entries,
+            None => continue,
+        };
+
+        for (name, entry) in entries.files {
+            files.push((relative.join(name), entry));
+        }
+        for name in entries.dirs.iter() {
+            dirs.push(relative.join(name));
+        }
+        if !entries.complete {
+            for entry in fs::read_dir(&absolute)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                if entry.file_type()?.is_dir()
+                    && name != "CVS"
+                    && !entries.dirs.contains(name.to_string_lossy().as_ref())
+                {
+                    dirs.push(relative.join(name));
+                }
+            }
+        }
+    }
+
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(files)
+}
+
+fn parse_tag(content: &str) -> Option<StickyTag> {
+    let line = content.lines().next()?;
+    let mut chars = line.chars();
+    let kind = chars.next()?;
+    let value = chars.as_str().to_string();
+
+    match kind {
+        'T' => Some(StickyTag::Branch(value)),
+        'N' => Some(StickyTag::Tag(value)),
+        'D' => Some(StickyTag::Date(value)),
+        _ => None,
+    }
+}
+
+fn parse_status(content: &str) -> HashMap<String, FileStatus> {
+    // Each file gets a block like this, where the file name is prefixed with
+    // "no file" if it isn't in the working copy:
+    //
+    // File: foo.c             Status: Up-to-date
+    //
+    //    Working revision:    1.2     ...
+    //    Repository revision: 1.2     /cvsroot/module/foo.c,v
+    let mut statuses = HashMap::new();
+    let mut current: Option<(String, FileStatus)> = None;
+
+    for line in content.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("File:") {
+            statuses.extend(current.take());
+
+            let (name, status) = match rest.split_once("Status:") {
+                Some(parts) => parts,
+                None => continue,
+            };
+            let name = name.trim();
+            let (name, working_file) = match name.strip_prefix("no file ") {
+                Some(name) => (name, false),
+                None => (name, true),
+            };
+            current = Some((
+                name.to_string(),
+                FileStatus {
+                    status: status.trim().to_string(),
+                    working_file,
+                    repository_revision: None,
+                },
+            ));
+        } else if let Some(rest) = line.strip_prefix("Repository revision:") {
+            if let Some((_, status)) = current.as_mut() {
+                status.repository_revision = rest
+                    .split_whitespace()
+                    .next()
+                    .filter(|revision| revision.starts_with(|c: char| c.is_ascii_digit()))
+                    .map(String::from);
+            }
+        }
+    }
+    statuses.extend(current);
+
+    statuses
+}
+
+fn parse_branch_tags(content: &str) -> HashSet<String> {
+    // Each file's header lists its tags, indented under "symbolic names:".
+    // Branch tags name either a magic revision with a 0 in the second last
+    // component, or a vendor branch with an odd number of components:
+    //
+    // symbolic names:
+    //         REL_1_2_BRANCH: 1.2.0.2
+    //         REL_1_2: 1.2
+    //         git2cvs: 1.1.1
+    let mut branches = HashSet::new();
+    let mut in_names = false;
+
+    for line in content.lines() {
+        if line.starts_with("symbolic names:") {
+            in_names = true;
+        } else if !in_names || !line.starts_with(char::is_whitespace) {
+            in_names = false;
+        } else if let Some((name, revision)) = line.trim().split_once(':') {
+            let components: Vec<_> = revision.trim().split('.').collect();
+            let magic = components.len() >= 4 && components[components.len() - 2] == "0";
+            if magic || components.len() % 2 == 1 {
+                branches.insert(name.to_string());
+            }
+        }
+    }
+
+    branches
+}
+
+/// Returns true if revision a comes after revision b, comparing each
+/// component numerically, so that 1.10 is newer than 1.9 and a branch
+/// revision is newer than the revision it branched from.
+pub fn newer_revision(a: &str, b: &str) -> bool {
+    let parse = |revision: &str| -> Vec<u64> {
+        revision
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+
+    parse(a) > parse(b)
+}
+
+/// How a git branch name is turned into a CVS branch tag, which can only
+/// contain ASCII letters, digits, - and _.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sanitise {
+    /// Each other character becomes __u followed by its code point in hex,
+    /// which can't collide with another branch's tag. So that a name can't
+    /// look like an escape, a _ that starts __u is escaped too.
+    Escape,
+    /// Each other character becomes _, which is more readable but can collide.
+    Replace,
+    /// Names that would need changing are refused.
+    Strict,
+}
+
+impl Sanitise {
+    pub const VARIANTS: &'static [&'static str] = &["escape", "replace", "strict"];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Sanitise::Escape => "escape",
+            Sanitise::Replace => "replace",
+            Sanitise::Strict => "strict",
+        }
+    }
+
+    pub fn apply(self, name: &str) -> Result<String> {
+        let mut out = String::new();
+
+        for (i, c) in name.char_indices() {
+            let escape_prefix = self == Sanitise::Escape && name[i..].starts_with("__u");
+            if (c.is_ascii_alphanumeric() || c == '-' || c == '_') && !escape_prefix {
+                out.push(c);
+                continue;
+            }
+
+            match self {
+                Sanitise::Escape => out.push_str(&format!("__u{:06x}", u32::from(c))),
+                Sanitise::Replace => out.push('_'),
+                Sanitise::Strict => {
+                    return Err(invalid_tag(name, format!("cannot contain {:?}", c)))
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Turns a CVS branch tag made with Sanitise::Escape back into the git branch
+/// name. Tags that escaping couldn't have produced are refused.
+pub fn desanitise_branch(tag: &str) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = tag;
+
+    while let Some(start) = rest.find("__u") {
+        out.push_str(&rest[..start]);
+        let hex = match rest.get(start + 3..start + 9) {
+            Some(hex) if hex.chars().all(|c| c.is_ascii_hexdigit()) => hex,
+            _ => return Err(invalid_tag(tag, "contains a malformed escape")),
+        };
+        match u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+            Some(c) => out.push(c),
+            None => {
+                return Err(invalid_tag(
+                    tag,
+                    format!("escapes invalid code point {}", hex),
+                ))
+            }
+        }
+        rest = &rest[start + 9..];
+    }
+    out.push_str(rest);
+
+    // Escapes of characters that don't need them would decode, but wouldn't
+    // come back the same.
+    if Sanitise::Escape.apply(&out)? != tag {
+        return Err(invalid_tag(tag, "is not an escaped git branch name"));
+    }
+    Ok(out)
+}
+
+fn invalid_tag<S: Into<String>>(tag: &str, problem: S) -> Error {
+    Error::InvalidTag {
+        tag: tag.into(),
+        problem: problem.into(),
+    }
+}
+
+/// Tags longer than this are legal in RCS files, but other tools that read
+/// them tend to have fixed size buffers.
+const MAX_TAG_LENGTH: usize = 255;
+
+/// Fails if CVS won't accept a name as a tag, or other tools are likely to
+/// choke on it.
+pub fn check_tag_name(name: &str) -> Result<()> {
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(invalid_tag(name, "doesn't start with a letter"));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && *c != '-' && *c != '_')
+    {
+        return Err(invalid_tag(name, format!("contains {:?}", c)));
+    }
+    if name == "HEAD" || name == "BASE" {
+        return Err(invalid_tag(name, "is reserved"));
+    }
+    if name.len() > MAX_TAG_LENGTH {
+        return Err(invalid_tag(
+            name,
+            format!("is longer than {} characters", MAX_TAG_LENGTH),
+        ));
+    }
+
+    Ok(())
+}
+
+impl std::str::FromStr for Sanitise {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "escape" => Ok(Sanitise::Escape),
+            "replace" => Ok(Sanitise::Replace),
+            "strict" => Ok(Sanitise::Strict),
+            _ => Err(Error::UnknownValue {
+                kind: "sanitisation scheme",
+                value: s.into(),
+                expected: Self::VARIANTS,
+            }),
+        }
+    }
+}
+
+lazy_static! {
+    static ref ARG_LIMITS: ArgLimits = ArgLimits::system();
+}
+
+/// Appends a `Git-Commit` trailer to a commit message, after a blank line.
+/// If the message already ends with trailers, such as the one added by
+/// `--annotate-authors`, the new one joins them.
+pub fn with_git_commit(message: &[u8], oid: &git2::Oid) -> Vec<u8> {
+    let end = message
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    let mut message = message[..end].to_vec();
+
+    if !message.is_empty() {
+        let paragraph = match message.windows(2).rposition(|w| w == b"\n\n") {
+            Some(i) => &message[i + 2..],
+            None => &[][..],
+        };
+        let is_trailer = |line: &[u8]| match line.iter().position(|b| *b == b':') {
+            Some(i) => {
+                i > 0
+                    && line[..i]
+                        .iter()
+                        .all(|b| b.is_ascii_alphanumeric() || *b == b'-')
+            }
+            None => false,
+        };
+        if paragraph.is_empty() || !paragraph.split(|b| *b == b'\n').all(is_trailer) {
+            message.push(b'\n');
+        }
+        message.push(b'\n');
+    }
+    message.extend_from_slice(format!("Git-Commit: {}\n", oid).as_bytes());
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::test_support::CvsFixture;
+
+    #[test]
+    fn test_with_git_commit() {
+        let oid = git2::Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let trailer = "Git-Commit: 0123456789abcdef0123456789abcdef01234567\n";
+
+        for (message, expected) in [
+            (&b""[..], trailer.to_string()),
+            (b" \n\n", trailer.to_string()),
+            (b"Subject", format!("Subject\n\n{}", trailer)),
+            (b"Subject\n", format!("Subject\n\n{}", trailer)),
+            (
+                b"Subject\n\nBody  \n\t\n",
+                format!("Subject\n\nBody\n\n{}", trailer),
+            ),
+            (
+                b"Subject\n\nGit-Author: Alice <alice@example.com>\n",
+                format!(
+                    "Subject\n\nGit-Author: Alice <alice@example.com>\n{}",
+                    trailer
+                ),
+            ),
+            (
+                b"Signed-off-by: Alice <alice@example.com>",
+                format!("Signed-off-by: Alice <alice@example.com>\n\n{}", trailer),
+            ),
+        ] {
+            assert_eq!(
+                expected.as_bytes(),
+                &with_git_commit(message, &oid)[..],
+                "{:?}",
+                String::from_utf8_lossy(message)
+            );
+        }
+
+        let mut expected = b"caf\xe9\n\n".to_vec();
+        expected.extend_from_slice(trailer.as_bytes());
+        assert_eq!(expected, with_git_commit(b"caf\xe9\n", &oid));
+    }
+
+    #[test]
+    fn test_parse_entries() {
+        let entries = parse_entries(
+            "/Makefile/1.3/Thu Jan  1 00:00:00 1970//\n/image.png/1.1/Thu Jan  1 00:00:00 1970/-kb/\n/added.c/0/Initial added.c//\n/gone.c/-1.2/dummy timestamp//\nD/subdir////\nD\n",
+        );
+
+        assert_eq!(2, entries.len());
+        assert_eq!("1.3", entries["Makefile"].revision);
+        assert_eq!("", entries["Makefile"].options);
+        assert_eq!("1.1", entries["image.png"].revision);
+        assert_eq!("-kb", entries["image.png"].options);
+    }
+
+    #[test]
+    fn test_entries() {
+        let mut entries = Entries::parse(include_str!("../tests/fixtures/entries/Entries"));
+        assert_eq!(
+            vec!["Makefile", "added.c", "gone.c", "image.png"],
+            entries.files.keys().collect::<Vec<_>>()
+        );
+        let makefile = &entries.files["Makefile"];
+        assert_eq!("1.3", makefile.revision);
+        assert_eq!("Thu Jan  1 00:00:00 1970", makefile.timestamp);
+        assert_eq!("", makefile.options);
+        assert_eq!("-kb", entries.files["image.png"].options);
+        assert!(entries.files["added.c"].is_added());
+        assert!(entries.files["gone.c"].is_removed());
+        assert_eq!(vec!["docs", "lib"], entries.dirs.iter().collect::<Vec<_>>());
+        assert!(entries.complete);
+
+        entries.apply_log(include_str!("../tests/fixtures/entries/Entries.Log"));
+        assert_eq!(
+            vec!["Makefile", "added.c", "gone.c", "new.c"],
+            entries.files.keys().collect::<Vec<_>>()
+        );
+        assert_eq!("1.4", entries.files["Makefile"].revision);
+        assert_eq!(
+            vec!["lib", "tests"],
+            entries.dirs.iter().collect::<Vec<_>>()
+        );
+
+        assert!(!Entries::parse("/a.c/1.1///\n").complete);
+    }
+
+    #[test]
+    fn test_scan_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |path: &str, content: &str| {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+        write("CVS/Entries", "/top.c/1.1///\nD/listed////\nD\n");
+        write("listed/CVS/Entries", "/a.c/1.2///\n/b.png/1.1//-kb/\n");
+        write(
+            "listed/CVS/Entries.Log",
+            "A /c.c/0/dummy timestamp//\nR /a.c/1.2///\n",
+        );
+        // Without the D line, CVS would look for this directory on disk.
+        write("unlisted/CVS/Entries", "/d.c/1.1///\n");
+        write("unlisted/plain.txt", "");
+
+        let paths = |entries: Vec<(PathBuf, Entry)>| {
+            entries
+                .into_iter()
+                .map(|(path, entry)| (path.display().to_string(), entry.revision))
+                .collect::<Vec<_>>()
+        };
+        let expected = |unlisted: bool| {
+            let mut expected = vec![
+                ("listed/b.png".to_string(), "1.1".to_string()),
+                ("listed/c.c".into(), "0".into()),
+                ("top.c".into(), "1.1".into()),
+            ];
+            if unlisted {
+                expected.push(("unlisted/d.c".into(), "1.1".into()));
+            }
+            expected
+        };
+        assert_eq!(expected(false), paths(scan_entries(dir.path()).unwrap()));
+
+        write("CVS/Entries", "/top.c/1.1///\nD/listed////\n");
+        assert_eq!(expected(true), paths(scan_entries(dir.path()).unwrap()));
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let statuses = parse_status(
+            "===================================================================\nFile: a.c              \tStatus: Up-to-date\n\n   Working revision:\t1.2\tThu Jan  1 00:00:00 1970\n   Repository revision:\t1.2\t/cvs/module/a.c,v\n   Commit Identifier:\tabc\n   Sticky Tag:\t\t(none)\n\n===================================================================\nFile: no file b.c       \tStatus: Up-to-date\n\n   Working revision:\tNo entry for b.c\n   Repository revision:\t1.3\t/cvs/module/Attic/b.c,v\n\n===================================================================\nFile: c.c              \tStatus: Locally Added\n\n   Working revision:\tNew file!\n   Repository revision:\tNo revision control file\n",
+        );
+
+        assert_eq!(3, statuses.len());
+        assert_eq!(
+            FileStatus {
+                status: "Up-to-date".into(),
+                working_file: true,
+                repository_revision: Some("1.2".into()),
+            },
+            statuses["a.c"]
+        );
+        assert!(!statuses["b.c"].working_file);
+        assert_eq!(Some("1.3"), statuses["b.c"].repository_revision.as_deref());
+        assert_eq!("Locally Added", statuses["c.c"].status);
+        assert_eq!(None, statuses["c.c"].repository_revision);
+    }
+
+    #[test]
+    fn test_parse_branch_tags() {
+        let branches = parse_branch_tags(
+            "\nRCS file: /cvs/module/a.c,v\nhead: 1.2\nbranch:\nlocks: strict\naccess list:\nsymbolic names:\n\tREL_1_2_BRANCH: 1.2.0.2\n\tREL_1_2: 1.2\n\tgit2cvs: 1.1.1\n\tinit: 1.1.1.1\nkeyword substitution: kv\ntotal revisions: 3;\tselected revisions: 0\ndescription:\n=============================================================================\n\nRCS file: /cvs/module/b.c,v\nhead: 1.1\nsymbolic names:\n\tfeature: 1.1.0.4\nkeyword substitution: kv\n",
+        );
+
+        let mut branches: Vec<_> = branches.into_iter().collect();
+        branches.sort();
+        assert_eq!(vec!["REL_1_2_BRANCH", "feature", "git2cvs"], branches);
+    }
+
+    #[test]
+    fn test_newer_revision() {
+        assert!(newer_revision("1.2", "1.1"));
+        assert!(newer_revision("1.10", "1.9"));
+        assert!(newer_revision("1.2.2.1", "1.2"));
+        assert!(!newer_revision("1.2", "1.2"));
+        assert!(!newer_revision("1.1", "1.2"));
+    }
+
+    #[test]
+    fn test_arg_chunker() {
+        // Budgets in terms of how many arguments of a given length fit.
+        let fit = |n: usize, len: usize| n * arg_cost(OsStr::new(&"x".repeat(len)));
+        let limit = |bytes| ArgLimits {
+            bytes,
+            count: usize::MAX,
+        };
+        let chunk = |limits: ArgLimits, args: &[&str]| {
+            let chunks = RefCell::new(Vec::new());
+            let mut chunker = ArgChunker::new(
+                Operation::Add,
+                |chunk| {
+                    chunks.borrow_mut().push(chunk.join(OsStr::new(" ")));
+                    Ok(())
+                },
+                limits,
+            );
+            for arg in args {
+                chunker.push(arg).unwrap();
+            }
+            chunker.flush().unwrap();
+            drop(chunker);
+            chunks.into_inner()
+        };
+
+        // Each argument takes its length plus a NUL and a pointer.
+        assert_eq!(vec!["ab cd"], chunk(limit(fit(2, 2)), &["ab", "cd"]));
+        assert_eq!(vec!["ab", "cd"], chunk(limit(fit(2, 2) - 1), &["ab", "cd"]));
+        assert_eq!(
+            vec!["ab cd", "e"],
+            chunk(limit(fit(2, 2)), &["ab", "cd", "e"])
+        );
+
+        // An argument over the limit is an error, and nothing is run for it
+        // or the arguments before it.
+        let ran = Cell::new(false);
+        let mut chunker = ArgChunker::new(
+            Operation::Add,
+            |_| {
+                ran.set(true);
+                Ok(())
+            },
+            limit(fit(1, 3)),
+        );
+        chunker.push("a").unwrap();
+        let err = chunker.push("bcdef").unwrap_err();
+        assert!(
+            matches!(&err, Error::ArgumentTooLong { arg, .. } if arg == Path::new("bcdef")),
+            "{:?}",
+            err
+        );
+        assert!(!ran.get());
+
+        assert!(chunk(limit(fit(1, 3)), &[]).is_empty());
+
+        // The count is a limit of its own.
+        let counted = ArgLimits {
+            bytes: usize::MAX,
+            count: 2,
+        };
+        assert_eq!(
+            vec!["a b", "c d", "e"],
+            chunk(counted, &["a", "b", "c", "d", "e"])
+        );
+        let both = ArgLimits {
+            bytes: fit(3, 1),
+            count: 3,
+        };
+        assert_eq!(
+            vec!["a b c", "dd ee", "f"],
+            chunk(both, &["a", "b", "c", "dd", "ee", "f"])
+        );
+
+        // The system limits leave room for the environment and the fixed
+        // arguments.
+        let system = ArgLimits::system();
+        assert!(system.bytes > 0);
+        assert_eq!(
+            ArgLimits::from_arg_max(1_000_000).bytes + 1000,
+            ArgLimits::from_arg_max(1_001_000).bytes
+        );
+        assert_eq!(0, ArgLimits::from_arg_max(FIXED_ARG_BYTES).bytes);
+
+        // Errors come back from push and finish.
+        let mut chunker = ArgChunker::new(
+            Operation::Add,
+            |_| Err(Error::Other("cvs failed".into())),
+            limit(fit(1, 2)),
+        );
+        chunker.push("ab").unwrap();
+        assert!(chunker.push("cd").is_err());
+        let mut chunker = ArgChunker::new(
+            Operation::Add,
+            |_| Err(Error::Other("cvs failed".into())),
+            limit(fit(1, 2)),
+        );
+        chunker.push("ab").unwrap();
+        assert!(chunker.flush().is_err());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "without being flushed")]
+    fn test_arg_chunker_unflushed() {
+        let mut chunker = ArgChunker::new(Operation::Add, |_| Ok(()), ArgLimits::system());
+        chunker.push("a").unwrap();
+    }
+
+    #[test]
+    fn test_choose_arg_max() {
+        assert_eq!(2_097_152, choose_arg_max(Some(2_097_152)));
+        assert_eq!(MAX_ARG_MAX, choose_arg_max(Some(isize::MAX)));
+        assert_eq!(DEFAULT_ARG_MAX, choose_arg_max(None));
+        assert_eq!(DEFAULT_ARG_MAX, choose_arg_max(Some(-1)));
+        assert_eq!(DEFAULT_ARG_MAX, choose_arg_max(Some(0)));
+    }
+
+    #[test]
+    fn test_sanitise() {
+        for scheme in &[Sanitise::Escape, Sanitise::Replace, Sanitise::Strict] {
+            assert_eq!("foo", scheme.apply("foo").unwrap());
+            assert_eq!("foo-Bar_quux0", scheme.apply("foo-Bar_quux0").unwrap());
+            assert_eq!(*scheme, scheme.as_str().parse().unwrap());
+        }
+
+        assert_eq!("__u000020", Sanitise::Escape.apply(" ").unwrap());
+        assert_eq!(
+            "feature__u00002fx__u0000e9",
+            Sanitise::Escape.apply("feature/x\u{e9}").unwrap()
+        );
+        assert_eq!(
+            "feature_x_",
+            Sanitise::Replace.apply("feature/x\u{e9}").unwrap()
+        );
+        assert!(format!("{:#}", Sanitise::Strict.apply("feature/x").unwrap_err()).contains("'/'"));
+        assert!("other".parse::<Sanitise>().is_err());
+
+        // A name that looks like an escape is escaped itself.
+        assert_eq!(
+            "__u00005f_u000020",
+            Sanitise::Escape.apply("__u000020").unwrap()
+        );
+        assert_eq!("a_u_b", Sanitise::Escape.apply("a_u_b").unwrap());
+    }
+
+    #[test]
+    fn test_sanitise_injective() {
+        // Every name up to five characters long from an alphabet that can
+        // spell escapes.
+        let alphabet = ['_', 'u', '0', '2', 'f', '/'];
+        let mut names = vec![String::new()];
+        let mut start = 0;
+        for _ in 0..5 {
+            let end = names.len();
+            for i in start..end {
+                for c in alphabet.iter() {
+                    let name = format!("{}{}", names[i], c);
+                    names.push(name);
+                }
+            }
+            start = end;
+        }
+
+        let mut seen = HashMap::new();
+        for name in names.iter() {
+            let tag = Sanitise::Escape.apply(name).unwrap();
+            if let Some(other) = seen.insert(tag.clone(), name) {
+                panic!("{:?} and {:?} are both sanitised to {}", other, name, tag);
+            }
+        }
+    }
+
+    #[test]
+    fn test_desanitise_branch() {
+        for name in &[
+            "main",
+            "feature/foo",
+            "release/1.0",
+            "1.x",
+            "fix-\u{1f41b}",
+            "caf\u{e9} au lait",
+            "__u000020",
+            "a__u",
+            "___u00002f_",
+            "a_u_b",
+        ] {
+            let tag = Sanitise::Escape.apply(name).unwrap();
+            assert_eq!(*name, desanitise_branch(&tag).unwrap(), "{}", tag);
+        }
+
+        assert_eq!(
+            "feature/foo",
+            desanitise_branch("feature__u00002ffoo").unwrap()
+        );
+        assert_eq!("fix-\u{1f41b}", desanitise_branch("fix-__u01f41b").unwrap());
+        assert!(desanitise_branch("a__u12").is_err());
+        assert!(desanitise_branch("a__u00002g").is_err());
+        assert!(desanitise_branch("a__u110000").is_err());
+        assert!(desanitise_branch("a__u00d800").is_err());
+        assert!(desanitise_branch("__u000061").is_err());
+    }
+
+    #[test]
+    fn test_check_tag_name() {
+        assert!(check_tag_name("release-1_0").is_ok());
+        assert!(check_tag_name("1_0").is_err());
+        assert!(check_tag_name("__u00002fx").is_err());
+        assert!(check_tag_name("").is_err());
+        assert!(check_tag_name("HEAD").is_err());
+        assert!(check_tag_name("REL_1_2_BRANCH").is_ok());
+        assert!(check_tag_name("REL_1.2").is_err());
+        assert!(check_tag_name(&"a".repeat(MAX_TAG_LENGTH)).is_ok());
+        assert!(check_tag_name(&"a".repeat(MAX_TAG_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(
+            Some(StickyTag::Branch("feature".into())),
+            parse_tag("Tfeature\n")
+        );
+        assert_eq!(Some(StickyTag::Tag("v1_0".into())), parse_tag("Nv1_0\n"));
+        assert_eq!(
+            Some(StickyTag::Date("2021.01.01.00.00.00".into())),
+            parse_tag("D2021.01.01.00.00.00\n")
+        );
+        assert_eq!(None, parse_tag(""));
+    }
+
+    #[test]
+    fn test_parse_cvsroot() {
+        let root: CvsRoot = ":pserver:anon:secret@cvs.example.com:2401/cvs"
+            .parse()
+            .unwrap();
+        assert_eq!(Method::Pserver, root.method());
+        assert_eq!(Some("anon"), root.user.as_deref());
+        assert_eq!(Some("secret"), root.password.as_deref());
+        assert_eq!(Some("cvs.example.com"), root.host());
+        assert_eq!(Some(2401), root.port);
+        assert_eq!("/cvs", root.path());
+        assert!(!root.is_local());
+        assert_eq!(
+            ":pserver:anon@cvs.example.com:2401/cvs",
+            root.to_command_arg(true)
+        );
+        assert_eq!(":pserver:anon@cvs.example.com:2401/cvs", root.to_string());
+
+        let root: CvsRoot = "/var/cvs".parse().unwrap();
+        assert_eq!(Method::Local, root.method());
+        assert!(root.is_local());
+        assert_eq!("/var/cvs", root.to_command_arg(false));
+
+        let root: CvsRoot = ":ext:alice@host/var/cvs".parse().unwrap();
+        assert_eq!(Some("host"), root.host());
+        assert_eq!(None, root.port);
+        assert_eq!("/var/cvs", root.path());
+        assert_eq!(":ext:alice@host:/var/cvs", root.to_command_arg(false));
+    }
+
+    #[test]
+    fn test_cvsroot_round_trip() {
+        let mut roots = vec!["/cvs".to_string(), "/a/b@c".to_string()];
+        for path in &["/cvs", "/srv/cvs/root", "/a:b@c"] {
+            for method in &["local", "fork"] {
+                roots.push(format!(":{}:{}", method, path));
+            }
+            for host in &["localhost", "cvs.example.com", "10.0.0.1"] {
+                for port in &["", "2401", "65535"] {
+                    for user in &["", "alice@", "anon.y-mous@"] {
+                        roots.push(format!(":ext:{}{}:{}{}", user, host, port, path));
+                        roots.push(format!(":pserver:{}{}:{}{}", user, host, port, path));
+                    }
+                    for user in &["alice:pw@", "anon::@", "bob:p@ss@", "carol:@"] {
+                        roots.push(format!(":pserver:{}{}:{}{}", user, host, port, path));
+                    }
+                }
+            }
+        }
+
+        for root in roots {
+            let parsed: CvsRoot = root.parse().unwrap();
+            assert_eq!(root, parsed.to_command_arg(false));
+
+            let stripped: CvsRoot = parsed.to_string().parse().unwrap();
+            assert_eq!(None, stripped.password);
+            assert_eq!(parsed.to_command_arg(true), stripped.to_command_arg(false));
+        }
+    }
+
+    #[test]
+    fn test_cvsroot_invalid() {
+        for (root, error) in &[
+            ("", "CVSROOT is empty"),
+            (
+                "cvs",
+                "CVSROOT cvs does not have an absolute repository path",
+            ),
+            (
+                ":local:cvs",
+                "CVSROOT :local:cvs does not have an absolute repository path",
+            ),
+            (":local", "CVSROOT :local has an unterminated access method"),
+            (
+                ":gserver:host:/cvs",
+                "CVSROOT :gserver:host:/cvs has unknown access method gserver",
+            ),
+            (":ext:host", "CVSROOT :ext:host has no repository path"),
+            (":ext:alice@:/cvs", "CVSROOT :ext:alice@:/cvs has no host"),
+            (":ext::/cvs", "CVSROOT :ext::/cvs has no host"),
+            (
+                ":ext:@host:/cvs",
+                "CVSROOT :ext:@host:/cvs has an empty user name",
+            ),
+            (
+                ":ext:alice:pw@host:/cvs",
+                "CVSROOT :ext:alice:pw@host:/cvs has a password, but only pserver accepts one",
+            ),
+            (
+                ":pserver:host:http/cvs",
+                "CVSROOT :pserver:host:http/cvs has invalid port http",
+            ),
+            (
+                ":pserver:host:0/cvs",
+                "CVSROOT :pserver:host:0/cvs has invalid port 0",
+            ),
+            (
+                ":pserver:host:65536/cvs",
+                "CVSROOT :pserver:host:65536/cvs has invalid port 65536",
+            ),
+        ] {
+            assert_eq!(
+                *error,
+                root.parse::<CvsRoot>().unwrap_err().to_string(),
+                "{}",
+                root
+            );
+        }
+    }
+
+    /// Creates a working copy directory with the given CVS/Entries and,
+    /// optionally, CVS/Tag content, and a fake cvs binary that logs its
+    /// arguments. The fake exits with the code in the exit file in the
+    /// directory, if there is one.
+    fn fake_checkout(entries: &str, tag: Option<&str>) -> (tempfile::TempDir, Repository) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cvs_dir = dir.path().join("checkout/src/CVS");
+        fs::create_dir_all(&cvs_dir).unwrap();
+        fs::write(cvs_dir.join("Entries"), entries).unwrap();
+        if let Some(tag) = tag {
+            fs::write(cvs_dir.join("Tag"), tag).unwrap();
+        }
+
+        let cvs = dir.path().join("cvs");
+        fs::write(
+            &cvs,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {}\nexit $(cat {} 2>/dev/null || echo 0)\n",
+                dir.path().join("log").display(),
+                dir.path().join("exit").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let repo = Repository {
+            cvs: cvs.into(),
+            cwd: dir.path().join("checkout"),
+            branch: tag
+                .and_then(|tag| tag.strip_prefix('T'))
+                .map(|tag| tag.trim().to_string()),
+            allowed: HashMap::new(),
+            runner: Runner::Execute,
+            arg_limits: *ARG_LIMITS,
+            output: Output::Capture,
+            retry: Retry::default(),
+        };
+        (dir, repo)
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let (dir, mut repo) = fake_checkout("", None);
+        fs::write(dir.path().join("exit"), "1\n").unwrap();
+
+        match repo.commit(b"message", None, None).unwrap_err() {
+            Error::CvsCommandFailed {
+                command,
+                status: ExitStatus::Exited(1),
+                attempts: 1,
+                ..
+            } => assert!(command.contains("commit -F"), "{}", command),
+            err => panic!("unexpected error {:?}", err),
+        }
+        match repo
+            .add_multiple(["a.c", "b.c"].iter(), KeywordMode::Default)
+            .unwrap_err()
+        {
+            Error::CvsCommandFailed { command, .. } => {
+                assert!(command.ends_with("add a.c b.c"), "{}", command)
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        // Allowing the code for one operation doesn't allow it for the rest.
+        repo.allowed.insert(Operation::Commit, vec![1]);
+        repo.commit(b"message", None, None).unwrap();
+        assert!(repo.add(OsStr::new("a.c"), KeywordMode::Default).is_err());
+
+        // The password in the CVSROOT isn't shown.
+        let root: CvsRoot = ":pserver:anon:secret@cvs.example.com:/cvs".parse().unwrap();
+        let message = format!(
+            "{:#}",
+            Context::new(repo.cvs.as_os_str())
+                .checkout(&root, "module", dir.path().join("checkout"), None)
+                .unwrap_err()
+        );
+        assert!(message.contains("checkout module failed with exit code 1"));
+        assert!(!message.contains("secret"), "{}", message);
+    }
+
+    #[test]
+    fn test_output() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (_dir, mut repo) = fake_checkout("", None);
+        let cvs = repo.cvs.clone();
+        let script = |body: &str| {
+            fs::write(&cvs, format!("#!/bin/sh\n{}\nexit 1\n", body)).unwrap();
+            fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+        };
+
+        // Only the last lines of the output make it into the error.
+        script("for i in $(seq 1 60); do echo out $i; done");
+        let message = format!("{:#}", repo.commit(b"message", None, None).unwrap_err());
+        assert!(message.contains("failed with exit code 1"), "{}", message);
+        assert!(message.ends_with("\nout 60"), "{}", message);
+        assert!(message.contains("\nout 11\n"), "{}", message);
+        assert!(!message.contains("\nout 10\n"), "{}", message);
+        assert_eq!(OUTPUT_TAIL_LINES + 1, message.lines().count());
+
+        script("echo oops >&2");
+        let message = format!("{:#}", repo.commit(b"message", None, None).unwrap_err());
+        assert!(message.ends_with("were:\noops"), "{}", message);
+
+        // Commands that succeed don't mind what they print.
+        repo.allowed.insert(Operation::Commit, vec![1]);
+        repo.commit(b"message", None, None).unwrap();
+
+        // Output that isn't captured can't be shown.
+        repo.output = Output::Inherit;
+        repo.allowed.clear();
+        script("true");
+        let message = format!("{:#}", repo.commit(b"message", None, None).unwrap_err());
+        assert!(message.ends_with("failed with exit code 1"), "{}", message);
+    }
+
+    #[test]
+    fn test_retry() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (dir, mut repo) = fake_checkout("", None);
+        repo.retry = Retry {
+            retries: 2,
+            delay: Duration::ZERO,
+        };
+        let (count, error_file) = (dir.path().join("count"), dir.path().join("error"));
+        let script = |failures: usize, error: &str| {
+            fs::write(&count, "").unwrap();
+            fs::write(&error_file, error).unwrap();
+            fs::write(
+                &repo.cvs,
+                format!(
+                    "#!/bin/sh\necho >> {count}\n\
+                     if [ $(wc -l < {count}) -le {} ]; then cat {} >&2; exit 1; fi\n",
+                    failures,
+                    error_file.display(),
+                    count = count.display()
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(&repo.cvs, fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        let attempts = || fs::read_to_string(&count).unwrap().lines().count();
+
+        // Lock failures are retried until one works.
+        script(2, "cvs [commit aborted]: lock failed - giving up");
+        repo.commit(b"message", None, None).unwrap();
+        assert_eq!(3, attempts());
+
+        script(
+            1,
+            "cvs add: failed to obtain dir lock in repository `/cvs/src'",
+        );
+        repo.add_multiple(["a.c"].iter(), KeywordMode::Default)
+            .unwrap();
+        assert_eq!(2, attempts());
+
+        // Running out of retries keeps the last output.
+        script(3, "cvs [commit aborted]: lock failed - giving up");
+        match repo.commit(b"message", None, None).unwrap_err() {
+            Error::CvsCommandFailed {
+                attempts: 3,
+                output,
+                ..
+            } => assert_eq!(
+                vec!["cvs [commit aborted]: lock failed - giving up"],
+                output
+            ),
+            err => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(3, attempts());
+
+        // Other failures, and operations that don't take locks, aren't.
+        script(1, "cvs [commit aborted]: correct above errors first!");
+        assert!(repo.commit(b"message", None, None).is_err());
+        assert_eq!(1, attempts());
+
+        script(1, "cvs commit: Up-to-date check failed for `a.c'");
+        assert!(repo.commit(b"message", None, None).is_err());
+        assert_eq!(1, attempts());
+
+        script(1, "cvs tag: lock failed");
+        assert!(repo.create_branch("feature").is_err());
+        assert_eq!(1, attempts());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = Retry {
+            retries: 100,
+            delay: Duration::from_secs(5),
+        };
+        assert_eq!(Duration::from_secs(5), retry.delay(1));
+        assert_eq!(Duration::from_secs(10), retry.delay(2));
+        assert_eq!(Duration::from_secs(20), retry.delay(3));
+        assert_eq!(retry.delay(17), retry.delay(100));
+    }
+
+    #[test]
+    fn test_no_input() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A cvs that asks for confirmation gets end of file, and can't start
+        // an editor either.
+        let (_dir, repo) = fake_checkout("", None);
+        fs::write(
+            &repo.cvs,
+            "#!/bin/sh\n[ \"$CVSEDITOR\" = false ] || exit 2\nread answer || exit 1\n",
+        )
+        .unwrap();
+        fs::set_permissions(&repo.cvs, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let message = format!("{:#}", repo.commit(b"message", None, None).unwrap_err());
+        assert!(message.ends_with("failed with exit code 1"), "{}", message);
+    }
+
+    #[test]
+    fn test_non_interactive_environment() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (dir, repo) = fake_checkout("", None);
+        let env = dir.path().join("env");
+        fs::write(
+            &repo.cvs,
+            format!("#!/bin/sh\n[ -t 0 ] && exit 2\nenv > {}\n", env.display()),
+        )
+        .unwrap();
+        fs::set_permissions(&repo.cvs, fs::Permissions::from_mode(0o755)).unwrap();
+
+        repo.commit(b"message", None, None).unwrap();
+        let env = fs::read_to_string(env).unwrap();
+        let vars: HashSet<&str> = env.lines().collect();
+        assert!(vars.contains("CVSEDITOR=false"), "{}", env);
+        if std::env::var_os("SSH_ASKPASS_REQUIRE").is_none() {
+            assert!(vars.contains("SSH_ASKPASS=false"), "{}", env);
+            assert!(vars.contains("SSH_ASKPASS_REQUIRE=force"), "{}", env);
+        }
+    }
+
+    #[test]
+    fn test_shielded() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A cvs that's sent SIGINT or SIGTERM, as it would be by Ctrl-C,
+        // carries on to the end.
+        let dir = tempfile::tempdir().unwrap();
+        let cvs = dir.path().join("cvs");
+        fs::write(&cvs, "#!/bin/sh\nkill -INT $$\nkill -TERM $$\nexit 3\n").unwrap();
+        fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            ExitStatus::Signaled(libc::SIGINT as u8),
+            Exec::cmd(&cvs).join().unwrap()
+        );
+        let exec = shielded(cvs.as_os_str()).arg("commit").arg("a file");
+        assert_eq!(ExitStatus::Exited(3), exec.clone().join().unwrap());
+
+        // Logs and errors show cvs, not the shell.
+        assert_eq!(
+            Exec::cmd(&cvs)
+                .arg("commit")
+                .arg("a file")
+                .to_cmdline_lossy(),
+            cmdline(&exec)
+        );
+    }
+
+    #[test]
+    fn test_switch_branch() {
+        let (dir, mut repo) = fake_checkout("", None);
+        repo.create_branch("feature").unwrap();
+        repo.switch_branch("feature").unwrap();
+        assert_eq!(Some("feature"), repo.branch.as_deref());
+        assert_eq!(
+            "tag -b feature\nupdate -r feature\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+
+        // A failed switch leaves the branch alone.
+        fs::write(dir.path().join("exit"), "1\n").unwrap();
+        assert!(repo.switch_branch("other").is_err());
+        assert_eq!(Some("feature"), repo.branch.as_deref());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let (dir, mut repo) = fake_checkout("/known.c/1.1///\n", None);
+        let dry_run = Rc::new(RefCell::new(DryRun::default()));
+        repo.runner = Runner::DryRun(dry_run.clone());
+
+        repo.add_multiple(["src/new.c"].iter(), KeywordMode::Default)
+            .unwrap();
+        repo.commit(b"Add new.c\n", Some("alice"), None).unwrap();
+        assert_eq!(
+            vec![PathBuf::from("src/unknown.c")],
+            repo.remove_multiple(["src/new.c", "src/known.c", "src/unknown.c"].iter())
+                .unwrap()
+        );
+        repo.set_keyword_mode("src/known.c", "kv").unwrap();
+        repo.create_branch("feature").unwrap();
+        repo.switch_branch("feature").unwrap();
+        assert_eq!(None, repo.branch);
+
+        // Nothing was run.
+        assert!(!dir.path().join("log").exists());
+        let commands = dry_run.borrow().commands.clone();
+        assert_eq!(6, commands.len(), "{:?}", commands);
+        assert!(commands[0].ends_with("add src/new.c"), "{:?}", commands);
+        assert_eq!("cvs commit as alice: Add new.c", commands[1]);
+        assert!(
+            commands[2].ends_with("remove src/new.c src/known.c"),
+            "{:?}",
+            commands
+        );
+        assert!(commands[5].ends_with("update -r feature"), "{:?}", commands);
+        assert!(dry_run.borrow().branches.contains("feature"));
+    }
+
+    #[test]
+    fn test_add_multiple_chunks() {
+        let (_dir, mut repo) = fake_checkout("", None);
+        let dry_run = Rc::new(RefCell::new(DryRun::default()));
+        repo.runner = Runner::DryRun(dry_run.clone());
+        repo.arg_limits = ArgLimits {
+            bytes: usize::MAX,
+            count: 2,
+        };
+
+        repo.add_multiple(["a.c", "b.c", "c.c"].iter(), KeywordMode::Default)
+            .unwrap();
+        let commands = dry_run.borrow().commands.clone();
+        assert_eq!(2, commands.len(), "{:?}", commands);
+        assert!(commands[0].ends_with("add a.c b.c"), "{:?}", commands);
+        assert!(commands[1].ends_with("add c.c"), "{:?}", commands);
+
+        // A path that can't fit at all fails before anything is run.
+        dry_run.borrow_mut().commands.clear();
+        repo.arg_limits = ArgLimits {
+            bytes: arg_cost(OsStr::new("a.c")),
+            count: 2,
+        };
+        let err = repo
+            .add_multiple(["a.c", "long.c"].iter(), KeywordMode::Default)
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::ArgumentTooLong { arg, .. } if arg == Path::new("long.c")),
+            "{:?}",
+            err
+        );
+        assert!(
+            err.to_string().starts_with("cannot run cvs add: "),
+            "{}",
+            err
+        );
+        let err = repo.remove_multiple(["a.c", "long.c"].iter()).unwrap_err();
+        assert!(
+            matches!(&err, Error::ArgumentTooLong { arg, .. } if arg == Path::new("long.c")),
+            "{:?}",
+            err
+        );
+        assert!(
+            err.to_string().starts_with("cannot run cvs remove: "),
+            "{}",
+            err
+        );
+        assert!(dry_run.borrow().commands.is_empty());
+    }
+
+    #[test]
+    fn test_create_branch_at() {
+        let (dir, repo) = fake_checkout("", None);
+        let root: CvsRoot = "/cvsroot".parse().unwrap();
+        let revisions = [
+            (PathBuf::from("src/a.c"), "1.2".to_string()),
+            (PathBuf::from("src/b.c"), "1.1".to_string()),
+            (PathBuf::from("src/dir/c.c"), "1.1".to_string()),
+        ];
+        let ctx = Context::new(repo.cvs.as_os_str());
+        ctx.create_branch_at(&root, "module", "feature", &revisions)
+            .unwrap();
+        ctx.create_branch_at(&root, ".", "other", &revisions[..1])
+            .unwrap();
+
+        assert_eq!(
+            "-d /cvsroot rtag -b -r 1.1 feature module/src/b.c module/src/dir/c.c\n\
+            -d /cvsroot rtag -b -r 1.2 feature module/src/a.c\n\
+            -d /cvsroot rtag -b -r 1.2 other src/a.c\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_allowed_exit() {
+        assert_eq!(
+            AllowedExit {
+                operation: Operation::Commit,
+                code: 1
+            },
+            "commit=1".parse().unwrap()
+        );
+        assert!("commit".parse::<AllowedExit>().is_err());
+        assert!("diff=1".parse::<AllowedExit>().is_err());
+        assert!("add=-1".parse::<AllowedExit>().is_err());
+    }
+
+    #[test]
+    fn test_remove_on_branch() {
+        // inherited.c exists at the branch point, added.c was added on the
+        // branch, and trunk-only.c was added to the trunk after the branch
+        // point, so CVS doesn't know about it here.
+        let (dir, repo) = fake_checkout(
+            "/inherited.c/1.2/dummy//Tfeature\n/added.c/1.1.2.1/dummy//Tfeature\n",
+            Some("Tfeature\n"),
+        );
+
+        let skipped = repo
+            .remove_multiple(
+                ["src/added.c", "src/inherited.c", "src/trunk-only.c"]
+                    .iter()
+                    .map(Path::new),
+            )
+            .unwrap();
+        assert_eq!(vec![PathBuf::from("src/trunk-only.c")], skipped);
+        assert_eq!(
+            "remove src/added.c src/inherited.c\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_remove_wrong_sticky_tag() {
+        let (dir, mut repo) = fake_checkout("/file.c/1.1/dummy//\n", None);
+
+        // A trunk working copy can't be used to remove files on a branch.
+        repo.branch = Some("feature".into());
+        assert!(repo.remove_multiple(std::iter::once("src/file.c")).is_err());
+
+        // Nor can a working copy stuck on a non-branch tag.
+        fs::write(dir.path().join("checkout/src/CVS/Tag"), "Nv1_0\n").unwrap();
+        repo.branch = None;
+        assert!(repo.remove_multiple(std::iter::once("src/file.c")).is_err());
+
+        assert!(!dir.path().join("log").exists());
+    }
+
+    #[test]
+    fn test_tracked_files() {
+        let (dir, repo) = fake_checkout(
+            "/b.c/1.1/dummy//\n/a.c/1.2/dummy//\n/gone.c/-1.1/dummy//\n",
+            None,
+        );
+        let sub = dir.path().join("checkout/src/sub");
+        fs::create_dir_all(sub.join("CVS")).unwrap();
+        fs::write(sub.join("CVS/Entries"), "/c.c/1.1/dummy//\nD\n").unwrap();
+        fs::create_dir_all(dir.path().join("checkout/src/untracked")).unwrap();
+
+        assert_eq!(
+            vec![
+                PathBuf::from("src/a.c"),
+                PathBuf::from("src/b.c"),
+                PathBuf::from("src/sub/c.c"),
+            ],
+            repo.tracked_files(".", None).unwrap()
+        );
+        assert_eq!(
+            vec![PathBuf::from("src/a.c")],
+            repo.tracked_files("src", Some(1)).unwrap()
+        );
+        assert!(repo
+            .tracked_files("src/untracked", None)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_locate_checkout() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = |path: &Path, content: &str| {
+            fs::create_dir_all(path.join("CVS")).unwrap();
+            fs::write(path.join("CVS/Repository"), content).unwrap();
+        };
+
+        // Nothing to go on, or a top level module.
+        assert_eq!(dir.path(), locate_checkout(dir.path(), "foo").unwrap());
+        assert_eq!(dir.path(), locate_checkout(dir.path(), ".").unwrap());
+
+        // One level, with the content directly in the target.
+        let one = dir.path().join("one");
+        repository(&one, "foo\n");
+        assert_eq!(one, locate_checkout(&one, "foo").unwrap());
+        assert_eq!(one, locate_checkout(&one, "/foo/").unwrap());
+
+        // Two levels, with the content in the last component.
+        let two = dir.path().join("two");
+        repository(&two, "foo\n");
+        repository(&two.join("bar"), "foo/bar\n");
+        assert_eq!(two.join("bar"), locate_checkout(&two, "foo/bar").unwrap());
+
+        // Three levels, with the full path, as an older CVS would record it.
+        let three = dir.path().join("three");
+        repository(&three.join("foo/bar/baz"), "/var/cvsroot/foo/bar/baz\n");
+        assert_eq!(
+            three.join("foo/bar/baz"),
+            locate_checkout(&three, "foo/bar/baz").unwrap()
+        );
+
+        // Something else entirely.
+        let other = dir.path().join("other");
+        repository(&other, "elsewhere\n");
+        assert!(
+            format!("{:#}", locate_checkout(&other, "foo/bar").unwrap_err()).contains("elsewhere")
+        );
+        // A module whose name merely ends the same way doesn't count.
+        repository(&other, "notfoo\n");
+        assert!(locate_checkout(&other, "foo").is_err());
+    }
+
+    /// Runs against a real CVS repository, so this is ignored unless a cvs
+    /// binary is available: run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_nested_modules_with_cvs() {
+        let cvs = CvsFixture::init();
+        for module in &["one", "one/two", "one/two/three"] {
+            cvs.import(module, &[("file.c", module)]);
+        }
+
+        for module in &["one", "one/two", "one/two/three"] {
+            let name = module.replace('/', "-");
+            let repo = cvs.checkout(module, &name, None);
+            assert_eq!(
+                *module,
+                fs::read_to_string(repo.cwd().join("file.c")).unwrap(),
+                "{}",
+                module
+            );
+            assert_eq!(
+                1,
+                repo.revisions(std::iter::once("file.c")).unwrap().len(),
+                "{}",
+                module
+            );
+
+            // Adds and removes are relative to the module.
+            fs::write(repo.cwd().join("added.c"), "added\n").unwrap();
+            repo.add(OsStr::new("added.c"), KeywordMode::Default)
+                .unwrap();
+            fs::remove_file(repo.cwd().join("file.c")).unwrap();
+            assert!(repo
+                .remove_multiple(std::iter::once("file.c"))
+                .unwrap()
+                .is_empty());
+            repo.commit(b"nested", None, None).unwrap();
+
+            let again = cvs.checkout(module, &format!("{}-again", name), None);
+            assert!(again.cwd().join("added.c").exists(), "{}", module);
+            assert!(!again.cwd().join("file.c").exists(), "{}", module);
+        }
+    }
+
+    /// Runs against a real CVS repository, so this is ignored unless a cvs
+    /// binary is available: run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_remove_on_branch_with_cvs() {
+        let cvs = CvsFixture::init();
+        cvs.import("module", &[("inherited.c", "inherited\n")]);
+        cvs.run(cvs.scratch(""), &["rtag", "-b", "feature", "module"]);
+
+        // Add a file to the trunk after the branch point.
+        let trunk = cvs.checkout("module", "trunk", None);
+        fs::write(trunk.cwd.join("trunk-only.c"), "trunk\n").unwrap();
+        trunk
+            .add(OsStr::new("trunk-only.c"), KeywordMode::Default)
+            .unwrap();
+        trunk.commit(b"trunk only", None, None).unwrap();
+
+        let branch = cvs.checkout("module", "branch", Some("feature"));
+        assert_eq!(
+            Some(StickyTag::Branch("feature".into())),
+            branch.sticky_tag(".").unwrap()
+        );
+        fs::write(branch.cwd.join("added.c"), "added\n").unwrap();
+        branch
+            .add(OsStr::new("added.c"), KeywordMode::Default)
+            .unwrap();
+        branch.commit(b"branch add", None, None).unwrap();
+
+        // Remove an inherited file, a branch-added file, and a file the branch
+        // has never seen.
+        fs::remove_file(branch.cwd.join("inherited.c")).unwrap();
+        fs::remove_file(branch.cwd.join("added.c")).unwrap();
+        assert_eq!(
+            vec![PathBuf::from("trunk-only.c")],
+            branch
+                .remove_multiple(["inherited.c", "added.c", "trunk-only.c"].iter())
+                .unwrap()
+        );
+        branch.commit(b"branch remove", None, None).unwrap();
+        assert!(branch
+            .revisions(["inherited.c", "added.c"].iter())
+            .unwrap()
+            .is_empty());
+
+        // Re-adding a file removed on the branch revives it there.
+        fs::write(branch.cwd.join("inherited.c"), "revived\n").unwrap();
+        branch
+            .add(OsStr::new("inherited.c"), KeywordMode::Default)
+            .unwrap();
+        branch.commit(b"branch re-add", None, None).unwrap();
+        let revisions = branch.revisions(std::iter::once("inherited.c")).unwrap();
+        assert_eq!(1, revisions.len());
+        assert!(
+            revisions[0].1.matches('.').count() >= 3,
+            "expected a branch revision: {:?}",
+            revisions
+        );
+
+        // And the trunk still has everything it had.
+        let trunk = cvs.checkout("module", "trunk2", None);
+        assert_eq!(
+            2,
+            trunk
+                .revisions(["inherited.c", "trunk-only.c"].iter())
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn test_keyword_mode() {
+        for (variant, mode) in KeywordMode::VARIANTS.iter().zip(KeywordMode::ALL) {
+            assert_eq!(*mode, variant.parse().unwrap());
+            assert_eq!(format!("-{}", variant), mode.to_string());
+        }
+        assert_eq!(KeywordMode::NoExpand, "-ko".parse().unwrap());
+        assert!("kz".parse::<KeywordMode>().is_err());
+
+        assert_eq!(None, KeywordMode::Default.add_option());
+        assert_eq!(Some("-ko".into()), KeywordMode::NoExpand.add_option());
+    }
+
+    /// Runs against a real CVS repository, so this is ignored unless a cvs
+    /// binary is available: run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_keyword_mode_round_trip_with_cvs() {
+        let cvs = CvsFixture::init();
+        cvs.import("module", &[("README", "readme\n")]);
+
+        let content = "/* $Id$ $Revision$ */\n";
+        let repo = cvs.checkout("module", "work", None);
+        for (path, mode) in [
+            ("kept.c", KeywordMode::NoExpand),
+            ("expanded.c", KeywordMode::Default),
+        ] {
+            fs::write(repo.cwd().join(path), content).unwrap();
+            repo.add_multiple(std::iter::once(path), mode).unwrap();
+        }
+        repo.commit(b"keywords", None, None).unwrap();
+
+        let again = cvs.checkout("module", "again", None);
+        assert_eq!(
+            content,
+            fs::read_to_string(again.cwd().join("kept.c")).unwrap()
+        );
+        assert!(fs::read_to_string(again.cwd().join("expanded.c"))
+            .unwrap()
+            .contains("$Id: expanded.c"));
+        assert_eq!(
+            vec![
+                (PathBuf::from("expanded.c"), "kv".to_string()),
+                (PathBuf::from("kept.c"), "o".to_string()),
+            ],
+            again
+                .keyword_modes(["expanded.c", "kept.c"].iter())
+                .unwrap()
+        );
     }
 }
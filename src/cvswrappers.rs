@@ -0,0 +1,187 @@
+use std::{collections::BTreeSet, fs, path::Path};
+
+use filetime::FileTime;
+use git2::{ObjectType, Oid};
+
+use crate::{cvs, state};
+
+/// Where the wrappers file goes within the target.
+pub const PATH: &str = ".cvswrappers";
+
+const HEADER: &str = "# Generated by git2cvs: files matching these patterns are binary.\n";
+
+/// Builds the content of a wrappers file: one line per pattern, sorted and
+/// deduplicated, each setting -kb.
+pub fn content<'a, I>(patterns: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let patterns: BTreeSet<_> = patterns.into_iter().collect();
+
+    let mut content = HEADER.to_string();
+    for pattern in patterns {
+        content.push_str(&format!("{} -k 'b'\n", pattern));
+    }
+    content.into_bytes()
+}
+
+/// Returns the patterns in an existing wrappers file.
+fn parse(content: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(String::from)
+        .collect()
+}
+
+/// Returns a pattern matching the extension of a file, if it has one that
+/// CVS can match. File names on their own are too specific to be worth
+/// listing.
+pub fn extension_pattern(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_str()?;
+    if is_valid(extension) && !extension.contains(['*', '?', '[']) {
+        Some(format!("*.{}", extension))
+    } else {
+        None
+    }
+}
+
+/// Checks that a pattern can be written to a wrappers file, which has no way
+/// of quoting whitespace.
+pub fn is_valid(pattern: &str) -> bool {
+    !pattern.is_empty()
+        && !pattern.starts_with('#')
+        && !pattern.contains(|c: char| c.is_whitespace() || c == '\'' || c == '/')
+}
+
+/// Tracks a wrappers file left in the working copy by a previous run, so that
+/// it's only rewritten if it changes.
+pub fn seed(state: &mut state::Global) -> anyhow::Result<()> {
+    let file = state.file(PATH)?;
+    if let Ok(content) = fs::read(file.absolute_path()) {
+        let oid = Oid::hash_object(ObjectType::Blob, &content)?;
+        state.save_oid(file, &oid);
+    }
+
+    Ok(())
+}
+
+/// Adds the given patterns and the extensions of the binary files written in
+/// the commit to the wrappers file, writing it to the working copy if it has
+/// changed. Patterns are never removed, so that the file only changes when
+/// something new turns up.
+pub fn update(
+    patterns: &[String],
+    time: i64,
+    state: &mut state::Global,
+    commit_state: &mut state::Commit,
+) -> anyhow::Result<()> {
+    let file = state.file(PATH)?;
+    if commit_state.has_seen(&file) {
+        anyhow::bail!(
+            "{} is in the git tree, so --write-cvswrappers cannot generate it",
+            PATH
+        );
+    }
+
+    let previous = state.get_oid(&file).copied();
+    let absolute = file.absolute_path();
+    let mut all: BTreeSet<String> = match previous {
+        Some(_) => parse(&fs::read(&absolute)?).into_iter().collect(),
+        None => BTreeSet::new(),
+    };
+    all.extend(patterns.iter().cloned());
+    all.extend(
+        commit_state
+            .iter_written_binary_files()
+            .filter_map(|file| extension_pattern(file.git_path())),
+    );
+
+    let content = content(all.iter().map(String::as_str));
+    let oid = Oid::hash_object(ObjectType::Blob, &content)?;
+    if previous != Some(oid) {
+        fs::write(&absolute, &content)?;
+        let time = FileTime::from_unix_time(time, 0);
+        filetime::set_file_times(&absolute, time, time)?;
+
+        log::debug!("updating {:?}", file.cvs_relative_path());
+        commit_state.written_file(file.clone(), content.len(), previous.is_none(), false);
+        if previous.is_none() {
+            commit_state.new_file(file.clone(), cvs::KeywordMode::Default);
+        }
+        state.save_oid(file.clone(), &oid);
+    }
+
+    commit_state.seen_file(file);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extension_pattern() {
+        assert_eq!(
+            Some("*.png".to_string()),
+            extension_pattern(Path::new("a/logo.png"))
+        );
+        assert_eq!(
+            Some("*.gz".to_string()),
+            extension_pattern(Path::new("dist.tar.gz"))
+        );
+        assert_eq!(None, extension_pattern(Path::new("firmware")));
+        assert_eq!(None, extension_pattern(Path::new(".bashrc")));
+        assert_eq!(None, extension_pattern(Path::new("a.b c")));
+    }
+
+    #[test]
+    fn test_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = state::Global::new(dir.path(), ".", state::Filter::default());
+        let read = || fs::read_to_string(dir.path().join(PATH)).unwrap();
+
+        let mut commit_state = state::Commit::new();
+        commit_state.written_file(state.file("logo.png").unwrap(), 1, true, true);
+        commit_state.written_file(state.file("blob").unwrap(), 1, true, true);
+        commit_state.written_file(state.file("main.c").unwrap(), 1, true, false);
+        update(&["*.dat".into()], 0, &mut state, &mut commit_state).unwrap();
+        assert_eq!(format!("{}*.dat -k 'b'\n*.png -k 'b'\n", HEADER), read());
+        assert_eq!(
+            1,
+            commit_state
+                .iter_new_files_with_mode(cvs::KeywordMode::Default)
+                .count()
+        );
+
+        // Nothing new means nothing to write, even though the pattern from
+        // the last commit is no longer given.
+        let mut commit_state = state::Commit::new();
+        commit_state.written_file(state.file("icon.png").unwrap(), 1, true, true);
+        update(&[], 0, &mut state, &mut commit_state).unwrap();
+        assert!(commit_state
+            .iter_written_files()
+            .all(|file| file.git_path() != Path::new(PATH)));
+
+        let mut commit_state = state::Commit::new();
+        commit_state.written_file(state.file("a.pdf").unwrap(), 1, true, true);
+        update(&[], 0, &mut state, &mut commit_state).unwrap();
+        assert_eq!(1, commit_state.files_modified());
+        assert_eq!(
+            format!("{}*.dat -k 'b'\n*.pdf -k 'b'\n*.png -k 'b'\n", HEADER),
+            read()
+        );
+
+        // A fresh state picks up the file that's already there.
+        let mut resumed = state::Global::new(dir.path(), ".", state::Filter::default());
+        seed(&mut resumed).unwrap();
+        let mut commit_state = state::Commit::new();
+        update(&["*.pdf".into()], 0, &mut resumed, &mut commit_state).unwrap();
+        assert_eq!(0, commit_state.iter_written_files().count());
+
+        let mut commit_state = state::Commit::new();
+        commit_state.seen_file(state.file(PATH).unwrap());
+        assert!(update(&[], 0, &mut state, &mut commit_state).is_err());
+    }
+}
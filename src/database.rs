@@ -1,46 +1,1726 @@
-use std::{ops::Deref, path::Path};
+use std::{
+    collections::HashMap,
+    fs, io,
+    ops::Deref,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use git2::Oid;
-use rusqlite::{params, Connection, OptionalExtension};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, ToSql};
+
+use crate::{
+    error::{Error, Result},
+    git::Commit,
+};
+
+// The tables, other than branch_mappings, whose rows belong to a git branch.
+const BRANCH_TABLES: &[&str] = &[
+    "commit_branches",
+    "file_revisions",
+    "file_state",
+    "exceptions",
+    "removed_files",
+];
+
+// SQLite limits the number of bound parameters in a single statement, so
+// batched queries are split into chunks of this size.
+const BATCH_SIZE: usize = 500;
+
+/// Formats an OID the way it's stored in the database. Everything that writes
+/// or queries an OID column should go through this.
+pub fn oid_hex(oid: &Oid) -> String {
+    format!("{}", oid)
+}
+
+pub fn parse_oid(hex: &str) -> Result<Oid> {
+    Ok(Oid::from_str(hex)?)
+}
 
 mod embedded {
     refinery::embed_migrations!("./migrations");
 }
 
-#[derive(Debug)]
-pub struct Database {
-    conn: Connection,
-}
+/// Statistics recorded for each exported commit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExportStats {
+    pub duration: Duration,
+    pub files_added: usize,
+    pub files_modified: usize,
+    pub files_removed: usize,
+    pub bytes_written: u64,
+    /// The commit time used for the export, if known.
+    pub commit_time: Option<i64>,
+    /// Lines changed relative to the commit's first parent.
+    pub lines_added: usize,
+    pub lines_removed: usize,
+    /// Nothing changed in CVS, so nothing was committed there.
+    pub empty: bool,
+}
+
+/// How export statistics are grouped in reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grouping {
+    Month,
+    Author,
+}
+
+impl Grouping {
+    pub const VARIANTS: &'static [&'static str] = &["month", "author"];
+
+    fn key(&self) -> &'static str {
+        match self {
+            Grouping::Month => "strftime('%Y-%m', cb.exported_at, 'unixepoch')",
+            Grouping::Author => "c.author_name || ' <' || c.author_email || '>'",
+        }
+    }
+}
+
+impl FromStr for Grouping {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "month" => Ok(Grouping::Month),
+            "author" => Ok(Grouping::Author),
+            _ => Err(Error::Other(format!("unknown grouping {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportRow {
+    pub key: String,
+    pub commits: i64,
+    pub duration_ms: i64,
+    pub files_added: i64,
+    pub files_modified: i64,
+    pub files_removed: i64,
+    pub bytes_written: i64,
+}
+
+/// A one-page overview of everything that has been exported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub commits: i64,
+    pub bytes_written: i64,
+    pub binary_files: i64,
+    /// Exported commits per month of their original commit time.
+    pub months: Vec<(String, i64)>,
+    /// The authors with the most exported commits.
+    pub authors: Vec<AuthorSummary>,
+    /// The files with the largest revisions, and their largest size.
+    pub largest_files: Vec<(String, i64)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorSummary {
+    pub author: String,
+    pub commits: i64,
+    pub lines_added: i64,
+    pub lines_removed: i64,
+}
+
+/// A CVS branch tag, and the git branch it was exported from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagRecord {
+    pub git_branch: String,
+    pub cvs_branch: String,
+    pub last_exported: Option<String>,
+}
+
+/// The git commit that created a CVS file revision.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevisionRecord {
+    pub oid: String,
+    pub git_branch: String,
+    pub author_name: String,
+    pub author_email: String,
+    pub summary: Option<String>,
+}
+
+/// Where a git commit appears in the exported branches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitRecord {
+    pub git_branch: String,
+    pub cvs_branch: String,
+    pub index: i64,
+    pub exported: bool,
+}
+
+/// The kinds of problem that can be recorded against a commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExceptionKind {
+    /// Exporting the commit failed.
+    Failed,
+    /// A tree entry in the commit couldn't be exported and was skipped.
+    SkippedEntry,
+    /// The commit was deliberately left out of the export; its changes are
+    /// included in the next exported commit.
+    SkippedCommit,
+    /// The push stopped before exporting the commit, which is where it
+    /// should resume.
+    Interrupted,
+}
+
+impl ExceptionKind {
+    pub const ALL: &'static [ExceptionKind] = &[
+        ExceptionKind::Failed,
+        ExceptionKind::SkippedEntry,
+        ExceptionKind::SkippedCommit,
+        ExceptionKind::Interrupted,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExceptionKind::Failed => "failed",
+            ExceptionKind::SkippedEntry => "skipped-entry",
+            ExceptionKind::SkippedCommit => "skipped-commit",
+            ExceptionKind::Interrupted => "interrupted",
+        }
+    }
+
+    /// Returns true if exporting the commit again may succeed.
+    pub fn retryable(&self) -> bool {
+        matches!(self, ExceptionKind::Failed | ExceptionKind::Interrupted)
+    }
+}
+
+impl FromStr for ExceptionKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failed" => Ok(ExceptionKind::Failed),
+            "skipped-entry" => Ok(ExceptionKind::SkippedEntry),
+            "skipped-commit" => Ok(ExceptionKind::SkippedCommit),
+            "interrupted" => Ok(ExceptionKind::Interrupted),
+            _ => Err(Error::Other(format!("unknown exception kind {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exception {
+    pub branch: String,
+    pub oid: String,
+    pub kind: ExceptionKind,
+    pub reason: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BranchSummary {
+    pub git_branch: String,
+    pub cvs_branch: String,
+    pub commits: i64,
+    pub exported: i64,
+    pub archived: bool,
+}
+
+/// How a git author is represented in CVS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Author {
+    pub git_email: String,
+    pub cvs_user: String,
+    pub display_name: Option<String>,
+}
+
+/// A commit that is left out of every export.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedCommit {
+    pub oid: Oid,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Database {
+    conn: Connection,
+    readonly: bool,
+}
+
+impl Database {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut conn = Connection::open(path)?;
+        embedded::migrations::runner().run(&mut conn)?;
+
+        Ok(Self {
+            conn,
+            readonly: false,
+        })
+    }
+
+    /// Writes a consistent copy of the database to a new file, without
+    /// migrating it. Unlike copying the file, this can't pick up a
+    /// half-written transaction from another connection.
+    pub fn backup<P: AsRef<Path>, B: AsRef<Path>>(path: P, backup: B) -> Result<()> {
+        let conn = Connection::open_with_flags(path.as_ref(), OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|source| Error::DatabaseOpen {
+                path: path.as_ref().into(),
+                source,
+            })?;
+        conn.execute("VACUUM INTO ?", params![backup.as_ref().to_string_lossy()])?;
+
+        Ok(())
+    }
+
+    /// Opens a copy of the database in the given directory, so that a dry run
+    /// can record an export without changing the real database. A database
+    /// that doesn't exist yet is copied as an empty one.
+    pub fn open_copy<P: AsRef<Path>, D: AsRef<Path>>(path: P, dir: D) -> Result<Self> {
+        let path = path.as_ref();
+        let copy = dir.as_ref().join("git2cvs.db");
+        match fs::copy(path, &copy) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::file("copy", path)(e)),
+        }
+
+        Self::open(copy)
+    }
+
+    /// Opens an existing database without migrating it, for commands that
+    /// only read. The database must already have every migration this binary
+    /// knows about; newer databases are accepted, since migrations only add
+    /// to the schema.
+    pub fn open_readonly<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(|source| Error::DatabaseOpen {
+            path: path.into(),
+            source,
+        })?;
+
+        let expected = embedded::migrations::runner()
+            .get_migrations()
+            .iter()
+            .map(|migration| migration.version())
+            .max()
+            .unwrap_or_default();
+        let version: Option<u32> = conn
+            .query_row(
+                "SELECT MAX(version) FROM refinery_schema_history",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|source| Error::NotADatabase {
+                path: path.into(),
+                source,
+            })?;
+        match version {
+            Some(version) if version >= expected => {
+                if version > expected {
+                    log::warn!(
+                        "database schema version {} is newer than this binary's {}",
+                        version,
+                        expected
+                    );
+                }
+            }
+            version => {
+                return Err(Error::Other(format!(
+                    "database schema version {} is older than this binary's {}; run a mutating command to migrate it",
+                    version.unwrap_or_default(),
+                    expected
+                )))
+            }
+        }
+
+        Ok(Self {
+            conn,
+            readonly: true,
+        })
+    }
+
+    fn writable(&self, operation: &'static str) -> Result<()> {
+        if self.readonly {
+            Err(Error::ReadOnly { operation })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs SQLite's integrity check, returning any problems found.
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+
+        Ok(rows
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|row| row != "ok")
+            .collect())
+    }
+
+    pub fn row_counts(&self) -> Result<Vec<(String, i64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+        )?;
+        let tables = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut counts = Vec::new();
+        for table in tables {
+            let count =
+                self.conn
+                    .query_row(&format!("SELECT COUNT(*) FROM \"{}\"", table), [], |row| {
+                        row.get(0)
+                    })?;
+            counts.push((table, count));
+        }
+        Ok(counts)
+    }
+
+    /// Deletes rows that refer to branches that are no longer mapped, and
+    /// commit metadata that no branch refers to. Returns the number of rows
+    /// deleted from each table.
+    pub fn prune_orphans(&mut self) -> Result<Vec<(&'static str, usize)>> {
+        self.writable("prune orphans")?;
+
+        let txn = self.conn.transaction()?;
+
+        let mut deleted = Vec::new();
+        for table in BRANCH_TABLES {
+            deleted.push((
+                *table,
+                txn.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE branch NOT IN (SELECT git FROM branch_mappings)",
+                        table
+                    ),
+                    [],
+                )?,
+            ));
+        }
+        deleted.push((
+            "commits",
+            txn.execute(
+                "DELETE FROM commits WHERE oid NOT IN (SELECT oid FROM commit_branches)",
+                [],
+            )?,
+        ));
+
+        txn.commit()?;
+        Ok(deleted)
+    }
+
+    pub fn analyze(&self) -> Result<()> {
+        self.writable("analyze")?;
+
+        Ok(self.conn.execute_batch("ANALYZE")?)
+    }
+
+    pub fn vacuum(&self) -> Result<()> {
+        self.writable("vacuum")?;
+
+        Ok(self.conn.execute_batch("VACUUM")?)
+    }
+
+    pub fn get_cvs_branch(&self, git: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT cvs FROM branch_mappings WHERE git = ?",
+                params![git],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Returns the index of the commit within the branch's history, if it's
+    /// part of it.
+    pub fn commit_index(&self, oid: &Oid, git_branch: &str) -> Result<Option<i64>> {
+        Ok(self
+            .conn
+            .prepare_cached(
+                "SELECT branch_index FROM commit_branches WHERE branch = ? AND oid = ?",
+            )?
+            .query_row(params![git_branch, oid_hex(oid)], |row| row.get(0))
+            .optional()?)
+    }
+
+    /// Batched version of commit_index: returns the index of each of the given
+    /// commits that is part of the branch's history.
+    pub fn commit_indices(&self, oids: &[Oid], git_branch: &str) -> Result<HashMap<Oid, i64>> {
+        let mut indices = HashMap::new();
+
+        for chunk in oids.chunks(BATCH_SIZE) {
+            let mut stmt = self.conn.prepare_cached(&format!(
+                "SELECT oid, branch_index FROM commit_branches WHERE branch = ? AND oid IN ({})",
+                vec!["?"; chunk.len()].join(", ")
+            ))?;
+
+            let hexes: Vec<String> = chunk.iter().map(oid_hex).collect();
+            let mut params: Vec<&dyn ToSql> = vec![&git_branch];
+            params.extend(hexes.iter().map(|hex| hex as &dyn ToSql));
+
+            let rows = stmt.query_map(params.as_slice(), |row| {
+                Ok((row.get::<_, String>(0)?, row.get(1)?))
+            })?;
+            for row in rows {
+                let (oid, index) = row?;
+                indices.insert(parse_oid(&oid)?, index);
+            }
+        }
+
+        Ok(indices)
+    }
+
+    pub fn lookup_tag(&self, cvs: &str) -> Result<Option<TagRecord>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT bm.git, bm.cvs, (SELECT cb.oid FROM commit_branches cb WHERE cb.branch = bm.git AND cb.exported_at IS NOT NULL ORDER BY cb.branch_index DESC LIMIT 1)
+                FROM branch_mappings bm WHERE bm.cvs = ?",
+                params![cvs],
+                |row| {
+                    Ok(TagRecord {
+                        git_branch: row.get(0)?,
+                        cvs_branch: row.get(1)?,
+                        last_exported: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn lookup_revision(&self, path: &str, revision: &str) -> Result<Option<RevisionRecord>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT fr.oid, fr.branch, c.author_name, c.author_email, c.summary
+                FROM file_revisions fr INNER JOIN commits c ON c.oid = fr.oid
+                WHERE fr.path = ? AND fr.revision = ?",
+                params![path, revision],
+                |row| {
+                    Ok(RevisionRecord {
+                        oid: row.get(0)?,
+                        git_branch: row.get(1)?,
+                        author_name: row.get(2)?,
+                        author_email: row.get(3)?,
+                        summary: row.get(4)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn lookup_commit(&self, oid: &Oid) -> Result<Vec<CommitRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT cb.branch, bm.cvs, cb.branch_index, cb.exported_at IS NOT NULL
+            FROM commit_branches cb INNER JOIN branch_mappings bm ON bm.git = cb.branch
+            WHERE cb.oid = ? ORDER BY cb.branch",
+        )?;
+
+        let rows = stmt.query_map(params![oid_hex(oid)], |row| {
+            Ok(CommitRecord {
+                git_branch: row.get(0)?,
+                cvs_branch: row.get(1)?,
+                index: row.get(2)?,
+                exported: row.get(3)?,
+            })
+        })?;
+
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Returns true if any commits were exported before file revisions were
+    /// recorded, in which case a missing revision isn't necessarily an error.
+    pub fn has_unrecorded_revisions(&self) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT 1 FROM commit_branches cb
+                WHERE cb.exported_at IS NOT NULL
+                AND cb.files_added + cb.files_modified > 0
+                AND NOT EXISTS (SELECT 1 FROM file_revisions fr WHERE fr.oid = cb.oid)
+                LIMIT 1",
+                [],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    pub fn record_revisions(
+        &mut self,
+        git_branch: &str,
+        oid: &Oid,
+        revisions: &[(PathBuf, String)],
+        sizes: &HashMap<PathBuf, (u64, bool)>,
+    ) -> Result<()> {
+        self.writable("record revisions")?;
+
+        let txn = self.conn.transaction()?;
+
+        let mut stmt = txn.prepare(
+            "INSERT OR REPLACE INTO file_revisions (branch, oid, path, revision, size, binary) VALUES (?, ?, ?, ?, ?, ?)",
+        )?;
+        for (path, revision) in revisions {
+            let size = sizes.get(path);
+            stmt.execute(params![
+                git_branch,
+                oid_hex(oid),
+                path.to_string_lossy(),
+                revision,
+                size.map(|(bytes, _)| *bytes as i64),
+                size.map(|(_, binary)| *binary)
+            ])?;
+        }
+        drop(stmt);
+
+        Ok(txn.commit()?)
+    }
+
+    /// Returns the newest revision recorded on the branch for each of the
+    /// given CVS paths. Paths without any recorded revisions are omitted.
+    pub fn latest_revisions<I, P>(
+        &self,
+        git_branch: &str,
+        paths: I,
+    ) -> Result<HashMap<PathBuf, String>>
+    where
+        I: Iterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.revisions_at(git_branch, None, paths)
+    }
+
+    /// Returns the newest revision of each of the given CVS paths as of the
+    /// commit at the given index on the branch, or the newest overall if
+    /// there's no index. Paths that a branch hasn't touched since it was
+    /// rooted on another branch get their revision from that branch.
+    pub fn revisions_at<I, P>(
+        &self,
+        git_branch: &str,
+        index: Option<i64>,
+        paths: I,
+    ) -> Result<HashMap<PathBuf, String>>
+    where
+        I: Iterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut stmt = self.conn.prepare(
+            "SELECT fr.revision FROM file_revisions fr
+            LEFT JOIN commit_branches cb ON cb.oid = fr.oid AND cb.branch = fr.branch
+            WHERE fr.path = ?1 AND fr.branch = ?2 AND (?3 IS NULL OR cb.branch_index <= ?3)",
+        )?;
+
+        let mut latest = HashMap::new();
+        let mut missing = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            let revisions = stmt
+                .query_map(params![path.to_string_lossy(), git_branch, index], |row| {
+                    row.get::<_, String>(0)
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            match revisions.into_iter().reduce(|a, b| {
+                if crate::cvs::newer_revision(&b, &a) {
+                    b
+                } else {
+                    a
+                }
+            }) {
+                Some(revision) => {
+                    latest.insert(path.to_path_buf(), revision);
+                }
+                None => missing.push(path.to_path_buf()),
+            }
+        }
+
+        if !missing.is_empty() {
+            if let Some((root_branch, root)) = self.branch_root(git_branch)? {
+                let root_index = self.commit_index(&root, &root_branch)?;
+                latest.extend(self.revisions_at(&root_branch, root_index, missing.iter())?);
+            }
+        }
+
+        Ok(latest)
+    }
+
+    pub fn record_exception(
+        &self,
+        git_branch: &str,
+        oid: &Oid,
+        kind: ExceptionKind,
+        reason: &str,
+    ) -> Result<()> {
+        self.writable("record an exception")?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+        self.conn.execute(
+            "INSERT INTO exceptions (branch, oid, kind, reason, created_at) VALUES (?, ?, ?, ?, ?)",
+            params![git_branch, oid_hex(oid), kind.as_str(), reason, now],
+        )?;
+
+        Ok(())
+    }
+
+    /// Records the keyword modes that files had when they were removed, so
+    /// that we can tell if they come back with a different mode.
+    pub fn record_removed_modes(
+        &mut self,
+        git_branch: &str,
+        modes: &[(PathBuf, String)],
+    ) -> Result<()> {
+        self.writable("record removed files")?;
+
+        let txn = self.conn.transaction()?;
+        let mut stmt = txn.prepare(
+            "INSERT OR REPLACE INTO removed_files (branch, path, keyword_mode) VALUES (?, ?, ?)",
+        )?;
+        for (path, mode) in modes {
+            stmt.execute(params![git_branch, path.to_string_lossy(), mode])?;
+        }
+        drop(stmt);
+
+        Ok(txn.commit()?)
+    }
+
+    /// Returns the keyword mode a file had when it was last removed, if it's
+    /// currently removed.
+    pub fn removed_mode(&self, git_branch: &str, path: &Path) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT keyword_mode FROM removed_files WHERE branch = ? AND path = ?")?
+            .query_row(params![git_branch, path.to_string_lossy()], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    /// Forgets the removal of files that have been added again.
+    pub fn forget_removed<I, P>(&mut self, git_branch: &str, paths: I) -> Result<()>
+    where
+        I: Iterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.writable("forget removed files")?;
+
+        let txn = self.conn.transaction()?;
+        let mut stmt = txn.prepare("DELETE FROM removed_files WHERE branch = ? AND path = ?")?;
+        for path in paths {
+            stmt.execute(params![git_branch, path.as_ref().to_string_lossy()])?;
+        }
+        drop(stmt);
+
+        Ok(txn.commit()?)
+    }
+
+    pub fn branch_summaries(&self) -> Result<Vec<BranchSummary>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT bm.git, bm.cvs, COUNT(cb.oid), COUNT(cb.exported_at), bm.archived
+            FROM branch_mappings bm LEFT JOIN commit_branches cb ON cb.branch = bm.git
+            GROUP BY bm.git, bm.cvs, bm.archived ORDER BY bm.git",
+        )?;
+
+        let rows = stmt.query_map([], |row| {
+            Ok(BranchSummary {
+                git_branch: row.get(0)?,
+                cvs_branch: row.get(1)?,
+                commits: row.get(2)?,
+                exported: row.get(3)?,
+                archived: row.get(4)?,
+            })
+        })?;
+
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Returns the last commit recorded on the branch, whether or not it has
+    /// been exported.
+    pub fn branch_tip(&self, git_branch: &str) -> Result<Option<Oid>> {
+        self.conn
+            .query_row(
+                "SELECT oid FROM commit_branches WHERE branch = ? ORDER BY branch_index DESC LIMIT 1",
+                params![git_branch],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .map(|oid| parse_oid(&oid))
+            .transpose()
+    }
+
+    /// Returns the fingerprint of the path filter the branch was exported
+    /// with, or None if it was exported without one.
+    pub fn filter_fingerprint(&self, git_branch: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT filter_fingerprint FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    pub fn set_filter_fingerprint(
+        &self,
+        git_branch: &str,
+        fingerprint: Option<&str>,
+    ) -> Result<()> {
+        self.writable("set a filter fingerprint")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET filter_fingerprint = ? WHERE git = ?",
+            params![fingerprint, git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the git subtree the branch was exported from, or None if it was
+    /// the whole repository.
+    pub fn branch_prefix(&self, git_branch: &str) -> Result<Option<PathBuf>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT prefix FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()?
+            .flatten()
+            .map(PathBuf::from))
+    }
+
+    pub fn set_branch_prefix(&self, git_branch: &str, prefix: Option<&Path>) -> Result<()> {
+        self.writable("set a branch prefix")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET prefix = ? WHERE git = ?",
+            params![prefix.map(|prefix| prefix.to_string_lossy()), git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the path map the branch was exported with, in its canonical
+    /// form, or None if paths weren't mapped.
+    pub fn path_map(&self, git_branch: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT path_map FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    pub fn set_path_map(&self, git_branch: &str, path_map: Option<&str>) -> Result<()> {
+        self.writable("set a path map")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET path_map = ? WHERE git = ?",
+            params![path_map, git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the paths renamed to avoid case collisions on the branch, as a
+    /// path map in its canonical form, or None if there weren't any.
+    pub fn case_renames(&self, git_branch: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT case_renames FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    pub fn set_case_renames(&self, git_branch: &str, renames: Option<&str>) -> Result<()> {
+        self.writable("record case renames")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET case_renames = ? WHERE git = ?",
+            params![renames, git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the paths renamed on the branch so they wouldn't be taken for
+    /// CVS directories, as a path map in its canonical form, or None if there
+    /// weren't any.
+    pub fn cvs_dir_renames(&self, git_branch: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT cvs_dir_renames FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    pub fn set_cvs_dir_renames(&self, git_branch: &str, renames: Option<&str>) -> Result<()> {
+        self.writable("record CVS directory renames")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET cvs_dir_renames = ? WHERE git = ?",
+            params![renames, git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the scheme used to turn the branch name into a CVS branch tag,
+    /// if one was recorded. Branches exported before the scheme was recorded
+    /// were escaped.
+    pub fn sanitise_scheme(&self, git_branch: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT sanitise FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    pub fn set_sanitise_scheme(&self, git_branch: &str, scheme: &str) -> Result<()> {
+        self.writable("set a sanitisation scheme")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET sanitise = ? WHERE git = ?",
+            params![scheme, git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns whether the branch is committed onto its own CVS branch tag
+    /// rather than the trunk.
+    pub fn branch_tagged(&self, git_branch: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT tagged FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(false))
+    }
+
+    pub fn set_branch_tagged(&self, git_branch: &str, tagged: bool) -> Result<()> {
+        self.writable("mark a branch tagged")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET tagged = ? WHERE git = ?",
+            params![tagged, git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns whether the branch was first exported with --merge-existing,
+    /// keeping files that CVS already had in the target.
+    pub fn merge_existing(&self, git_branch: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT merge_existing FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(false))
+    }
+
+    pub fn set_merge_existing(&self, git_branch: &str, merge_existing: bool) -> Result<()> {
+        self.writable("record --merge-existing")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET merge_existing = ? WHERE git = ?",
+            params![merge_existing, git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the branch and commit that the branch's CVS branch was rooted
+    /// at, if it forked from another exported branch.
+    pub fn branch_root(&self, git_branch: &str) -> Result<Option<(String, Oid)>> {
+        let root: Option<(Option<String>, Option<String>)> = self
+            .conn
+            .query_row(
+                "SELECT root_branch, root_oid FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        match root {
+            Some((Some(branch), Some(oid))) => Ok(Some((branch, parse_oid(&oid)?))),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn set_branch_root(&self, git_branch: &str, root_branch: &str, root: &Oid) -> Result<()> {
+        self.writable("set a branch root")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET root_branch = ?, root_oid = ? WHERE git = ?",
+            params![root_branch, oid_hex(root), git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the commit the branch's export started from, if it was
+    /// exported with --since-commit rather than from the start of its history.
+    pub fn since_commit(&self, git_branch: &str) -> Result<Option<Oid>> {
+        let oid: Option<Option<String>> = self
+            .conn
+            .query_row(
+                "SELECT since_oid FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match oid.flatten() {
+            Some(oid) => Ok(Some(parse_oid(&oid)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_since_commit(&self, git_branch: &str, since: &Oid) -> Result<()> {
+        self.writable("set a branch's first commit")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET since_oid = ? WHERE git = ?",
+            params![oid_hex(since), git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the commit that the revision exported under this name resolved
+    /// to when it was last pushed, or None if it's a branch rather than a
+    /// revision.
+    pub fn resolved_rev(&self, git_branch: &str) -> Result<Option<Oid>> {
+        let oid: Option<Option<String>> = self
+            .conn
+            .query_row(
+                "SELECT rev_oid FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match oid.flatten() {
+            Some(oid) => Ok(Some(parse_oid(&oid)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_resolved_rev(&self, git_branch: &str, oid: &Oid) -> Result<()> {
+        self.writable("record what a revision resolved to")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET rev_oid = ? WHERE git = ?",
+            params![oid_hex(oid), git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the git repository a branch was last pushed from, as returned by
+    /// Repository::common_dir.
+    pub fn git_repository(&self, git_branch: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT git_repository FROM branch_mappings WHERE git = ?",
+                params![git_branch],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    pub fn set_git_repository(&self, git_branch: &str, path: &str) -> Result<()> {
+        self.writable("record the git repository")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET git_repository = ? WHERE git = ?",
+            params![path, git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn archive_branch(&self, git_branch: &str) -> Result<()> {
+        self.writable("archive a branch")?;
+
+        self.conn.execute(
+            "UPDATE branch_mappings SET archived = 1 WHERE git = ?",
+            params![git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Deletes a branch mapping and everything recorded against the branch.
+    pub fn delete_branch(&mut self, git_branch: &str) -> Result<()> {
+        self.writable("delete a branch")?;
+
+        let txn = self.conn.transaction()?;
+        txn.execute(
+            "DELETE FROM branch_mappings WHERE git = ?",
+            params![git_branch],
+        )?;
+        for table in BRANCH_TABLES {
+            txn.execute(
+                &format!("DELETE FROM {} WHERE branch = ?", table),
+                params![git_branch],
+            )?;
+        }
+
+        Ok(txn.commit()?)
+    }
+
+    /// Moves a branch mapping, and everything recorded against it, to a new
+    /// git branch name. The CVS branch is unchanged.
+    pub fn rename_branch(&mut self, from: &str, to: &str) -> Result<()> {
+        self.writable("rename a branch")?;
+
+        let txn = self.conn.transaction()?;
+        txn.execute(
+            "UPDATE branch_mappings SET git = ? WHERE git = ?",
+            params![to, from],
+        )?;
+        for table in BRANCH_TABLES {
+            txn.execute(
+                &format!("UPDATE {} SET branch = ? WHERE branch = ?", table),
+                params![to, from],
+            )?;
+        }
+
+        Ok(txn.commit()?)
+    }
+
+    /// Records an administrative action taken against a branch.
+    pub fn record_audit(
+        &self,
+        command: &str,
+        git_branch: &str,
+        action: &str,
+        detail: Option<&str>,
+    ) -> Result<()> {
+        self.writable("record an audit entry")?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        self.conn.execute(
+            "INSERT INTO audit_log (created_at, command, branch, action, detail) VALUES (?, ?, ?, ?, ?)",
+            params![now, command, git_branch, action, detail],
+        )?;
+
+        Ok(())
+    }
+
+    /// Counts the exceptions recorded on the branch, by kind.
+    pub fn exception_counts(&self, git_branch: &str) -> Result<HashMap<ExceptionKind, i64>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT kind, COUNT(*) FROM exceptions WHERE branch = ? GROUP BY kind")?;
+
+        let rows = stmt.query_map(params![git_branch], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?))
+        })?;
+
+        let mut counts = HashMap::new();
+        for row in rows {
+            let (kind, count) = row?;
+            counts.insert(kind.parse()?, count);
+        }
+        Ok(counts)
+    }
+
+    /// Removes any retryable exceptions for a commit that has now been
+    /// exported successfully.
+    pub fn clear_retryable_exceptions(&self, git_branch: &str, oid: &Oid) -> Result<()> {
+        self.writable("clear retryable exceptions")?;
+
+        self.conn.execute(
+            "DELETE FROM exceptions WHERE branch = ? AND oid = ? AND kind IN (?, ?)",
+            params![
+                git_branch,
+                oid_hex(oid),
+                ExceptionKind::Failed.as_str(),
+                ExceptionKind::Interrupted.as_str()
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn exceptions(
+        &self,
+        git_branch: Option<&str>,
+        kind: Option<ExceptionKind>,
+    ) -> Result<Vec<Exception>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT branch, oid, kind, reason, created_at FROM exceptions
+            WHERE (?1 IS NULL OR branch = ?1) AND (?2 IS NULL OR kind = ?2)
+            ORDER BY id",
+        )?;
+
+        let rows = stmt.query_map(params![git_branch, kind.map(|k| k.as_str())], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get::<_, String>(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })?;
+
+        let mut exceptions = Vec::new();
+        for row in rows {
+            let (branch, oid, kind, reason, created_at) = row?;
+            exceptions.push(Exception {
+                branch,
+                oid,
+                kind: kind.parse()?,
+                reason,
+                created_at,
+            });
+        }
+
+        Ok(exceptions)
+    }
+
+    /// Returns every commit recorded on the branch, in order.
+    pub fn branch_commits(&self, git_branch: &str) -> Result<Vec<Oid>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT oid FROM commit_branches WHERE branch = ? ORDER BY branch_index")?;
+
+        let rows = stmt.query_map(params![git_branch], |row| row.get::<_, String>(0))?;
+
+        let mut oids = Vec::new();
+        for row in rows {
+            oids.push(parse_oid(&row?)?);
+        }
+        Ok(oids)
+    }
+
+    /// Returns the commits on the branch after the last exported commit, in
+    /// order.
+    pub fn unexported_commits(&self, git_branch: &str) -> Result<Vec<Oid>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT oid FROM commit_branches
+            WHERE branch = ?1 AND branch_index > (
+                SELECT COALESCE(MAX(branch_index), -1) FROM commit_branches
+                WHERE branch = ?1 AND exported_at IS NOT NULL
+            )
+            ORDER BY branch_index",
+        )?;
+
+        let rows = stmt.query_map(params![git_branch], |row| row.get::<_, String>(0))?;
+
+        let mut oids = Vec::new();
+        for row in rows {
+            oids.push(parse_oid(&row?)?);
+        }
+        Ok(oids)
+    }
+
+    pub fn last_exported_commit(&self, git_branch: &str) -> Result<Option<Oid>> {
+        let oid: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT oid FROM commit_branches WHERE branch = ? AND exported_at IS NOT NULL ORDER BY branch_index DESC LIMIT 1",
+                params![git_branch],
+                |row| row.get(0),
+            )
+            .optional()?;
 
-impl Database {
-    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let mut conn = Connection::open(path)?;
-        embedded::migrations::runner().run(&mut conn)?;
+        Ok(match oid {
+            Some(oid) => Some(parse_oid(&oid)?),
+            None => None,
+        })
+    }
 
-        Ok(Self { conn })
+    /// Returns the last exported commit on the branch, and when it was
+    /// exported.
+    pub fn last_export(&self, git_branch: &str) -> Result<Option<(Oid, i64)>> {
+        self.conn
+            .query_row(
+                "SELECT oid, exported_at FROM commit_branches WHERE branch = ? AND exported_at IS NOT NULL ORDER BY branch_index DESC LIMIT 1",
+                params![git_branch],
+                |row| Ok((row.get::<_, String>(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .map(|(oid, exported_at)| Ok((parse_oid(&oid)?, exported_at)))
+            .transpose()
     }
 
-    pub fn get_cvs_branch(&self, git: &str) -> anyhow::Result<Option<String>> {
+    /// Returns when the commit was exported on the branch, or None if it
+    /// hasn't been.
+    pub fn exported_at(&self, git_branch: &str, oid: &Oid) -> Result<Option<i64>> {
         Ok(self
             .conn
             .query_row(
-                "SELECT cvs FROM branch_mappings WHERE git = ?",
-                params![git],
+                "SELECT exported_at FROM commit_branches WHERE branch = ? AND oid = ?",
+                params![git_branch, oid_hex(oid)],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    /// Returns the last commit before the given index on the branch whose
+    /// changes were actually exported, rather than skipped.
+    pub fn previous_exported_commit(&self, git_branch: &str, before: i64) -> Result<Option<Oid>> {
+        let oid: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT oid FROM commit_branches cb
+                WHERE branch = ?1 AND exported_at IS NOT NULL AND branch_index < ?2
+                AND NOT EXISTS (
+                    SELECT 1 FROM exceptions e
+                    WHERE e.branch = cb.branch AND e.oid = cb.oid AND e.kind = ?3
+                )
+                ORDER BY branch_index DESC LIMIT 1",
+                params![git_branch, before, ExceptionKind::SkippedCommit.as_str()],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(match oid {
+            Some(oid) => Some(parse_oid(&oid)?),
+            None => None,
+        })
+    }
+
+    /// Forgets that a commit was exported, along with its statistics.
+    pub fn unmark_exported(&self, git_branch: &str, oid: &Oid) -> Result<()> {
+        self.writable("unmark a commit exported")?;
+
+        self.conn.execute(
+            "UPDATE commit_branches SET exported_at = NULL, duration_ms = NULL, files_added = NULL, files_modified = NULL, files_removed = NULL, bytes_written = NULL, commit_time = NULL, lines_added = NULL, lines_removed = NULL, empty = 0 WHERE oid = ? AND branch = ?",
+            params![oid_hex(oid), git_branch],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns whether a commit was pushed without a cvs commit because it
+    /// changed nothing in CVS.
+    pub fn exported_empty(&self, git_branch: &str, oid: &Oid) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT empty FROM commit_branches WHERE oid = ? AND branch = ? AND exported_at IS NOT NULL",
+                params![oid_hex(oid), git_branch],
                 |row| row.get(0),
             )
+            .optional()?
+            .unwrap_or(false))
+    }
+
+    /// Returns the CVS revisions created by a commit on the branch.
+    pub fn commit_revisions(&self, git_branch: &str, oid: &Oid) -> Result<Vec<(PathBuf, String)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT path, revision FROM file_revisions WHERE oid = ? AND branch = ? ORDER BY path",
+        )?;
+        let rows = stmt.query_map(params![oid_hex(oid), git_branch], |row| {
+            Ok((PathBuf::from(row.get::<_, String>(0)?), row.get(1)?))
+        })?;
+
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Returns the latest commit time used by an export on the branch.
+    pub fn latest_commit_time(&self, git_branch: &str) -> Result<Option<i64>> {
+        Ok(self.conn.query_row(
+            "SELECT MAX(commit_time) FROM commit_branches WHERE branch = ?",
+            params![git_branch],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn mark_exported(&self, git_branch: &str, oid: &Oid, stats: &ExportStats) -> Result<()> {
+        self.writable("mark a commit exported")?;
+        update_exported(&self.conn, git_branch, oid, stats)
+    }
+
+    /// Marks a commit exported once it's been committed to CVS, along with
+    /// the files it wrote (with their new blobs) and removed (with None), in
+    /// one transaction. If the push dies, the database then agrees with the
+    /// working copy about the last commit that made it.
+    pub fn mark_pushed(
+        &mut self,
+        git_branch: &str,
+        oid: &Oid,
+        stats: &ExportStats,
+        files: &[(PathBuf, Option<Oid>)],
+    ) -> Result<()> {
+        self.writable("mark a commit pushed")?;
+
+        let txn = self.conn.transaction()?;
+        update_exported(&txn, git_branch, oid, stats)?;
+
+        let mut upsert =
+            txn.prepare("INSERT OR REPLACE INTO file_state (branch, path, oid) VALUES (?, ?, ?)")?;
+        let mut delete = txn.prepare("DELETE FROM file_state WHERE branch = ? AND path = ?")?;
+        for (path, oid) in files {
+            let path = path.to_string_lossy();
+            match oid {
+                Some(oid) => upsert.execute(params![git_branch, path, oid_hex(oid)])?,
+                None => delete.execute(params![git_branch, path])?,
+            };
+        }
+        drop((upsert, delete));
+
+        Ok(txn.commit()?)
+    }
+
+    /// Marks every commit on the branch up to and including the given index as
+    /// exported, without recording any statistics.
+    pub fn mark_exported_through(&self, git_branch: &str, index: usize) -> Result<()> {
+        self.writable("mark commits exported")?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+        self.conn.execute(
+            "UPDATE commit_branches SET exported_at = ? WHERE branch = ? AND branch_index <= ?",
+            params![now, git_branch, index],
+        )?;
+
+        Ok(())
+    }
+
+    /// Replaces the stored blob OID of every file on the branch.
+    pub fn write_file_state<I, P>(&mut self, git_branch: &str, files: I) -> Result<()>
+    where
+        I: Iterator<Item = (P, Oid)>,
+        P: AsRef<Path>,
+    {
+        self.writable("write file state")?;
+
+        let txn = self.conn.transaction()?;
+
+        txn.execute(
+            "DELETE FROM file_state WHERE branch = ?",
+            params![git_branch],
+        )?;
+
+        let mut stmt =
+            txn.prepare("INSERT INTO file_state (branch, path, oid) VALUES (?, ?, ?)")?;
+        for (path, oid) in files {
+            stmt.execute(params![
+                git_branch,
+                path.as_ref().to_string_lossy(),
+                oid_hex(&oid)
+            ])?;
+        }
+        drop(stmt);
+
+        Ok(txn.commit()?)
+    }
+
+    /// Returns the blob last written to each path in the working copy for the
+    /// branch.
+    pub fn file_state(&self, git_branch: &str) -> Result<HashMap<PathBuf, Oid>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT path, oid FROM file_state WHERE branch = ?")?;
+
+        let rows = stmt.query_map(params![git_branch], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut files = HashMap::new();
+        for row in rows {
+            let (path, oid) = row?;
+            files.insert(PathBuf::from(path), parse_oid(&oid)?);
+        }
+        Ok(files)
+    }
+
+    /// Aggregates the statistics of exported commits, optionally limited to a
+    /// single branch.
+    pub fn report(&self, grouping: Grouping, git_branch: Option<&str>) -> Result<Vec<ReportRow>> {
+        // As in summary, the branch condition is left out entirely rather
+        // than made optional, so that SQLite can use the index.
+        let (filter, branch): (&str, Vec<&dyn ToSql>) = match &git_branch {
+            Some(branch) => ("AND cb.branch = ?", vec![branch]),
+            None => ("", Vec::new()),
+        };
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} AS key, COUNT(*), SUM(cb.duration_ms), SUM(cb.files_added), SUM(cb.files_modified), SUM(cb.files_removed), SUM(cb.bytes_written)
+            FROM commit_branches cb INNER JOIN commits c ON c.oid = cb.oid
+            WHERE cb.exported_at IS NOT NULL {}
+            GROUP BY key ORDER BY key",
+            grouping.key(),
+            filter
+        ))?;
+
+        let rows = stmt.query_map(branch.as_slice(), |row| {
+            Ok(ReportRow {
+                key: row.get(0)?,
+                commits: row.get(1)?,
+                duration_ms: row.get(2)?,
+                files_added: row.get(3)?,
+                files_modified: row.get(4)?,
+                files_removed: row.get(5)?,
+                bytes_written: row.get(6)?,
+            })
+        })?;
+
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Summarises everything exported, optionally limited to a single
+    /// branch. Each part is a single aggregate query, so this stays cheap on
+    /// large databases.
+    pub fn summary(&self, git_branch: Option<&str>, limit: usize) -> Result<Summary> {
+        // "?1 IS NULL OR branch = ?1" stops SQLite from using the branch
+        // indexes, so the condition is only added when there's a branch.
+        let filter = |column: &str| match git_branch {
+            Some(_) => format!("AND {} = ?", column),
+            None => String::new(),
+        };
+        let branch: Vec<&dyn ToSql> = git_branch.iter().map(|b| b as &dyn ToSql).collect();
+        let limit = limit as i64;
+        let mut limited = branch.clone();
+        limited.push(&limit);
+
+        let (commits, bytes_written) = self.conn.query_row(
+            &format!(
+                "SELECT COUNT(*), IFNULL(SUM(bytes_written), 0) FROM commit_branches
+                WHERE exported_at IS NOT NULL {}",
+                filter("branch")
+            ),
+            branch.as_slice(),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        let binary_files = self.conn.query_row(
+            &format!(
+                "SELECT COUNT(DISTINCT path) FROM file_revisions WHERE binary {}",
+                filter("branch")
+            ),
+            branch.as_slice(),
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT strftime('%Y-%m', c.time, 'unixepoch') AS month, COUNT(*)
+            FROM commit_branches cb INNER JOIN commits c ON c.oid = cb.oid
+            WHERE cb.exported_at IS NOT NULL {}
+            GROUP BY month ORDER BY month",
+            filter("cb.branch")
+        ))?;
+        let months = stmt
+            .query_map(branch.as_slice(), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} AS key, COUNT(*) AS commits, IFNULL(SUM(cb.lines_added), 0), IFNULL(SUM(cb.lines_removed), 0)
+            FROM commit_branches cb INNER JOIN commits c ON c.oid = cb.oid
+            WHERE cb.exported_at IS NOT NULL {}
+            GROUP BY key ORDER BY commits DESC, key LIMIT ?",
+            Grouping::Author.key(),
+            filter("cb.branch")
+        ))?;
+        let authors = stmt
+            .query_map(limited.as_slice(), |row| {
+                Ok(AuthorSummary {
+                    author: row.get(0)?,
+                    commits: row.get(1)?,
+                    lines_added: row.get(2)?,
+                    lines_removed: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT path, MAX(size) AS largest FROM file_revisions
+            WHERE size IS NOT NULL {}
+            GROUP BY path ORDER BY largest DESC, path LIMIT ?",
+            filter("branch")
+        ))?;
+        let largest_files = stmt
+            .query_map(limited.as_slice(), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Summary {
+            commits,
+            bytes_written,
+            binary_files,
+            months,
+            authors,
+            largest_files,
+        })
+    }
+
+    pub fn authors(&self) -> Result<Vec<Author>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT git_email, cvs_user, display_name FROM authors ORDER BY git_email")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Author {
+                git_email: row.get(0)?,
+                cvs_user: row.get(1)?,
+                display_name: row.get(2)?,
+            })
+        })?;
+
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Adds or replaces the given authors.
+    pub fn put_authors<'a, I>(&mut self, authors: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = &'a Author>,
+    {
+        self.writable("update authors")?;
+
+        let txn = self.conn.transaction()?;
+
+        let mut stmt = txn.prepare(
+            "INSERT OR REPLACE INTO authors (git_email, cvs_user, display_name) VALUES (?, ?, ?)",
+        )?;
+        let mut count = 0;
+        for author in authors {
+            count += stmt.execute(params![
+                &author.git_email,
+                &author.cvs_user,
+                &author.display_name
+            ])?;
+        }
+        drop(stmt);
+
+        txn.commit()?;
+        Ok(count)
+    }
+
+    /// Removes an author, returning false if they weren't mapped.
+    pub fn remove_author(&self, git_email: &str) -> Result<bool> {
+        self.writable("remove an author")?;
+
+        Ok(self.conn.execute(
+            "DELETE FROM authors WHERE git_email = ?",
+            params![git_email],
+        )? > 0)
+    }
+
+    /// Returns the replacement CVS log message for a commit, if any.
+    pub fn message_override(&self, oid: &Oid) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT message FROM message_overrides WHERE oid = ?")?
+            .query_row(params![oid_hex(oid)], |row| row.get(0))
             .optional()?)
     }
 
+    pub fn set_message_override(&self, oid: &Oid, message: &str) -> Result<()> {
+        self.writable("set a message override")?;
+
+        self.conn.execute(
+            "INSERT OR REPLACE INTO message_overrides (oid, message) VALUES (?, ?)",
+            params![oid_hex(oid), message],
+        )?;
+
+        Ok(())
+    }
+
+    /// Removes a message override, returning false if there wasn't one.
+    pub fn remove_message_override(&self, oid: &Oid) -> Result<bool> {
+        self.writable("remove a message override")?;
+
+        Ok(self.conn.execute(
+            "DELETE FROM message_overrides WHERE oid = ?",
+            params![oid_hex(oid)],
+        )? > 0)
+    }
+
+    pub fn skipped_commits(&self) -> Result<Vec<SkippedCommit>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT oid, reason FROM skipped_commits ORDER BY created_at, oid")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?;
+
+        let mut skipped = Vec::new();
+        for row in rows {
+            let (oid, reason) = row?;
+            skipped.push(SkippedCommit {
+                oid: parse_oid(&oid)?,
+                reason,
+            });
+        }
+
+        Ok(skipped)
+    }
+
+    pub fn skip_commit(&self, oid: &Oid, reason: Option<&str>) -> Result<()> {
+        self.writable("skip a commit")?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO skipped_commits (oid, reason, created_at) VALUES (?, ?, ?)",
+            params![oid_hex(oid), reason, now],
+        )?;
+
+        Ok(())
+    }
+
+    /// Stops skipping a commit, returning false if it wasn't being skipped.
+    pub fn unskip_commit(&self, oid: &Oid) -> Result<bool> {
+        self.writable("stop skipping a commit")?;
+
+        Ok(self.conn.execute(
+            "DELETE FROM skipped_commits WHERE oid = ?",
+            params![oid_hex(oid)],
+        )? > 0)
+    }
+
+    /// Records a CVSROOT and module created by init, returning false if they
+    /// were already recorded.
+    pub fn record_cvsroot(&self, cvsroot: &str, module: Option<&str>) -> Result<bool> {
+        self.writable("record a CVSROOT")?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        Ok(self.conn.execute(
+            "INSERT OR IGNORE INTO cvsroots (cvsroot, module, created_at) VALUES (?, ?, ?)",
+            params![cvsroot, module.unwrap_or_default(), now],
+        )? > 0)
+    }
+
     pub fn write_branch<I, D>(
         &mut self,
         git_branch: &str,
         cvs_branch: &str,
         commits: I,
-    ) -> anyhow::Result<()>
+    ) -> Result<()>
     where
         I: Iterator<Item = D>,
-        D: Deref<Target = Oid>,
+        D: Deref<Target = Commit>,
     {
+        self.writable("write branch")?;
+
         let txn = self.conn.transaction()?;
 
         txn.execute(
@@ -55,11 +1735,741 @@ impl Database {
 
         let mut stmt = txn
             .prepare("INSERT INTO commit_branches (oid, branch, branch_index) VALUES (?, ?, ?)")?;
-        for (i, oid) in commits.enumerate() {
-            stmt.execute(params![format!("{}", *oid), git_branch, i])?;
+        let mut metadata = txn.prepare(
+            "INSERT OR REPLACE INTO commits (oid, author_name, author_email, time, summary) VALUES (?, ?, ?, ?, ?)",
+        )?;
+        for (i, commit) in commits.enumerate() {
+            let oid = oid_hex(&commit.id());
+            stmt.execute(params![&oid, git_branch, i])?;
+            metadata.execute(params![
+                &oid,
+                commit.author_name(),
+                commit.author_email(),
+                commit.time().seconds(),
+                commit.summary()
+            ])?;
+        }
+        drop(stmt);
+        drop(metadata);
+
+        Ok(txn.commit()?)
+    }
+
+    /// Records commits that have been added to the end of a branch since it
+    /// was written, leaving what's already recorded alone.
+    pub fn append_commits<I, D>(&mut self, git_branch: &str, commits: I) -> Result<()>
+    where
+        I: Iterator<Item = D>,
+        D: Deref<Target = Commit>,
+    {
+        self.writable("append commits")?;
+
+        let txn = self.conn.transaction()?;
+        let next: i64 = txn.query_row(
+            "SELECT COALESCE(MAX(branch_index), -1) + 1 FROM commit_branches WHERE branch = ?",
+            params![git_branch],
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = txn
+            .prepare("INSERT INTO commit_branches (oid, branch, branch_index) VALUES (?, ?, ?)")?;
+        let mut metadata = txn.prepare(
+            "INSERT OR REPLACE INTO commits (oid, author_name, author_email, time, summary) VALUES (?, ?, ?, ?, ?)",
+        )?;
+        for (i, commit) in commits.enumerate() {
+            let oid = oid_hex(&commit.id());
+            stmt.execute(params![&oid, git_branch, next + i as i64])?;
+            metadata.execute(params![
+                &oid,
+                commit.author_name(),
+                commit.author_email(),
+                commit.time().seconds(),
+                commit.summary()
+            ])?;
         }
         drop(stmt);
+        drop(metadata);
 
         Ok(txn.commit()?)
     }
 }
+
+fn update_exported(
+    conn: &Connection,
+    git_branch: &str,
+    oid: &Oid,
+    stats: &ExportStats,
+) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+    conn.execute(
+        "UPDATE commit_branches SET exported_at = ?, duration_ms = ?, files_added = ?, files_modified = ?, files_removed = ?, bytes_written = ?, commit_time = ?, lines_added = ?, lines_removed = ?, empty = ? WHERE oid = ? AND branch = ?",
+        params![
+            now,
+            stats.duration.as_millis() as i64,
+            stats.files_added as i64,
+            stats.files_modified as i64,
+            stats.files_removed as i64,
+            stats.bytes_written as i64,
+            stats.commit_time,
+            stats.lines_added as i64,
+            stats.lines_removed as i64,
+            stats.empty,
+            oid_hex(oid),
+            git_branch
+        ],
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::Repository;
+
+    #[test]
+    fn test_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(dir.path()).unwrap();
+        let tree = raw
+            .find_tree(raw.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let time = git2::Time::new(1_600_000_000, 0);
+        let alice = git2::Signature::new("Alice", "alice@example.com", &time).unwrap();
+        let bob = git2::Signature::new("Bob", "bob@example.com", &time).unwrap();
+        let first = raw
+            .commit(Some("HEAD"), &alice, &alice, "first", &tree, &[])
+            .unwrap();
+        let parent = raw.find_commit(first).unwrap();
+        let second = raw
+            .commit(Some("HEAD"), &bob, &bob, "second", &tree, &[&parent])
+            .unwrap();
+
+        let repo = Repository::open(dir.path(), 0).unwrap();
+        let commits = [repo.commit(&first).unwrap(), repo.commit(&second).unwrap()];
+
+        let mut db = Database::open(":memory:").unwrap();
+        db.write_branch("main", "main", commits.iter().map(|c| c.as_ref()))
+            .unwrap();
+        db.mark_exported(
+            "main",
+            &first,
+            &ExportStats {
+                duration: Duration::from_millis(1500),
+                files_added: 2,
+                files_modified: 0,
+                files_removed: 0,
+                bytes_written: 100,
+                commit_time: None,
+                lines_added: 10,
+                lines_removed: 0,
+                empty: false,
+            },
+        )
+        .unwrap();
+
+        // Only exported commits should be included.
+        let rows = db.report(Grouping::Author, Some("main")).unwrap();
+        assert_eq!(
+            vec![ReportRow {
+                key: "Alice <alice@example.com>".into(),
+                commits: 1,
+                duration_ms: 1500,
+                files_added: 2,
+                files_modified: 0,
+                files_removed: 0,
+                bytes_written: 100,
+            }],
+            rows
+        );
+
+        assert_eq!(1, db.report(Grouping::Month, None).unwrap().len());
+        assert!(db
+            .report(Grouping::Month, Some("other"))
+            .unwrap()
+            .is_empty());
+
+        let mut sizes = HashMap::new();
+        sizes.insert(PathBuf::from("big.png"), (5000, true));
+        sizes.insert(PathBuf::from("small.c"), (10, false));
+        db.record_revisions(
+            "main",
+            &first,
+            &[
+                (PathBuf::from("big.png"), "1.1".into()),
+                (PathBuf::from("small.c"), "1.1".into()),
+                (PathBuf::from("unknown.c"), "1.1".into()),
+            ],
+            &sizes,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Summary {
+                commits: 1,
+                bytes_written: 100,
+                binary_files: 1,
+                months: vec![("2020-09".into(), 1)],
+                authors: vec![AuthorSummary {
+                    author: "Alice <alice@example.com>".into(),
+                    commits: 1,
+                    lines_added: 10,
+                    lines_removed: 0,
+                }],
+                largest_files: vec![("big.png".into(), 5000)],
+            },
+            db.summary(Some("main"), 1).unwrap()
+        );
+        assert_eq!(0, db.summary(Some("other"), 10).unwrap().commits);
+        assert_eq!(
+            db.summary(Some("main"), 1).unwrap(),
+            db.summary(None, 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(dir.path()).unwrap();
+        let tree = raw
+            .find_tree(raw.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let sig = git2::Signature::now("Alice", "alice@example.com").unwrap();
+        let oid = raw
+            .commit(
+                Some("HEAD"),
+                &sig,
+                &sig,
+                "Add things\n\nDetails.",
+                &tree,
+                &[],
+            )
+            .unwrap();
+
+        let repo = Repository::open(dir.path(), 0).unwrap();
+        let commit = repo.commit(&oid).unwrap();
+
+        let mut db = Database::open(":memory:").unwrap();
+        db.write_branch("feature/x", "feature__u00002fx", std::iter::once(commit))
+            .unwrap();
+        db.record_revisions(
+            "feature/x",
+            &oid,
+            &[(PathBuf::from("src/a.c"), "1.1".to_string())],
+            &HashMap::new(),
+        )
+        .unwrap();
+        db.mark_exported(
+            "feature/x",
+            &oid,
+            &ExportStats {
+                files_added: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(TagRecord {
+                git_branch: "feature/x".into(),
+                cvs_branch: "feature__u00002fx".into(),
+                last_exported: Some(oid.to_string()),
+            }),
+            db.lookup_tag("feature__u00002fx").unwrap()
+        );
+        assert_eq!(None, db.lookup_tag("nope").unwrap());
+        assert!(!db.branch_tagged("feature/x").unwrap());
+        db.set_branch_tagged("feature/x", true).unwrap();
+        assert!(db.branch_tagged("feature/x").unwrap());
+        assert!(!db.branch_tagged("nope").unwrap());
+        assert!(!db.merge_existing("feature/x").unwrap());
+        db.set_merge_existing("feature/x", true).unwrap();
+        assert!(db.merge_existing("feature/x").unwrap());
+        assert!(!db.merge_existing("nope").unwrap());
+
+        assert_eq!(
+            Some(RevisionRecord {
+                oid: oid.to_string(),
+                git_branch: "feature/x".into(),
+                author_name: "Alice".into(),
+                author_email: "alice@example.com".into(),
+                summary: Some("Add things".into()),
+            }),
+            db.lookup_revision("src/a.c", "1.1").unwrap()
+        );
+        assert_eq!(None, db.lookup_revision("src/a.c", "1.2").unwrap());
+        assert!(!db.has_unrecorded_revisions().unwrap());
+
+        assert_eq!(
+            vec![CommitRecord {
+                git_branch: "feature/x".into(),
+                cvs_branch: "feature__u00002fx".into(),
+                index: 0,
+                exported: true,
+            }],
+            db.lookup_commit(&oid).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(dir.path()).unwrap();
+        let tree = raw
+            .find_tree(raw.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let sig = git2::Signature::now("Alice", "alice@example.com").unwrap();
+        let mut oids: Vec<Oid> = Vec::new();
+        for message in &["first", "second", "third"] {
+            let parent = oids.last().map(|oid| raw.find_commit(*oid).unwrap());
+            let parents: Vec<_> = parent.iter().collect();
+            oids.push(
+                raw.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+                    .unwrap(),
+            );
+        }
+
+        let repo = Repository::open(dir.path(), 0).unwrap();
+        let commits: Vec<_> = oids.iter().map(|oid| repo.commit(oid).unwrap()).collect();
+
+        let mut db = Database::open(":memory:").unwrap();
+        db.write_branch("main", "main", commits.iter().map(|c| c.as_ref()))
+            .unwrap();
+        db.mark_exported("main", &oids[0], &ExportStats::default())
+            .unwrap();
+        db.record_exception(
+            "main",
+            &oids[0],
+            ExceptionKind::SkippedEntry,
+            "sub: gitlink",
+        )
+        .unwrap();
+        db.record_exception("main", &oids[1], ExceptionKind::Failed, "cvs broke")
+            .unwrap();
+
+        assert_eq!(Some(oids[0]), db.last_exported_commit("main").unwrap());
+        assert_eq!(
+            Some(oids[0]),
+            db.last_export("main").unwrap().map(|(oid, _)| oid)
+        );
+        assert_eq!(None, db.last_export("other").unwrap());
+        assert!(db.exported_at("main", &oids[0]).unwrap().is_some());
+        assert_eq!(None, db.exported_at("main", &oids[1]).unwrap());
+        assert_eq!(oids[1..].to_vec(), db.unexported_commits("main").unwrap());
+
+        let failed = db
+            .exceptions(Some("main"), Some(ExceptionKind::Failed))
+            .unwrap();
+        assert_eq!(1, failed.len());
+        assert_eq!(oids[1].to_string(), failed[0].oid);
+        assert_eq!("cvs broke", failed[0].reason);
+        assert_eq!(2, db.exceptions(None, None).unwrap().len());
+        assert!(db.exceptions(Some("other"), None).unwrap().is_empty());
+
+        let counts = db.exception_counts("main").unwrap();
+        assert_eq!(Some(&1), counts.get(&ExceptionKind::Failed));
+        assert_eq!(Some(&1), counts.get(&ExceptionKind::SkippedEntry));
+
+        db.record_exception("main", &oids[1], ExceptionKind::Interrupted, "out of time")
+            .unwrap();
+        db.clear_retryable_exceptions("main", &oids[1]).unwrap();
+        assert!(db
+            .exceptions(Some("main"), None)
+            .unwrap()
+            .iter()
+            .all(|exception| !exception.kind.retryable()));
+
+        assert_eq!(
+            vec![BranchSummary {
+                git_branch: "main".into(),
+                cvs_branch: "main".into(),
+                commits: 3,
+                exported: 1,
+                archived: false,
+            }],
+            db.branch_summaries().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_prune_orphans() {
+        let mut db = Database::open(":memory:").unwrap();
+        db.conn
+            .execute_batch(
+                "INSERT INTO branch_mappings (git, cvs) VALUES ('main', 'main');
+                INSERT INTO commit_branches (oid, branch, branch_index) VALUES ('a', 'main', 0), ('b', 'gone', 0);
+                INSERT INTO commits (oid, author_name, author_email, time) VALUES ('a', '', '', 0), ('b', '', '', 0);
+                INSERT INTO file_state (branch, path, oid) VALUES ('main', 'x', 'a'), ('gone', 'x', 'b');
+                INSERT INTO exceptions (branch, oid, kind, reason, created_at) VALUES ('gone', 'b', 'failed', '', 0);",
+            )
+            .unwrap();
+
+        assert!(db.integrity_check().unwrap().is_empty());
+        assert_eq!(
+            vec![
+                ("commit_branches", 1),
+                ("file_revisions", 0),
+                ("file_state", 1),
+                ("exceptions", 1),
+                ("removed_files", 0),
+                ("commits", 1),
+            ],
+            db.prune_orphans().unwrap()
+        );
+
+        let counts = db.row_counts().unwrap();
+        let count = |table: &str| counts.iter().find(|(name, _)| name == table).unwrap().1;
+        assert_eq!(1, count("commit_branches"));
+        assert_eq!(1, count("commits"));
+        assert_eq!(0, count("exceptions"));
+    }
+
+    #[test]
+    fn test_message_overrides() {
+        let db = Database::open(":memory:").unwrap();
+        let oid = Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+
+        assert_eq!(None, db.message_override(&oid).unwrap());
+        db.set_message_override(&oid, "first").unwrap();
+        db.set_message_override(&oid, "redacted").unwrap();
+        assert_eq!(Some("redacted".into()), db.message_override(&oid).unwrap());
+
+        assert!(db.remove_message_override(&oid).unwrap());
+        assert!(!db.remove_message_override(&oid).unwrap());
+        assert_eq!(None, db.message_override(&oid).unwrap());
+    }
+
+    #[test]
+    fn test_record_cvsroot() {
+        let db = Database::open(":memory:").unwrap();
+
+        assert!(db.record_cvsroot("/cvs", None).unwrap());
+        assert!(db.record_cvsroot("/cvs", Some("project")).unwrap());
+        assert!(!db.record_cvsroot("/cvs", Some("project")).unwrap());
+
+        let count: i64 = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM cvsroots", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(2, count);
+    }
+
+    #[test]
+    fn test_skipped_commits() {
+        let db = Database::open(":memory:").unwrap();
+
+        db.skip_commit(&fake_oid(2), Some("dataset")).unwrap();
+        db.skip_commit(&fake_oid(1), None).unwrap();
+        db.skip_commit(&fake_oid(2), Some("2 GB dataset")).unwrap();
+        let mut skipped = db.skipped_commits().unwrap();
+        skipped.sort_by_key(|skipped| skipped.oid);
+        assert_eq!(
+            vec![
+                SkippedCommit {
+                    oid: fake_oid(1),
+                    reason: None,
+                },
+                SkippedCommit {
+                    oid: fake_oid(2),
+                    reason: Some("2 GB dataset".into()),
+                },
+            ],
+            skipped
+        );
+
+        assert!(db.unskip_commit(&fake_oid(1)).unwrap());
+        assert!(!db.unskip_commit(&fake_oid(1)).unwrap());
+        assert_eq!(1, db.skipped_commits().unwrap().len());
+    }
+
+    #[test]
+    fn test_open_readonly() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        assert!(matches!(
+            Database::open_readonly(&path),
+            Err(Error::DatabaseOpen { .. })
+        ));
+
+        Database::open(&path)
+            .unwrap()
+            .set_message_override(&fake_oid(1), "message")
+            .unwrap();
+
+        let db = Database::open_readonly(&path).unwrap();
+        assert_eq!(
+            Some("message".into()),
+            db.message_override(&fake_oid(1)).unwrap()
+        );
+
+        let err = db.remove_message_override(&fake_oid(1)).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::ReadOnly {
+                    operation: "remove a message override"
+                }
+            ),
+            "{:?}",
+            err
+        );
+
+        // Pretend that the database predates the last migration.
+        let conn = Connection::open(&path).unwrap();
+        conn.execute(
+            "DELETE FROM refinery_schema_history WHERE version = (SELECT MAX(version) FROM refinery_schema_history)",
+            [],
+        )
+        .unwrap();
+        assert!(Database::open_readonly(&path).is_err());
+    }
+
+    fn fake_oid(i: usize) -> Oid {
+        parse_oid(&format!("{:040x}", i)).unwrap()
+    }
+
+    fn populate_branch(db: &mut Database, branch: &str, count: usize) {
+        let txn = db.conn.transaction().unwrap();
+        {
+            let mut stmt = txn
+                .prepare("INSERT INTO commit_branches (oid, branch, branch_index) VALUES (?, ?, ?)")
+                .unwrap();
+            for i in 0..count {
+                stmt.execute(params![oid_hex(&fake_oid(i)), branch, i])
+                    .unwrap();
+            }
+        }
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn test_commit_index() {
+        let mut db = Database::open(":memory:").unwrap();
+        populate_branch(&mut db, "main", 1200);
+        populate_branch(&mut db, "other", 10);
+
+        assert_eq!(Some(42), db.commit_index(&fake_oid(42), "main").unwrap());
+        assert_eq!(None, db.commit_index(&fake_oid(42), "missing").unwrap());
+        assert_eq!(None, db.commit_index(&fake_oid(5000), "main").unwrap());
+
+        // Enough OIDs to need more than one batch.
+        let oids: Vec<Oid> = (0..1100).map(|i| fake_oid(i * 2)).collect();
+        let indices = db.commit_indices(&oids, "main").unwrap();
+        assert_eq!(600, indices.len());
+        assert_eq!(Some(&1198), indices.get(&fake_oid(1198)));
+        assert_eq!(None, indices.get(&fake_oid(1200)));
+
+        assert_eq!(5, db.commit_indices(&oids, "other").unwrap().len());
+    }
+
+    #[test]
+    fn test_file_state() {
+        let mut db = Database::open(":memory:").unwrap();
+        assert!(db.file_state("main").unwrap().is_empty());
+
+        db.write_file_state(
+            "main",
+            vec![("a.c", fake_oid(1)), ("dir/b.c", fake_oid(2))].into_iter(),
+        )
+        .unwrap();
+        db.write_file_state("other", vec![("c.c", fake_oid(3))].into_iter())
+            .unwrap();
+
+        let files = db.file_state("main").unwrap();
+        assert_eq!(2, files.len());
+        assert_eq!(Some(&fake_oid(2)), files.get(Path::new("dir/b.c")));
+
+        // Writing the state again replaces it.
+        db.write_file_state("main", vec![("a.c", fake_oid(4))].into_iter())
+            .unwrap();
+        let files = db.file_state("main").unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!(Some(&fake_oid(4)), files.get(Path::new("a.c")));
+
+        // Pushing a commit updates only the files it touched.
+        populate_branch(&mut db, "main", 3);
+        db.mark_pushed(
+            "main",
+            &fake_oid(0),
+            &ExportStats::default(),
+            &[("a.c".into(), None), ("d.c".into(), Some(fake_oid(5)))],
+        )
+        .unwrap();
+        let files = db.file_state("main").unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!(Some(&fake_oid(5)), files.get(Path::new("d.c")));
+        assert_eq!(Some(fake_oid(0)), db.last_exported_commit("main").unwrap());
+    }
+
+    #[test]
+    fn test_append_commits() {
+        let mut git = crate::test_support::GitBuilder::new();
+        let oids: Vec<_> = (0..4)
+            .map(|i| git.file("a.c", format!("{}\n", i)).commit("commit"))
+            .collect();
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let commits: Vec<_> = oids.iter().map(|oid| repo.commit(oid).unwrap()).collect();
+
+        let mut db = Database::open(":memory:").unwrap();
+        db.write_branch("main", "main", commits[..2].iter().map(|c| c.as_ref()))
+            .unwrap();
+        db.mark_exported("main", &oids[1], &ExportStats::default())
+            .unwrap();
+        db.append_commits("main", commits[2..].iter().map(|c| c.as_ref()))
+            .unwrap();
+
+        assert_eq!(Some(oids[1]), db.last_exported_commit("main").unwrap());
+        assert_eq!(oids[2..].to_vec(), db.unexported_commits("main").unwrap());
+        assert_eq!(Some(oids[3]), db.branch_tip("main").unwrap());
+        assert_eq!(oids, db.branch_commits("main").unwrap());
+        assert_eq!(Some(3), db.commit_index(&oids[3], "main").unwrap());
+    }
+
+    #[test]
+    fn test_revisions_at() {
+        let mut db = Database::open(":memory:").unwrap();
+        populate_branch(&mut db, "main", 3);
+        db.conn
+            .execute(
+                "INSERT INTO branch_mappings (git, cvs) VALUES ('feature', 'feature')",
+                [],
+            )
+            .unwrap();
+        db.conn
+            .execute(
+                "INSERT INTO commit_branches (oid, branch, branch_index) VALUES (?, 'feature', 0)",
+                params![oid_hex(&fake_oid(10))],
+            )
+            .unwrap();
+        let record = |db: &mut Database, branch: &str, oid: usize, path: &str, revision: &str| {
+            db.record_revisions(
+                branch,
+                &fake_oid(oid),
+                &[(PathBuf::from(path), revision.to_string())],
+                &HashMap::new(),
+            )
+            .unwrap()
+        };
+        record(&mut db, "main", 0, "a.c", "1.1");
+        record(&mut db, "main", 0, "b.c", "1.1");
+        record(&mut db, "main", 2, "a.c", "1.2");
+        record(&mut db, "feature", 10, "b.c", "1.1.2.1");
+
+        let paths = ["a.c", "b.c", "c.c"];
+        let revisions = |db: &Database, branch: &str, index: Option<i64>| {
+            let mut revisions: Vec<_> = db
+                .revisions_at(branch, index, paths.iter())
+                .unwrap()
+                .into_iter()
+                .map(|(path, revision)| (path.to_string_lossy().into_owned(), revision))
+                .collect();
+            revisions.sort();
+            revisions
+        };
+        let expected = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(path, revision)| (path.to_string(), revision.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            expected(&[("a.c", "1.2"), ("b.c", "1.1")]),
+            revisions(&db, "main", None)
+        );
+        assert_eq!(
+            expected(&[("a.c", "1.1"), ("b.c", "1.1")]),
+            revisions(&db, "main", Some(1))
+        );
+
+        // Without a root, the feature branch only knows what it committed.
+        assert_eq!(
+            expected(&[("b.c", "1.1.2.1")]),
+            revisions(&db, "feature", None)
+        );
+        assert_eq!(None, db.branch_root("feature").unwrap());
+
+        db.set_branch_root("feature", "main", &fake_oid(1)).unwrap();
+        assert_eq!(
+            Some(("main".to_string(), fake_oid(1))),
+            db.branch_root("feature").unwrap()
+        );
+        assert_eq!(
+            expected(&[("a.c", "1.1"), ("b.c", "1.1.2.1")]),
+            revisions(&db, "feature", None)
+        );
+    }
+
+    #[test]
+    fn test_undo_queries() {
+        let mut db = Database::open(":memory:").unwrap();
+        populate_branch(&mut db, "main", 4);
+        db.mark_exported_through("main", 3).unwrap();
+        db.record_exception(
+            "main",
+            &fake_oid(2),
+            ExceptionKind::SkippedCommit,
+            "skipped",
+        )
+        .unwrap();
+        db.record_revisions(
+            "main",
+            &fake_oid(3),
+            &[
+                (PathBuf::from("src/b.c"), "1.2".into()),
+                (PathBuf::from("src/a.c"), "1.3".into()),
+            ],
+            &HashMap::new(),
+        )
+        .unwrap();
+
+        // Commit 2 was skipped, so commit 1 holds what CVS had before 3.
+        assert_eq!(
+            Some(fake_oid(1)),
+            db.previous_exported_commit("main", 3).unwrap()
+        );
+        assert_eq!(None, db.previous_exported_commit("main", 0).unwrap());
+        assert_eq!(
+            vec![
+                (PathBuf::from("src/a.c"), "1.3".to_string()),
+                (PathBuf::from("src/b.c"), "1.2".to_string()),
+            ],
+            db.commit_revisions("main", &fake_oid(3)).unwrap()
+        );
+
+        db.unmark_exported("main", &fake_oid(3)).unwrap();
+        assert_eq!(Some(fake_oid(2)), db.last_exported_commit("main").unwrap());
+        assert_eq!(vec![fake_oid(3)], db.unexported_commits("main").unwrap());
+    }
+
+    // This is a benchmark rather than a test; run it with
+    // cargo test --release -- --ignored bench_commit_index
+    #[test]
+    #[ignore]
+    fn bench_commit_index() {
+        const ROWS: usize = 500_000;
+        const LOOKUPS: usize = 10_000;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = Database::open(dir.path().join("bench.db")).unwrap();
+        populate_branch(&mut db, "main", ROWS);
+
+        let start = std::time::Instant::now();
+        for i in 0..LOOKUPS {
+            let index = (i * 7919) % ROWS;
+            assert_eq!(
+                Some(index as i64),
+                db.commit_index(&fake_oid(index), "main").unwrap()
+            );
+        }
+        let per_lookup = start.elapsed() / LOOKUPS as u32;
+        println!("commit_index: {:?} per lookup", per_lookup);
+        assert!(per_lookup < Duration::from_millis(1));
+
+        let oids: Vec<Oid> = (0..LOOKUPS).map(|i| fake_oid((i * 7919) % ROWS)).collect();
+        let start = std::time::Instant::now();
+        assert_eq!(LOOKUPS, db.commit_indices(&oids, "main").unwrap().len());
+        println!("commit_indices: {:?} for {} OIDs", start.elapsed(), LOOKUPS);
+    }
+}
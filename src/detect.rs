@@ -0,0 +1,564 @@
+use std::{borrow::Cow, fmt, path::Path};
+
+use crate::{
+    attributes::{Glob, TreeAttributes},
+    cvs::KeywordMode,
+    git::Repository,
+};
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+const UTF16LE_BOM: &[u8] = b"\xff\xfe";
+const UTF16BE_BOM: &[u8] = b"\xfe\xff";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Utf16 {
+    Le,
+    Be,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Glob,
+    Attribute,
+    Utf8Bom,
+    Utf16Bom(Utf16),
+    Utf16Pattern(Utf16),
+    NulByte,
+    Libgit2,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Glob => write!(f, "command-line glob"),
+            Reason::Attribute => write!(f, "gitattributes"),
+            Reason::Utf8Bom => write!(f, "UTF-8 byte order mark"),
+            Reason::Utf16Bom(utf16) => write!(f, "UTF-16{:?} byte order mark", utf16),
+            Reason::Utf16Pattern(utf16) => write!(f, "UTF-16{:?} NUL pattern", utf16),
+            Reason::NulByte => write!(f, "NUL byte in content"),
+            Reason::Libgit2 => write!(f, "libgit2 heuristic"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Detection {
+    pub binary: bool,
+    pub reason: Reason,
+}
+
+/// A keyword mode to add files matching a glob with, given as mode=glob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeywordGlob {
+    mode: KeywordMode,
+    glob: Glob,
+}
+
+impl std::str::FromStr for KeywordGlob {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((mode, glob)) => Ok(Self {
+                mode: mode.parse()?,
+                glob: glob.parse()?,
+            }),
+            None => anyhow::bail!("keyword glob {} is not in the form mode=glob", s),
+        }
+    }
+}
+
+impl Detection {
+    fn utf16(&self) -> Option<Utf16> {
+        match self.reason {
+            Reason::Utf16Bom(utf16) | Reason::Utf16Pattern(utf16) => Some(utf16),
+            _ => None,
+        }
+    }
+}
+
+/// Decides whether blobs should be added to CVS as binary files.
+///
+/// Detection runs as a pipeline: globs given on the command line are
+/// consulted first, then gitattributes, then our own content sniffing, and
+/// finally libgit2's heuristic if nothing else had an opinion.
+///
+/// Where the tree being exported is known, its own .gitattributes files come
+/// first, followed by the attributes libgit2 finds for the repository, which
+/// include the info/attributes file and whatever is checked out.
+#[derive(Debug, Clone, Default)]
+pub struct Detector {
+    transcode_utf16: bool,
+    tree_attributes: TreeAttributes,
+    binary_globs: Vec<Glob>,
+    text_globs: Vec<Glob>,
+    keyword_mode: KeywordMode,
+    keyword_globs: Vec<KeywordGlob>,
+}
+
+impl Detector {
+    pub fn new(transcode_utf16: bool) -> Self {
+        Self {
+            transcode_utf16,
+            tree_attributes: TreeAttributes::default(),
+            binary_globs: Vec::new(),
+            text_globs: Vec::new(),
+            keyword_mode: KeywordMode::Default,
+            keyword_globs: Vec::new(),
+        }
+    }
+
+    /// Forces paths in the CVS module that match the given globs to be binary
+    /// or text. A glob given as both can never be satisfied, so it's an error.
+    pub fn with_globs(mut self, binary: &[Glob], text: &[Glob]) -> anyhow::Result<Self> {
+        if let Some(glob) = binary.iter().find(|glob| text.contains(glob)) {
+            anyhow::bail!("{} is given as both a binary and a text glob", glob);
+        }
+
+        self.binary_globs = binary.to_vec();
+        self.text_globs = text.to_vec();
+        Ok(self)
+    }
+
+    /// Sets the keyword mode text files are added to CVS with, and the modes
+    /// for paths in the CVS module matching the given globs, which apply to
+    /// binary files too. Where several globs match, the last one wins.
+    pub fn with_keyword_modes(mut self, default: KeywordMode, globs: &[KeywordGlob]) -> Self {
+        self.keyword_mode = default;
+        self.keyword_globs = globs.to_vec();
+        self
+    }
+
+    /// Returns the patterns that make files binary in any directory, from the
+    /// binary globs and the .gitattributes file at the top of the tree.
+    pub fn binary_patterns(
+        &self,
+        repo: &Repository,
+        tree: &git2::Oid,
+    ) -> anyhow::Result<Vec<Glob>> {
+        let mut patterns: Vec<_> = self
+            .binary_globs
+            .iter()
+            .filter(|glob| glob.file_name_pattern().is_some())
+            .cloned()
+            .collect();
+        patterns.extend(self.tree_attributes.binary_patterns(repo, tree)?);
+        Ok(patterns)
+    }
+
+    /// Returns the keyword mode to add a file at the given path in the CVS
+    /// module with.
+    pub fn keyword_mode(&self, cvs_path: &Path, detection: &Detection) -> KeywordMode {
+        match self
+            .keyword_globs
+            .iter()
+            .rev()
+            .find(|glob| glob.glob.matches(cvs_path))
+        {
+            Some(glob) => glob.mode,
+            None if detection.binary => KeywordMode::Binary,
+            None => self.keyword_mode,
+        }
+    }
+
+    /// Detects a blob at the given path, which is in git and cvs_path in the
+    /// CVS module. root is the tree the path is in, and the path that tree is
+    /// placed under, if known.
+    pub fn detect(
+        &self,
+        repo: &Repository,
+        root: Option<(&git2::Oid, &Path)>,
+        path: &Path,
+        cvs_path: &Path,
+        blob: &git2::Blob,
+    ) -> anyhow::Result<Detection> {
+        Ok(self
+            .detect_path(repo, root, path, cvs_path)?
+            .unwrap_or_else(|| self.detect_content(blob)))
+    }
+
+    /// Runs the stages of the pipeline that only depend on where the blob
+    /// is, returning None if they don't say either way.
+    pub fn detect_path(
+        &self,
+        repo: &Repository,
+        root: Option<(&git2::Oid, &Path)>,
+        path: &Path,
+        cvs_path: &Path,
+    ) -> anyhow::Result<Option<Detection>> {
+        if let Some(detection) = self.detect_globs(cvs_path)? {
+            return Ok(Some(detection));
+        }
+
+        let from_tree = match root {
+            Some((tree, prefix)) => {
+                self.tree_attributes
+                    .binary(repo, tree, path.strip_prefix(prefix)?)?
+            }
+            None => None,
+        };
+        let binary = match from_tree {
+            Some(binary) => Some(binary),
+            None => repo.binary_attribute(path)?,
+        };
+
+        Ok(binary.map(|binary| Detection {
+            binary,
+            reason: Reason::Attribute,
+        }))
+    }
+
+    fn detect_globs(&self, cvs_path: &Path) -> anyhow::Result<Option<Detection>> {
+        let binary = self.binary_globs.iter().find(|glob| glob.matches(cvs_path));
+        let text = self.text_globs.iter().find(|glob| glob.matches(cvs_path));
+        let binary = match (binary, text) {
+            (Some(binary), Some(text)) => anyhow::bail!(
+                "{} matches both --binary-glob {} and --text-glob {}",
+                cvs_path.display(),
+                binary,
+                text
+            ),
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return Ok(None),
+        };
+
+        Ok(Some(Detection {
+            binary,
+            reason: Reason::Glob,
+        }))
+    }
+
+    /// Runs the content stages of the pipeline, which only depend on the blob
+    /// and not where it is.
+    pub fn detect_content(&self, blob: &git2::Blob) -> Detection {
+        sniff(blob.content()).unwrap_or_else(|| Detection {
+            binary: blob.is_binary(),
+            reason: Reason::Libgit2,
+        })
+    }
+
+    /// Returns the content that should be written to the CVS working copy for
+    /// a blob with the given detection result.
+    pub fn content<'a>(&self, detection: &Detection, content: &'a [u8]) -> Cow<'a, [u8]> {
+        match detection.utf16() {
+            Some(utf16) if self.transcode_utf16 => match transcode(utf16, content) {
+                Some(transcoded) => Cow::Owned(transcoded.into_bytes()),
+                None => {
+                    log::warn!("cannot transcode invalid UTF-16; writing content unchanged");
+                    Cow::Borrowed(content)
+                }
+            },
+            _ => Cow::Borrowed(content),
+        }
+    }
+}
+
+fn sniff(content: &[u8]) -> Option<Detection> {
+    let text = |reason| {
+        Some(Detection {
+            binary: false,
+            reason,
+        })
+    };
+
+    if content.starts_with(UTF16LE_BOM) {
+        return text(Reason::Utf16Bom(Utf16::Le));
+    }
+    if content.starts_with(UTF16BE_BOM) {
+        return text(Reason::Utf16Bom(Utf16::Be));
+    }
+    if let Some(utf16) = utf16_pattern(content) {
+        return text(Reason::Utf16Pattern(utf16));
+    }
+
+    // libgit2 only looks at the first 8000 bytes, which lets formats with
+    // long ASCII headers through as text. Looking at the whole blob is more
+    // expensive, but a NUL anywhere in an 8-bit encoding is a pretty solid
+    // indicator.
+    if content.contains(&0) {
+        return Some(Detection {
+            binary: true,
+            reason: Reason::NulByte,
+        });
+    }
+
+    if content.starts_with(UTF8_BOM) {
+        return text(Reason::Utf8Bom);
+    }
+
+    None
+}
+
+fn utf16_pattern(content: &[u8]) -> Option<Utf16> {
+    // Without a BOM, we only recognise the common case of UTF-16 text that's
+    // entirely within the ASCII range: every other byte is NUL, and the rest
+    // are printable or whitespace.
+    let units = content.chunks_exact(2);
+    if content.is_empty() || !units.remainder().is_empty() {
+        return None;
+    }
+
+    let is_text = |c: u8| c.is_ascii_graphic() || matches!(c, b' ' | b'\t' | b'\n' | b'\r');
+    let matches = |low: usize, high: usize| {
+        units
+            .clone()
+            .all(|unit| unit[high] == 0 && is_text(unit[low]))
+    };
+
+    if matches(0, 1) {
+        Some(Utf16::Le)
+    } else if matches(1, 0) {
+        Some(Utf16::Be)
+    } else {
+        None
+    }
+}
+
+fn transcode(utf16: Utf16, content: &[u8]) -> Option<String> {
+    let units = content.chunks_exact(2);
+    if !units.remainder().is_empty() {
+        return None;
+    }
+
+    let units = units.map(|unit| match utf16 {
+        Utf16::Le => u16::from_le_bytes([unit[0], unit[1]]),
+        Utf16::Be => u16::from_be_bytes([unit[0], unit[1]]),
+    });
+
+    // The BOM has no value once the content is UTF-8, so we'll drop it.
+    let mut out = char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .ok()?;
+    if out.starts_with('\u{feff}') {
+        out.remove(0);
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UTF16LE_BOM_RC: &[u8] = include_bytes!("../tests/fixtures/detect/utf16le-bom.rc");
+    const UTF16BE_BOM_RC: &[u8] = include_bytes!("../tests/fixtures/detect/utf16be-bom.rc");
+    const UTF16LE_RESX: &[u8] = include_bytes!("../tests/fixtures/detect/utf16le-nobom.resx");
+    const UTF8_BOM_TXT: &[u8] = include_bytes!("../tests/fixtures/detect/utf8-bom.txt");
+    const ASCII_HEADER_BIN: &[u8] = include_bytes!("../tests/fixtures/detect/ascii-header.bin");
+
+    fn detect_path(path: &str, content: &[u8]) -> Detection {
+        let dir = tempfile::tempdir().unwrap();
+        let oid = git2::Repository::init(dir.path())
+            .unwrap()
+            .blob(content)
+            .unwrap();
+        std::fs::write(dir.path().join(".gitattributes"), "*.dat binary\n").unwrap();
+
+        let repo = Repository::open(dir.path(), 0).unwrap();
+        let blob = repo.blob(&oid).unwrap();
+
+        Detector::default()
+            .detect(&repo, None, Path::new(path), Path::new(path), &blob)
+            .unwrap()
+    }
+
+    fn detect(content: &[u8]) -> Detection {
+        detect_path("file", content)
+    }
+
+    #[test]
+    fn test_sniff_utf16() {
+        assert_eq!(
+            Detection {
+                binary: false,
+                reason: Reason::Utf16Bom(Utf16::Le)
+            },
+            detect(UTF16LE_BOM_RC)
+        );
+        assert_eq!(
+            Detection {
+                binary: false,
+                reason: Reason::Utf16Bom(Utf16::Be)
+            },
+            detect(UTF16BE_BOM_RC)
+        );
+        assert_eq!(
+            Detection {
+                binary: false,
+                reason: Reason::Utf16Pattern(Utf16::Le)
+            },
+            detect(UTF16LE_RESX)
+        );
+    }
+
+    #[test]
+    fn test_sniff_utf8_bom() {
+        assert_eq!(
+            Detection {
+                binary: false,
+                reason: Reason::Utf8Bom
+            },
+            detect(UTF8_BOM_TXT)
+        );
+    }
+
+    #[test]
+    fn test_sniff_ascii_header() {
+        assert_eq!(
+            Detection {
+                binary: true,
+                reason: Reason::NulByte
+            },
+            detect(ASCII_HEADER_BIN)
+        );
+    }
+
+    #[test]
+    fn test_attribute_override() {
+        assert_eq!(
+            Detection {
+                binary: true,
+                reason: Reason::Attribute
+            },
+            detect_path("file.dat", UTF8_BOM_TXT)
+        );
+    }
+
+    #[test]
+    fn test_glob_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        git2::Repository::init(dir.path()).unwrap();
+        std::fs::write(dir.path().join(".gitattributes"), "*.dat binary\n").unwrap();
+        let repo = Repository::open(dir.path(), 0).unwrap();
+
+        let globs = |globs: &[&str]| -> Vec<Glob> {
+            globs.iter().map(|glob| glob.parse().unwrap()).collect()
+        };
+        let detector = Detector::default()
+            .with_globs(
+                &globs(&["*.bin", "firmware/**"]),
+                &globs(&["x.dat", "*.txt"]),
+            )
+            .unwrap();
+        let detect = |path: &str, content: &[u8]| {
+            let oid = git2::Repository::open(dir.path())
+                .unwrap()
+                .blob(content)
+                .unwrap();
+            let blob = repo.blob(&oid).unwrap();
+            detector.detect(
+                &repo,
+                None,
+                Path::new(path),
+                &Path::new(".").join(path),
+                &blob,
+            )
+        };
+
+        // Globs beat both the attributes and the content.
+        assert_eq!(
+            Detection {
+                binary: false,
+                reason: Reason::Glob
+            },
+            detect("x.dat", b"\0\0").unwrap()
+        );
+        assert_eq!(
+            Detection {
+                binary: true,
+                reason: Reason::Glob
+            },
+            detect("a.bin", b"plain old text\n").unwrap()
+        );
+
+        assert_eq!(
+            Detection {
+                binary: true,
+                reason: Reason::Glob
+            },
+            detect("firmware/boot/img", b"plain old text\n").unwrap()
+        );
+        assert_eq!(
+            Detection {
+                binary: false,
+                reason: Reason::Libgit2
+            },
+            detect("lib/firmware/img", b"plain old text\n").unwrap()
+        );
+
+        // Without a glob, the attributes still beat the content.
+        assert_eq!(
+            Detection {
+                binary: true,
+                reason: Reason::Attribute
+            },
+            detect("doc/y.dat", b"plain old text\n").unwrap()
+        );
+
+        // A path matching both kinds of glob can't be decided.
+        let detector = Detector::default()
+            .with_globs(&globs(&["*.dat"]), &globs(&["doc/*"]))
+            .unwrap();
+        assert!(detector
+            .detect_path(&repo, None, Path::new("doc/y.dat"), Path::new("doc/y.dat"))
+            .is_err());
+        assert!(Detector::default()
+            .with_globs(&globs(&["*.dat"]), &globs(&["*.dat"]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_keyword_mode() {
+        let detector = Detector::default().with_keyword_modes(
+            KeywordMode::NoExpand,
+            &["kk=*.h".parse().unwrap(), "kv=legacy/**".parse().unwrap()],
+        );
+        let mode = |path: &str, binary: bool| {
+            detector.keyword_mode(
+                Path::new(path),
+                &Detection {
+                    binary,
+                    reason: Reason::Libgit2,
+                },
+            )
+        };
+
+        assert_eq!(KeywordMode::NoExpand, mode("a.c", false));
+        assert_eq!(KeywordMode::Binary, mode("a.png", true));
+        assert_eq!(KeywordMode::KeywordOnly, mode("a.h", false));
+        assert_eq!(KeywordMode::KeywordOnly, mode("a.h", true));
+        assert_eq!(KeywordMode::Default, mode("legacy/a.h", false));
+
+        assert!("ko".parse::<KeywordGlob>().is_err());
+        assert!("kz=*.c".parse::<KeywordGlob>().is_err());
+    }
+
+    #[test]
+    fn test_libgit2_fallback() {
+        assert_eq!(
+            Detection {
+                binary: false,
+                reason: Reason::Libgit2
+            },
+            detect(b"plain old text\n")
+        );
+    }
+
+    #[test]
+    fn test_transcode() {
+        let detection = detect(UTF16LE_BOM_RC);
+
+        assert_eq!(
+            UTF16LE_BOM_RC,
+            Detector::new(false)
+                .content(&detection, UTF16LE_BOM_RC)
+                .as_ref()
+        );
+        assert_eq!(
+            "// Resource script\r\nSTRINGTABLE\r\nBEGIN\r\n    IDS_HELLO \"Hello, world\"\r\nEND\r\n"
+                .as_bytes(),
+            Detector::new(true).content(&detection, UTF16LE_BOM_RC).as_ref()
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use std::{io, path::PathBuf, time::SystemTimeError};
+
+use git2::Oid;
+use subprocess::ExitStatus;
+
+use crate::cvs::{Operation, StickyTag};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The errors from the git, CVS, and database layers, carrying enough detail
+/// for callers to tell them apart without looking at the message.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("cannot find branch {name}")]
+    BranchNotFound { name: String },
+
+    /// A reference such as HEAD that isn't a symbolic reference to a branch.
+    #[error("{name} is not a symbolic reference; pass --branch or --rev")]
+    DetachedHead { name: String },
+
+    #[error("cannot find {name}; pass --branch or --rev")]
+    ReferenceNotFound {
+        name: String,
+        #[source]
+        source: git2::Error,
+    },
+
+    #[error("cannot find a git repository at or above {}", .path.display())]
+    RepositoryNotFound {
+        path: PathBuf,
+        #[source]
+        source: git2::Error,
+    },
+
+    #[error("cannot find {kind} {oid}")]
+    ObjectNotFound {
+        kind: &'static str,
+        oid: Oid,
+        #[source]
+        source: git2::Error,
+    },
+
+    /// A commit whose parent isn't in the repository, when that isn't
+    /// explained by it being a shallow clone.
+    #[error("commit {commit} has parent {parent}, which is missing from the repository")]
+    MissingParent { commit: Oid, parent: Oid },
+
+    #[error("{} is a shallow clone, so the start of its history is missing; run git fetch --unshallow, or pass --allow-shallow to export only the history it has", .path.display())]
+    ShallowClone { path: PathBuf },
+
+    #[error("cannot resolve {rev} to a commit")]
+    RevisionNotFound {
+        rev: String,
+        #[source]
+        source: git2::Error,
+    },
+
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+
+    /// A cvs command that exited unsuccessfully, with the last lines of its
+    /// output if that was captured. The command is as it would be shown to a
+    /// user, so it needn't include every argument.
+    #[error("{}", describe_failure(.command, .status, .output, *.attempts))]
+    CvsCommandFailed {
+        command: String,
+        status: ExitStatus,
+        output: Vec<String>,
+        /// How many times the command was run before giving up.
+        attempts: u32,
+    },
+
+    #[error("cannot run cvs")]
+    CvsNotRun(#[from] subprocess::PopenError),
+
+    /// An argument that can't fit on a command line even by itself.
+    #[error("cannot run cvs {}: {} is too long to pass to it, taking {cost} bytes when only {available} are available", .operation.as_str(), .arg.display())]
+    ArgumentTooLong {
+        operation: Operation,
+        arg: PathBuf,
+        cost: usize,
+        available: usize,
+    },
+
+    /// A CVSROOT that can't be parsed, with what's wrong with it.
+    #[error("{}", describe_cvsroot(.root, .problem))]
+    InvalidCvsRoot { root: String, problem: String },
+
+    /// A name that CVS won't take as a tag, or that isn't a tag we made.
+    #[error("CVS tag {tag:?} {problem}")]
+    InvalidTag { tag: String, problem: String },
+
+    /// An option value that isn't one of the ones we know.
+    #[error("unknown {kind} {value}; expected one of {}", .expected.join(", "))]
+    UnknownValue {
+        kind: &'static str,
+        value: String,
+        expected: &'static [&'static str],
+    },
+
+    /// An exit code to allow that isn't given as OPERATION=CODE.
+    #[error("expected OPERATION=CODE, not {spec}")]
+    InvalidAllowedExit { spec: String },
+
+    /// A checkout that's of a different module than the one asked for.
+    #[error("checkout of {module} into {} has repository {repository} instead", .target.display())]
+    CheckoutMismatch {
+        module: String,
+        target: PathBuf,
+        repository: String,
+    },
+
+    /// A directory of the working copy that isn't on the branch being
+    /// committed to.
+    #[error("{} has sticky tag {actual:?}, but we are committing to {expected:?}", .dir.display())]
+    StickyTagMismatch {
+        dir: PathBuf,
+        actual: Option<StickyTag>,
+        expected: Option<StickyTag>,
+    },
+
+    /// Paths that can't all be exported, because they'd end up in the same
+    /// place in CVS.
+    #[error("{} would both be exported to {}", describe_paths(.paths), .target.display())]
+    PathConflict {
+        paths: Vec<PathBuf>,
+        target: PathBuf,
+    },
+
+    #[error(transparent)]
+    Database(#[from] rusqlite::Error),
+
+    // Boxed, since it's much bigger than the rest.
+    #[error(transparent)]
+    Migration(Box<refinery::Error>),
+
+    #[error("cannot open {} read-only", .path.display())]
+    DatabaseOpen {
+        path: PathBuf,
+        #[source]
+        source: rusqlite::Error,
+    },
+
+    #[error("{} is not a git2cvs database", .path.display())]
+    NotADatabase {
+        path: PathBuf,
+        #[source]
+        source: rusqlite::Error,
+    },
+
+    #[error("cannot {operation}: the database was opened read-only")]
+    ReadOnly { operation: &'static str },
+
+    #[error("cannot {action} {}", .path.display())]
+    File {
+        action: &'static str,
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("the system clock is set before 1970")]
+    Clock(#[from] SystemTimeError),
+
+    /// Anything that callers aren't expected to handle specially.
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<refinery::Error> for Error {
+    fn from(e: refinery::Error) -> Self {
+        Error::Migration(Box::new(e))
+    }
+}
+
+impl Error {
+    /// Whether the database couldn't be used because another connection had
+    /// it locked.
+    pub fn is_database_busy(&self) -> bool {
+        let code = match self {
+            Error::Database(e)
+            | Error::DatabaseOpen { source: e, .. }
+            | Error::NotADatabase { source: e, .. } => match e {
+                rusqlite::Error::SqliteFailure(e, _) => Some(e.code),
+                _ => None,
+            },
+            _ => None,
+        };
+        matches!(
+            code,
+            Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+        )
+    }
+
+    /// Returns a function that wraps an I/O error on the given path.
+    pub fn file<P: Into<PathBuf>>(action: &'static str, path: P) -> impl FnOnce(io::Error) -> Self {
+        let path = path.into();
+        move |source| Error::File {
+            action,
+            path,
+            source,
+        }
+    }
+}
+
+fn describe_failure(
+    command: &str,
+    status: &ExitStatus,
+    output: &[String],
+    attempts: u32,
+) -> String {
+    let mut message = match status {
+        ExitStatus::Exited(code) => format!("{} failed with exit code {}", command, code),
+        ExitStatus::Signaled(signal) => format!("{} was killed by signal {}", command, signal),
+        status => format!("{} failed: {:?}", command, status),
+    };
+    if attempts > 1 {
+        message.push_str(&format!(" after {} attempts", attempts));
+    }
+    if !output.is_empty() {
+        message.push_str("; the last lines of output were:\n");
+        message.push_str(&output.join("\n"));
+    }
+    message
+}
+
+fn describe_cvsroot(root: &str, problem: &str) -> String {
+    if root.is_empty() {
+        format!("CVSROOT {}", problem)
+    } else {
+        format!("CVSROOT {} {}", root, problem)
+    }
+}
+
+fn describe_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" and ")
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use crate::{database::ExportStats, git::Commit};
+
+/// Where a commit is within the commits being exported in a push.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The 1-based position of the commit.
+    pub index: usize,
+    pub total: usize,
+}
+
+/// Something that happened to a commit during a push.
+pub enum Event<'a> {
+    Exported {
+        commit: &'a Commit,
+        stats: &'a ExportStats,
+    },
+    Skipped {
+        commit: &'a Commit,
+        reason: &'a str,
+    },
+    Failed {
+        commit: &'a Commit,
+        error: &'a anyhow::Error,
+        duration: Duration,
+    },
+}
+
+impl Event<'_> {
+    pub fn commit(&self) -> &Commit {
+        match self {
+            Event::Exported { commit, .. }
+            | Event::Skipped { commit, .. }
+            | Event::Failed { commit, .. } => commit,
+        }
+    }
+}
+
+/// Receives the events of a push as they happen.
+pub trait Listener {
+    fn event(&mut self, progress: Progress, event: &Event<'_>);
+}
+
+impl Listener for Vec<Box<dyn Listener>> {
+    fn event(&mut self, progress: Progress, event: &Event<'_>) {
+        for listener in self.iter_mut() {
+            listener.event(progress, event);
+        }
+    }
+}
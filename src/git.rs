@@ -1,23 +1,192 @@
-use std::{collections::VecDeque, path::Path};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    ffi::OsStr,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+};
 
-use git2::{ErrorCode, Oid};
+use git2::{AttrCheckFlags, AttrValue, ErrorCode, ObjectType, Oid, TreeWalkResult};
+
+use crate::{
+    attributes,
+    cache::{Lru, Stats},
+    error::{Error, Result},
+};
 
 pub struct Repository {
     repo: git2::Repository,
+
+    // libgit2 objects borrow the repository, so we can't cache them directly;
+    // instead, we cache the handful of fields we actually use.
+    commits: RefCell<Lru<Oid, Rc<Commit>>>,
+    trees: RefCell<Lru<Oid, Rc<Vec<TreeEntry>>>>,
+
+    // The subtree being exported, if it's not the whole repository.
+    prefix: Option<PathBuf>,
+
+    // The commits a shallow clone has without their parents, and whether
+    // histories may stop at them.
+    shallow: HashSet<Oid>,
+    allow_shallow: bool,
+
+    // Whether authors are resolved with .mailmap, and the mailmaps read so
+    // far, by the OID of the blob they were read from.
+    use_mailmap: bool,
+    mailmaps: RefCell<HashMap<Oid, git2::Mailmap>>,
 }
 
 impl Repository {
-    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+    /// Opens a repository, caching up to the given number of commits and
+    /// trees. A budget of zero disables caching.
+    pub fn open<P: AsRef<Path>>(path: P, budget: usize) -> Result<Self> {
+        Self::with_budget(git2::Repository::open(path)?, budget)
+    }
+
+    /// Opens the repository that a path is in, which may be anywhere in its
+    /// working tree or a linked worktree's, or a bare repository, caching as
+    /// open does.
+    pub fn discover<P: AsRef<Path>>(path: P, budget: usize) -> Result<Self> {
+        let path = path.as_ref();
+        let repo =
+            git2::Repository::discover(path).map_err(|source| Error::RepositoryNotFound {
+                path: path.into(),
+                source,
+            })?;
+        Self::with_budget(repo, budget)
+    }
+
+    fn with_budget(repo: git2::Repository, budget: usize) -> Result<Self> {
+        // A linked worktree's own directory has no shallow file; the clone's
+        // is in the common directory.
+        let shallow = read_shallow(&find_common_dir(&repo)?.join("shallow"))?;
+
         Ok(Self {
-            repo: git2::Repository::open(path)?,
+            repo,
+            commits: RefCell::new(Lru::new(budget)),
+            trees: RefCell::new(Lru::new(budget)),
+            prefix: None,
+            shallow,
+            allow_shallow: false,
+            use_mailmap: false,
+            mailmaps: RefCell::new(HashMap::new()),
         })
     }
 
-    pub fn blob(&self, oid: &Oid) -> anyhow::Result<git2::Blob> {
-        Ok(self.repo.find_blob(*oid)?)
+    /// Returns the directory holding the repository's objects and refs. Every
+    /// worktree of a clone shares it, so it tells one clone from another.
+    pub fn common_dir(&self) -> Result<PathBuf> {
+        find_common_dir(&self.repo)
+    }
+
+    /// Lets histories in a shallow clone stop where the clone does, rather
+    /// than failing there.
+    pub fn with_shallow_allowed(mut self, allow: bool) -> Self {
+        self.allow_shallow = allow;
+        self
+    }
+
+    /// Resolves the authors of the commits that are read with the .mailmap
+    /// committed in each, or failing that the one at HEAD.
+    pub fn with_mailmap(mut self, use_mailmap: bool) -> Self {
+        self.use_mailmap = use_mailmap;
+        self
+    }
+
+    pub fn is_shallow(&self) -> bool {
+        // libgit2 only looks for a shallow file in a linked worktree's own
+        // directory, so this goes by the one read from the common directory.
+        !self.shallow.is_empty()
+    }
+
+    /// Fails with ShallowClone if the repository is a shallow clone, unless
+    /// that's been allowed.
+    pub fn check_shallow(&self) -> Result<()> {
+        if self.is_shallow() && !self.allow_shallow {
+            return Err(Error::ShallowClone {
+                path: self.repo.path().to_path_buf(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Only exports the given subtree: each commit's tree is replaced by the
+    /// tree at the prefix, so paths within it lose the prefix.
+    pub fn with_prefix<P: AsRef<Path>>(mut self, prefix: Option<P>) -> Self {
+        self.prefix = prefix
+            .map(|prefix| {
+                prefix
+                    .as_ref()
+                    .components()
+                    .filter(|component| matches!(component, Component::Normal(_)))
+                    .collect::<PathBuf>()
+            })
+            .filter(|prefix| !prefix.as_os_str().is_empty());
+        self
     }
 
-    pub fn branch(&self, name: &str, remote: bool) -> anyhow::Result<Option<Branch>> {
+    pub fn prefix(&self) -> Option<&Path> {
+        self.prefix.as_deref()
+    }
+
+    /// Returns the tree at the prefix within a commit's tree. A prefix that's
+    /// a file gives a tree containing just that file, and one that doesn't
+    /// exist gives an empty tree.
+    fn prefix_tree(&self, tree: &git2::Tree) -> Result<Oid> {
+        let prefix = match &self.prefix {
+            Some(prefix) => prefix,
+            None => return Ok(tree.id()),
+        };
+
+        let entry = match tree.get_path(prefix) {
+            Ok(entry) => entry,
+            Err(e) if e.code() == ErrorCode::NotFound => {
+                return Ok(self.repo.treebuilder(None)?.write()?)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match entry.kind() {
+            Some(ObjectType::Tree) => Ok(entry.id()),
+            _ => {
+                let mut builder = self.repo.treebuilder(None)?;
+                builder.insert(entry.name_bytes(), entry.id(), entry.filemode())?;
+                Ok(builder.write()?)
+            }
+        }
+    }
+
+    /// Returns whether gitattributes force the given path to be treated as
+    /// binary or text, or None if no relevant attribute is set.
+    pub fn binary_attribute(&self, path: &Path) -> Result<Option<bool>> {
+        let flags = AttrCheckFlags::default();
+
+        if AttrValue::from_string(self.repo.get_attr(path, "binary", flags)?) == AttrValue::True {
+            return Ok(Some(true));
+        }
+
+        Ok(
+            match AttrValue::from_string(self.repo.get_attr(path, "text", flags)?) {
+                AttrValue::True => Some(false),
+                AttrValue::False => Some(true),
+                _ => None,
+            },
+        )
+    }
+
+    pub fn blob(&self, oid: &Oid) -> Result<git2::Blob<'_>> {
+        self.repo
+            .find_blob(*oid)
+            .map_err(|source| Error::ObjectNotFound {
+                kind: "blob",
+                oid: *oid,
+                source,
+            })
+    }
+
+    pub fn branch(&self, name: &str, remote: bool) -> Result<Option<Branch<'_>>> {
         match self.repo.find_branch(
             name,
             if remote {
@@ -26,53 +195,1534 @@ impl Repository {
                 git2::BranchType::Local
             },
         ) {
-            Ok(branch) => Ok(Some(Branch { branch })),
+            Ok(branch) => Ok(Some(Branch {
+                name: branch.name()?.unwrap_or_default().to_string(),
+                tip: branch.get().peel_to_commit()?.id(),
+                repo: self,
+            })),
             Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
             Err(e) => Err(e.into()),
         }
     }
 
-    pub fn commit(&self, oid: &Oid) -> anyhow::Result<git2::Commit> {
-        Ok(self.repo.find_commit(*oid)?)
+    /// Returns a branch, failing with BranchNotFound if it doesn't exist.
+    pub fn require_branch(&self, name: &str, remote: bool) -> Result<Branch<'_>> {
+        self.branch(name, remote)?
+            .ok_or_else(|| Error::BranchNotFound { name: name.into() })
+    }
+
+    /// Returns the name of the branch that HEAD refers to, or that the origin
+    /// remote's HEAD refers to if remote is true.
+    pub fn head_branch(&self, remote: bool) -> Result<String> {
+        let (name, prefix) = if remote {
+            ("refs/remotes/origin/HEAD", "refs/remotes/")
+        } else {
+            ("HEAD", "refs/heads/")
+        };
+
+        let head = self
+            .repo
+            .find_reference(name)
+            .map_err(|source| Error::ReferenceNotFound {
+                name: name.into(),
+                source,
+            })?;
+        let target = match head.symbolic_target() {
+            Some(target) => target,
+            None => return Err(Error::DetachedHead { name: name.into() }),
+        };
+        match target.strip_prefix(prefix) {
+            Some(branch) => {
+                log::info!("using branch {}, which {} refers to", branch, name);
+                Ok(branch.to_string())
+            }
+            None => Err(Error::Other(format!(
+                "{} refers to {}, which is not a branch",
+                name, target
+            ))),
+        }
+    }
+
+    /// Returns the name and tip of every local or remote branch.
+    pub fn branch_tips(&self, remote: bool) -> Result<Vec<(String, Oid)>> {
+        let mut tips = Vec::new();
+        for branch in self.repo.branches(Some(if remote {
+            git2::BranchType::Remote
+        } else {
+            git2::BranchType::Local
+        }))? {
+            let (branch, _) = branch?;
+            if let (Some(name), Some(oid)) = (branch.name()?, branch.get().target()) {
+                tips.push((name.to_string(), oid));
+            }
+        }
+
+        Ok(tips)
+    }
+
+    /// Returns the names of the local or remote branches matching a glob, in
+    /// which * and ? don't match /, sorted by name.
+    pub fn matching_branches(&self, glob: &str, remote: bool) -> Result<Vec<String>> {
+        let mut names: Vec<_> = self
+            .branch_tips(remote)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| attributes::wildmatch(glob.as_bytes(), name.as_bytes()))
+            .collect();
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// Resolves a revision, such as an OID, a tag, or HEAD~3, to a commit.
+    pub fn resolve_commit(&self, rev: &str) -> Result<Oid> {
+        Ok(self
+            .repo
+            .revparse_single(rev)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|source| Error::RevisionNotFound {
+                rev: rev.into(),
+                source,
+            })?
+            .id())
+    }
+
+    /// Returns the history leading up to the commit a revision resolves to,
+    /// as if it were the tip of a branch named after the revision.
+    /// Returns a branch as if its tip were the given commit, whatever the ref
+    /// says now.
+    pub fn branch_at(&self, name: &str, tip: Oid) -> Branch<'_> {
+        Branch {
+            name: name.into(),
+            tip,
+            repo: self,
+        }
+    }
+
+    pub fn revision(&self, rev: &str) -> Result<Branch<'_>> {
+        Ok(Branch {
+            name: rev.into(),
+            tip: self.resolve_commit(rev)?,
+            repo: self,
+        })
+    }
+
+    pub fn commit(&self, oid: &Oid) -> Result<Rc<Commit>> {
+        if let Some(commit) = self.commits.borrow_mut().get(oid) {
+            return Ok(commit);
+        }
+
+        let commit = self
+            .repo
+            .find_commit(*oid)
+            .map_err(|source| Error::ObjectNotFound {
+                kind: "commit",
+                oid: *oid,
+                source,
+            })?;
+        let author = self.author(&commit)?;
+        let commit = Rc::new(Commit {
+            id: commit.id(),
+            tree_id: self.prefix_tree(&commit.tree()?)?,
+            author_name: String::from_utf8_lossy(author.name_bytes()).into_owned(),
+            author_email: String::from_utf8_lossy(author.email_bytes()).into_owned(),
+            time: commit.time(),
+            message: commit.message_raw_bytes().to_vec(),
+        });
+
+        self.commits.borrow_mut().insert(*oid, commit.clone());
+        Ok(commit)
+    }
+
+    /// Returns a commit's author, as the mailmap in effect at the commit has it
+    /// if that's wanted.
+    fn author(&self, commit: &git2::Commit) -> Result<git2::Signature<'static>> {
+        let author = commit.author().to_owned();
+        if !self.use_mailmap {
+            return Ok(author);
+        }
+        // An unborn HEAD just doesn't have a mailmap to fall back on.
+        let blob = match mailmap_blob(&commit.tree()?).or_else(|| {
+            let head = self.repo.head().and_then(|head| head.peel_to_tree()).ok()?;
+            mailmap_blob(&head)
+        }) {
+            Some(blob) => blob,
+            None => return Ok(author),
+        };
+
+        let mut mailmaps = self.mailmaps.borrow_mut();
+        let mailmap = match mailmaps.entry(blob) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let content = self.repo.find_blob(blob)?;
+                log::debug!("reading .mailmap from blob {}", blob);
+                entry.insert(git2::Mailmap::from_buffer(&String::from_utf8_lossy(
+                    content.content(),
+                ))?)
+            }
+        };
+        Ok(mailmap.resolve_signature(&author)?)
+    }
+
+    /// Returns the number of lines added and removed by a commit relative to
+    /// its first parent. Binary files don't count.
+    pub fn line_stats(&self, commit: &Commit) -> Result<(usize, usize)> {
+        let raw = self.repo.find_commit(commit.id())?;
+        let parent = match raw.parent(0) {
+            Ok(parent) => Some(self.repo.find_tree(self.prefix_tree(&parent.tree()?)?)?),
+            Err(e) if e.code() == ErrorCode::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let stats = self
+            .repo
+            .diff_tree_to_tree(
+                parent.as_ref(),
+                Some(&self.repo.find_tree(commit.tree_id())?),
+                None,
+            )?
+            .stats()?;
+
+        Ok((stats.insertions(), stats.deletions()))
+    }
+
+    /// Returns the blobs that differ between two trees, where a missing old
+    /// tree is empty. Submodules aren't included.
+    pub fn changed_blobs(&self, old: Option<&Oid>, new: &Oid) -> Result<Vec<BlobChange>> {
+        let old = match old {
+            Some(old) => Some(self.repo.find_tree(*old)?),
+            None => None,
+        };
+        let diff =
+            self.repo
+                .diff_tree_to_tree(old.as_ref(), Some(&self.repo.find_tree(*new)?), None)?;
+
+        let blob = |file: git2::DiffFile| match file.mode() {
+            git2::FileMode::Blob | git2::FileMode::BlobExecutable | git2::FileMode::Link => {
+                Some(file.id())
+            }
+            _ => None,
+        };
+        Ok(diff
+            .deltas()
+            .filter_map(|delta| {
+                let path = delta
+                    .new_file()
+                    .path()
+                    .or_else(|| delta.old_file().path())?;
+                let (old, new) = (blob(delta.old_file()), blob(delta.new_file()));
+                if old.is_none() && new.is_none() {
+                    None
+                } else {
+                    Some(BlobChange {
+                        path: path.to_path_buf(),
+                        old,
+                        new,
+                    })
+                }
+            })
+            .collect())
+    }
+
+    /// Returns the entries that differ between two commits' trees, where a
+    /// missing old commit is an empty tree. Trees aren't reported themselves,
+    /// only the entries within them, and an entry that changes kind is
+    /// reported as a removal and an addition.
+    pub fn diff(&self, old: Option<&Commit>, new: &Commit) -> Result<Vec<Change>> {
+        let old = match old {
+            Some(old) => Some(self.repo.find_tree(old.tree_id())?),
+            None => None,
+        };
+        let diff = self.repo.diff_tree_to_tree(
+            old.as_ref(),
+            Some(&self.repo.find_tree(new.tree_id())?),
+            None,
+        )?;
+
+        let entry = |file: git2::DiffFile| -> Result<Option<TreeEntry>> {
+            let kind = match file.mode() {
+                git2::FileMode::Blob | git2::FileMode::BlobExecutable | git2::FileMode::Link => {
+                    ObjectType::Blob
+                }
+                git2::FileMode::Commit => ObjectType::Commit,
+                git2::FileMode::Tree => ObjectType::Tree,
+                _ => return Ok(None),
+            };
+            let path = match file.path_bytes() {
+                Some(path) => path,
+                None => return Ok(None),
+            };
+            Ok(Some(TreeEntry {
+                name: path.rsplit(|c| *c == b'/').next().unwrap_or(path).to_vec(),
+                id: file.id(),
+                filemode: i32::from(file.mode()),
+                kind: Some(kind),
+            }))
+        };
+
+        let mut changes = Vec::new();
+        for delta in diff.deltas() {
+            let path = match delta
+                .new_file()
+                .path_bytes()
+                .or_else(|| delta.old_file().path_bytes())
+            {
+                Some(path) => PathBuf::from(OsStr::from_bytes(path)),
+                None => continue,
+            };
+            let (old, new) = (entry(delta.old_file())?, entry(delta.new_file())?);
+            if old.is_some() || new.is_some() {
+                changes.push(Change { path, old, new });
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Follows the symlink at the given path within a tree to the regular file
+    /// it eventually points to, returning the file's blob and mode. Links that
+    /// are absolute, lead out of the tree, go through a symlinked directory or
+    /// don't end at a file resolve to None.
+    pub fn resolve_symlink(&self, tree: &Oid, path: &Path) -> Result<Option<(Oid, i32)>> {
+        let tree = self.repo.find_tree(*tree)?;
+        let mut path = path.to_path_buf();
+        for _ in 0..MAX_SYMLINK_DEPTH {
+            if path.as_os_str().is_empty() {
+                return Ok(None);
+            }
+            let entry = match tree.get_path(&path) {
+                Ok(entry) => entry,
+                Err(e) if e.code() == ErrorCode::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            let mode = entry.filemode();
+            if mode == i32::from(git2::FileMode::Blob)
+                || mode == i32::from(git2::FileMode::BlobExecutable)
+            {
+                return Ok(Some((entry.id(), mode)));
+            } else if mode != i32::from(git2::FileMode::Link) {
+                return Ok(None);
+            }
+
+            let blob = self.repo.find_blob(entry.id())?;
+            let mut next = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+            for component in Path::new(OsStr::from_bytes(blob.content())).components() {
+                match component {
+                    Component::Normal(name) => next.push(name),
+                    Component::CurDir => {}
+                    Component::ParentDir if next.pop() => {}
+                    _ => return Ok(None),
+                }
+            }
+            path = next;
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the blob at the given path within a tree, if there's a file
+    /// there.
+    pub fn blob_at(&self, tree: &Oid, path: &Path) -> Result<Option<Oid>> {
+        let tree = self.repo.find_tree(*tree)?;
+        match tree.get_path(path) {
+            Ok(entry) if entry.kind() == Some(ObjectType::Blob) => Ok(Some(entry.id())),
+            Ok(_) => Ok(None),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns the URL that .gitmodules at the top of the given tree has for
+    /// the submodule at path, if any.
+    pub fn submodule_url(&self, tree: &Oid, path: &Path) -> Result<Option<String>> {
+        let tree = self.repo.find_tree(*tree)?;
+        let entry = match tree.get_name(".gitmodules") {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let blob = self.repo.find_blob(entry.id())?;
+
+        Ok(parse_gitmodules(blob.content())
+            .into_iter()
+            .find(|(submodule, _)| Path::new(submodule) == path)
+            .map(|(_, url)| url))
+    }
+
+    /// Returns the best common ancestor of two commits, or None if they don't
+    /// share any history.
+    pub fn merge_base(&self, a: &Oid, b: &Oid) -> Result<Option<Oid>> {
+        match self.repo.merge_base(*a, *b) {
+            Ok(oid) => Ok(Some(oid)),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn commit_cache_stats(&self) -> Stats {
+        self.commits.borrow().stats()
+    }
+
+    pub fn tree_cache_stats(&self) -> Stats {
+        self.trees.borrow().stats()
+    }
+
+    /// Walks the given tree in pre-order, in the same way as
+    /// git2::Tree::walk, but using cached tree entries where possible.
+    pub fn walk_tree<F, E>(&self, oid: &Oid, mut callback: F) -> Result<(), E>
+    where
+        F: FnMut(&Path, &TreeEntry) -> Result<TreeWalkResult, E>,
+        E: From<Error>,
+    {
+        self.walk_subtree(oid, Path::new(""), &mut callback)?;
+        Ok(())
+    }
+
+    fn walk_subtree<F, E>(&self, oid: &Oid, root: &Path, callback: &mut F) -> Result<bool, E>
+    where
+        F: FnMut(&Path, &TreeEntry) -> Result<TreeWalkResult, E>,
+        E: From<Error>,
+    {
+        for entry in self.tree_entries(oid)?.iter() {
+            match callback(root, entry)? {
+                TreeWalkResult::Ok => {}
+                TreeWalkResult::Skip => continue,
+                TreeWalkResult::Abort => return Ok(false),
+            }
+
+            if entry.kind() == Some(ObjectType::Tree) {
+                let path = root.join(entry.file_name()?);
+                if !self.walk_subtree(&entry.id, &path, callback)? {
+                    return Ok(false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn tree_entries(&self, oid: &Oid) -> Result<Rc<Vec<TreeEntry>>> {
+        if let Some(entries) = self.trees.borrow_mut().get(oid) {
+            return Ok(entries);
+        }
+
+        let tree = self
+            .repo
+            .find_tree(*oid)
+            .map_err(|source| Error::ObjectNotFound {
+                kind: "tree",
+                oid: *oid,
+                source,
+            })?;
+        let entries = Rc::new(
+            tree.iter()
+                .map(|entry| TreeEntry {
+                    name: entry.name_bytes().to_vec(),
+                    id: entry.id(),
+                    filemode: entry.filemode(),
+                    kind: entry.kind(),
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        self.trees.borrow_mut().insert(*oid, entries.clone());
+        Ok(entries)
     }
 }
 
-pub struct Branch<'repo> {
-    branch: git2::Branch<'repo>,
+/// A path whose blob differs between two trees. Either side is None where the
+/// path doesn't exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobChange {
+    pub path: PathBuf,
+    pub old: Option<Oid>,
+    pub new: Option<Oid>,
+}
+
+/// An entry that differs between two trees. Either side is None where the
+/// path doesn't exist.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub path: PathBuf,
+    pub old: Option<TreeEntry>,
+    pub new: Option<TreeEntry>,
+}
+
+#[derive(Clone)]
+pub struct Commit {
+    id: Oid,
+    tree_id: Oid,
+    author_name: String,
+    author_email: String,
+    time: git2::Time,
+    message: Vec<u8>,
+}
+
+impl Commit {
+    pub fn id(&self) -> Oid {
+        self.id
+    }
+
+    pub fn tree_id(&self) -> Oid {
+        self.tree_id
+    }
+
+    pub fn author_name(&self) -> &str {
+        &self.author_name
+    }
+
+    pub fn author_email(&self) -> &str {
+        &self.author_email
+    }
+
+    pub fn time(&self) -> git2::Time {
+        self.time
+    }
+
+    pub fn message_raw_bytes(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// The first line of the commit message.
+    pub fn summary(&self) -> String {
+        let message = String::from_utf8_lossy(&self.message);
+        message
+            .trim_start()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
 }
 
-impl Branch<'_> {
-    pub fn linear_history(&self) -> anyhow::Result<VecDeque<Oid>> {
-        // We'll build a linear history here: a set of commit OIDs that, in
-        // order, will provide a plausible representation of the history of the
-        // branch. We'll do that by only following the first parent, and
-        // essentially treating merge commits as simple squash commits.
-        let mut commits = VecDeque::new();
+/// Opens the submodules of a repository as gitlinks to them are found, making
+/// their objects readable through the superproject's repository.
+pub struct Submodules<'a> {
+    repo: &'a Repository,
+    opened: RefCell<HashMap<PathBuf, git2::Repository>>,
+}
+
+impl<'a> Submodules<'a> {
+    pub fn new(repo: &'a Repository) -> Self {
+        Self {
+            repo,
+            opened: RefCell::new(HashMap::new()),
+        }
+    }
 
-        let mut commit = self.branch.get().peel_to_commit()?;
-        loop {
-            let next = commit.parent(0);
-            commits.push_front(commit.id());
+    /// Makes the commit pinned by the gitlink at the given path readable
+    /// through the superproject's repository. Nested submodules are found
+    /// through the submodules containing them, which must have been loaded
+    /// first.
+    pub fn load(&self, path: &Path, oid: &Oid) -> Result<()> {
+        if !self.opened.borrow().contains_key(path) {
+            let submodule = self.open(path).map_err(|e| {
+                Error::Other(format!(
+                    "cannot open submodule {} at {}: {}",
+                    path.display(),
+                    oid,
+                    e
+                ))
+            })?;
 
-            match next {
-                Ok(parent) => {
-                    commit = parent;
+            let objects = submodule.path().join("objects");
+            match objects.to_str() {
+                Some(objects) => self.repo.repo.odb()?.add_disk_alternate(objects)?,
+                None => {
+                    return Err(Error::Other(format!(
+                        "submodule {} has a non-UTF-8 path",
+                        path.display()
+                    )))
                 }
-                Err(e) if e.code() == ErrorCode::NotFound => {
-                    break;
+            }
+            self.opened
+                .borrow_mut()
+                .insert(path.to_path_buf(), submodule);
+        }
+
+        if self.repo.repo.find_commit(*oid).is_err() {
+            return Err(Error::Other(format!(
+                "submodule {} does not contain commit {}",
+                path.display(),
+                oid
+            )));
+        }
+        Ok(())
+    }
+
+    fn open(&self, path: &Path) -> Result<git2::Repository> {
+        let opened = self.opened.borrow();
+        let parent = opened
+            .iter()
+            .filter(|(parent, _)| path.starts_with(parent))
+            .max_by_key(|(parent, _)| parent.components().count());
+        let (repo, relative) = match parent {
+            Some((parent, repo)) => (
+                repo,
+                path.strip_prefix(parent)
+                    .expect("parent submodule should contain the path"),
+            ),
+            None => (&self.repo.repo, path),
+        };
+
+        let name = match relative.to_str() {
+            Some(name) => name,
+            None => return Err(Error::Other("submodule path is not valid UTF-8".into())),
+        };
+        let submodule = repo.find_submodule(name)?.open()?;
+        Ok(submodule)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeEntry {
+    name: Vec<u8>,
+    id: Oid,
+    filemode: i32,
+    kind: Option<ObjectType>,
+}
+
+impl TreeEntry {
+    pub fn name_bytes(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// The entry's name as a path component. Git allows names that aren't
+    /// valid UTF-8, which are fine on Unix, but not names that would change
+    /// the meaning of the path.
+    pub fn file_name(&self) -> Result<&OsStr> {
+        if matches!(&self.name[..], b"" | b"." | b"..")
+            || self.name.iter().any(|c| *c == b'/' || *c == 0)
+        {
+            return Err(Error::Other(format!(
+                "tree entry name \"{}\" cannot be represented as a path",
+                escape_bytes(&self.name)
+            )));
+        }
+
+        Ok(OsStr::from_bytes(&self.name))
+    }
+
+    pub fn id(&self) -> Oid {
+        self.id
+    }
+
+    pub fn filemode(&self) -> i32 {
+        self.filemode
+    }
+
+    pub fn kind(&self) -> Option<ObjectType> {
+        self.kind
+    }
+}
+
+/// Returns the blob of the .mailmap at the top of a tree, if there is one.
+fn mailmap_blob(tree: &git2::Tree) -> Option<Oid> {
+    tree.get_name(".mailmap")
+        .filter(|entry| entry.kind() == Some(ObjectType::Blob))
+        .map(|entry| entry.id())
+}
+
+/// Returns whether a branch name given by the user is a glob, rather than the
+/// name of one branch.
+pub fn is_glob(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
+
+/// Formats bytes that are probably a path for display, hex escaping anything
+/// that isn't valid UTF-8 or is a control character.
+pub fn escape_bytes(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut rest = bytes;
+
+    loop {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(valid) => (valid, &b""[..]),
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                let len = e.error_len().unwrap_or(invalid.len());
+                rest = &invalid[len..];
+                (std::str::from_utf8(valid).unwrap(), &invalid[..len])
+            }
+        };
+
+        for c in valid.chars() {
+            if c.is_control() {
+                out.push_str(&format!("\\x{:02x}", c as u32));
+            } else {
+                out.push(c);
+            }
+        }
+        for byte in invalid {
+            out.push_str(&format!("\\x{:02x}", byte));
+        }
+
+        if invalid.is_empty() {
+            return out;
+        }
+    }
+}
+
+/// A branch, or a revision standing in for one, as it was when it was looked
+/// up.
+pub struct Branch<'repo> {
+    name: String,
+    tip: Oid,
+    repo: &'repo Repository,
+}
+
+impl<'repo> Branch<'repo> {
+    /// Returns the commit at the tip of the branch.
+    pub fn tip(&self) -> Oid {
+        self.tip
+    }
+
+    /// Returns the linear history of the branch, oldest commit first: a set
+    /// of commits that, in order, provide a plausible representation of its
+    /// history. Only first parents are followed, so merge commits are
+    /// essentially treated as squash commits.
+    ///
+    /// Commits are read as the history is iterated, rather than all up front.
+    pub fn history(&self) -> Result<History<'repo>> {
+        // libgit2 can't walk past the edge of a shallow clone, so the history
+        // has to be found the slow way.
+        if !self.repo.shallow.is_empty() {
+            let mut commits = self.first_parents()?.collect::<Result<Vec<_>>>()?;
+            commits.reverse();
+            return Ok(History::Listed(commits.into_iter()));
+        }
+
+        let mut walk = self.repo.repo.revwalk()?;
+        walk.push(self.tip)?;
+        walk.simplify_first_parent()?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+
+        Ok(History::Walk(walk))
+    }
+
+    /// Returns the history of the branch from the given commit onwards,
+    /// leaving out everything before it.
+    pub fn history_since(&self, since: Oid) -> Result<History<'repo>> {
+        if self.commits_since(since)?.is_none() {
+            return Err(self.not_in_history(since));
+        }
+
+        let mut history = self.history()?;
+        match &mut history {
+            History::Walk(walk) => {
+                for parent in self.repo.repo.find_commit(since)?.parent_ids().take(1) {
+                    walk.hide(parent)?;
                 }
-                Err(e) => {
-                    return Err(e.into());
+            }
+            History::Listed(commits) => {
+                let before = commits.as_slice().iter().position(|oid| *oid == since);
+                if let Some(before) = before.filter(|before| *before > 0) {
+                    commits.nth(before - 1);
                 }
+            }
+        }
+        Ok(history)
+    }
+
+    /// Returns how many commits the branch's history has, from the given
+    /// commit onwards if there is one. Only parents are read, so this is much
+    /// cheaper than going through the history itself.
+    pub fn history_len(&self, since: Option<Oid>) -> Result<usize> {
+        if let Some(since) = since {
+            return match self.commits_since(since)? {
+                Some(count) => Ok(count + 1),
+                None => Err(self.not_in_history(since)),
             };
         }
 
-        Ok(commits)
+        let mut len = 0;
+        for oid in self.first_parents()? {
+            oid?;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    /// Returns the first commit of the branch's history if it's where a
+    /// shallow clone stops, rather than a real root.
+    pub fn shallow_root(&self) -> Result<Option<Oid>> {
+        if self.repo.shallow.is_empty() {
+            return Ok(None);
+        }
+
+        let mut root = None;
+        for oid in self.first_parents()? {
+            root = Some(oid?);
+        }
+        Ok(root.filter(|oid| self.repo.shallow.contains(oid)))
+    }
+
+    fn not_in_history(&self, oid: Oid) -> Error {
+        Error::Other(format!(
+            "commit {} is not on the first-parent history of branch {}",
+            oid, self.name
+        ))
+    }
+
+    /// Returns the whole linear history of the branch at once.
+    pub fn linear_history(&self) -> Result<VecDeque<Oid>> {
+        self.history()?.collect()
+    }
+
+    /// Resolves a revision given by the user, such as an abbreviated SHA, to a
+    /// commit on the branch's first-parent history.
+    pub fn resolve(&self, rev: &str) -> Result<Oid> {
+        let oid = self.repo.resolve_commit(rev)?;
+        match self.commits_since(oid)? {
+            Some(_) => Ok(oid),
+            None => Err(self.not_in_history(oid)),
+        }
+    }
+
+    /// Returns how many commits the branch's first-parent history has after
+    /// the given commit, or None if the commit isn't on it.
+    pub fn commits_since(&self, since: Oid) -> Result<Option<usize>> {
+        for (count, oid) in self.first_parents()?.enumerate() {
+            if oid? == since {
+                return Ok(Some(count));
+            }
+        }
+
+        Ok(None)
     }
 
-    pub fn name(&self) -> anyhow::Result<&str> {
-        // We'll unwrap because process_branches filters down to only branches
-        // that have names.
-        Ok(self.branch.name()?.unwrap())
+    /// Returns the branch's first-parent history from the tip backwards.
+    fn first_parents(&self) -> Result<FirstParents<'repo>> {
+        Ok(FirstParents {
+            repo: self.repo,
+            next: Some(self.repo.repo.find_commit(self.tip)?),
+        })
+    }
+}
+
+/// The first-parent history of a branch, oldest commit first.
+pub enum History<'repo> {
+    Walk(git2::Revwalk<'repo>),
+    Listed(std::vec::IntoIter<Oid>),
+}
+
+impl Iterator for History<'_> {
+    type Item = Result<Oid>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            History::Walk(walk) => walk.next().map(|oid| oid.map_err(Error::from)),
+            History::Listed(commits) => commits.next().map(Ok),
+        }
+    }
+}
+
+/// Follows first parents back from a commit, stopping at a root or, if
+/// they're allowed, where a shallow clone stops.
+struct FirstParents<'repo> {
+    repo: &'repo Repository,
+    next: Option<git2::Commit<'repo>>,
+}
+
+impl Iterator for FirstParents<'_> {
+    type Item = Result<Oid>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let commit = self.next.take()?;
+        let parent = match commit.parent_ids().next() {
+            Some(parent) => parent,
+            None => return Some(Ok(commit.id())),
+        };
+
+        if self.repo.shallow.contains(&commit.id()) {
+            if !self.repo.allow_shallow {
+                return Some(Err(Error::ShallowClone {
+                    path: self.repo.repo.path().to_path_buf(),
+                }));
+            }
+            return Some(Ok(commit.id()));
+        }
+
+        match self.repo.repo.find_commit(parent) {
+            Ok(parent) => {
+                self.next = Some(parent);
+                Some(Ok(commit.id()))
+            }
+            Err(e) if e.code() == ErrorCode::NotFound => Some(Err(Error::MissingParent {
+                commit: commit.id(),
+                parent,
+            })),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+/// Finds the directory that a repository shares with its other worktrees,
+/// which is its own directory unless it's a linked worktree.
+fn find_common_dir(repo: &git2::Repository) -> Result<PathBuf> {
+    let path = repo.path();
+    let common = if repo.is_worktree() {
+        let file = path.join("commondir");
+        let common = fs::read_to_string(&file).map_err(Error::file("read", file))?;
+        path.join(common.trim_end())
+    } else {
+        path.to_path_buf()
+    };
+    common
+        .canonicalize()
+        .map_err(Error::file("resolve", common))
+}
+
+/// Reads the commits listed in a shallow clone's shallow file, which is empty
+/// or missing in a full clone.
+fn read_shallow(path: &Path) -> Result<HashSet<Oid>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(Error::file("read", path)(e)),
+    };
+
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| Ok(Oid::from_str(line)?))
+        .collect()
+}
+
+/// Returns the path and URL of each submodule in a .gitmodules file. This only
+/// understands as much of the config format as git writes there.
+fn parse_gitmodules(content: &[u8]) -> Vec<(String, String)> {
+    let mut submodules = Vec::new();
+    let (mut path, mut url) = (None, None);
+    for line in String::from_utf8_lossy(content).lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            if let (Some(path), Some(url)) = (path.take(), url.take()) {
+                submodules.push((path, url));
+            }
+        } else if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim().trim_matches('"').to_string();
+            match key.trim().to_lowercase().as_str() {
+                "path" => path = Some(value),
+                "url" => url = Some(value),
+                _ => {}
+            }
+        }
+    }
+    if let (Some(path), Some(url)) = (path, url) {
+        submodules.push((path, url));
+    }
+
+    submodules
+}
+
+/// What to do with submodules that aren't exported with --recurse-submodules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmodulePolicy {
+    /// Leave them out with a warning.
+    Skip,
+    /// Fail the commit, listing every submodule found.
+    Error,
+    /// Export a text file in their place recording the URL and commit.
+    Placeholder,
+}
+
+impl SubmodulePolicy {
+    pub const VARIANTS: &'static [&'static str] = &["skip", "error", "placeholder"];
+}
+
+impl FromStr for SubmodulePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(SubmodulePolicy::Skip),
+            "error" => Ok(SubmodulePolicy::Error),
+            "placeholder" => Ok(SubmodulePolicy::Placeholder),
+            _ => Err(Error::Other(format!("unknown submodule policy {}", s))),
+        }
+    }
+}
+
+/// How many symlinks are followed before giving up, as on Linux.
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// What to do with symlinks in git trees, which CVS can't store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Leave them out with a warning.
+    Skip,
+    /// Export the content of the file they point to in the same tree.
+    Dereference,
+    /// Create them in the working copy, and then fail the commit.
+    Materialise,
+}
+
+impl Symlinks {
+    pub const VARIANTS: &'static [&'static str] = &["skip", "dereference", "materialise"];
+}
+
+impl FromStr for Symlinks {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Symlinks::Skip),
+            "dereference" => Ok(Symlinks::Dereference),
+            "materialise" => Ok(Symlinks::Materialise),
+            _ => Err(Error::Other(format!("unknown symlink policy {}", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for Symlinks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Symlinks::Skip => "skip",
+            Symlinks::Dereference => "dereference",
+            Symlinks::Materialise => "materialise",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_name() {
+        let entry = |name: &[u8]| TreeEntry {
+            name: name.to_vec(),
+            id: Oid::zero(),
+            filemode: 0o100644,
+            kind: Some(ObjectType::Blob),
+        };
+
+        assert_eq!(
+            OsStr::new("file.txt"),
+            entry(b"file.txt").file_name().unwrap()
+        );
+        assert_eq!(
+            OsStr::from_bytes(b"caf\xe9.txt"),
+            entry(b"caf\xe9.txt").file_name().unwrap()
+        );
+
+        for name in &[&b""[..], b".", b"..", b"a/b", b"nul\0"] {
+            assert!(entry(name).file_name().is_err());
+        }
+        assert!(
+            format!("{:#}", entry(b"caf\xe9/x").file_name().unwrap_err()).contains("caf\\xe9/x")
+        );
+    }
+
+    #[test]
+    fn test_escape_bytes() {
+        assert_eq!("plain.txt", escape_bytes(b"plain.txt"));
+        assert_eq!("caf\u{e9}.txt", escape_bytes("caf\u{e9}.txt".as_bytes()));
+        assert_eq!("caf\\xe9.txt", escape_bytes(b"caf\xe9.txt"));
+        assert_eq!("a\\x0ab\\xff", escape_bytes(b"a\nb\xff"));
+    }
+
+    #[test]
+    fn test_head_branch() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(dir.path()).unwrap();
+        let tree = raw
+            .find_tree(raw.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+        let oid = raw
+            .commit(Some("refs/heads/trunk"), &sig, &sig, "first", &tree, &[])
+            .unwrap();
+        raw.set_head("refs/heads/trunk").unwrap();
+        raw.reference("refs/remotes/origin/main", oid, false, "test")
+            .unwrap();
+
+        let repo = Repository::open(dir.path(), 0).unwrap();
+        assert_eq!("trunk", repo.head_branch(false).unwrap());
+        assert!(matches!(
+            repo.head_branch(true),
+            Err(Error::ReferenceNotFound { .. })
+        ));
+
+        raw.reference_symbolic(
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/main",
+            false,
+            "test",
+        )
+        .unwrap();
+        assert_eq!("origin/main", repo.head_branch(true).unwrap());
+
+        raw.set_head_detached(oid).unwrap();
+        assert!(matches!(
+            repo.head_branch(false),
+            Err(Error::DetachedHead { name }) if name == "HEAD"
+        ));
+    }
+
+    #[test]
+    fn test_resolve_symlink() {
+        let mut git = crate::test_support::GitBuilder::new();
+        let oid = git
+            .file("a.txt", "a")
+            .executable("dir/run", "#!/bin/sh")
+            .symlink("direct", "a.txt")
+            .symlink("dir/up", "./../a.txt")
+            .symlink("chain", "dir/up")
+            .symlink("exec", "dir/run")
+            .symlink("absolute", "/etc/passwd")
+            .symlink("outside", "../a.txt")
+            .symlink("to-dir", "dir")
+            .symlink("via-dir", "to-dir/run")
+            .symlink("loop", "loop")
+            .commit("links");
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let tree = repo.commit(&oid).unwrap().tree_id();
+        let a = git
+            .raw()
+            .find_tree(tree)
+            .unwrap()
+            .get_name("a.txt")
+            .unwrap()
+            .id();
+        let resolve = |path: &str| repo.resolve_symlink(&tree, Path::new(path)).unwrap();
+
+        for path in ["direct", "dir/up", "chain"] {
+            assert_eq!(Some((a, 0o100644)), resolve(path), "{}", path);
+        }
+        assert_eq!(0o100755, resolve("exec").unwrap().1);
+        for path in [
+            "absolute", "outside", "to-dir", "via-dir", "loop", "missing",
+        ] {
+            assert_eq!(None, resolve(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_parse_gitmodules() {
+        assert_eq!(
+            vec![
+                ("lib/a".to_string(), "https://example.com/a.git".to_string()),
+                ("b".to_string(), "../b.git".to_string()),
+            ],
+            parse_gitmodules(
+                b"[submodule \"a\"]\n\tpath = lib/a\n\turl = https://example.com/a.git\n\
+                  [submodule \"no-url\"]\n\tpath = c\n\
+                  [submodule \"b\"]\n\turl = \"../b.git\"\n\tpath = b\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_base() {
+        let mut git = crate::test_support::GitBuilder::new();
+        let first = git.file("a.txt", "a\n").commit("first");
+        let trunk = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        git.branch("feature").checkout("feature");
+        let feature = git.file("b.txt", "b\n").commit("feature");
+        git.checkout(&trunk);
+        let second = git.file("a.txt", "a2\n").commit("second");
+
+        let repo = Repository::open(git.path(), 0).unwrap();
+        assert_eq!(Some(first), repo.merge_base(&feature, &second).unwrap());
+        assert_eq!(Some(first), repo.merge_base(&first, &second).unwrap());
+
+        // An unrelated root commit shares nothing.
+        let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+        let tree = git
+            .raw()
+            .find_tree(git.raw().treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let orphan = git
+            .raw()
+            .commit(None, &sig, &sig, "orphan", &tree, &[])
+            .unwrap();
+        assert_eq!(None, repo.merge_base(&orphan, &second).unwrap());
+    }
+
+    #[test]
+    fn test_discover() {
+        let mut git = crate::test_support::GitBuilder::new();
+        git.file("src/lib/a.c", "a\n").commit("first");
+        let trunk = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let common = git.path().join(".git").canonicalize().unwrap();
+
+        // The files aren't checked out, so make the directories to start from.
+        let nested = git.path().join("src/lib");
+        fs::create_dir_all(&nested).unwrap();
+        let repo = Repository::discover(&nested, 0).unwrap();
+        assert_eq!(common, repo.common_dir().unwrap());
+        assert_eq!(trunk, repo.head_branch(false).unwrap());
+
+        // A linked worktree has its own HEAD, but the same objects and refs.
+        let dir = tempfile::tempdir().unwrap();
+        git.branch("feature");
+        let feature = git
+            .raw()
+            .find_branch("feature", git2::BranchType::Local)
+            .unwrap();
+        let mut opts = git2::WorktreeAddOptions::new();
+        opts.reference(Some(feature.get()));
+        git.raw()
+            .worktree("feature", &dir.path().join("feature"), Some(&opts))
+            .unwrap();
+        fs::create_dir_all(dir.path().join("feature/src")).unwrap();
+        let repo = Repository::discover(dir.path().join("feature/src"), 0).unwrap();
+        assert_eq!(common, repo.common_dir().unwrap());
+        assert_eq!("feature", repo.head_branch(false).unwrap());
+
+        let bare = dir.path().join("bare.git");
+        git2::Repository::init_bare(&bare).unwrap();
+        let repo = Repository::discover(&bare, 0).unwrap();
+        assert_eq!(bare.canonicalize().unwrap(), repo.common_dir().unwrap());
+
+        assert!(matches!(
+            Repository::discover(dir.path(), 0),
+            Err(Error::RepositoryNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_mailmap() {
+        let mut git = crate::test_support::GitBuilder::new();
+        let first = git.file("a.txt", "a\n").commit("first");
+        let second = git
+            .file(
+                ".mailmap",
+                "Real Name <real@example.com> <test@example.com>\n",
+            )
+            .commit("second");
+
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let commit = repo.commit(&second).unwrap();
+        assert_eq!("Test", commit.author_name());
+        assert_eq!("test@example.com", commit.author_email());
+
+        // A commit from before .mailmap was added uses HEAD's.
+        let repo = Repository::open(git.path(), 0).unwrap().with_mailmap(true);
+        for oid in [first, second] {
+            let commit = repo.commit(&oid).unwrap();
+            assert_eq!("Real Name", commit.author_name());
+            assert_eq!("real@example.com", commit.author_email());
+        }
+
+        // The .mailmap committed with a commit takes precedence over HEAD's.
+        let third = git
+            .file(
+                ".mailmap",
+                "Other Name <other@example.com> <test@example.com>\n",
+            )
+            .commit("third");
+        git.remove(".mailmap").commit("fourth");
+        let repo = Repository::open(git.path(), 0).unwrap().with_mailmap(true);
+        assert_eq!(
+            "other@example.com",
+            repo.commit(&third).unwrap().author_email()
+        );
+        assert_eq!(
+            "test@example.com",
+            repo.commit(&first).unwrap().author_email()
+        );
+    }
+
+    #[test]
+    fn test_matching_branches() {
+        let mut git = crate::test_support::GitBuilder::new();
+        git.file("a.txt", "a\n").commit("first");
+        git.branch("release/2")
+            .branch("release/1")
+            .branch("release/3/fix");
+
+        let repo = Repository::open(git.path(), 0).unwrap();
+        assert_eq!(
+            vec!["release/1".to_string(), "release/2".into()],
+            repo.matching_branches("release/*", false).unwrap()
+        );
+        assert_eq!(
+            vec!["release/3/fix".to_string()],
+            repo.matching_branches("release/?/*", false).unwrap()
+        );
+        assert!(repo
+            .matching_branches("feature/*", false)
+            .unwrap()
+            .is_empty());
+
+        assert!(is_glob("release/*"));
+        assert!(is_glob("release/[12]"));
+        assert!(!is_glob("release/1"));
+    }
+
+    #[test]
+    fn test_shallow() {
+        let mut git = crate::test_support::GitBuilder::new();
+        let first = git.file("a.txt", "a\n").commit("first");
+        let second = git.file("a.txt", "b\n").commit("second");
+        let third = git.file("a.txt", "c\n").commit("third");
+        let trunk = git.raw().head().unwrap().shorthand().unwrap().to_string();
+
+        // A shallow clone has the commits at its edge, but not their parents.
+        let hex = first.to_string();
+        fs::remove_file(
+            git.raw()
+                .path()
+                .join("objects")
+                .join(&hex[..2])
+                .join(&hex[2..]),
+        )
+        .unwrap();
+        fs::write(git.raw().path().join("shallow"), format!("{}\n", second)).unwrap();
+
+        let repo = Repository::open(git.path(), 0).unwrap();
+        assert!(matches!(
+            repo.check_shallow(),
+            Err(Error::ShallowClone { .. })
+        ));
+        let branch = repo.require_branch(&trunk, false).unwrap();
+        assert!(matches!(
+            branch.history_len(None),
+            Err(Error::ShallowClone { .. })
+        ));
+
+        let repo = Repository::open(git.path(), 0)
+            .unwrap()
+            .with_shallow_allowed(true);
+        repo.check_shallow().unwrap();
+        let branch = repo.require_branch(&trunk, false).unwrap();
+        assert_eq!(2, branch.history_len(None).unwrap());
+        assert_eq!(
+            VecDeque::from(vec![second, third]),
+            branch.linear_history().unwrap()
+        );
+        assert_eq!(
+            vec![third],
+            branch
+                .history_since(third)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        );
+        assert_eq!(Some(second), branch.shallow_root().unwrap());
+
+        // A linked worktree of the clone is just as shallow.
+        let dir = tempfile::tempdir().unwrap();
+        git.raw()
+            .worktree("linked", &dir.path().join("linked"), None)
+            .unwrap();
+        let repo = Repository::discover(dir.path().join("linked"), 0).unwrap();
+        assert!(matches!(
+            repo.check_shallow(),
+            Err(Error::ShallowClone { .. })
+        ));
+        let repo = repo.with_shallow_allowed(true);
+        let branch = repo.require_branch(&trunk, false).unwrap();
+        assert_eq!(Some(second), branch.shallow_root().unwrap());
+
+        // Without the shallow file, the missing commit is just missing.
+        fs::remove_file(git.raw().path().join("shallow")).unwrap();
+        let repo = Repository::open(git.path(), 0).unwrap();
+        repo.check_shallow().unwrap();
+        let branch = repo.require_branch(&trunk, false).unwrap();
+        assert_eq!(None, branch.shallow_root().unwrap());
+        assert!(matches!(
+            branch.history_len(None),
+            Err(Error::MissingParent { commit, parent }) if commit == second && parent == first
+        ));
+    }
+
+    #[test]
+    fn test_history_since() {
+        let mut git = crate::test_support::GitBuilder::new();
+        let first = git.file("a.txt", "a\n").commit("first");
+        let second = git.file("a.txt", "b\n").commit("second");
+        let trunk = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        git.branch("feature").checkout("feature");
+        let feature = git.file("b.txt", "b\n").commit("feature");
+        git.checkout(&trunk);
+        let merge = git.merge("merge", feature);
+
+        let repo = Repository::open(git.path(), 0).unwrap();
+        assert_eq!(
+            second,
+            repo.resolve_commit(&format!("{}~1", trunk)).unwrap()
+        );
+        assert!(matches!(
+            repo.resolve_commit("nonexistent"),
+            Err(Error::RevisionNotFound { rev, .. }) if rev == "nonexistent"
+        ));
+
+        assert!(matches!(
+            repo.require_branch("missing", false),
+            Err(Error::BranchNotFound { name }) if name == "missing"
+        ));
+        let branch = repo.require_branch(&trunk, false).unwrap();
+        assert_eq!(
+            vec![second, merge],
+            branch
+                .history_since(second)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        );
+        assert_eq!(
+            branch.linear_history().unwrap(),
+            branch
+                .history_since(first)
+                .unwrap()
+                .collect::<Result<VecDeque<_>>>()
+                .unwrap()
+        );
+        assert_eq!(3, branch.history_len(None).unwrap());
+        let revision = repo.revision(&format!("{}~1", trunk)).unwrap();
+        assert_eq!(second, revision.tip());
+        assert_eq!(
+            VecDeque::from(vec![first, second]),
+            revision.linear_history().unwrap()
+        );
+        assert_eq!(2, branch.history_len(Some(second)).unwrap());
+        assert_eq!(Some(2), branch.commits_since(first).unwrap());
+        assert_eq!(Some(0), branch.commits_since(merge).unwrap());
+        assert_eq!(None, branch.commits_since(feature).unwrap());
+
+        // The feature commit is only reachable through the merge's second
+        // parent.
+        assert!(branch.history_since(feature).is_err());
+        let message = format!("{:#}", branch.history_len(Some(feature)).unwrap_err());
+        assert_eq!(
+            format!(
+                "commit {} is not on the first-parent history of branch {}",
+                feature, trunk
+            ),
+            message
+        );
+    }
+
+    #[test]
+    fn test_prefix() {
+        let mut git = crate::test_support::GitBuilder::new();
+        let oid = git
+            .file("services/legacy/foo.c", "foo\n")
+            .file("services/legacy/lib/bar.c", "bar\n")
+            .file("README", "readme\n")
+            .commit("first");
+
+        let names = |prefix: &str| {
+            let repo = Repository::open(git.path(), 0)
+                .unwrap()
+                .with_prefix(Some(prefix));
+            let commit = repo.commit(&oid).unwrap();
+            let mut names = Vec::new();
+            repo.walk_tree(&commit.tree_id(), |path, entry| -> Result<_> {
+                names.push(path.join(entry.file_name()?));
+                Ok(TreeWalkResult::Ok)
+            })
+            .unwrap();
+            names
+        };
+        let expected: Vec<PathBuf> = vec!["foo.c".into(), "lib".into(), "lib/bar.c".into()];
+        assert_eq!(expected, names("services/legacy"));
+        assert_eq!(expected, names("./services/legacy/"));
+        assert_eq!(vec![PathBuf::from("foo.c")], names("services/legacy/foo.c"));
+        assert!(names("services/missing").is_empty());
+
+        let repo = Repository::open(git.path(), 0)
+            .unwrap()
+            .with_prefix(Some("/"));
+        assert_eq!(None, repo.prefix());
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut git = crate::test_support::GitBuilder::new();
+        let first = git
+            .file("same.txt", "same\n")
+            .file("dir/changed.txt", "old\n")
+            .file("gone.txt", "gone\n")
+            .file("script.sh", "#!/bin/sh\n")
+            .file("kind", "file\n")
+            .commit("first");
+        let second = git
+            .file("dir/changed.txt", "new\n")
+            .remove("gone.txt")
+            .executable("script.sh", "#!/bin/sh\n")
+            .file("dir/added.txt", "added\n")
+            .remove("kind")
+            .file("kind/nested.txt", "nested\n")
+            .commit("second");
+
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let first = repo.commit(&first).unwrap();
+        let second = repo.commit(&second).unwrap();
+        let summary = |changes: Vec<Change>| {
+            changes
+                .into_iter()
+                .map(|change| {
+                    let mode = |entry: &Option<TreeEntry>| entry.as_ref().map(TreeEntry::filemode);
+                    (
+                        change.path.to_string_lossy().into_owned(),
+                        mode(&change.old),
+                        mode(&change.new),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec![
+                ("dir/added.txt".to_string(), None, Some(0o100644)),
+                (
+                    "dir/changed.txt".to_string(),
+                    Some(0o100644),
+                    Some(0o100644)
+                ),
+                ("gone.txt".to_string(), Some(0o100644), None),
+                ("kind".to_string(), Some(0o100644), None),
+                ("kind/nested.txt".to_string(), None, Some(0o100644)),
+                ("script.sh".to_string(), Some(0o100644), Some(0o100755)),
+            ],
+            summary(repo.diff(Some(&first), &second).unwrap())
+        );
+
+        let changes = repo.diff(None, &first).unwrap();
+        assert_eq!(5, changes.len());
+        assert!(changes.iter().all(|change| change.old.is_none()));
+        let changed = changes
+            .iter()
+            .find(|change| change.path == Path::new("dir/changed.txt"))
+            .unwrap()
+            .new
+            .as_ref()
+            .unwrap();
+        assert_eq!(b"changed.txt", changed.name_bytes());
+        assert_eq!(Some(ObjectType::Blob), changed.kind());
+        assert!(repo.diff(Some(&second), &second).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_line_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(dir.path()).unwrap();
+        let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+        let commit = |content: &[u8], parents: &[&git2::Commit]| {
+            let mut builder = raw.treebuilder(None).unwrap();
+            builder
+                .insert("a.txt", raw.blob(content).unwrap(), 0o100644)
+                .unwrap();
+            let tree = raw.find_tree(builder.write().unwrap()).unwrap();
+            raw.commit(None, &sig, &sig, "commit", &tree, parents)
+                .unwrap()
+        };
+        let first = commit(b"one\ntwo\n", &[]);
+        let second = commit(b"one\nthree\nfour\n", &[&raw.find_commit(first).unwrap()]);
+
+        let repo = Repository::open(dir.path(), 0).unwrap();
+        assert_eq!(
+            (2, 0),
+            repo.line_stats(&repo.commit(&first).unwrap()).unwrap()
+        );
+        assert_eq!(
+            (2, 1),
+            repo.line_stats(&repo.commit(&second).unwrap()).unwrap()
+        );
     }
 }
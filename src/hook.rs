@@ -0,0 +1,341 @@
+use std::{
+    io::{self, BufRead},
+    time::Duration,
+};
+
+use anyhow::Context;
+use git2::Oid;
+use structopt::StructOpt;
+
+use crate::{
+    attributes, budget,
+    database::Database,
+    git,
+    lock::RunLock,
+    push::{self, Mode, Outcome, PushOpt},
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    #[structopt(flatten)]
+    push: PushOpt,
+
+    #[structopt(
+        long,
+        default_value = "10m",
+        parse(try_from_str = budget::parse_duration),
+        help = "how long to wait for another run to release the database"
+    )]
+    lock_timeout: Duration,
+}
+
+/// A ref update, as given to a post-receive hook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Update {
+    pub old: Oid,
+    pub new: Oid,
+    pub refname: String,
+}
+
+impl Opt {
+    pub fn push(&self) -> &PushOpt {
+        &self.push
+    }
+
+    pub fn push_mut(&mut self) -> &mut PushOpt {
+        &mut self.push
+    }
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    if opt.push.watch.is_some() {
+        anyhow::bail!("--watch cannot be used with hook");
+    }
+
+    let updates = parse_updates(io::stdin().lock())?;
+    export_updates(opt.push, opt.lock_timeout, &updates)
+}
+
+/// Parses the `<old> <new> <refname>` lines git gives a post-receive hook.
+pub fn parse_updates<R: BufRead>(input: R) -> anyhow::Result<Vec<Update>> {
+    let mut updates = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            anyhow::bail!("line {}: expected <old> <new> <refname>", i + 1);
+        }
+        let parse = |sha: &str| {
+            Oid::from_str(sha).with_context(|| format!("line {}: invalid object {}", i + 1, sha))
+        };
+        updates.push(Update {
+            old: parse(fields[0])?,
+            new: parse(fields[1])?,
+            refname: fields[2].to_string(),
+        });
+    }
+
+    Ok(updates)
+}
+
+/// Exports each updated branch that's being pushed to CVS. The branches given
+/// with -b are exported; without any, the branches that have been exported
+/// before are. A ref that fails is reported without stopping the others, and
+/// nothing is logged below a warning unless something goes wrong.
+pub fn export_updates(
+    mut push: PushOpt,
+    lock_timeout: Duration,
+    updates: &[Update],
+) -> anyhow::Result<()> {
+    push.quiet = true;
+    let _lock = RunLock::acquire_waiting(&push.database, lock_timeout)?;
+
+    let mut exported = 0;
+    let mut failed = 0;
+    for update in updates {
+        let branch = match update.refname.strip_prefix("refs/heads/") {
+            Some(branch) => branch,
+            None => continue,
+        };
+        if !is_configured(&push, branch)? {
+            log::debug!("ignoring update to unconfigured branch {}", branch);
+            continue;
+        }
+        if update.new.is_zero() {
+            log::info!("not exporting deleted branch {}", branch);
+            continue;
+        }
+
+        exported += 1;
+        if let Err(e) = export_update(&mut push, branch, update) {
+            log::error!("cannot export {}: {:#}", update.refname, e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} of {} refs failed", failed, exported);
+    }
+    Ok(())
+}
+
+/// Returns whether updates to the branch should be exported.
+fn is_configured(push: &PushOpt, branch: &str) -> anyhow::Result<bool> {
+    if !push.branches.is_empty() {
+        return Ok(push.branches.iter().any(|configured| {
+            configured == branch
+                || (git::is_glob(configured)
+                    && attributes::wildmatch(configured.as_bytes(), branch.as_bytes()))
+        }));
+    }
+
+    let db = Database::open(&push.database)?;
+    Ok(db.get_cvs_branch(branch)?.is_some())
+}
+
+fn export_update(push: &mut PushOpt, branch: &str, update: &Update) -> anyhow::Result<()> {
+    // CVS history can't be rewritten, so only new commits on top of what was
+    // there before can be exported.
+    if !update.old.is_zero() {
+        let repo = git2::Repository::discover(&push.git)?;
+        if repo.merge_base(update.old, update.new)? != update.old {
+            anyhow::bail!("{} is not a fast-forward from {}", update.new, update.old);
+        }
+    }
+
+    // The ref may have moved on by the time this runs, but it's the update
+    // we were told about that's exported.
+    push.branch = Some(branch.to_string());
+    push.tip = Some(update.new);
+    match push::export(push, Mode::New)? {
+        Outcome::Finished => Ok(()),
+        Outcome::Stopped => anyhow::bail!(
+            "stopped before every commit was exported; push with --resume to continue"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_support::{entries_cvs, fake_push_opt, GitBuilder};
+
+    #[test]
+    fn test_parse_updates() {
+        let old = "0000000000000000000000000000000000000000";
+        let new = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        let input = format!(
+            "{old} {new} refs/heads/main\n\n{new} {old} refs/tags/v1\n",
+            old = old,
+            new = new
+        );
+
+        assert_eq!(
+            vec![
+                Update {
+                    old: Oid::zero(),
+                    new: Oid::from_str(new).unwrap(),
+                    refname: "refs/heads/main".into(),
+                },
+                Update {
+                    old: Oid::from_str(new).unwrap(),
+                    new: Oid::zero(),
+                    refname: "refs/tags/v1".into(),
+                },
+            ],
+            parse_updates(input.as_bytes()).unwrap()
+        );
+
+        assert!(parse_updates(&b"abc refs/heads/main\n"[..]).is_err());
+        assert!(parse_updates(&b"old new refs/heads/main\n"[..]).is_err());
+    }
+
+    fn update(old: Oid, new: Oid, refname: &str) -> Update {
+        Update {
+            old,
+            new,
+            refname: refname.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_export_updates() {
+        let mut git = GitBuilder::new();
+        let first = git.file("a.c", "a\n").commit("first");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+
+        let opt = fake_push_opt(git.path(), dir.path(), &["-b", &branch, "-b", "other"]);
+        export_updates(
+            opt,
+            Duration::ZERO,
+            &[
+                update(Oid::zero(), first, &format!("refs/heads/{}", branch)),
+                update(Oid::zero(), first, "refs/heads/unconfigured"),
+                update(Oid::zero(), first, "refs/tags/v1"),
+            ],
+        )
+        .unwrap();
+
+        // A rewritten branch fails on its own, and doesn't stop the next
+        // update from being exported, which only goes up to the commit it's
+        // updated to even though the branch has moved on since. Deleting a
+        // branch leaves CVS alone, and doesn't count towards the refs tried.
+        let second = git.file("b.c", "b\n").commit("second");
+        git.file("c.c", "c\n").commit("third");
+        let opt = fake_push_opt(git.path(), dir.path(), &["-b", &branch, "-b", "other"]);
+        let message = format!(
+            "{:#}",
+            export_updates(
+                opt,
+                Duration::ZERO,
+                &[
+                    update(second, first, "refs/heads/other"),
+                    update(first, Oid::zero(), "refs/heads/other"),
+                    update(first, second, &format!("refs/heads/{}", branch)),
+                ],
+            )
+            .unwrap_err()
+        );
+        assert_eq!("1 of 2 refs failed", message);
+
+        assert_eq!(
+            "add src\n\
+             add src/a.c\n\
+             commit first\n\
+             add src/b.c\n\
+             commit second\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_export_update_not_fast_forward() {
+        let mut git = GitBuilder::new();
+        let first = git.file("a.c", "a\n").commit("first");
+        let second = git.file("b.c", "b\n").commit("second");
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+
+        let mut opt = fake_push_opt(git.path(), dir.path(), &[]);
+        let message = format!(
+            "{:#}",
+            export_update(
+                &mut opt,
+                "other",
+                &update(second, first, "refs/heads/other")
+            )
+            .unwrap_err()
+        );
+        assert_eq!(
+            format!("{} is not a fast-forward from {}", first, second),
+            message
+        );
+        assert!(!dir.path().join("log").exists());
+    }
+
+    #[test]
+    fn test_is_configured() {
+        let git = GitBuilder::new();
+        let dir = tempfile::tempdir().unwrap();
+
+        let opt = fake_push_opt(git.path(), dir.path(), &["-b", "main", "-b", "release/*"]);
+        assert!(is_configured(&opt, "main").unwrap());
+        assert!(is_configured(&opt, "release/1.0").unwrap());
+        assert!(!is_configured(&opt, "mainline").unwrap());
+        assert!(!is_configured(&opt, "feature").unwrap());
+    }
+
+    #[test]
+    fn test_export_updates_configured_by_database() {
+        let mut git = GitBuilder::new();
+        let first = git.file("a.c", "a\n").commit("first");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+        let opt = fake_push_opt(git.path(), dir.path(), &["-b", &branch]);
+        export_updates(
+            opt,
+            Duration::ZERO,
+            &[update(
+                Oid::zero(),
+                first,
+                &format!("refs/heads/{}", branch),
+            )],
+        )
+        .unwrap();
+
+        // Without -b, only the branch that's been exported before is.
+        let second = git.file("b.c", "b\n").commit("second");
+        git.branch("other");
+        let opt = fake_push_opt(git.path(), dir.path(), &[]);
+        export_updates(
+            opt,
+            Duration::ZERO,
+            &[
+                update(first, second, &format!("refs/heads/{}", branch)),
+                update(Oid::zero(), second, "refs/heads/other"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            "add src\n\
+             add src/a.c\n\
+             commit first\n\
+             add src/b.c\n\
+             commit second\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert!(db.get_cvs_branch(&branch).unwrap().is_some());
+        assert_eq!(None, db.get_cvs_branch("other").unwrap());
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    ffi::OsString,
+    path::{Component, Path},
+};
+
+use structopt::StructOpt;
+
+use crate::{cvs, database::Database, lock::RunLock};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub cvsroot: Option<cvs::CvsRoot>,
+
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(long, default_value = "cvs", help = "cvs binary to use")]
+    cvs: OsString,
+
+    #[structopt(short, long, help = "create an empty module to export into")]
+    module: Option<String>,
+
+    #[structopt(long, help = "prepare a CVSROOT that already exists")]
+    force: bool,
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    let _lock = RunLock::acquire(&opt.database)?;
+    let db = Database::open(&opt.database)?;
+    init(
+        &cvs::Context::new(&opt.cvs),
+        &db,
+        opt.cvsroot.as_ref().expect("parse_args sets the CVSROOT"),
+        opt.module.as_deref(),
+        opt.force,
+    )
+}
+
+/// Runs `cvs init` on a local CVSROOT, creates the module if there is one,
+/// and records them both in the database.
+fn init(
+    ctx: &cvs::Context,
+    db: &Database,
+    cvsroot: &cvs::CvsRoot,
+    module: Option<&str>,
+    force: bool,
+) -> anyhow::Result<()> {
+    if !cvsroot.is_local() {
+        anyhow::bail!("{} is not a local CVSROOT", cvsroot);
+    }
+    if let Some(module) = module {
+        check_module(module)?;
+    }
+
+    let root = Path::new(cvsroot.path());
+    if root.join("CVSROOT").is_dir() && !force {
+        anyhow::bail!(
+            "{} is already a CVS repository; pass --force to prepare it anyway",
+            root.display()
+        );
+    }
+
+    log::info!("initialising {}", cvsroot);
+    ctx.init(cvsroot)?;
+
+    if let Some(module) = module {
+        if root.join(module).is_dir() {
+            log::info!("module {} already exists", module);
+        } else {
+            log::info!("creating module {}", module);
+            ctx.import_module(cvsroot, module)?;
+        }
+    }
+
+    if !db.record_cvsroot(&cvsroot.to_string(), module)? {
+        log::debug!("{} was already recorded", cvsroot);
+    }
+    Ok(())
+}
+
+/// Checks that a module names a directory within the repository, other than
+/// the administrative one.
+fn check_module(module: &str) -> anyhow::Result<()> {
+    let path = Path::new(module);
+    if module.is_empty()
+        || !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        anyhow::bail!("module {} is not a path within the repository", module);
+    }
+    if path.starts_with("CVSROOT") {
+        anyhow::bail!("module {} is within CVSROOT", module);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::*;
+
+    #[test]
+    fn test_init() {
+        let dir = tempfile::tempdir().unwrap();
+        let cvs = dir.path().join("cvs");
+        let log = dir.path().join("log");
+        fs::write(
+            &cvs,
+            format!(
+                "#!/bin/sh\n\
+                 echo \"$3 $6\" >> {log}\n\
+                 [ \"$3\" = init ] && mkdir -p \"$2/CVSROOT\"\n\
+                 exit 0\n",
+                log = log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let ctx = cvs::Context::new(cvs.as_os_str());
+        let db = Database::open(":memory:").unwrap();
+        let root = dir.path().join("root");
+        let cvsroot: cvs::CvsRoot = root.to_str().unwrap().parse().unwrap();
+
+        init(&ctx, &db, &cvsroot, Some("project"), false).unwrap();
+        assert_eq!("init \nimport project\n", fs::read_to_string(&log).unwrap());
+
+        let message = format!("{:#}", init(&ctx, &db, &cvsroot, None, false).unwrap_err());
+        assert!(message.contains("pass --force"), "{}", message);
+
+        fs::create_dir(root.join("project")).unwrap();
+        init(&ctx, &db, &cvsroot, Some("project"), true).unwrap();
+        assert_eq!(
+            "init \nimport project\ninit \n",
+            fs::read_to_string(&log).unwrap()
+        );
+
+        let remote: cvs::CvsRoot = ":pserver:anon@example.com:/cvs".parse().unwrap();
+        assert!(init(&ctx, &db, &remote, None, false).is_err());
+    }
+
+    #[test]
+    fn test_check_module() {
+        check_module("project").unwrap();
+        check_module("projects/foo").unwrap();
+        assert!(check_module("").is_err());
+        assert!(check_module("../elsewhere").is_err());
+        assert!(check_module("/project").is_err());
+        assert!(check_module("CVSROOT").is_err());
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use git2::Oid;
+use structopt::StructOpt;
+
+use crate::{
+    attributes::Glob,
+    authors::AuthorMap,
+    cvs,
+    database::Database,
+    detect::Detector,
+    git::{self, Commit, Repository},
+    json::Value,
+    output::{self, Format},
+    pathmap::PathMap,
+    push::{self, Walker},
+    state,
+    template::Template,
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    #[structopt(short, long, help = "the branch being exported")]
+    branch: String,
+
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(short, long, default_value = ".", help = "git repository")]
+    git: OsString,
+
+    #[structopt(
+        long,
+        help = "the commit to inspect; defaults to the next commit to be exported"
+    )]
+    commit: Option<Oid>,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "src",
+        help = "the target directory within the cvs checkout; can be . to use the top level"
+    )]
+    target: PathBuf,
+
+    #[structopt(long, help = "the paths the branch is exported with")]
+    include: Vec<PathBuf>,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        parse(try_from_str = state::parse_exclude),
+        help = "the globs the branch is exported without"
+    )]
+    exclude: Vec<Glob>,
+
+    #[structopt(
+        long,
+        help = "the directory of the git repository the branch is exported from"
+    )]
+    prefix: Option<PathBuf>,
+
+    #[structopt(long, help = "transcode UTF-16 text files to UTF-8")]
+    transcode_utf16: bool,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "treat files matching this glob as binary"
+    )]
+    binary_glob: Vec<Glob>,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "treat files matching this glob as text"
+    )]
+    text_glob: Vec<Glob>,
+
+    #[structopt(
+        long,
+        help = "export the trees of submodules as ordinary files under their paths"
+    )]
+    recurse_submodules: bool,
+
+    #[structopt(
+        long,
+        default_value = "skip",
+        possible_values = git::Symlinks::VARIANTS,
+        help = "what to do with symlinks, which CVS can't store"
+    )]
+    symlinks: git::Symlinks,
+
+    #[structopt(
+        long,
+        default_value = "skip",
+        possible_values = git::SubmodulePolicy::VARIANTS,
+        help = "what to do with submodules without --recurse-submodules"
+    )]
+    submodules: git::SubmodulePolicy,
+
+    #[structopt(long, help = "append the git author to the CVS commit message")]
+    annotate_authors: bool,
+
+    #[structopt(
+        long,
+        help = "use the commit author as it is, rather than as .mailmap has it"
+    )]
+    no_mailmap: bool,
+
+    #[structopt(long, help = "template for the CVS commit message")]
+    message_template: Option<Template>,
+
+    #[structopt(
+        long,
+        help = "leave the Git-Commit trailer out of the CVS commit message"
+    )]
+    no_sha_trailer: bool,
+
+    #[structopt(
+        long,
+        help = "author file to use for authors that aren't mapped in the database"
+    )]
+    authors: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        default_value = "table",
+        possible_values = Format::VARIANTS,
+        help = "output format"
+    )]
+    format: Format,
+}
+
+/// Something exporting a commit does to the CVS working copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operation {
+    /// A directory that has to be added before the files in it.
+    AddDirectory(PathBuf),
+    Add {
+        path: PathBuf,
+        oid: Oid,
+        binary: bool,
+    },
+    Write {
+        path: PathBuf,
+        old: Oid,
+        new: Oid,
+    },
+    Remove {
+        path: PathBuf,
+        old: Oid,
+    },
+}
+
+impl Operation {
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::AddDirectory(_) => "add directory",
+            Operation::Add { .. } => "add",
+            Operation::Write { .. } => "write",
+            Operation::Remove { .. } => "remove",
+        }
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Operation::AddDirectory(path)
+            | Operation::Add { path, .. }
+            | Operation::Write { path, .. }
+            | Operation::Remove { path, .. } => path,
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            Operation::AddDirectory(_) => String::new(),
+            Operation::Add { oid, binary, .. } => {
+                format!("{} {}", oid, if *binary { "binary" } else { "text" })
+            }
+            Operation::Write { old, new, .. } => format!("{} -> {}", old, new),
+            Operation::Remove { old, .. } => old.to_string(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut fields = vec![
+            ("operation", self.name().into()),
+            ("path", self.path().to_string_lossy().into_owned().into()),
+        ];
+        match self {
+            Operation::AddDirectory(_) => {}
+            Operation::Add { oid, binary, .. } => {
+                fields.push(("new", oid.to_string().into()));
+                fields.push(("binary", (*binary).into()));
+            }
+            Operation::Write { old, new, .. } => {
+                fields.push(("old", old.to_string().into()));
+                fields.push(("new", new.to_string().into()));
+            }
+            Operation::Remove { old, .. } => fields.push(("old", old.to_string().into())),
+        }
+        Value::object(fields)
+    }
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    let db = Database::open_readonly(&opt.database)?;
+    if db.get_cvs_branch(&opt.branch)?.is_none() {
+        anyhow::bail!("branch {} has not been exported", opt.branch);
+    }
+    let repo = Repository::discover(&opt.git, 0)?
+        .with_prefix(opt.prefix.as_ref())
+        .with_mailmap(!opt.no_mailmap);
+
+    let oid = match opt.commit {
+        Some(oid) => oid,
+        None => match db.unexported_commits(&opt.branch)?.first() {
+            Some(oid) => *oid,
+            None => anyhow::bail!("branch {} has no commits left to export", opt.branch),
+        },
+    };
+    let commit = repo.commit(&oid)?;
+    let last = match db.last_exported_commit(&opt.branch)? {
+        Some(last) => Some(repo.commit(&last)?),
+        None => None,
+    };
+
+    let detector =
+        Detector::new(opt.transcode_utf16).with_globs(&opt.binary_glob, &opt.text_glob)?;
+    let submodules = if opt.recurse_submodules {
+        Some(git::Submodules::new(&repo))
+    } else {
+        None
+    };
+    let walker = Walker {
+        repo: &repo,
+        detector: &detector,
+        submodules: submodules.as_ref(),
+        skip_broken_submodules: true,
+        status: None,
+        symlinks: opt.symlinks,
+        submodule_policy: opt.submodules,
+    };
+    let operations = operations(
+        last.as_deref(),
+        &commit,
+        &walker,
+        &opt.target,
+        state::Filter::new(&opt.include).with_excludes(&opt.exclude),
+        &push::exported_path_map(&db, &opt.branch)?,
+    )?;
+
+    let authors = AuthorMap::load(&db, opt.authors.as_deref())?;
+    let (message, _) = push::commit_message(
+        &commit,
+        &db,
+        &authors,
+        opt.annotate_authors,
+        opt.message_template
+            .as_ref()
+            .map(|template| (template, opt.branch.as_str())),
+    )?;
+    // The trailer is added as the commit is made, so it's added the same way
+    // here.
+    let message = if opt.no_sha_trailer {
+        message
+    } else {
+        cvs::with_git_commit(&message, &oid)
+    };
+    let message = String::from_utf8_lossy(&message);
+    let skipped = db
+        .skipped_commits()?
+        .iter()
+        .any(|skipped| skipped.oid == oid);
+
+    match opt.format {
+        Format::Table => {
+            println!("commit {}", oid);
+            match &last {
+                Some(last) => println!("relative to {}", last.id()),
+                None => println!("relative to an empty module"),
+            }
+            if skipped {
+                println!("skipped: its changes will be exported with the next commit");
+            }
+            println!();
+            if operations.is_empty() {
+                println!("nothing to do");
+            } else {
+                print!(
+                    "{}",
+                    output::table(
+                        &["operation", "path", "detail"],
+                        &operations
+                            .iter()
+                            .map(|operation| vec![
+                                operation.name().to_string(),
+                                operation.path().display().to_string(),
+                                operation.detail(),
+                            ])
+                            .collect::<Vec<_>>(),
+                    )
+                );
+            }
+            println!();
+            for line in message.lines() {
+                println!("    {}", line);
+            }
+        }
+        Format::Json => println!(
+            "{}",
+            Value::object(vec![
+                ("commit", oid.to_string().into()),
+                ("relative_to", last.map(|last| last.id().to_string()).into()),
+                ("skipped", skipped.into()),
+                (
+                    "operations",
+                    Value::Array(operations.iter().map(Operation::to_json).collect()),
+                ),
+                ("message", message.into_owned().into()),
+            ])
+        ),
+    }
+
+    Ok(())
+}
+
+/// Works out what exporting a commit on top of the last exported commit does
+/// to the working copy, without touching it: directories first, then adds,
+/// writes, and removes, each in path order.
+fn operations(
+    last: Option<&Commit>,
+    commit: &Commit,
+    walker: &Walker,
+    target: &Path,
+    filter: state::Filter,
+    path_map: &PathMap,
+) -> anyhow::Result<Vec<Operation>> {
+    // The states are never written out, so the base doesn't matter.
+    let seed = |commit: Option<&Commit>| -> anyhow::Result<state::Global> {
+        let mut state =
+            state::Global::new("", target, filter.clone()).with_path_map(path_map.clone());
+        if let Some(commit) = commit {
+            push::seed_state(commit, &mut state, walker)?;
+        }
+        Ok(state)
+    };
+    let before = seed(last)?;
+    let after = seed(Some(commit))?;
+
+    let before_files: HashMap<_, _> = before
+        .iter_files()
+        .map(|(file, oid)| (file.cvs_relative_path(), *oid))
+        .collect();
+    let mut directories: BTreeSet<PathBuf> = before_files
+        .keys()
+        .flat_map(|path| path.ancestors().skip(1))
+        .map(Path::to_path_buf)
+        .collect();
+    if last.is_some() {
+        directories.extend(target.ancestors().map(Path::to_path_buf));
+    }
+    directories.insert(PathBuf::new());
+    directories.insert(PathBuf::from("."));
+
+    let mut new_directories = BTreeSet::new();
+    let mut changes = Vec::new();
+    let mut seen = BTreeSet::new();
+    for (file, oid) in after.iter_files() {
+        let path = file.cvs_relative_path();
+        match before_files.get(&path) {
+            Some(old) if old == oid => {}
+            Some(old) => changes.push(Operation::Write {
+                path: path.clone(),
+                old: *old,
+                new: *oid,
+            }),
+            None => {
+                for ancestor in path.ancestors().skip(1) {
+                    if !directories.contains(ancestor) {
+                        new_directories.insert(ancestor.to_path_buf());
+                    }
+                }
+                let blob = walker.repo.blob(oid)?;
+                let binary = walker
+                    .detector
+                    .detect(
+                        walker.repo,
+                        Some((&commit.tree_id(), Path::new(""))),
+                        file.git_path(),
+                        &file.cvs_relative_path(),
+                        &blob,
+                    )?
+                    .binary;
+                changes.push(Operation::Add {
+                    path: path.clone(),
+                    oid: *oid,
+                    binary,
+                });
+            }
+        }
+        seen.insert(path);
+    }
+    for (path, old) in before_files.iter() {
+        if !seen.contains(path) {
+            changes.push(Operation::Remove {
+                path: path.clone(),
+                old: *old,
+            });
+        }
+    }
+
+    let order = |operation: &Operation| match operation {
+        Operation::AddDirectory(_) => 0,
+        Operation::Add { .. } => 1,
+        Operation::Write { .. } => 2,
+        Operation::Remove { .. } => 3,
+    };
+    changes.sort_by(|a, b| (order(a), a.path()).cmp(&(order(b), b.path())));
+
+    Ok(new_directories
+        .into_iter()
+        .map(Operation::AddDirectory)
+        .chain(changes)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::GitBuilder;
+
+    #[test]
+    fn test_operations() {
+        let mut git = GitBuilder::new();
+        let first = git
+            .file("README", "readme\n")
+            .file("lib/a.c", "a\n")
+            .file("docs/old.txt", "old\n")
+            .commit("first");
+        let second = git
+            .file("README", "readme 2\n")
+            .file("lib/deep/b.c", "b\n")
+            .binary("logo.png", "png")
+            .remove("docs/old.txt")
+            .commit("second");
+
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let detector = Detector::default();
+        let walker = Walker {
+            repo: &repo,
+            detector: &detector,
+            submodules: None,
+            skip_broken_submodules: true,
+            status: None,
+            symlinks: git::Symlinks::Skip,
+            submodule_policy: git::SubmodulePolicy::Skip,
+        };
+        let first = repo.commit(&first).unwrap();
+        let second = repo.commit(&second).unwrap();
+        let blob = |commit: &Commit, path: &str| {
+            let mut found = None;
+            repo.walk_tree(&commit.tree_id(), |dir, entry| -> anyhow::Result<_> {
+                if dir.join(entry.file_name()?) == Path::new(path) {
+                    found = Some(entry.id());
+                }
+                Ok(git2::TreeWalkResult::Ok)
+            })
+            .unwrap();
+            found.unwrap()
+        };
+
+        assert_eq!(
+            vec![
+                Operation::AddDirectory("src/lib/deep".into()),
+                Operation::Add {
+                    path: "src/lib/deep/b.c".into(),
+                    oid: blob(&second, "lib/deep/b.c"),
+                    binary: false,
+                },
+                Operation::Add {
+                    path: "src/logo.png".into(),
+                    oid: blob(&second, "logo.png"),
+                    binary: true,
+                },
+                Operation::Write {
+                    path: "src/README".into(),
+                    old: blob(&first, "README"),
+                    new: blob(&second, "README"),
+                },
+                Operation::Remove {
+                    path: "src/docs/old.txt".into(),
+                    old: blob(&first, "docs/old.txt"),
+                },
+            ],
+            operations(
+                Some(&first),
+                &second,
+                &walker,
+                Path::new("src"),
+                state::Filter::default(),
+                &PathMap::default(),
+            )
+            .unwrap()
+        );
+
+        // The first commit on a branch also adds the target, and the filter
+        // is honoured.
+        let operations = operations(
+            None,
+            &first,
+            &walker,
+            Path::new("src"),
+            state::Filter::new(["lib"]),
+            &PathMap::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                Operation::AddDirectory("src".into()),
+                Operation::AddDirectory("src/lib".into()),
+                Operation::Add {
+                    path: "src/lib/a.c".into(),
+                    oid: blob(&first, "lib/a.c"),
+                    binary: false,
+                },
+            ],
+            operations
+        );
+    }
+}
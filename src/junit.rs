@@ -0,0 +1,225 @@
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tempfile::NamedTempFile;
+
+use crate::events::{Event, Listener, Progress};
+
+/// Collects the outcome of each commit in a push, and writes them as a JUnit
+/// XML report when dropped.
+pub struct Report {
+    path: PathBuf,
+    branch: String,
+    cases: Vec<Case>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Case {
+    name: String,
+    duration: Duration,
+    outcome: Outcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Outcome {
+    Passed,
+    Skipped(String),
+    Failed { message: String, detail: String },
+}
+
+impl Report {
+    pub fn new<P: AsRef<Path>>(path: P, branch: &str) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            branch: branch.to_string(),
+            cases: Vec::new(),
+        }
+    }
+
+    fn render(&self) -> String {
+        let count =
+            |f: fn(&Outcome) -> bool| self.cases.iter().filter(|case| f(&case.outcome)).count();
+        let total: Duration = self.cases.iter().map(|case| case.duration).sum();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\" time=\"{:.3}\">",
+            escape(&self.branch),
+            self.cases.len(),
+            count(|outcome| matches!(outcome, Outcome::Failed { .. })),
+            count(|outcome| matches!(outcome, Outcome::Skipped(_))),
+            total.as_secs_f64()
+        );
+
+        for case in self.cases.iter() {
+            let _ = write!(
+                xml,
+                "    <testcase classname=\"git2cvs.{}\" name=\"{}\" time=\"{:.3}\"",
+                escape(&self.branch),
+                escape(&case.name),
+                case.duration.as_secs_f64()
+            );
+            match &case.outcome {
+                Outcome::Passed => xml.push_str("/>\n"),
+                Outcome::Skipped(reason) => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n      <skipped message=\"{}\"/>\n    </testcase>",
+                        escape(reason)
+                    );
+                }
+                Outcome::Failed { message, detail } => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n      <failure type=\"export\" message=\"{}\">{}</failure>\n    </testcase>",
+                        escape(message),
+                        escape(detail)
+                    );
+                }
+            }
+        }
+
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+
+    /// Writes the report to a temporary file alongside the destination, then
+    /// renames it into place.
+    fn write(&self) -> anyhow::Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = NamedTempFile::new_in(dir)?;
+        file.write_all(self.render().as_bytes())?;
+        file.persist(&self.path)?;
+
+        Ok(())
+    }
+}
+
+impl Listener for Report {
+    fn event(&mut self, progress: Progress, event: &Event<'_>) {
+        let commit = event.commit();
+        let (duration, outcome) = match event {
+            Event::Exported { stats, .. } => (stats.duration, Outcome::Passed),
+            Event::Skipped { reason, .. } => {
+                (Duration::default(), Outcome::Skipped(reason.to_string()))
+            }
+            Event::Failed {
+                error, duration, ..
+            } => (
+                *duration,
+                Outcome::Failed {
+                    message: error.to_string(),
+                    detail: format!("{:?}", error),
+                },
+            ),
+        };
+
+        self.cases.push(Case {
+            name: format!("{} {}", progress.index, &commit.id().to_string()[..8]),
+            duration,
+            outcome,
+        });
+    }
+}
+
+impl Drop for Report {
+    fn drop(&mut self) {
+        if let Err(e) = self.write() {
+            log::error!(
+                "cannot write JUnit report to {}: {:#}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Escapes text for use in XML attributes and content, dropping characters
+/// that XML 1.0 doesn't allow at all.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' || c == '\u{fffe}' || c == '\u{ffff}' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            "a &lt;b&gt; &amp; &quot;c&quot; &apos;d&apos;\tbell",
+            escape("a <b> & \"c\" 'd'\t\u{7}bell")
+        );
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.xml");
+
+        let mut report = Report::new(&path, "main");
+        report.cases = vec![
+            Case {
+                name: "1 1111111a".into(),
+                duration: Duration::from_millis(1500),
+                outcome: Outcome::Passed,
+            },
+            Case {
+                name: "2 2222222b".into(),
+                duration: Duration::default(),
+                outcome: Outcome::Skipped("dataset".into()),
+            },
+            Case {
+                name: "3 3333333c".into(),
+                duration: Duration::from_millis(250),
+                outcome: Outcome::Failed {
+                    message: "cvs commit failed".into(),
+                    detail: "cvs commit failed\n\nCaused by:\n    <stderr>".into(),
+                },
+            },
+        ];
+        drop(report);
+
+        assert_eq!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites>
+  <testsuite name="main" tests="3" failures="1" errors="0" skipped="1" time="1.750">
+    <testcase classname="git2cvs.main" name="1 1111111a" time="1.500"/>
+    <testcase classname="git2cvs.main" name="2 2222222b" time="0.000">
+      <skipped message="dataset"/>
+    </testcase>
+    <testcase classname="git2cvs.main" name="3 3333333c" time="0.250">
+      <failure type="export" message="cvs commit failed">cvs commit failed
+
+Caused by:
+    &lt;stderr&gt;</failure>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#,
+            std::fs::read_to_string(&path).unwrap()
+        );
+        assert_eq!(1, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+}
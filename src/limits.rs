@@ -0,0 +1,142 @@
+use std::{os::unix::ffi::OsStrExt, path::Path};
+
+/// The most offending paths to list in an error.
+const SAMPLE: usize = 10;
+
+/// What CVS adds to a path when it stores a file that's been removed.
+const ATTIC: &str = "Attic/";
+
+/// What RCS adds to the name of each archive file.
+const RCS_SUFFIX: &str = ",v";
+
+/// Limits on the paths we ask the CVS server to create, so that a path the
+/// server's filesystem can't hold is caught before we get to cvs commit, which
+/// doesn't say which file was the problem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathLimits {
+    /// The longest path component, in bytes.
+    pub component: usize,
+    /// The longest path within the module, in bytes, allowing for the file
+    /// ending up in the Attic.
+    pub length: Option<usize>,
+    /// The most components a path within the module can have.
+    pub depth: Option<usize>,
+}
+
+impl PathLimits {
+    /// Describes how a path within the module breaks the limits, if it does.
+    pub fn violation(&self, path: &Path) -> Option<String> {
+        if let Some(component) = path
+            .components()
+            .map(|component| component.as_os_str().as_bytes().len())
+            .find(|len| *len > self.component)
+        {
+            return Some(format!("has a component of {} bytes", component));
+        }
+
+        let length = path.as_os_str().as_bytes().len() + ATTIC.len() + RCS_SUFFIX.len();
+        if matches!(self.length, Some(limit) if length > limit) {
+            return Some(format!("is {} bytes long in the Attic", length));
+        }
+
+        let depth = path.components().count();
+        if matches!(self.depth, Some(limit) if depth > limit) {
+            return Some(format!("is {} levels deep", depth));
+        }
+
+        None
+    }
+
+    /// Fails if any of the given paths break the limits, naming them.
+    pub fn check<I, P>(&self, paths: I) -> anyhow::Result<()>
+    where
+        I: Iterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let violations: Vec<_> = paths
+            .filter_map(|path| {
+                self.violation(path.as_ref())
+                    .map(|violation| format!("{} {}", path.as_ref().display(), violation))
+            })
+            .collect();
+        if violations.is_empty() {
+            return Ok(());
+        }
+
+        anyhow::bail!(
+            "{} paths are too long for CVS (a --path-map rule can move them): {}{}",
+            violations.len(),
+            violations
+                .iter()
+                .take(SAMPLE)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join("; "),
+            if violations.len() > SAMPLE {
+                "; ..."
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_violation() {
+        let limits = PathLimits {
+            component: 10,
+            length: Some(30),
+            depth: Some(3),
+        };
+
+        assert_eq!(None, limits.violation(Path::new("src/0123456789")));
+        assert_eq!(
+            Some("has a component of 11 bytes".to_string()),
+            limits.violation(Path::new("src/0123456789a/b"))
+        );
+        // 22 bytes, plus the Attic and ,v.
+        assert_eq!(None, limits.violation(Path::new("src/abcdefgh/ijklmnopq")));
+        assert_eq!(
+            Some("is 31 bytes long in the Attic".to_string()),
+            limits.violation(Path::new("src/abcdefgh/ijklmnopqr"))
+        );
+        assert_eq!(
+            Some("is 4 levels deep".to_string()),
+            limits.violation(Path::new("src/a/b/c"))
+        );
+
+        let unlimited = PathLimits {
+            component: 250,
+            length: None,
+            depth: None,
+        };
+        let deep: PathBuf = (0..100).map(|_| "a".repeat(200)).collect();
+        assert_eq!(None, unlimited.violation(&deep));
+        assert!(unlimited
+            .violation(&Path::new("src").join("a".repeat(251)))
+            .is_some());
+    }
+
+    #[test]
+    fn test_check() {
+        let limits = PathLimits {
+            component: 3,
+            length: None,
+            depth: None,
+        };
+        assert!(limits.check(["a/b", "abc"].iter()).is_ok());
+
+        let paths: Vec<_> = (0..12).map(|i| format!("long{}", i)).collect();
+        let message = format!("{:#}", limits.check(paths.iter()).unwrap_err());
+        assert!(message
+            .starts_with("12 paths are too long for CVS (a --path-map rule can move them): long0 has a component of 5 bytes; long1"));
+        assert!(message.ends_with("long9 has a component of 5 bytes; ..."));
+        assert!(!message.contains("long10"));
+    }
+}
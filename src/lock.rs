@@ -0,0 +1,206 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+/// How often a run waiting for the lock checks whether it's been released.
+const WAIT_POLL: Duration = Duration::from_millis(200);
+
+/// An exclusive lock on a metadata database, held for the duration of a run
+/// that modifies CVS.
+///
+/// The lock is an flock on a file alongside the database, which also contains
+/// the PID of the holder. The file is removed when the lock is dropped, and
+/// the kernel releases the lock if git2cvs is killed, so a file left behind by
+/// a crash doesn't stop the next run.
+#[derive(Debug)]
+pub struct RunLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl RunLock {
+    pub fn acquire<P: AsRef<Path>>(database: P) -> anyhow::Result<Self> {
+        let path = lock_path(database.as_ref());
+        match Self::try_acquire(path.clone())? {
+            Some(lock) => Ok(lock),
+            None => anyhow::bail!("database is locked by {}", holder(&path)),
+        }
+    }
+
+    /// Acquires the lock, waiting up to the timeout for another run to
+    /// release it first.
+    pub fn acquire_waiting<P: AsRef<Path>>(database: P, timeout: Duration) -> anyhow::Result<Self> {
+        let path = lock_path(database.as_ref());
+        let deadline = Instant::now() + timeout;
+        let mut waiting = false;
+        loop {
+            if let Some(lock) = Self::try_acquire(path.clone())? {
+                return Ok(lock);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                anyhow::bail!(
+                    "timed out waiting for {} to release the database",
+                    holder(&path)
+                );
+            }
+            if !waiting {
+                log::info!("waiting for another run to release {}", path.display());
+                waiting = true;
+            }
+            thread::sleep(WAIT_POLL.min(deadline - now));
+        }
+    }
+
+    fn try_acquire(path: PathBuf) -> anyhow::Result<Option<Self>> {
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .with_context(|| format!("cannot create {}", path.display()))?;
+            if !try_lock(&file).with_context(|| format!("cannot lock {}", path.display()))? {
+                return Ok(None);
+            }
+
+            // The previous holder removes the file before releasing the lock,
+            // so if we locked a file that's no longer at the path, we have to
+            // start again with whatever is there now.
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.ino() == file.metadata()?.ino() => {}
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("cannot stat {}", path.display())),
+            }
+
+            file.set_len(0)?;
+            writeln!(file, "{}", std::process::id())?;
+
+            return Ok(Some(Self { path, _file: file }));
+        }
+    }
+
+    pub fn is_held<P: AsRef<Path>>(database: P) -> bool {
+        // Taking the lock and letting it go straight away is the only way to
+        // tell a live holder from a file left behind by one that died.
+        match File::open(lock_path(database.as_ref())) {
+            Ok(file) => !matches!(try_lock(&file), Ok(true)),
+            Err(_) => false,
+        }
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        // The file goes before the lock is released when it's closed, so that
+        // nobody can take a lock on a file that's about to disappear.
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!("cannot remove lock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Tries to take an exclusive lock on the file without blocking, returning
+/// false if another process holds it. The lock is released when the file is
+/// closed.
+fn try_lock(file: &File) -> io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// Describes the holder of a lock for error messages.
+fn holder(path: &Path) -> String {
+    let mut pid = String::new();
+    let read = File::open(path).and_then(|mut file| file.read_to_string(&mut pid));
+    match (read, pid.trim()) {
+        (Ok(_), pid) if !pid.is_empty() => format!("another run (PID {})", pid),
+        _ => "another run".into(),
+    }
+}
+
+fn lock_path(database: &Path) -> PathBuf {
+    let mut path = database.as_os_str().to_owned();
+    path.push(".lock");
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("git2cvs.db");
+
+        assert!(!RunLock::is_held(&database));
+        let lock = RunLock::acquire(&database).unwrap();
+        assert!(RunLock::is_held(&database));
+        assert!(RunLock::acquire(&database).is_err());
+
+        drop(lock);
+        assert!(!RunLock::is_held(&database));
+        RunLock::acquire(&database).unwrap();
+    }
+
+    #[test]
+    fn test_stale_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("git2cvs.db");
+
+        // A lock file left behind by a run that died isn't locked, so it
+        // doesn't get in the way.
+        fs::write(lock_path(&database), "99999999\n").unwrap();
+        assert!(!RunLock::is_held(&database));
+        let lock = RunLock::acquire(&database).unwrap();
+        assert!(RunLock::is_held(&database));
+        assert_eq!(
+            format!("{}\n", std::process::id()),
+            fs::read_to_string(lock_path(&database)).unwrap()
+        );
+
+        let err = RunLock::acquire(&database).unwrap_err();
+        assert_eq!(
+            format!(
+                "database is locked by another run (PID {})",
+                std::process::id()
+            ),
+            err.to_string()
+        );
+        drop(lock);
+        assert!(!lock_path(&database).exists());
+    }
+
+    #[test]
+    fn test_acquire_waiting() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("git2cvs.db");
+
+        let lock = RunLock::acquire(&database).unwrap();
+        assert!(RunLock::acquire_waiting(&database, Duration::ZERO).is_err());
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(lock);
+        });
+        RunLock::acquire_waiting(&database, Duration::from_secs(60)).unwrap();
+        releaser.join().unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+use std::ffi::OsString;
+
+use git2::Oid;
+use structopt::StructOpt;
+
+use crate::{
+    cvs,
+    database::Database,
+    git::Repository,
+    json::Value,
+    output::{self, Format},
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(
+        short,
+        long,
+        default_value = ".",
+        help = "git repository, to resolve abbreviated commits"
+    )]
+    git: OsString,
+
+    #[structopt(
+        long,
+        default_value = "table",
+        possible_values = Format::VARIANTS,
+        help = "output format"
+    )]
+    format: Format,
+
+    #[structopt(subcommand)]
+    query: Query,
+}
+
+#[derive(Debug, StructOpt)]
+enum Query {
+    /// Finds the git branch and latest exported commit for a CVS branch tag.
+    Tag { name: String },
+
+    /// Finds the git commit that created a CVS file revision.
+    Revision { path: String, revision: String },
+
+    /// Finds the CVS branches that a git commit was exported to.
+    Commit { oid: String },
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    let db = Database::open_readonly(&opt.database)?;
+
+    let (headers, rows) = match &opt.query {
+        Query::Tag { name } => {
+            let tag = match db.lookup_tag(name)? {
+                Some(tag) => tag,
+                None => match cvs::desanitise_branch(name) {
+                    Ok(git_branch) if git_branch != *name => anyhow::bail!(
+                        "CVS tag {} is not recorded in the database; it would be the tag for git branch {}",
+                        name,
+                        git_branch
+                    ),
+                    _ => anyhow::bail!("CVS tag {} is not recorded in the database", name),
+                },
+            };
+
+            (
+                vec!["git branch", "cvs branch", "last exported"],
+                vec![vec![
+                    tag.git_branch.into(),
+                    tag.cvs_branch.into(),
+                    tag.last_exported.into(),
+                ]],
+            )
+        }
+        Query::Revision { path, revision } => {
+            let record = match db.lookup_revision(path, revision)? {
+                Some(record) => record,
+                None if db.has_unrecorded_revisions()? => anyhow::bail!(
+                    "revision {} of {} is not recorded; it may have been exported before git2cvs recorded file revisions",
+                    revision,
+                    path
+                ),
+                None => anyhow::bail!("revision {} of {} is not recorded", revision, path),
+            };
+
+            (
+                vec!["commit", "git branch", "author", "summary"],
+                vec![vec![
+                    record.oid.into(),
+                    record.git_branch.into(),
+                    format!("{} <{}>", record.author_name, record.author_email).into(),
+                    record.summary.into(),
+                ]],
+            )
+        }
+        Query::Commit { oid } => {
+            // A full SHA doesn't need the repository, so a commit that's
+            // since been rewritten away can still be looked up.
+            let resolved = match Oid::from_str(oid) {
+                Ok(resolved) if oid.len() == 40 => resolved,
+                _ => Repository::discover(&opt.git, 0)?.resolve_commit(oid)?,
+            };
+            let records = db.lookup_commit(&resolved)?;
+            if records.is_empty() {
+                anyhow::bail!("commit {} is not recorded in the database", oid);
+            }
+
+            (
+                vec!["git branch", "cvs branch", "index", "exported"],
+                records
+                    .into_iter()
+                    .map(|record| {
+                        vec![
+                            record.git_branch.into(),
+                            record.cvs_branch.into(),
+                            record.index.into(),
+                            record.exported.into(),
+                        ]
+                    })
+                    .collect(),
+            )
+        }
+    };
+
+    match opt.format {
+        Format::Table => print!("{}", table(&headers, &rows)),
+        Format::Json => println!("{}", json(&headers, rows)),
+    }
+
+    Ok(())
+}
+
+fn table(headers: &[&str], rows: &[Vec<Value>]) -> String {
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|value| match value {
+                    Value::Null => String::new(),
+                    Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                })
+                .collect()
+        })
+        .collect();
+
+    output::table(headers, &rows)
+}
+
+fn json(headers: &[&str], rows: Vec<Vec<Value>>) -> Value {
+    Value::Array(
+        rows.into_iter()
+            .map(|row| {
+                Value::object(
+                    headers
+                        .iter()
+                        .map(|header| header.replace(' ', "_"))
+                        .zip(row),
+                )
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::GitBuilder;
+
+    #[test]
+    fn test_short_sha() {
+        let mut git = GitBuilder::new();
+        let oid = git.file("a.txt", "a\n").commit("first");
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("git2cvs.db");
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let mut db = Database::open(&database).unwrap();
+        db.write_branch("main", "main", std::iter::once(repo.commit(&oid).unwrap()))
+            .unwrap();
+        drop(db);
+
+        let lookup = |oid: &str| {
+            let mut opt =
+                Opt::from_iter_safe(["lookup", "-g", git.path().to_str().unwrap(), "commit", oid])
+                    .unwrap();
+            opt.database = database.clone().into();
+            run(opt)
+        };
+        lookup(&oid.to_string()[..7]).unwrap();
+        lookup(&oid.to_string()).unwrap();
+        assert!(lookup("0000000").is_err());
+    }
+}
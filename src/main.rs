@@ -1,233 +1,500 @@
 #[macro_use]
 extern crate lazy_static;
 
-use std::{
-    ffi::OsString,
-    fs::{self, Permissions},
-    os::unix::prelude::PermissionsExt,
-    path::PathBuf,
-};
-
-use database::Database;
-use filetime::FileTime;
-use git::Repository;
-use git2::{Commit, ObjectType, TreeEntry, TreeWalkResult};
+use std::ffi::OsString;
+
+use push::{Outcome, PushOpt};
 use structopt::StructOpt;
-use tempfile::tempdir;
 
+mod adopt;
+mod attributes;
+mod authors;
+mod batch;
+mod budget;
+mod bundle;
+mod cache;
+mod config;
+mod console;
 mod cvs;
+mod cvswrappers;
 mod database;
+mod detect;
+mod error;
+mod events;
 mod git;
+mod hook;
+mod init;
+mod inspect;
+mod json;
+mod junit;
+mod limits;
+mod lock;
+mod lookup;
+mod maintain;
+mod messages;
+mod output;
+mod pathmap;
+mod permissions;
+mod progress;
+mod push;
+mod reconcile;
+mod report;
+mod skips;
 mod state;
+mod status;
+mod sync;
+mod template;
+#[cfg(test)]
+mod test_support;
+mod undo;
+mod verify;
+mod watch;
+
+// The options every command shares are global, so that they can be given
+// before the command as well as after it.
+#[derive(Debug, StructOpt)]
+struct Args {
+    #[structopt(short, long, global = true, env = "CVSROOT", help = "CVSROOT")]
+    cvsroot: Option<OsString>,
+
+    #[structopt(short, long, global = true, help = "metadata database")]
+    database: Option<OsString>,
+
+    #[structopt(subcommand)]
+    command: Opt,
+}
 
 #[derive(Debug, StructOpt)]
-struct Opt {
-    #[structopt(short, long, help = "the branch to push")]
-    branch: String,
-
-    #[structopt(long, default_value = "cvs", help = "cvs binary to use")]
-    cvs: OsString,
-
-    #[structopt(short, long, env = "CVSROOT", help = "CVSROOT")]
-    cvsroot: OsString,
-
-    #[structopt(short, long, help = "metadata database")]
-    database: OsString,
-
-    #[structopt(short, long, help = "git repository")]
-    git: OsString,
-
-    #[structopt(
-        short,
-        long,
-        default_value = ".",
-        help = "cvs module to check out, if any"
-    )]
-    module: String,
-
-    #[structopt(short, long, help = "use a remote branch")]
-    remote: bool,
-
-    #[structopt(
-        short,
-        long,
-        default_value = "src",
-        help = "the target directory within the cvs checkout; can be . to write at the top level"
-    )]
-    target: OsString,
+enum Opt {
+    /// Pushes a git branch into CVS. This is the default command.
+    Push(PushOpt),
+
+    /// Creates a local CVSROOT, and optionally a module in it, to push into.
+    Init(init::Opt),
+
+    /// Re-exports commits on a branch that failed with retryable errors.
+    RetryFailures(PushOpt),
+
+    /// Summarises the exported branches.
+    Status(status::Opt),
+
+    /// Reports statistics on exported commits.
+    Report(report::Opt),
+
+    /// Maps CVS tags and file revisions back to git commits, and vice versa.
+    Lookup(lookup::Opt),
+
+    /// Records an existing CVS export of a branch in the database.
+    Adopt(adopt::Opt),
+
+    /// Checks, prunes, and compacts the database.
+    DbMaintain(maintain::Opt),
+
+    /// Manages the mapping of git authors to CVS users.
+    Author(authors::Opt),
+
+    /// Manages replacement CVS log messages for individual commits.
+    Message(messages::Opt),
+
+    /// Finds recorded branches that no longer exist in git, and archives,
+    /// deletes, or renames them.
+    SyncBranches(sync::Opt),
+
+    /// Manages the commits that are left out of every export.
+    Skip(skips::Opt),
+
+    /// Reverts the last exported commit on a branch in CVS, so that it can be
+    /// exported again.
+    UndoLast(undo::Opt),
+
+    /// Runs the pushes described by a job file one after another, sharing a
+    /// database.
+    Batch(batch::Opt),
+
+    /// Shows what exporting a commit would do to CVS, without doing it.
+    Inspect(inspect::Opt),
+
+    /// Checks that a fresh CVS checkout matches an exported commit.
+    Verify(verify::Opt),
+
+    /// Exports the branches a git push updated, reading the input of a
+    /// post-receive hook.
+    Hook(hook::Opt),
 }
 
-fn main() -> anyhow::Result<()> {
-    env_logger::init();
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(exit_code(&err));
+    }
+}
 
-    let opt = Opt::from_args();
-    let cvs_ctx = cvs::Context::new(&opt.cvs);
-    let mut db = Database::open(&opt.database)?;
-    let repo = Repository::open(&opt.git)?;
+fn run() -> anyhow::Result<()> {
+    let args = config::apply(with_command(std::env::args_os().collect()))?;
+    let opt = parse_args(&args).unwrap_or_else(|err| err.exit());
 
-    let branch = match repo.branch(&opt.branch, opt.remote)? {
-        Some(branch) => branch,
-        None => anyhow::bail!("cannot find branch {}", &opt.branch),
+    // RUST_LOG still wins over -v, as it always has.
+    let verbose = match &opt {
+        Opt::Push(opt) | Opt::RetryFailures(opt) => opt.verbose,
+        Opt::Batch(opt) => opt.verbose,
+        Opt::Hook(opt) => opt.push().verbose,
+        _ => 0,
+    };
+    // Only the commands that export, and watch with them, report their
+    // progress by default; the rest only mention problems. That includes the
+    // hook, whose output goes back to whoever pushed.
+    let exporting = matches!(opt, Opt::Push(_) | Opt::RetryFailures(_) | Opt::Batch(_));
+    env_logger::Builder::new()
+        .filter_level(match verbose {
+            0 if exporting => log::LevelFilter::Info,
+            0 => log::LevelFilter::Warn,
+            1 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        })
+        .parse_default_env()
+        .init();
+
+    let push_opt = match &opt {
+        Opt::Push(opt) | Opt::RetryFailures(opt) => Some(opt),
+        Opt::Hook(opt) => Some(opt.push()),
+        _ => None,
     };
+    if let Some(opt) = push_opt.filter(|opt| opt.print_config) {
+        return config::print(&args, opt.config.as_deref());
+    }
 
-    if db.get_cvs_branch(&opt.branch)?.is_some() {
-        anyhow::bail!("TODO: support updating existing branches");
+    match opt {
+        Opt::Push(opt) => exit_for(push::run(opt, false)?),
+        Opt::RetryFailures(opt) => exit_for(push::run(opt, true)?),
+        Opt::Init(opt) => init::run(opt),
+        Opt::Status(opt) => status::run(opt),
+        Opt::Report(opt) => report::run(opt),
+        Opt::Lookup(opt) => lookup::run(opt),
+        Opt::Adopt(opt) => adopt::run(opt),
+        Opt::DbMaintain(opt) => maintain::run(opt),
+        Opt::Author(opt) => authors::run(opt),
+        Opt::Message(opt) => messages::run(opt),
+        Opt::SyncBranches(opt) => sync::run(opt),
+        Opt::Skip(opt) => skips::run(opt),
+        Opt::UndoLast(opt) => undo::run(opt),
+        Opt::Batch(opt) => exit_for(batch::run(opt)?),
+        Opt::Inspect(opt) => inspect::run(opt),
+        Opt::Hook(opt) => hook::run(opt),
+        Opt::Verify(opt) => verify::run(opt),
     }
-    let cvs_branch = cvs::sanitise_branch(&opt.branch);
-
-    let commits = branch.linear_history()?;
-    db.write_branch(&opt.branch, &cvs_branch, commits.iter())?;
-
-    let tempdir = tempdir()?;
-    let cvs_repo = cvs_ctx.checkout(&opt.cvsroot, &opt.module, tempdir.path())?;
-
-    // Ensure we have a target directory.
-    // TODO: handle an already existing directory.
-    let target: PathBuf = [tempdir.path().as_os_str(), &opt.target].iter().collect();
-    log::trace!("target: {:?}", &target);
-    fs::create_dir_all(&target)?;
-
-    let mut state = state::Global::new(tempdir.path(), &opt.target);
-
-    // We have to add the target directory to the CVS repository before we can
-    // do anything.
-    cvs_repo.add(&opt.target, false)?;
-
-    for (i, oid) in commits.iter().enumerate() {
-        let commit = repo.commit(oid)?;
-        let mut commit_state = state::Commit::new();
-
-        commit.tree()?.walk(
-            git2::TreeWalkMode::PreOrder,
-            |path, entry| match walk_tree_entry(
-                path,
-                entry,
-                &commit,
-                &mut state,
-                &mut commit_state,
-                &repo,
-            ) {
-                Ok(result) => result,
-                Err(e) => {
-                    log::error!(
-                        "error walking entry with path {} and {:?}: {:?}",
-                        path,
-                        entry.name(),
-                        e
-                    );
-                    TreeWalkResult::Abort
-                }
-            },
-        )?;
+}
 
-        // Remove files that have been removed.
-        cvs_repo.remove_multiple(
-            state
-                .remove_files_unseen_in_commit(&commit_state)
-                .into_iter()
-                .map(|file| {
-                    // Everyone loves a good hidden side effect, right?
-                    fs::remove_file(file.absolute_path()).unwrap();
-
-                    file.cvs_relative_path()
-                }),
-        )?;
-
-        // Add files that have been added.
-        cvs_repo.add_multiple(
-            commit_state
-                .iter_new_non_binary_files()
-                .map(|file| file.cvs_relative_path()),
-            false,
-        )?;
-        cvs_repo.add_multiple(
-            commit_state
-                .iter_new_binary_files()
-                .map(|file| file.cvs_relative_path()),
-            true,
-        )?;
-
-        // Actually commit.
-        cvs_repo.commit(commit.message_raw_bytes())?;
-
-        log::trace!("commit {}/{}: {}", i + 1, commits.len(), oid);
+/// Adds the push command to arguments that don't give one: git2cvs used to
+/// only push, so an invocation without a command is a push.
+fn with_command(args: Vec<OsString>) -> Vec<OsString> {
+    use structopt::clap::ErrorKind;
+
+    let err = match Args::from_iter_safe(&args) {
+        Ok(_) => return args,
+        Err(err)
+            if matches!(
+                err.kind,
+                ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed
+            ) =>
+        {
+            return args
+        }
+        Err(err) => err,
+    };
+    let mut push = args.clone();
+    push.insert(1.min(push.len()), "push".into());
+    match Args::from_iter_safe(&push) {
+        Ok(_) => push,
+        // Where neither parses, whichever got further has the more useful
+        // error.
+        Err(push_err) if failed_at(&push[1..], &push_err) > failed_at(&args, &err) => push,
+        Err(_) => args,
+    }
+}
+
+/// Returns how many arguments clap got through before the error, counting
+/// the program name, or all of them if the error isn't about one.
+fn failed_at(args: &[OsString], err: &structopt::clap::Error) -> usize {
+    err.info
+        .iter()
+        .flatten()
+        .filter_map(|info| args.iter().position(|arg| arg == info.as_str()))
+        .min()
+        .unwrap_or(args.len())
+}
+
+/// Parses the command line, handing the shared options to the command.
+fn parse_args<I>(args: I) -> Result<Opt, structopt::clap::Error>
+where
+    I: IntoIterator,
+    I::Item: Into<OsString> + Clone,
+{
+    use structopt::clap::{Error, ErrorKind};
+
+    let Args {
+        cvsroot,
+        database,
+        command: mut opt,
+    } = Args::from_iter_safe(args)?;
+    let required = |arg: &str| {
+        Error::with_description(
+            &format!(
+                "The following required arguments were not provided:\n    {}",
+                arg
+            ),
+            ErrorKind::MissingRequiredArgument,
+        )
+    };
+    let database = database.ok_or_else(|| required("--database <database>"))?;
+    let cvsroot = || -> Result<_, Error> {
+        let cvsroot = cvsroot
+            .as_ref()
+            .ok_or_else(|| required("--cvsroot <cvsroot>"))?;
+        let cvsroot = cvsroot.to_str().ok_or_else(|| {
+            Error::value_validation_auto(format!("CVSROOT {:?} isn't valid UTF-8", cvsroot))
+        })?;
+        match cvsroot.parse::<cvs::CvsRoot>() {
+            Ok(cvsroot) => Ok(Some(cvsroot)),
+            Err(err) => Err(Error::value_validation_auto(format!(
+                "Invalid value for '--cvsroot <cvsroot>': {}",
+                err
+            ))),
+        }
+    };
+
+    match &mut opt {
+        Opt::Push(opt) | Opt::RetryFailures(opt) => {
+            opt.database = database;
+            opt.cvsroot = cvsroot()?;
+        }
+        Opt::Hook(opt) => {
+            opt.push_mut().database = database;
+            opt.push_mut().cvsroot = cvsroot()?;
+        }
+        Opt::Init(opt) => {
+            opt.database = database;
+            opt.cvsroot = cvsroot()?;
+        }
+        Opt::UndoLast(opt) => {
+            opt.database = database;
+            opt.cvsroot = cvsroot()?;
+        }
+        Opt::Verify(opt) => {
+            opt.database = database;
+            opt.cvsroot = cvsroot()?;
+        }
+        Opt::Status(opt) => opt.database = database,
+        Opt::Report(opt) => opt.database = database,
+        Opt::Lookup(opt) => opt.database = database,
+        Opt::Adopt(opt) => opt.database = database,
+        Opt::DbMaintain(opt) => opt.database = database.into(),
+        Opt::Author(opt) => opt.database = database,
+        Opt::Message(opt) => opt.database = database,
+        Opt::SyncBranches(opt) => opt.database = database.into(),
+        Opt::Skip(opt) => opt.database = database,
+        Opt::Batch(opt) => opt.database = database,
+        Opt::Inspect(opt) => opt.database = database,
     }
 
-    Ok(())
+    Ok(opt)
+}
+
+/// The exit status used when a push stops before exporting every commit; push
+/// with --resume to continue.
+const EXIT_RESUMABLE: i32 = 75;
+
+/// Exit statuses for failures that a wrapper script might handle differently,
+/// taken from sysexits.h. Everything else exits with status 1.
+const EXIT_USAGE: i32 = 64;
+const EXIT_DATA: i32 = 65;
+const EXIT_NO_INPUT: i32 = 66;
+const EXIT_UNAVAILABLE: i32 = 69;
+const EXIT_IO: i32 = 74;
+
+/// Picks the exit status for an error from the first cause that git2cvs
+/// recognises.
+fn exit_code(err: &anyhow::Error) -> i32 {
+    use error::Error;
+
+    match err.chain().find_map(|cause| cause.downcast_ref::<Error>()) {
+        Some(Error::BranchNotFound { .. } | Error::RepositoryNotFound { .. }) => EXIT_NO_INPUT,
+        Some(Error::CvsCommandFailed { .. } | Error::CvsNotRun(_)) => EXIT_UNAVAILABLE,
+        Some(
+            Error::InvalidCvsRoot { .. }
+            | Error::InvalidTag { .. }
+            | Error::UnknownValue { .. }
+            | Error::InvalidAllowedExit { .. },
+        ) => EXIT_USAGE,
+        Some(
+            Error::PathConflict { .. }
+            | Error::MissingParent { .. }
+            | Error::ArgumentTooLong { .. }
+            | Error::CheckoutMismatch { .. }
+            | Error::StickyTagMismatch { .. },
+        ) => EXIT_DATA,
+        // Another run had the database, so trying again later may work.
+        Some(e) if e.is_database_busy() => EXIT_RESUMABLE,
+        Some(
+            Error::Database(_)
+            | Error::Migration(_)
+            | Error::DatabaseOpen { .. }
+            | Error::NotADatabase { .. },
+        ) => EXIT_IO,
+        _ => 1,
+    }
 }
 
-fn walk_tree_entry(
-    path: &str,
-    entry: &TreeEntry,
-    commit: &Commit,
-    state: &mut state::Global,
-    commit_state: &mut state::Commit,
-    repo: &Repository,
-) -> anyhow::Result<TreeWalkResult> {
-    let mut git_path = PathBuf::from(path);
-    if let Some(name) = entry.name() {
-        git_path.push(name);
+fn exit_for(outcome: Outcome) -> anyhow::Result<()> {
+    match outcome {
+        Outcome::Finished => Ok(()),
+        Outcome::Stopped => std::process::exit(EXIT_RESUMABLE),
     }
-    let file = state.file(git_path);
-    let absolute = file.absolute_path();
-
-    match entry.kind() {
-        Some(ObjectType::Blob) => {
-            let oid = entry.id();
-            let blob = repo.blob(&oid)?;
-
-            // Figure out if we need to write this: does the blob OID match the
-            // previously written OID for this file?
-            let maybe_oid = state.get_oid(&file);
-            match maybe_oid {
-                Some(last_oid) if &oid == last_oid => {
-                    // It does match, so we don't need to do anything.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        let not_found = || error::Error::BranchNotFound {
+            name: "main".into(),
+        };
+        assert_eq!(EXIT_NO_INPUT, exit_code(&not_found().into()));
+        assert_eq!(
+            EXIT_NO_INPUT,
+            exit_code(&anyhow::Error::from(not_found()).context("cannot push"))
+        );
+        assert_eq!(
+            EXIT_DATA,
+            exit_code(
+                &error::Error::PathConflict {
+                    paths: vec!["a".into(), "b".into()],
+                    target: "a".into(),
                 }
-                _ => {
-                    // We need to write the file, either because it doesn't
-                    // exist or has new content.
-                    fs::write(&absolute, blob.content())?;
-
-                    // CVS uses the modification time, so let's set
-                    // that.
-                    let time = FileTime::from_unix_time(commit.time().seconds(), 0);
-                    filetime::set_file_times(&absolute, time, time)?;
-
-                    // The file may be executable, so let's check.
-                    if (entry.filemode() & 0o111) != 0 {
-                        let perm = fs::metadata(&absolute)?.permissions().mode() | 0o111;
-
-                        fs::set_permissions(&absolute, Permissions::from_mode(perm))?;
-                    }
-
-                    // If it's a new file, we need to inform CVS.
-                    if maybe_oid.is_none() {
-                        commit_state.new_file(file.clone(), blob.is_binary());
-                    }
-
-                    // Finally, we'll store the OID that we just wrote to the
-                    // filesystem.
-                    state.save_oid(file.clone(), &oid);
+                .into()
+            )
+        );
+        assert_eq!(
+            EXIT_UNAVAILABLE,
+            exit_code(
+                &error::Error::CvsCommandFailed {
+                    command: "cvs commit".into(),
+                    status: subprocess::ExitStatus::Exited(1),
+                    output: Vec::new(),
+                    attempts: 1,
                 }
-            };
-
-            commit_state.seen_file(file);
-            Ok(TreeWalkResult::Ok)
-        }
-        Some(ObjectType::Tree) => {
-            if fs::metadata(&absolute).is_err() {
-                fs::create_dir_all(absolute)?;
+                .into()
+            )
+        );
+        assert_eq!(
+            EXIT_USAGE,
+            exit_code(
+                &anyhow::Error::from(cvs::check_tag_name("HEAD").unwrap_err())
+                    .context("invalid --cvs-branch")
+            )
+        );
+        assert_eq!(
+            EXIT_USAGE,
+            exit_code(&"cvs".parse::<cvs::CvsRoot>().unwrap_err().into())
+        );
+        let busy = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            None,
+        );
+        assert_eq!(EXIT_RESUMABLE, exit_code(&error::Error::from(busy).into()));
+        assert_eq!(
+            EXIT_IO,
+            exit_code(&error::Error::from(rusqlite::Error::QueryReturnedNoRows).into())
+        );
+        assert_eq!(1, exit_code(&anyhow::anyhow!("something else")));
+    }
 
-                // We do need to add the directory to the new file tracking for
-                // this commit, because it has to be included in "cvs add".
-                commit_state.new_file(file, false);
+    #[test]
+    fn test_parse_args() {
+        let database = |args: &[&str]| match parse_args(args).unwrap() {
+            Opt::Status(opt) => opt.database,
+            Opt::Push(opt) => {
+                assert_eq!(vec!["main".to_string()], opt.branches);
+                opt.database
             }
+            Opt::Hook(opt) => {
+                assert_eq!("/cvs", opt.push().cvsroot().path());
+                opt.push().database.clone()
+            }
+            _ => unreachable!(),
+        };
+
+        assert_eq!("db", database(&["git2cvs", "-d", "db", "status"]));
+        assert_eq!("db", database(&["git2cvs", "status", "-d", "db"]));
+        assert_eq!("db", database(&["git2cvs", "-ddb", "status"]));
+        assert_eq!(
+            "db",
+            database(&["git2cvs", "--cvsroot=/cvs", "-ddb", "hook", "-b", "main"])
+        );
+        assert_eq!(
+            "db",
+            database(&[
+                "git2cvs",
+                "hook",
+                "-c",
+                "/cvs",
+                "-b",
+                "main",
+                "--database=db"
+            ])
+        );
+
+        assert_eq!(
+            structopt::clap::ErrorKind::MissingRequiredArgument,
+            parse_args(&["git2cvs", "status"]).unwrap_err().kind
+        );
+    }
 
-            Ok(TreeWalkResult::Ok)
-        }
-        _ => {
-            log::trace!("unknown kind: {:?}", entry.kind());
-            Ok(TreeWalkResult::Skip)
-        }
+    #[test]
+    fn test_with_command() {
+        let with_command = |args: &[&str]| -> Vec<String> {
+            with_command(args.iter().map(OsString::from).collect())
+                .into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .collect()
+        };
+
+        // Commands are left alone, wherever the shared options are.
+        assert_eq!(
+            vec!["git2cvs", "-ddb", "status"],
+            with_command(&["git2cvs", "-ddb", "status"])
+        );
+        assert_eq!(
+            vec!["git2cvs", "--help"],
+            with_command(&["git2cvs", "--help"])
+        );
+
+        // Anything else is a push.
+        assert_eq!(vec!["git2cvs", "push"], with_command(&["git2cvs"]));
+        assert_eq!(
+            vec!["git2cvs", "push", "-ddb", "-b", "main"],
+            with_command(&["git2cvs", "-ddb", "-b", "main"])
+        );
+        let opt = match parse_args(with_command(&[
+            "git2cvs", "-ddb", "-c", "/cvs", "-b", "main",
+        ])) {
+            Ok(Opt::Push(opt)) => opt,
+            _ => unreachable!(),
+        };
+        assert_eq!("db", opt.database);
+        assert_eq!(vec!["main".to_string()], opt.branches);
+
+        // When neither parses, the error is from whichever got further: the
+        // command's for a typo in its options, but the push's for a typo
+        // after the push options.
+        assert_eq!(
+            vec!["git2cvs", "status", "--bogus"],
+            with_command(&["git2cvs", "status", "--bogus"])
+        );
+        assert_eq!(
+            vec!["git2cvs", "push", "-b", "main", "--bogus"],
+            with_command(&["git2cvs", "-b", "main", "--bogus"])
+        );
     }
 }
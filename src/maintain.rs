@@ -0,0 +1,195 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use structopt::StructOpt;
+
+use crate::{database::Database, lock::RunLock, output};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub database: PathBuf,
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    // We don't want to vacuum a database out from under a push, so we'll take
+    // the lock ourselves for the duration.
+    if RunLock::is_held(&opt.database) {
+        anyhow::bail!("refusing to maintain a database that is in use by another run");
+    }
+    let _lock = RunLock::acquire(&opt.database)?;
+
+    let backup = backup(&opt.database)?;
+    println!("backed up database to {}", backup.display());
+
+    let size_before = fs::metadata(&opt.database)?.len();
+    let mut db = Database::open(&opt.database)?;
+    let counts_before = db.row_counts()?;
+
+    let problems = db.integrity_check()?;
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        anyhow::bail!("integrity check failed; the backup has not been modified");
+    }
+
+    for (table, deleted) in db.prune_orphans()? {
+        if deleted > 0 {
+            println!("pruned {} orphaned rows from {}", deleted, table);
+        }
+    }
+    db.analyze()?;
+    db.vacuum()?;
+
+    let counts_after = db.row_counts()?;
+    drop(db);
+    let size_after = fs::metadata(&opt.database)?.len();
+
+    print!(
+        "{}",
+        changes(&counts_before, &counts_after, size_before, size_after)
+    );
+
+    Ok(())
+}
+
+/// Tabulates the row count of each table and the size of the file before and
+/// after maintenance.
+fn changes(
+    counts_before: &[(String, i64)],
+    counts_after: &[(String, i64)],
+    size_before: u64,
+    size_after: u64,
+) -> String {
+    let mut rows: Vec<Vec<String>> = counts_before
+        .iter()
+        .map(|(table, before)| {
+            let after = counts_after
+                .iter()
+                .find(|(name, _)| name == table)
+                .map(|(_, count)| *count)
+                .unwrap_or_default();
+            vec![table.clone(), before.to_string(), after.to_string()]
+        })
+        .collect();
+    rows.push(vec![
+        "(file size)".into(),
+        size_before.to_string(),
+        size_after.to_string(),
+    ]);
+    output::table(&["table", "before", "after"], &rows)
+}
+
+fn backup(database: &Path) -> anyhow::Result<PathBuf> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut path = database.as_os_str().to_owned();
+    path.push(format!(".{}.bak", now));
+    let path = PathBuf::from(path);
+
+    Database::backup(database, &path)
+        .with_context(|| format!("cannot back up database to {}", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a database with one mapped branch and rows left over from a
+    /// branch that isn't mapped any more.
+    fn seeded(dir: &Path) -> PathBuf {
+        let database = dir.join("git2cvs.db");
+        Database::open(&database).unwrap();
+        rusqlite::Connection::open(&database)
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO branch_mappings (git, cvs) VALUES ('main', 'main');
+                INSERT INTO commit_branches (oid, branch, branch_index) VALUES ('a', 'main', 0), ('b', 'gone', 0);
+                INSERT INTO commits (oid, author_name, author_email, time) VALUES ('a', '', '', 0), ('b', '', '', 0);
+                INSERT INTO exceptions (branch, oid, kind, reason, created_at) VALUES ('gone', 'b', 'failed', '', 0);",
+            )
+            .unwrap();
+        database
+    }
+
+    fn backups(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("bak".as_ref()))
+            .collect()
+    }
+
+    fn count(db: &Database, table: &str) -> i64 {
+        db.row_counts()
+            .unwrap()
+            .into_iter()
+            .find(|(name, _)| name == table)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn test_maintain() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = seeded(dir.path());
+
+        run(Opt {
+            database: database.clone(),
+        })
+        .unwrap();
+
+        let db = Database::open_readonly(&database).unwrap();
+        assert_eq!(1, count(&db, "commit_branches"));
+        assert_eq!(1, count(&db, "commits"));
+        assert_eq!(0, count(&db, "exceptions"));
+
+        // The backup is taken before anything is pruned.
+        let backups = backups(dir.path());
+        assert_eq!(1, backups.len());
+        let backup = Database::open_readonly(&backups[0]).unwrap();
+        assert!(backup.integrity_check().unwrap().is_empty());
+        assert_eq!(2, count(&backup, "commit_branches"));
+        assert_eq!(1, count(&backup, "exceptions"));
+
+        assert!(!RunLock::is_held(&database));
+    }
+
+    #[test]
+    fn test_refuses_while_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = seeded(dir.path());
+
+        let _lock = RunLock::acquire(&database).unwrap();
+        let err = run(Opt {
+            database: database.clone(),
+        })
+        .unwrap_err();
+        assert_eq!(
+            "refusing to maintain a database that is in use by another run",
+            err.to_string()
+        );
+        assert!(backups(dir.path()).is_empty());
+        let db = Database::open_readonly(&database).unwrap();
+        assert_eq!(1, count(&db, "exceptions"));
+    }
+
+    #[test]
+    fn test_changes() {
+        let before = vec![("commits".to_string(), 10), ("exceptions".to_string(), 2)];
+        let after = vec![("commits".to_string(), 7)];
+        assert_eq!(
+            "table        before  after\n\
+             commits      10      7\n\
+             exceptions   2       0\n\
+             (file size)  8192    4096\n",
+            changes(&before, &after, 8192, 4096)
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use structopt::StructOpt;
+
+use crate::{database::Database, git::Repository};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(
+        short,
+        long,
+        default_value = ".",
+        help = "git repository, to resolve abbreviated commits"
+    )]
+    git: OsString,
+
+    #[structopt(subcommand)]
+    action: Action,
+}
+
+#[derive(Debug, StructOpt)]
+enum Action {
+    /// Replaces the CVS log message of a commit.
+    Set {
+        oid: String,
+
+        #[structopt(
+            short,
+            long,
+            help = "read the message from this file instead of standard input"
+        )]
+        file: Option<PathBuf>,
+    },
+
+    /// Shows the replacement CVS log message of a commit.
+    Show { oid: String },
+
+    /// Removes the replacement CVS log message of a commit.
+    Remove { oid: String },
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    let db = match opt.action {
+        Action::Show { .. } => Database::open_readonly(&opt.database)?,
+        _ => Database::open(&opt.database)?,
+    };
+    let repo = Repository::discover(&opt.git, 0)?;
+
+    match opt.action {
+        Action::Set { oid, file } => {
+            let message = match file {
+                Some(file) => fs::read_to_string(&file)
+                    .with_context(|| format!("cannot read {}", file.display()))?,
+                None => {
+                    let mut message = String::new();
+                    io::stdin().read_to_string(&mut message)?;
+                    message
+                }
+            };
+            if message.trim().is_empty() {
+                anyhow::bail!("the replacement message is empty");
+            }
+
+            db.set_message_override(&repo.resolve_commit(&oid)?, &message)?;
+        }
+        Action::Show { oid } => match db.message_override(&repo.resolve_commit(&oid)?)? {
+            Some(message) => print!("{}", message),
+            None => anyhow::bail!("commit {} has no message override", oid),
+        },
+        Action::Remove { oid } => {
+            if !db.remove_message_override(&repo.resolve_commit(&oid)?)? {
+                anyhow::bail!("commit {} has no message override", oid);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::GitBuilder;
+
+    #[test]
+    fn test_short_sha() {
+        let mut git = GitBuilder::new();
+        let oid = git.file("a.txt", "a\n").commit("first");
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("git2cvs.db");
+        let message = dir.path().join("message");
+        fs::write(&message, "replaced\n").unwrap();
+        let short = &oid.to_string()[..7];
+        let run_message = |args: &[&str]| {
+            let mut argv = vec!["message", "-g", git.path().to_str().unwrap()];
+            argv.extend(args);
+            let mut opt = Opt::from_iter_safe(argv).unwrap();
+            opt.database = database.clone().into();
+            run(opt)
+        };
+
+        run_message(&["set", short, "--file", message.to_str().unwrap()]).unwrap();
+        let db = Database::open(&database).unwrap();
+        assert_eq!(
+            Some("replaced\n".to_string()),
+            db.message_override(&oid).unwrap()
+        );
+        drop(db);
+
+        run_message(&["remove", short]).unwrap();
+        assert!(run_message(&["show", short]).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use std::str::FromStr;
+
+/// Output formats for commands that report on the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+impl Format {
+    pub const VARIANTS: &'static [&'static str] = &["table", "json"];
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            _ => anyhow::bail!("unknown format {}", s),
+        }
+    }
+}
+
+/// Renders rows as a plain text table with left aligned columns.
+pub fn table<S: AsRef<str>>(headers: &[&str], rows: &[Vec<S>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.as_ref().chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    let mut push_row = |cells: &mut dyn Iterator<Item = &str>| {
+        let line: Vec<String> = cells
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    };
+
+    push_row(&mut headers.iter().copied());
+    for row in rows {
+        push_row(&mut row.iter().map(|cell| cell.as_ref()));
+    }
+
+    out
+}
+
+/// Formats a Unix timestamp as a UTC date.
+pub fn date(seconds: i64) -> String {
+    // Howard Hinnant's days_from_civil, in reverse.
+    let days = seconds.div_euclid(86400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        assert_eq!(
+            "a    bb\nccc  d\n",
+            table(&["a", "bb"], &[vec!["ccc", "d"]])
+        );
+    }
+
+    #[test]
+    fn test_date() {
+        assert_eq!("1970-01-01", date(0));
+        assert_eq!("1969-12-31", date(-1));
+        assert_eq!("2000-02-29", date(951_782_400));
+        assert_eq!("2020-09-13", date(1_600_000_000));
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+/// Rules that move git paths to different places in CVS. Each rule replaces a
+/// leading run of path components, and the longest matching rule wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathMap {
+    // Sorted by the number of components in from, longest first, then by
+    // from, so that the first match is the right one and equivalent maps
+    // compare equal.
+    rules: Vec<(PathBuf, PathBuf)>,
+}
+
+impl PathMap {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Adds a rule, replacing any rule for the same path.
+    pub fn insert(&mut self, from: PathBuf, to: PathBuf) {
+        self.rules.retain(|(other, _)| *other != from);
+        self.rules.push((from, to));
+        sort_rules(&mut self.rules);
+    }
+
+    /// Adds the rules of another map, which take precedence over this one's
+    /// for the same path.
+    pub fn extend(&mut self, other: &PathMap) {
+        for (from, to) in other.rules.iter() {
+            self.insert(from.clone(), to.clone());
+        }
+    }
+
+    /// Returns where a git path goes in CVS, relative to the target.
+    pub fn map<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        for (from, to) in self.rules.iter() {
+            if let Ok(rest) = path.strip_prefix(from) {
+                // Joining an empty path would leave a trailing slash, which
+                // can't be used as a file name.
+                if rest.as_os_str().is_empty() {
+                    return Cow::Owned(to.clone());
+                }
+                return Cow::Owned(to.join(rest));
+            }
+        }
+        Cow::Borrowed(path)
+    }
+
+    /// Finds two git paths that the map sends to the same place in CVS.
+    pub fn collision<'a, I>(&self, paths: I) -> Option<(&'a Path, &'a Path)>
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut seen: HashMap<Cow<'a, Path>, &'a Path> = HashMap::new();
+        for path in paths {
+            if let Some(other) = seen.insert(self.map(path), path) {
+                if other != path {
+                    let mut pair = [other, path];
+                    pair.sort();
+                    return Some((pair[0], pair[1]));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Splits a path into its normal components, rejecting anything that could
+/// leave the target.
+fn normalise(path: &str, line: usize) -> anyhow::Result<PathBuf> {
+    let mut normalised = PathBuf::new();
+    for component in Path::new(path.trim()).components() {
+        match component {
+            Component::Normal(name) => normalised.push(name),
+            Component::CurDir => {}
+            _ => anyhow::bail!("line {}: {:?} must be a relative path", line, path),
+        }
+    }
+    if normalised.as_os_str().is_empty() {
+        anyhow::bail!("line {}: a rule cannot map the top level", line);
+    }
+    Ok(normalised)
+}
+
+impl FromStr for PathMap {
+    type Err = anyhow::Error;
+
+    /// Parses lines of the form from=to. Blank lines and lines starting with
+    /// # are ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules: Vec<(PathBuf, PathBuf)> = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (from, to) = match line.split_once('=') {
+                Some(rule) => rule,
+                None => anyhow::bail!("line {}: expected from=to, not {:?}", i + 1, line),
+            };
+            let (from, to) = (normalise(from, i + 1)?, normalise(to, i + 1)?);
+            if let Some((_, existing)) = rules.iter().find(|(other, _)| *other == from) {
+                if *existing != to {
+                    anyhow::bail!(
+                        "line {}: {} is already mapped to {}",
+                        i + 1,
+                        from.display(),
+                        existing.display()
+                    );
+                }
+                continue;
+            }
+            rules.push((from, to));
+        }
+
+        sort_rules(&mut rules);
+        Ok(Self { rules })
+    }
+}
+
+fn sort_rules(rules: &mut [(PathBuf, PathBuf)]) {
+    rules.sort_by(|(a, _), (b, _)| {
+        b.components()
+            .count()
+            .cmp(&a.components().count())
+            .then_with(|| a.cmp(b))
+    });
+}
+
+/// Writes the rules one per line, in the form they're parsed from.
+impl fmt::Display for PathMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (from, to) in self.rules.iter() {
+            writeln!(f, "{}={}", from.display(), to.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map() {
+        let map: PathMap = "# Layout differences\n\
+            headers=include\n\
+            \n\
+            headers/internal/ = private\n\
+            ./docs/README.md=README\n"
+            .parse()
+            .unwrap();
+
+        let map_str = |path: &str| map.map(Path::new(path)).into_owned();
+        assert_eq!(PathBuf::from("include/a.h"), map_str("headers/a.h"));
+        assert_eq!(PathBuf::from("include"), map_str("headers"));
+        assert_eq!(
+            PathBuf::from("private/b.h"),
+            map_str("headers/internal/b.h")
+        );
+        assert_eq!(PathBuf::from("README"), map_str("docs/README.md"));
+        assert_eq!(PathBuf::from("headersx/a.h"), map_str("headersx/a.h"));
+        assert_eq!(PathBuf::from("src/a.c"), map_str("src/a.c"));
+
+        // The canonical form parses back to the same map.
+        assert_eq!(
+            "docs/README.md=README\nheaders/internal=private\nheaders=include\n",
+            map.to_string()
+        );
+        assert_eq!(map, map.to_string().parse().unwrap());
+
+        assert!("headers".parse::<PathMap>().is_err());
+        assert!("a=../b".parse::<PathMap>().is_err());
+        assert!("/a=b".parse::<PathMap>().is_err());
+        assert!(".=b".parse::<PathMap>().is_err());
+        assert!("a=b\na=c".parse::<PathMap>().is_err());
+        assert!("a=b\na/=b".parse::<PathMap>().is_ok());
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut map: PathMap = "headers=include\nsrc=lib".parse().unwrap();
+        map.extend(&"headers/Foo.h=include/Foo~1.h\nsrc=source".parse().unwrap());
+        assert_eq!(
+            "headers/Foo.h=include/Foo~1.h\nheaders=include\nsrc=source\n",
+            map.to_string()
+        );
+        assert_eq!(
+            Some("include/Foo~1.h"),
+            map.map(Path::new("headers/Foo.h")).to_str()
+        );
+    }
+
+    #[test]
+    fn test_collision() {
+        let map: PathMap = "headers=include".parse().unwrap();
+        let paths = [
+            Path::new("include/a.h"),
+            Path::new("headers/b.h"),
+            Path::new("headers/a.h"),
+        ];
+        assert_eq!(
+            Some((Path::new("headers/a.h"), Path::new("include/a.h"))),
+            map.collision(paths.iter().copied())
+        );
+        assert_eq!(None, map.collision(paths[..2].iter().copied()));
+        assert_eq!(None, PathMap::default().collision(paths.iter().copied()));
+    }
+}
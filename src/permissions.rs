@@ -0,0 +1,138 @@
+use std::{fs, os::unix::ffi::OsStrExt, path::Path};
+
+use filetime::FileTime;
+use git2::{ObjectType, Oid};
+
+use crate::{cvs, state};
+
+/// Builds the content of a permissions manifest: one line per executable file,
+/// giving its mode in octal and its path, sorted by path.
+pub fn manifest<'a, I>(executables: I) -> anyhow::Result<Vec<u8>>
+where
+    I: IntoIterator<Item = (&'a Path, u32)>,
+{
+    let mut executables: Vec<_> = executables.into_iter().collect();
+    executables.sort();
+
+    let mut content = Vec::new();
+    for (path, mode) in executables {
+        let path = path.as_os_str().as_bytes();
+        if path.contains(&b'\n') {
+            anyhow::bail!(
+                "cannot list {} in the permissions manifest",
+                crate::git::escape_bytes(path)
+            );
+        }
+
+        content.extend_from_slice(format!("{:o} ", mode).as_bytes());
+        content.extend_from_slice(path);
+        content.push(b'\n');
+    }
+
+    Ok(content)
+}
+
+/// Tracks a manifest left in the working copy by a previous run, so that it's
+/// only rewritten if it changes.
+pub fn seed(path: &Path, state: &mut state::Global) -> anyhow::Result<()> {
+    let file = state.file(path)?;
+    if let Ok(content) = fs::read(file.absolute_path()) {
+        let oid = Oid::hash_object(ObjectType::Blob, &content)?;
+        state.save_oid(file, &oid);
+    }
+
+    Ok(())
+}
+
+/// Regenerates the manifest at the given path from the executable files known
+/// after the commit, writing it to the working copy if it has changed.
+pub fn update(
+    path: &Path,
+    time: i64,
+    state: &mut state::Global,
+    commit_state: &mut state::Commit,
+) -> anyhow::Result<()> {
+    let file = state.file(path)?;
+    if commit_state.has_seen(&file) {
+        anyhow::bail!(
+            "{} is in the git tree, so it cannot be used as the permissions manifest",
+            path.display()
+        );
+    }
+
+    let content = manifest(state.iter_executable_files())?;
+    let oid = Oid::hash_object(ObjectType::Blob, &content)?;
+    let previous = state.get_oid(&file).copied();
+    if previous != Some(oid) {
+        let absolute = file.absolute_path();
+        fs::write(&absolute, &content)?;
+        let time = FileTime::from_unix_time(time, 0);
+        filetime::set_file_times(&absolute, time, time)?;
+
+        log::debug!(
+            "updating permissions manifest {:?}",
+            file.cvs_relative_path()
+        );
+        commit_state.written_file(file.clone(), content.len(), previous.is_none(), false);
+        if previous.is_none() {
+            commit_state.new_file(file.clone(), cvs::KeywordMode::Default);
+        }
+        state.save_oid(file.clone(), &oid);
+    }
+
+    commit_state.seen_file(file);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = state::Global::new(dir.path(), ".", state::Filter::default());
+        let manifest_path = Path::new(".permissions");
+
+        let mut commit_state = state::Commit::new();
+        state.set_mode(&state.file("z.sh").unwrap(), 0o100755);
+        state.set_mode(&state.file("bin/a").unwrap(), 0o100775);
+        state.set_mode(&state.file("README").unwrap(), 0o100644);
+        update(manifest_path, 0, &mut state, &mut commit_state).unwrap();
+        assert_eq!(
+            "775 bin/a\n755 z.sh\n",
+            fs::read_to_string(dir.path().join(".permissions")).unwrap()
+        );
+        assert_eq!(1, commit_state.files_added());
+        assert_eq!(1, commit_state.iter_new_files().count());
+
+        // The same set shouldn't be written again, and the manifest isn't
+        // removed by a walk of the whole tree.
+        let mut commit_state = state::Commit::new();
+        commit_state.walked_whole_tree();
+        update(manifest_path, 0, &mut state, &mut commit_state).unwrap();
+        assert_eq!(0, commit_state.iter_written_files().count());
+        assert!(state.remove_files(&commit_state).is_empty());
+
+        let mut commit_state = state::Commit::new();
+        state.set_mode(&state.file("z.sh").unwrap(), 0o100644);
+        state.set_mode(&state.file("bin/a").unwrap(), 0o100644);
+        update(manifest_path, 0, &mut state, &mut commit_state).unwrap();
+        assert_eq!(1, commit_state.files_modified());
+        assert_eq!(
+            "",
+            fs::read_to_string(dir.path().join(".permissions")).unwrap()
+        );
+
+        // A fresh state picks up the manifest that's already there.
+        let mut resumed = state::Global::new(dir.path(), ".", state::Filter::default());
+        seed(manifest_path, &mut resumed).unwrap();
+        let mut commit_state = state::Commit::new();
+        update(manifest_path, 0, &mut resumed, &mut commit_state).unwrap();
+        assert_eq!(0, commit_state.iter_written_files().count());
+
+        let mut commit_state = state::Commit::new();
+        commit_state.seen_file(state.file(".permissions").unwrap());
+        assert!(update(manifest_path, 0, &mut state, &mut commit_state).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    cell::RefCell,
+    io::{self, IsTerminal, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    events::{Event, Listener, Progress},
+    git::Commit,
+};
+
+/// How often the status line is redrawn on a terminal.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often progress is logged when standard error isn't a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The longest commit subject shown before it's truncated.
+const SUBJECT_WIDTH: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Off,
+    /// Redraw a line on standard error in place.
+    Terminal,
+    /// Log a line every so often.
+    Log,
+}
+
+/// Shows the commit being exported and how many files it has written so far.
+///
+/// On a terminal, this is a line at the bottom of standard error that's
+/// rewritten in place, and cleared before anything else is written there: as
+/// a listener, it has to come before any listener that writes lines.
+/// Otherwise, a log line is written every so often.
+pub struct StatusLine {
+    mode: Mode,
+    inner: RefCell<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    current: Option<Current>,
+    /// Whether the line is on the screen.
+    shown: bool,
+    last_update: Option<Instant>,
+    /// The time spent on finished commits, and how many there were.
+    elapsed: Duration,
+    finished: u32,
+}
+
+struct Current {
+    progress: Progress,
+    oid: String,
+    summary: String,
+    files: usize,
+    started: Instant,
+}
+
+impl StatusLine {
+    pub fn stderr(quiet: bool) -> Self {
+        let mode = if quiet || !log::log_enabled!(log::Level::Info) {
+            Mode::Off
+        } else if io::stderr().is_terminal() {
+            Mode::Terminal
+        } else {
+            Mode::Log
+        };
+
+        Self {
+            mode,
+            inner: RefCell::new(Inner::default()),
+        }
+    }
+
+    /// Starts showing a commit.
+    pub fn start(&self, progress: Progress, commit: &Commit) {
+        self.inner.borrow_mut().current = Some(Current {
+            progress,
+            oid: commit.id().to_string()[..8].to_string(),
+            summary: commit.summary(),
+            files: 0,
+            started: Instant::now(),
+        });
+        self.update(true);
+    }
+
+    /// Counts a file written for the current commit.
+    pub fn file_written(&self) {
+        if let Some(current) = &mut self.inner.borrow_mut().current {
+            current.files += 1;
+        }
+        self.update(false);
+    }
+
+    /// Takes the line off the screen until the next commit starts, so that
+    /// output from cvs doesn't end up on the end of it.
+    pub fn clear(&self) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.shown {
+            // Losing the status line isn't worth failing the push over.
+            let _ = write!(io::stderr().lock(), "\r\x1b[K");
+            inner.shown = false;
+        }
+    }
+
+    fn finish(&self) {
+        {
+            let mut inner = self.inner.borrow_mut();
+            if let Some(current) = inner.current.take() {
+                inner.elapsed += current.started.elapsed();
+                inner.finished += 1;
+            }
+        }
+        self.clear();
+    }
+
+    fn update(&self, force: bool) {
+        let mut inner = self.inner.borrow_mut();
+        let now = Instant::now();
+        let due = |interval| match inner.last_update {
+            Some(last) => now.duration_since(last) >= interval,
+            None => true,
+        };
+        let line = match self.mode {
+            Mode::Terminal if force || due(REDRAW_INTERVAL) => inner.render(now),
+            Mode::Log if due(LOG_INTERVAL) => inner.render(now),
+            _ => return,
+        };
+        let line = match line {
+            Some(line) => line,
+            None => return,
+        };
+
+        inner.last_update = Some(now);
+        if self.mode == Mode::Terminal {
+            let _ = write!(io::stderr().lock(), "\r\x1b[K{}", line);
+            inner.shown = true;
+        } else {
+            log::info!("{}", line);
+        }
+    }
+}
+
+impl Inner {
+    fn render(&self, now: Instant) -> Option<String> {
+        let current = self.current.as_ref()?;
+        let mut line = format!(
+            "[{}/{}] {} {}: {} files written",
+            current.progress.index,
+            current.progress.total,
+            current.oid,
+            truncate(&current.summary, SUBJECT_WIDTH),
+            current.files
+        );
+
+        // The commits left, including this one, are assumed to take as long
+        // as the ones so far did on average.
+        if self.finished > 0 {
+            let remaining = (current.progress.total + 1).saturating_sub(current.progress.index);
+            let estimate = (self.elapsed / self.finished) * remaining as u32;
+            let left = estimate.saturating_sub(now.duration_since(current.started));
+            line.push_str(&format!(", about {} left", format_duration(left)));
+        }
+
+        Some(line)
+    }
+}
+
+impl Listener for Rc<StatusLine> {
+    fn event(&mut self, _progress: Progress, _event: &Event<'_>) {
+        self.finish();
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, _) => format!("{}h{:02}m", h, m),
+    }
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        text.to_string()
+    } else {
+        let mut truncated: String = text.chars().take(width - 3).collect();
+        truncated.push_str("...");
+        truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let now = Instant::now();
+        let mut inner = Inner {
+            current: Some(Current {
+                progress: Progress { index: 3, total: 5 },
+                oid: "0123abcd".into(),
+                summary: "Make the frobnicator considerably faster on very large inputs".into(),
+                files: 7,
+                started: now,
+            }),
+            ..Inner::default()
+        };
+        assert_eq!(
+            Some("[3/5] 0123abcd Make the frobnicator considerably fas...: 7 files written".into()),
+            inner.render(now)
+        );
+
+        // Two commits took ten seconds, so the three that are left should
+        // take fifteen.
+        inner.elapsed = Duration::from_secs(10);
+        inner.finished = 2;
+        assert!(inner
+            .render(now)
+            .unwrap()
+            .ends_with("7 files written, about 15s left"));
+        assert!(inner
+            .render(now + Duration::from_secs(20))
+            .unwrap()
+            .ends_with(", about 0s left"));
+
+        inner.current = None;
+        assert_eq!(None, inner.render(now));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!("42s", format_duration(Duration::from_secs(42)));
+        assert_eq!("3m05s", format_duration(Duration::from_secs(185)));
+        assert_eq!("1h02m", format_duration(Duration::from_secs(3725)));
+    }
+}
@@ -0,0 +1,6296 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs::{self, Permissions},
+    io,
+    os::unix::prelude::{OsStrExt, PermissionsExt},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use filetime::FileTime;
+use git2::{ObjectType, TreeWalkResult};
+use structopt::StructOpt;
+use tempfile::{tempdir, TempDir};
+
+use crate::{
+    attributes,
+    authors::AuthorMap,
+    budget,
+    bundle::Bundle,
+    console, cvs, cvswrappers,
+    database::{self, Database, ExceptionKind, ExportStats},
+    detect, error,
+    events::{self, Event, Listener},
+    git::{self, Commit, Repository, TreeEntry},
+    junit, limits,
+    lock::RunLock,
+    pathmap::PathMap,
+    permissions, progress, reconcile, state, template, verify, watch,
+};
+
+#[derive(Debug, StructOpt)]
+pub struct PushOpt {
+    #[structopt(
+        short = "b",
+        long = "branch",
+        help = "the branch to push; defaults to the branch HEAD refers to. May be given more than once to push several branches in turn"
+    )]
+    pub branches: Vec<String>,
+
+    #[structopt(
+        long,
+        conflicts_with = "branches",
+        help = "push the history leading up to a revision, such as a tag, a commit, or origin/main~10, instead of a branch; the revision names it in the database"
+    )]
+    pub rev: Option<String>,
+
+    /// The branch being pushed.
+    #[structopt(skip)]
+    pub branch: Option<String>,
+
+    /// The commit to push the branch up to, if not its tip, such as the one a
+    /// hook is told the branch is being updated to.
+    #[structopt(skip)]
+    pub tip: Option<git2::Oid>,
+
+    #[structopt(long, default_value = "cvs", help = "cvs binary to use")]
+    pub cvs: OsString,
+
+    #[structopt(
+        long,
+        help = "treat this exit code from a cvs operation as success, as OPERATION=CODE: for example, commit=1"
+    )]
+    pub allow_cvs_exit: Vec<cvs::AllowedExit>,
+
+    #[structopt(
+        long,
+        default_value = "capture",
+        possible_values = cvs::Output::VARIANTS,
+        help = "capture cvs output into the log, or let it through to the terminal for interactive debugging"
+    )]
+    pub cvs_output: cvs::Output,
+
+    #[structopt(
+        long,
+        default_value = "3",
+        help = "how many times to retry a cvs add, commit or remove that fails because another client holds a lock"
+    )]
+    pub cvs_retries: u32,
+
+    #[structopt(
+        long,
+        default_value = "10s",
+        parse(try_from_str = budget::parse_duration),
+        help = "how long to wait before the first retry of a cvs command, such as 30s; the wait doubles for each retry after"
+    )]
+    pub cvs_retry_delay: Duration,
+
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub cvsroot: Option<cvs::CvsRoot>,
+
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(
+        short,
+        long,
+        default_value = ".",
+        help = "git repository or bundle file"
+    )]
+    pub git: OsString,
+
+    #[structopt(
+        long,
+        default_value = "git",
+        help = "git binary to use when reading bundles"
+    )]
+    pub git_binary: OsString,
+
+    #[structopt(
+        long,
+        default_value = "4096",
+        help = "number of git commits and trees to cache; 0 disables the cache"
+    )]
+    pub object_cache_size: usize,
+
+    #[structopt(long, help = "keep the working directory after the run")]
+    pub keep_workdir: bool,
+
+    #[structopt(
+        long,
+        help = "log the cvs commands that would change the repository instead of running them, leaving the database untouched"
+    )]
+    pub dry_run: bool,
+
+    #[structopt(short, long, help = "don't show progress as commits are exported")]
+    pub quiet: bool,
+
+    #[structopt(
+        short,
+        long,
+        default_value = ".",
+        help = "cvs module to check out, if any"
+    )]
+    pub module: String,
+
+    #[structopt(short, long, help = "use a remote branch")]
+    pub remote: bool,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "src",
+        help = "the target directory within the cvs checkout; can be . to write at the top level"
+    )]
+    pub target: OsString,
+
+    #[structopt(
+        long,
+        help = "record failed commits and keep going; their changes are included in the next commit"
+    )]
+    pub continue_on_error: bool,
+
+    #[structopt(
+        long,
+        help = "continue a push that stopped part way through from the first commit that didn't reach CVS"
+    )]
+    pub resume: bool,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "only export this path within the git tree; may be given more than once"
+    )]
+    pub include: Vec<PathBuf>,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        parse(try_from_str = state::parse_exclude),
+        help = "never export paths matching this glob, or anything within directories matching it; may be given more than once"
+    )]
+    pub exclude: Vec<attributes::Glob>,
+
+    #[structopt(
+        long,
+        help = "only export this directory of the git repository, as if it were the top level"
+    )]
+    pub prefix: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "move paths in CVS according to the from=to prefix rules in this file; the longest matching prefix wins"
+    )]
+    pub path_map: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "maintain a file at this path within the target listing the modes of executable files"
+    )]
+    pub permissions_manifest: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "maintain a .cvswrappers file within the target listing the patterns of binary files"
+    )]
+    pub write_cvswrappers: bool,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "leave this commit out of the export, folding its changes into the next commit; may be given more than once"
+    )]
+    pub skip_commit: Vec<String>,
+
+    #[structopt(
+        long,
+        help = "export a new branch even if the target directory in the CVS module already contains files, replacing them"
+    )]
+    pub allow_existing_content: bool,
+
+    #[structopt(
+        long,
+        conflicts_with = "allow-existing-content",
+        help = "export a new branch into a target directory that already contains files, keeping the ones that aren't in git"
+    )]
+    pub merge_existing: bool,
+
+    #[structopt(long, help = "transcode UTF-16 text files to UTF-8")]
+    pub transcode_utf16: bool,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "add files in the CVS module matching this glob as binary, whatever their attributes or content; may be given more than once"
+    )]
+    pub binary_glob: Vec<attributes::Glob>,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "add files in the CVS module matching this glob as text, whatever their attributes or content; may be given more than once. A file matching both a --binary-glob and a --text-glob is an error"
+    )]
+    pub text_glob: Vec<attributes::Glob>,
+
+    #[structopt(
+        long,
+        default_value = "kv",
+        possible_values = cvs::KeywordMode::VARIANTS,
+        help = "keyword substitution mode to add text files with; ko keeps $Id$ and friends exactly as they are in git"
+    )]
+    pub keyword_mode: cvs::KeywordMode,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "add files in the CVS module matching a glob with a keyword mode, given as mode=glob, such as ko=*.c; applies to binary files too, and the last matching glob wins; may be given more than once"
+    )]
+    pub keyword_glob: Vec<detect::KeywordGlob>,
+
+    #[structopt(
+        long,
+        help = "commit onto this CVS branch tag instead of the trunk, creating it if CVS doesn't have it, and recording it as the branch's CVS name rather than the sanitised git name"
+    )]
+    pub cvs_branch: Option<String>,
+
+    #[structopt(
+        long,
+        help = "the git branch that's committed to the CVS trunk; other branches are committed onto a CVS branch of their own"
+    )]
+    pub trunk: Option<String>,
+
+    #[structopt(
+        long,
+        help = "move commits that are older than an earlier commit on the branch to just after it"
+    )]
+    pub clamp_timestamps: bool,
+
+    #[structopt(
+        long,
+        help = "run cvs commit even for commits that change nothing in CVS, rather than skipping them"
+    )]
+    pub allow_empty: bool,
+
+    #[structopt(
+        long,
+        help = "split cvs command lines as though ARG_MAX were this many bytes, rather than asking the system; for debugging"
+    )]
+    pub arg_max: Option<usize>,
+
+    #[structopt(
+        long,
+        default_value = "escape",
+        possible_values = cvs::Sanitise::VARIANTS,
+        help = "how characters that CVS tags can't contain are handled in the branch name"
+    )]
+    pub sanitise: cvs::Sanitise,
+
+    #[structopt(
+        long,
+        default_value = "64",
+        help = "number of tracked files to check for external changes when resuming"
+    )]
+    pub verify_sample: usize,
+
+    #[structopt(
+        long,
+        help = "check every tracked file for external changes when resuming"
+    )]
+    pub paranoid: bool,
+
+    #[structopt(
+        long,
+        default_value = "warn",
+        possible_values = verify::DirtyPolicy::VARIANTS,
+        help = "what to do about untracked files in the working copy when resuming"
+    )]
+    pub dirty_workdir: verify::DirtyPolicy,
+
+    #[structopt(
+        long,
+        alias = "author-map",
+        help = "author file to use for authors that aren't mapped in the database"
+    )]
+    pub authors: Option<PathBuf>,
+
+    #[structopt(long, help = "append the git author to each CVS commit message")]
+    pub annotate_authors: bool,
+
+    #[structopt(
+        long,
+        help = "use commit authors as they are, rather than as the repository's .mailmap has them"
+    )]
+    pub no_mailmap: bool,
+
+    #[structopt(
+        long,
+        help = "template for CVS commit messages, with placeholders such as {message} and {short_sha}"
+    )]
+    pub message_template: Option<template::Template>,
+
+    #[structopt(
+        long,
+        overrides_with = "no-sha-trailer",
+        help = "append a Git-Commit trailer with the git commit ID to each CVS commit message (the default)"
+    )]
+    pub sha_trailer: bool,
+
+    #[structopt(
+        long,
+        overrides_with = "sha-trailer",
+        help = "don't append a Git-Commit trailer to CVS commit messages"
+    )]
+    pub no_sha_trailer: bool,
+
+    #[structopt(long, help = "commit to CVS as the mapped CVS user of each git author")]
+    pub preserve_authors: bool,
+
+    #[structopt(
+        long,
+        help = "CVS user to commit as for unmapped authors with --preserve-authors, instead of the current user"
+    )]
+    pub default_author: Option<String>,
+
+    #[structopt(
+        long,
+        help = "fail if any commit to be exported has an unmapped author"
+    )]
+    pub strict_authors: bool,
+
+    #[structopt(
+        short,
+        long,
+        parse(from_occurrences),
+        help = "log more detail about each commit; may be given twice"
+    )]
+    pub verbose: u8,
+
+    #[structopt(
+        long,
+        help = "TOML file of options, named as on the command line; options given here take precedence"
+    )]
+    pub config: Option<PathBuf>,
+
+    #[structopt(long, help = "print the options that would be used, as TOML, and exit")]
+    pub print_config: bool,
+
+    #[structopt(
+        long,
+        parse(try_from_str = budget::parse_duration),
+        help = "stop before starting a commit that would likely end after this long, such as 4h or 30m"
+    )]
+    pub max_runtime: Option<Duration>,
+
+    #[structopt(
+        long,
+        help = "stop after committing this many commits to CVS; the rest are left for the next push with --resume"
+    )]
+    pub max_commits: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "start a new export at this commit, treating its tree as the initial import"
+    )]
+    pub since_commit: Option<String>,
+
+    #[structopt(
+        long,
+        help = "export from a shallow clone, starting a new export where its history does"
+    )]
+    pub allow_shallow: bool,
+
+    #[structopt(
+        long,
+        help = "write a JUnit XML report with a test case for each commit to this path"
+    )]
+    pub report_junit: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = budget::parse_duration),
+        help = "keep running, exporting new commits on the branch at this interval, such as 5m"
+    )]
+    pub watch: Option<Duration>,
+
+    #[structopt(
+        long,
+        help = "run git fetch before looking for new commits when watching"
+    )]
+    pub fetch: bool,
+
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "stop watching once this many exports in a row have failed, or 0 to keep trying"
+    )]
+    pub watch_max_failures: u32,
+
+    // A watch keeps one working directory for the whole session.
+    #[structopt(skip)]
+    pub session_workdir: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "stop before the next commit once this file exists, removing it"
+    )]
+    pub stop_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "export the trees of submodules as ordinary files under their paths"
+    )]
+    pub recurse_submodules: bool,
+
+    #[structopt(
+        long,
+        default_value = "skip",
+        possible_values = git::Symlinks::VARIANTS,
+        help = "what to do with symlinks, which CVS can't store: skip them, dereference them within the tree, or materialise them and fail"
+    )]
+    pub symlinks: git::Symlinks,
+
+    #[structopt(
+        long,
+        default_value = "skip",
+        possible_values = git::SubmodulePolicy::VARIANTS,
+        help = "what to do with submodules without --recurse-submodules: skip them, fail, or export a placeholder file"
+    )]
+    pub submodules: git::SubmodulePolicy,
+
+    #[structopt(
+        long,
+        default_value = "error",
+        possible_values = state::CaseCollisions::VARIANTS,
+        help = "what to do with paths that differ only in case: fail, or export the newer one under a numbered name"
+    )]
+    pub case_collisions: state::CaseCollisions,
+
+    #[structopt(
+        long,
+        help = "export paths named CVS, which CVS would take for its own directories, under --cvs-dir-substitute rather than failing"
+    )]
+    pub rename_cvs_dirs: bool,
+
+    #[structopt(
+        long,
+        default_value = "CVS_",
+        parse(try_from_str = state::parse_cvs_dir_substitute),
+        help = "the name --rename-cvs-dirs gives paths named CVS"
+    )]
+    pub cvs_dir_substitute: String,
+
+    #[structopt(
+        long,
+        help = "treat paths named CVS in any case as CVS's own directories, as a case-insensitive checkout would"
+    )]
+    pub cvs_dirs_ignore_case: bool,
+
+    #[structopt(
+        long,
+        help = "delete files in the working copy that neither CVS nor git knows about"
+    )]
+    pub clean_strays: bool,
+
+    #[structopt(
+        long,
+        help = "pathspec of stray files to leave alone, relative to the top of the working copy"
+    )]
+    pub keep_stray: Vec<String>,
+
+    #[structopt(
+        long,
+        default_value = "250",
+        help = "longest path component to create in CVS, in bytes; the default leaves room for ,v"
+    )]
+    pub max_component_length: usize,
+
+    #[structopt(
+        long,
+        help = "longest path to create in CVS, in bytes, relative to the module"
+    )]
+    pub max_path_length: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "most path components to create in CVS, relative to the module"
+    )]
+    pub max_path_depth: Option<usize>,
+}
+
+impl PushOpt {
+    /// The CVSROOT, which parse_args always sets for a push.
+    pub fn cvsroot(&self) -> &cvs::CvsRoot {
+        self.cvsroot.as_ref().expect("parse_args sets the CVSROOT")
+    }
+
+    /// The branch being pushed, which is resolved from HEAD at the start of a
+    /// push if it wasn't given.
+    pub fn branch(&self) -> &str {
+        self.branch
+            .as_deref()
+            .expect("branch should be resolved before use")
+    }
+
+    /// Whether more than one branch is to be pushed, or a glob that may match
+    /// more than one.
+    pub fn several_branches(&self) -> bool {
+        self.branches.len() > 1 || self.branches.iter().any(|branch| git::is_glob(branch))
+    }
+
+    /// Looks up the branch being pushed, or the revision standing in for it.
+    pub fn lookup<'r>(&self, repo: &'r Repository) -> error::Result<git::Branch<'r>> {
+        match (&self.rev, self.tip) {
+            (Some(rev), _) => repo.revision(rev),
+            (None, Some(tip)) => Ok(repo.branch_at(self.branch(), tip)),
+            (None, None) => repo.require_branch(self.branch(), self.remote),
+        }
+    }
+
+    /// Whether CVS commit messages get a Git-Commit trailer. --sha-trailer and
+    /// --no-sha-trailer override each other, so whichever came last is set.
+    pub fn sha_trailer(&self) -> bool {
+        self.sha_trailer || !self.no_sha_trailer
+    }
+
+    /// The files within the target that the export generates rather than
+    /// taking from git.
+    pub fn generated_files(&self) -> Vec<&Path> {
+        let mut generated: Vec<&Path> = self
+            .permissions_manifest
+            .iter()
+            .map(PathBuf::as_path)
+            .collect();
+        if self.write_cvswrappers {
+            generated.push(Path::new(cvswrappers::PATH));
+        }
+        generated
+    }
+
+    pub fn path_limits(&self) -> limits::PathLimits {
+        limits::PathLimits {
+            component: self.max_component_length,
+            length: self.max_path_length,
+            depth: self.max_path_depth,
+        }
+    }
+}
+
+/// How a push ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Finished,
+    Stopped,
+}
+
+/// What a push does with the branch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Exports a branch for the first time.
+    New,
+    /// Re-attempts failed commits, or continues a push that stopped early.
+    Retry,
+    /// Exports the commits added to the branch since it was last pushed, along
+    /// with anything else that hasn't been exported yet.
+    Update,
+}
+
+pub fn run(mut opt: PushOpt, retry: bool) -> anyhow::Result<Outcome> {
+    let mode = if retry { Mode::Retry } else { Mode::New };
+    if let Some(interval) = opt.watch {
+        if opt.several_branches() {
+            anyhow::bail!("--watch can only watch one branch");
+        }
+        if opt.dry_run {
+            anyhow::bail!("--dry-run cannot be used with --watch");
+        }
+        return watch::run(opt, mode, interval);
+    }
+
+    if opt.several_branches() && opt.cvs_branch.is_some() {
+        anyhow::bail!("--cvs-branch can only be given when pushing one branch");
+    }
+
+    let _lock = RunLock::acquire(&opt.database)?;
+    if !opt.several_branches() {
+        return export(&mut opt, mode);
+    }
+
+    // A branch that forked from another should be given after it, so that
+    // the commits they share are already in CVS when it's exported. The
+    // branches share a working directory, so each carries on with the
+    // checkout the last one left.
+    let branches = expand_branches(&opt)?;
+    let (workdir, _tempdir) = create_workdir(opt.keep_workdir)?;
+    opt.session_workdir = Some(workdir.clone());
+    let mut results = Vec::new();
+    for branch in &branches {
+        log::info!("pushing branch {}", branch);
+        opt.branch = Some(branch.clone());
+        let result = export(&mut opt, mode);
+        if let Err(e) = &result {
+            // Whatever the failed export left in the checkout shouldn't go
+            // in with the next branch.
+            log::error!("cannot export {}: {:?}", branch, e);
+            watch::discard_checkout(&workdir)?;
+        }
+        let stopped = matches!(result, Ok(Outcome::Stopped));
+        results.push(result);
+        if stopped {
+            break;
+        }
+    }
+
+    for (i, branch) in branches.iter().enumerate() {
+        match results.get(i) {
+            Some(Ok(Outcome::Finished)) => log::info!("{}: exported", branch),
+            Some(Ok(Outcome::Stopped)) => log::warn!("{}: stopped early", branch),
+            Some(Err(_)) => log::error!("{}: failed", branch),
+            None => log::warn!("{}: not pushed", branch),
+        }
+    }
+    let failed = results.iter().filter(|result| result.is_err()).count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} branches failed", failed, branches.len());
+    }
+    if results
+        .iter()
+        .any(|result| matches!(result, Ok(Outcome::Stopped)))
+    {
+        return Ok(Outcome::Stopped);
+    }
+    Ok(Outcome::Finished)
+}
+
+/// Expands any globs among the branches given with -b into the branches that
+/// match them. Where it can be told, a glob's matches are put in the order
+/// they forked in, so that each comes after the branch it forked from.
+fn expand_branches(opt: &PushOpt) -> anyhow::Result<Vec<String>> {
+    if !opt.branches.iter().any(|branch| git::is_glob(branch)) {
+        return Ok(opt.branches.clone());
+    }
+    if Bundle::open(&opt.git)?.is_some() {
+        anyhow::bail!("-b cannot be a glob when pushing from a bundle");
+    }
+    let repo = Repository::discover(&opt.git, 0)?;
+
+    let mut branches: Vec<String> = Vec::new();
+    for pattern in &opt.branches {
+        if !git::is_glob(pattern) {
+            if !branches.contains(pattern) {
+                branches.push(pattern.clone());
+            }
+            continue;
+        }
+
+        let mut matched: Vec<_> = repo
+            .matching_branches(pattern, opt.remote)?
+            .into_iter()
+            .filter(|branch| !branches.contains(branch))
+            .collect();
+        if matched.is_empty() {
+            anyhow::bail!("no branches match {}", pattern);
+        }
+        sort_by_fork_point(&repo, &mut matched, &branches, opt)?;
+        branches.extend(matched);
+    }
+
+    Ok(branches)
+}
+
+/// Sorts branches by the latest commit each shares with another branch, or
+/// the branches being pushed before them, with the earliest first. A branch
+/// that forked from another is sorted after it unless both carried on from
+/// the same commit, when it can't be told which forked from which; ties are
+/// sorted by name.
+fn sort_by_fork_point(
+    repo: &Repository,
+    branches: &mut [String],
+    before: &[String],
+    opt: &PushOpt,
+) -> anyhow::Result<()> {
+    let mut tips = HashMap::new();
+    for branch in before.iter().chain(branches.iter()).chain(opt.trunk.iter()) {
+        if let Some(found) = repo.branch(branch, opt.remote)? {
+            tips.insert(branch.as_str(), found.tip());
+        }
+    }
+
+    let mut depths = HashMap::new();
+    for branch in branches.iter() {
+        let tip = tips[branch.as_str()];
+        let mut depth = 0;
+        for (other, other_tip) in &tips {
+            if *other == branch.as_str() {
+                continue;
+            }
+            if let Some(base) = repo.merge_base(&tip, other_tip)? {
+                depth = depth.max(repo.revision(&base.to_string())?.history_len(None)?);
+            }
+        }
+        depths.insert(branch.clone(), depth);
+    }
+
+    branches.sort_by(|a, b| depths[a].cmp(&depths[b]).then_with(|| a.cmp(b)));
+    Ok(())
+}
+
+/// Creates a temporary working directory, which is removed when the returned
+/// handle is dropped unless it's to be kept.
+pub fn create_workdir(keep: bool) -> anyhow::Result<(PathBuf, Option<TempDir>)> {
+    let tempdir = tempdir()?;
+    let workdir = tempdir.path().to_path_buf();
+    if keep {
+        log::info!(
+            "keeping working directory {}",
+            tempdir.into_path().display()
+        );
+        Ok((workdir, None))
+    } else {
+        Ok((workdir, Some(tempdir)))
+    }
+}
+
+/// Runs a single push. The caller has to hold the run lock.
+pub fn export(opt: &mut PushOpt, mode: Mode) -> anyhow::Result<Outcome> {
+    let cvs_ctx = opt
+        .allow_cvs_exit
+        .iter()
+        .fold(cvs::Context::new(&opt.cvs), |ctx, allowed| {
+            ctx.allow_exit(*allowed)
+        });
+    let cvs_ctx = if opt.dry_run {
+        cvs_ctx.dry_run()
+    } else {
+        cvs_ctx
+    };
+    let cvs_ctx = match opt.arg_max {
+        Some(arg_max) => cvs_ctx.arg_max(arg_max),
+        None => cvs_ctx,
+    }
+    .output(opt.cvs_output)
+    .retry(cvs::Retry {
+        retries: opt.cvs_retries,
+        delay: opt.cvs_retry_delay,
+    });
+
+    let (workdir, _tempdir) = match &opt.session_workdir {
+        Some(workdir) => (workdir.clone(), None),
+        None => create_workdir(opt.keep_workdir)?,
+    };
+    let mut db = if opt.dry_run {
+        Database::open_copy(&opt.database, &workdir)?
+    } else {
+        Database::open(&opt.database)?
+    };
+
+    let mut location = None;
+    let repo = match Bundle::open(&opt.git)? {
+        Some(bundle) => {
+            // A thin bundle leaves out the commits it's based on, and the
+            // repository it's unbundled into starts out empty, so the history
+            // couldn't be read.
+            if let Some(prerequisite) = bundle.prerequisites().first() {
+                anyhow::bail!(
+                    "{} is a thin bundle that requires commit {}; create one with the whole history of the branch instead",
+                    Path::new(&opt.git).display(),
+                    prerequisite
+                );
+            }
+
+            let path = workdir.join("bundle.git");
+            bundle.unbundle(&opt.git_binary, &path)?;
+            Repository::open(&path, opt.object_cache_size)?
+        }
+        None => {
+            let repo = Repository::discover(&opt.git, opt.object_cache_size)?;
+            let path = repo.common_dir()?;
+            log::info!("using the git repository at {}", path.display());
+            location = Some(path.to_string_lossy().into_owned());
+            repo
+        }
+    }
+    .with_prefix(opt.prefix.as_ref())
+    .with_shallow_allowed(opt.allow_shallow)
+    .with_mailmap(!opt.no_mailmap);
+    repo.check_shallow()?;
+    if opt.branch.is_none() {
+        let branch = match opt.rev.as_ref().or_else(|| opt.branches.first()) {
+            Some(branch) => branch.clone(),
+            None => repo.head_branch(opt.remote)?,
+        };
+        opt.branch = Some(branch);
+    }
+    // Commits to skip can be abbreviated, and have to be on the branch.
+    let skip_commits = if opt.skip_commit.is_empty() {
+        Vec::new()
+    } else {
+        let branch = opt.lookup(&repo)?;
+        opt.skip_commit
+            .iter()
+            .map(|rev| branch.resolve(rev))
+            .collect::<error::Result<Vec<_>>>()?
+    };
+
+    // The JUnit report is written when the listeners are dropped, so that it's
+    // there even if we bail out part way through.
+    let status = Rc::new(progress::StatusLine::stderr(opt.quiet));
+    let mut listeners: Vec<Box<dyn Listener>> = vec![Box::new(status.clone())];
+    if !opt.quiet {
+        listeners.push(Box::new(console::Console::stderr()));
+    }
+    if let Some(path) = &opt.report_junit {
+        listeners.push(Box::new(junit::Report::new(path, opt.branch())));
+    }
+
+    let detector = detect::Detector::new(opt.transcode_utf16)
+        .with_globs(&opt.binary_glob, &opt.text_glob)?
+        .with_keyword_modes(opt.keyword_mode, &opt.keyword_glob);
+    let submodules = if opt.recurse_submodules {
+        Some(git::Submodules::new(&repo))
+    } else {
+        None
+    };
+    let authors = AuthorMap::load(&db, opt.authors.as_deref())?;
+
+    let read_commit = |oid: &git2::Oid| {
+        repo.commit(oid)
+            .with_context(|| format!("cannot read commit {}", oid))
+    };
+
+    // Pushing a branch that's already been exported exports whatever's been
+    // added to it since.
+    let mode = if mode == Mode::New && db.get_cvs_branch(opt.branch())?.is_some() {
+        log::info!("branch {} has been exported; updating it", opt.branch());
+        Mode::Update
+    } else {
+        mode
+    };
+    // Another clone of the same project can carry on the export, since the
+    // history is checked against what's recorded, but it may not be up to
+    // date.
+    if let Some(location) = &location {
+        match db.git_repository(opt.branch())? {
+            Some(recorded) if recorded != *location => log::warn!(
+                "{} was last pushed from the git repository at {}, not {}",
+                opt.branch(),
+                recorded,
+                location
+            ),
+            _ => {}
+        }
+    }
+
+    // A branch exported from partway through its history has to carry on
+    // from the same commit.
+    let mut since = match &opt.since_commit {
+        Some(rev) => Some(repo.resolve_commit(rev)?),
+        None => None,
+    };
+    let resolved = match &opt.rev {
+        Some(rev) => Some(repo.resolve_commit(rev)?),
+        None => None,
+    };
+    if mode != Mode::New {
+        match (db.resolved_rev(opt.branch())?, &opt.rev) {
+            (Some(_), None) => anyhow::bail!(
+                "{} was exported as a revision; pass --rev {} to push it",
+                opt.branch(),
+                opt.branch()
+            ),
+            (None, Some(rev)) => anyhow::bail!(
+                "{} was exported as a branch; pass --branch {} to push it",
+                rev,
+                rev
+            ),
+            _ => {}
+        }
+
+        let recorded = db.since_commit(opt.branch())?;
+        if since.is_some() && since != recorded {
+            anyhow::bail!(
+                "branch {} was exported from {}, not {}",
+                opt.branch(),
+                recorded.map_or("the start of its history".to_string(), |oid| oid
+                    .to_string()),
+                opt.since_commit.as_deref().unwrap_or_default()
+            );
+        }
+    }
+
+    // When retrying, we pick up from the last commit that was exported, and
+    // only go as far as the last commit with a retryable failure.
+    let (mut plan, resume_from) = if mode == Mode::Retry {
+        if db.get_cvs_branch(opt.branch())?.is_none() {
+            anyhow::bail!("branch {} has not been exported", opt.branch());
+        }
+
+        let exceptions: Vec<_> = db
+            .exceptions(Some(opt.branch()), None)?
+            .into_iter()
+            .filter(|exception| exception.kind.retryable())
+            .collect();
+        let interrupted = exceptions
+            .iter()
+            .any(|exception| exception.kind == ExceptionKind::Interrupted);
+        let retryable = exceptions
+            .iter()
+            .map(|exception| database::parse_oid(&exception.oid))
+            .collect::<Result<Vec<_>, _>>()?;
+        let indices = db.commit_indices(&retryable, opt.branch())?;
+
+        let resume_from = db.last_exported_commit(opt.branch())?;
+        let first_pending = match &resume_from {
+            Some(oid) => db.commit_index(oid, opt.branch())?.unwrap_or(-1) + 1,
+            None => 0,
+        };
+
+        // A commit that failed with --continue-on-error had its changes
+        // committed along with the next commit that made it, so there's
+        // nothing left to retry; it's as good as skipped.
+        let mut superseded: Vec<_> = indices
+            .iter()
+            .filter(|(_, index)| **index < first_pending)
+            .collect();
+        superseded.sort_by_key(|(_, index)| **index);
+        for (oid, _) in superseded {
+            log::warn!(
+                "commit {} wasn't exported on its own; its changes were exported with a later commit",
+                oid
+            );
+            db.record_exception(
+                opt.branch(),
+                oid,
+                ExceptionKind::SkippedCommit,
+                "failed; its changes were exported with a later commit",
+            )?;
+            db.clear_retryable_exceptions(opt.branch(), oid)?;
+        }
+        let last_retryable = indices
+            .into_values()
+            .filter(|index| *index >= first_pending)
+            .max();
+
+        let mut pending = db.unexported_commits(opt.branch())?;
+        let plan = match last_retryable {
+            // A push that was stopped early carries on to the end of the
+            // branch, which it hadn't got as far as recording.
+            Some(last) if last >= first_pending && interrupted => {
+                let branch = opt.lookup(&repo)?;
+                let recorded = db.branch_commits(opt.branch())?.len();
+                Plan::new(pending).with_history(
+                    &branch,
+                    db.since_commit(opt.branch())?,
+                    recorded,
+                )?
+            }
+            Some(last) if last >= first_pending => {
+                pending.truncate((last - first_pending + 1) as usize);
+                Plan::new(pending)
+            }
+            _ => {
+                log::info!("no retryable failures on {}", opt.branch());
+                return Ok(Outcome::Finished);
+            }
+        };
+
+        (plan, resume_from)
+    } else if mode == Mode::Update {
+        // Updating picks up from the last commit that was exported too, and
+        // goes to the new tip of the branch.
+        if db.get_cvs_branch(opt.branch())?.is_none() {
+            anyhow::bail!("branch {} has not been exported", opt.branch());
+        }
+        let branch = opt.lookup(&repo)?;
+        let since = db.since_commit(opt.branch())?;
+        let mut history = match since {
+            Some(since) => branch.history_since(since)?,
+            None => branch.history()?,
+        };
+
+        // What's recorded has to still be the start of the branch, or the
+        // new commits wouldn't apply on top of what's in CVS.
+        let recorded = db.branch_commits(opt.branch())?;
+        for (index, oid) in recorded.iter().enumerate() {
+            if history.next().transpose()? != Some(*oid) {
+                anyhow::bail!(
+                    "commit {} is no longer at position {} on branch {}; was its history rewritten?",
+                    oid,
+                    index,
+                    opt.branch()
+                );
+            }
+        }
+        // A push that died part way through leaves commits that were planned,
+        // but never reached CVS. Carrying on with them has to be asked for, so
+        // that it isn't mistaken for pushing new commits.
+        let unpushed = db.unexported_commits(opt.branch())?;
+        if let Some(first) = unpushed.first() {
+            if !opt.resume {
+                anyhow::bail!(
+                    "the last push of {} stopped before commit {}, leaving {} commits unpushed; pass --resume to continue from there",
+                    opt.branch(),
+                    first,
+                    unpushed.len()
+                );
+            }
+            log::info!(
+                "resuming {} from commit {}, with {} commits unpushed",
+                opt.branch(),
+                first,
+                unpushed.len()
+            );
+        }
+
+        // New commits are recorded as they're exported.
+        let plan = Plan::new(unpushed).with_new(history.collect::<error::Result<_>>()?);
+        let new = plan.len() - plan.pending.len();
+        if new > 0 {
+            log::info!("found {} new commits on {}", new, opt.branch());
+        }
+        if plan.is_empty() {
+            log::debug!("nothing to export on {}", opt.branch());
+            return Ok(Outcome::Finished);
+        }
+
+        (plan, db.last_exported_commit(opt.branch())?)
+    } else {
+        let branch = opt.lookup(&repo)?;
+
+        // Starting from a later commit treats its tree as the initial import.
+        // A shallow clone's history starts where the clone does, so that's
+        // where its export starts, as if it had been passed as --since-commit.
+        match since {
+            Some(since) => log::info!("exporting {} from commit {}", opt.branch(), since),
+            None => {
+                since = branch.shallow_root()?;
+                if let Some(root) = since {
+                    log::warn!(
+                        "exporting {} from commit {}, where the shallow clone's history starts",
+                        opt.branch(),
+                        root
+                    );
+                }
+            }
+        }
+        (Plan::new(Vec::new()).with_history(&branch, since, 0)?, None)
+    };
+
+    if opt.strict_authors {
+        let mut unmapped = BTreeSet::new();
+        for oid in plan.commits() {
+            unmapped.extend(authors.unmapped([read_commit(&oid)?.as_ref()]));
+        }
+        if !unmapped.is_empty() {
+            anyhow::bail!(
+                "unmapped authors: {}",
+                unmapped.into_iter().collect::<Vec<_>>().join(", ")
+            );
+        }
+    }
+
+    let filter = state::Filter::new(&opt.include).with_excludes(&opt.exclude);
+    if mode != Mode::New && db.filter_fingerprint(opt.branch())? != filter.fingerprint() {
+        anyhow::bail!(
+            "branch {} was exported with a different set of --include paths (only --exclude can change between pushes)",
+            opt.branch()
+        );
+    }
+    if mode != Mode::New {
+        check_sanitise_scheme(&db, opt.branch(), opt.sanitise)?;
+        if let Some(requested) = &opt.cvs_branch {
+            let recorded = db.get_cvs_branch(opt.branch())?.unwrap_or_default();
+            if recorded != *requested {
+                anyhow::bail!(
+                    "branch {} was exported to CVS branch {}, not {}",
+                    opt.branch(),
+                    recorded,
+                    requested
+                );
+            }
+        }
+        if db.branch_prefix(opt.branch())?.as_deref() != repo.prefix() {
+            anyhow::bail!(
+                "branch {} was exported with a different --prefix",
+                opt.branch()
+            );
+        }
+    }
+
+    // The map decides where every file lives in CVS, so it has to stay the
+    // same for the life of the branch; once recorded, the flag can be left
+    // off.
+    let mut path_map = match &opt.path_map {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("cannot read path map {}", path.display()))?
+            .parse::<PathMap>()
+            .with_context(|| format!("cannot parse path map {}", path.display()))?,
+        None => PathMap::default(),
+    };
+    if mode != Mode::New {
+        let stored = stored_path_map(&db, opt.branch())?;
+        if opt.path_map.is_some() && stored != path_map {
+            anyhow::bail!(
+                "branch {} was exported with a different --path-map",
+                opt.branch()
+            );
+        }
+        path_map = stored;
+    }
+
+    // Paths the CVS server can't hold would otherwise only show up when cvs
+    // commit fails, without saying which file was the problem. The commits
+    // in between are checked as they're exported.
+    if let Some(tip) = plan.last() {
+        let tip = read_commit(&tip)?;
+        let mut tip_state = state::Global::new(&workdir, &opt.target, filter.clone())
+            .with_path_map(path_map.clone());
+        seed_state(
+            &tip,
+            &mut tip_state,
+            &Walker {
+                repo: &repo,
+                detector: &detector,
+                submodules: submodules.as_ref(),
+                skip_broken_submodules: true,
+                status: None,
+                symlinks: opt.symlinks,
+                submodule_policy: opt.submodules,
+            },
+        )
+        .with_context(|| format!("cannot export commit {}", tip.id()))?;
+        tip_state
+            .check_path_map()
+            .with_context(|| format!("cannot export commit {}", tip.id()))?;
+        opt.path_limits()
+            .check(
+                tip_state
+                    .iter_files()
+                    .map(|(file, _)| file.cvs_relative_path()),
+            )
+            .with_context(|| format!("cannot export commit {}", tip.id()))?;
+    }
+
+    // A missing local repository would otherwise only show up as a failed
+    // checkout.
+    let cvsroot = opt.cvsroot();
+    if cvsroot.is_local() && !Path::new(cvsroot.path()).join("CVSROOT").is_dir() {
+        anyhow::bail!("{} is not a CVS repository", cvsroot);
+    }
+    if let Some(host) = cvsroot.host() {
+        log::debug!("using {} CVSROOT on {}", cvsroot.method().as_str(), host);
+    }
+
+    // Given a trunk, every other branch is committed onto a CVS branch of its
+    // own. A new one is rooted where the git branch forked from a branch
+    // that's already been exported, or on the trunk if it shares nothing. A
+    // --cvs-branch that CVS doesn't have yet is created the same way.
+    let tagged = match (&opt.cvs_branch, &opt.trunk) {
+        (Some(name), _) if mode == Mode::New => {
+            !cvs_ctx.branch_exists(opt.cvsroot(), &opt.module, name)?
+        }
+        (Some(_), _) => false,
+        (None, _) if mode != Mode::New => db.branch_tagged(opt.branch())?,
+        (None, Some(trunk)) => trunk != opt.branch(),
+        (None, None) => false,
+    };
+    let new_cvs_branch = if mode == Mode::New {
+        Some(cvs_branch_name(
+            &db,
+            opt.branch(),
+            opt.cvs_branch.as_deref(),
+            opt.sanitise,
+        )?)
+    } else {
+        None
+    };
+    if let (Some(name), true) = (&new_cvs_branch, tagged) {
+        cvs::check_tag_name(name)
+            .with_context(|| format!("cannot create a CVS branch for {}", opt.branch()))?;
+    }
+    let mut unrooted = Vec::new();
+    let root = match &new_cvs_branch {
+        Some(name) if tagged => {
+            let commits: Vec<_> = plan.commits().collect();
+            let root = fork_point(&repo, &db, opt, &commits)?;
+            if let Some(root) = &root {
+                if db.filter_fingerprint(&root.branch)? != filter.fingerprint() {
+                    anyhow::bail!(
+                        "branch {} forked from {}, which was exported with a different set of --include paths (only --exclude can change between pushes)",
+                        opt.branch(),
+                        root.branch
+                    );
+                }
+                if stored_path_map(&db, &root.branch)? != path_map {
+                    anyhow::bail!(
+                        "branch {} forked from {}, which was exported with a different --path-map",
+                        opt.branch(),
+                        root.branch
+                    );
+                }
+                let revisions = root_revisions(
+                    &db,
+                    root,
+                    read_commit(&root.oid)?.as_ref(),
+                    &opt.target,
+                    filter.clone(),
+                    &Walker {
+                        repo: &repo,
+                        detector: &detector,
+                        submodules: submodules.as_ref(),
+                        skip_broken_submodules: true,
+                        status: None,
+                        symlinks: opt.symlinks,
+                        submodule_policy: opt.submodules,
+                    },
+                    &mut unrooted,
+                )?;
+                log::info!(
+                    "creating CVS branch {} at commit {} of {}",
+                    name,
+                    root.oid,
+                    root.branch
+                );
+                cvs_ctx.create_branch_at(opt.cvsroot(), &opt.module, name, &revisions)?;
+            }
+            root
+        }
+        _ => None,
+    };
+    let cvs_branch = match &opt.cvs_branch {
+        // A new CVS branch off the trunk is created once the trunk is checked
+        // out.
+        Some(_) if tagged && root.is_none() && mode == Mode::New => None,
+        Some(cvs_branch) => Some(cvs_branch.clone()),
+        None if root.is_some() => new_cvs_branch.clone(),
+        None if tagged && mode != Mode::New => db.get_cvs_branch(opt.branch())?,
+        None => None,
+    };
+    // A watch, or a push of several branches, leaves the checkout from its last
+    // successful export in place.
+    let checkout_dir = workdir.join("cvs");
+    let mut cvs_repo = if checkout_dir.exists() {
+        log::debug!("updating the checkout in {}", checkout_dir.display());
+        cvs_ctx.update_checkout(&opt.module, &checkout_dir, cvs_branch.as_deref())?
+    } else {
+        cvs_ctx.checkout(
+            opt.cvsroot(),
+            &opt.module,
+            &checkout_dir,
+            cvs_branch.as_deref(),
+        )?
+    };
+    let checkout = cvs_repo.cwd().to_path_buf();
+
+    if let Some(cvs_branch) = &new_cvs_branch {
+        if root.is_some() {
+            // The checkout is already on the new CVS branch.
+        } else if tagged {
+            // The new CVS branch starts out with whatever is on the trunk,
+            // which the first commit replaces.
+            log::info!("creating CVS branch {} from the trunk", cvs_branch);
+            cvs_repo.create_branch(cvs_branch)?;
+            cvs_repo.switch_branch(cvs_branch)?;
+        } else if !opt.allow_existing_content && !opt.merge_existing {
+            // A new branch shouldn't be mixed in with whatever is already in
+            // the target unless that's really what was meant.
+            let first = plan.first().map(|oid| read_commit(&oid)).transpose()?;
+            check_empty(&cvs_repo, Path::new(&opt.target), &repo, first.as_deref())?;
+        }
+
+        // The rest of the commits are recorded as they're exported, but the
+        // ones the branch shares with where it forked from are already in CVS.
+        let shared = match &root {
+            Some(root) => plan
+                .commits()
+                .take(root.index + 1)
+                .map(|oid| read_commit(&oid))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        db.write_branch(opt.branch(), cvs_branch, shared.iter().map(Rc::as_ref))?;
+        // A branch committed onto an existing CVS branch stays there without
+        // the flag.
+        db.set_branch_tagged(opt.branch(), tagged || opt.cvs_branch.is_some())?;
+        db.set_merge_existing(opt.branch(), opt.merge_existing)?;
+        db.set_sanitise_scheme(opt.branch(), opt.sanitise.as_str())?;
+        db.set_filter_fingerprint(opt.branch(), filter.fingerprint().as_deref())?;
+        if let Some(since) = &since {
+            db.set_since_commit(opt.branch(), since)?;
+        }
+        db.set_branch_prefix(opt.branch(), repo.prefix())?;
+        if !path_map.is_empty() {
+            db.set_path_map(opt.branch(), Some(&path_map.to_string()))?;
+        }
+        if let Some(root) = &root {
+            db.set_branch_root(opt.branch(), &root.branch, &root.oid)?;
+            db.set_case_renames(opt.branch(), db.case_renames(&root.branch)?.as_deref())?;
+            db.set_cvs_dir_renames(opt.branch(), db.cvs_dir_renames(&root.branch)?.as_deref())?;
+            db.mark_exported_through(opt.branch(), root.index)?;
+        }
+    }
+    if let Some(resolved) = &resolved {
+        db.set_resolved_rev(opt.branch(), resolved)?;
+    }
+    if let Some(location) = &location {
+        db.set_git_repository(opt.branch(), location)?;
+    }
+
+    // The commits that a forked branch shares with the branch it forked from
+    // are already in CVS, so we carry on from the fork point.
+    let resume_from = match &root {
+        Some(root) => {
+            plan.skip(root.index + 1);
+            Some(root.oid)
+        }
+        None => resume_from,
+    };
+    if plan.is_empty() {
+        log::info!("nothing to export on {}", opt.branch());
+        return Ok(Outcome::Finished);
+    }
+
+    // Ensure we have a target directory.
+    let target: PathBuf = [checkout.as_os_str(), &opt.target].iter().collect();
+    log::trace!("target: {:?}", &target);
+    let target_exists = target.exists();
+    fs::create_dir_all(&target)?;
+
+    // Paths renamed for case collisions or CVS directories stay where they
+    // were put.
+    path_map.extend(&stored_cvs_dir_renames(&db, opt.branch())?);
+    path_map.extend(&stored_case_renames(&db, opt.branch())?);
+    let mut state = state::Global::new(&checkout, &opt.target, filter.clone())
+        .with_path_map(path_map)
+        .with_cvs_dirs_ignoring_case(opt.cvs_dirs_ignore_case);
+
+    let resuming = resume_from.is_some();
+    match resume_from {
+        Some(oid) => {
+            // The checkout should already contain the last exported commit, so
+            // we just need to know what's in it.
+            seed_state(
+                read_commit(&oid)?.as_ref(),
+                &mut state,
+                &Walker {
+                    repo: &repo,
+                    detector: &detector,
+                    submodules: submodules.as_ref(),
+                    skip_broken_submodules: opt.continue_on_error,
+                    status: None,
+                    symlinks: opt.symlinks,
+                    submodule_policy: opt.submodules,
+                },
+            )?;
+            for path in &unrooted {
+                state.forget(&state.file(path)?);
+            }
+            let stored = db.file_state(opt.branch())?;
+            if !stored.is_empty() {
+                apply_entries(&mut state, &checkout, &opt.target, &stored, filter)?;
+            }
+            if let Some(manifest) = &opt.permissions_manifest {
+                permissions::seed(manifest, &mut state)?;
+            }
+            if opt.write_cvswrappers {
+                cvswrappers::seed(&mut state)?;
+            }
+
+            // If the last push died while cvs commit was running, some of the
+            // next commit may already be in CVS.
+            if let Some(pending) = plan.first() {
+                let pending = read_commit(&pending)?;
+                let landed = reconcile::reconcile(
+                    read_commit(&oid)?.as_ref(),
+                    &pending,
+                    &repo,
+                    &cvs_repo,
+                    &mut db,
+                    opt.branch(),
+                    &mut state,
+                )?;
+                if landed > 0 {
+                    log::warn!(
+                        "{} files of commit {} were already committed to CVS; committing the rest",
+                        landed,
+                        pending.id()
+                    );
+                }
+            }
+        }
+        None if target_exists => {
+            // The target is already in CVS, so anything in it is replaced by
+            // the first commit, unless it's being merged with.
+            let merge_with = match plan.first() {
+                Some(first) if opt.merge_existing => Some((&repo, read_commit(&first)?.tree_id())),
+                _ => None,
+            };
+            seed_existing(&cvs_repo, Path::new(&opt.target), &mut state, merge_with)?;
+        }
+        None => {
+            // We have to add the target directory to the CVS repository before
+            // we can do anything.
+            cvs_repo.add(&opt.target, cvs::KeywordMode::Default)?;
+        }
+    }
+
+    // Files that were kept by --merge-existing aren't from git, but they
+    // aren't strays or untracked either. Later pushes go by what the first
+    // one recorded.
+    let kept = if opt.merge_existing || db.merge_existing(opt.branch())? {
+        untracked_in_cvs(&cvs_repo, Path::new(&opt.target), &state)?
+    } else {
+        Vec::new()
+    };
+
+    // Anything that neither CVS nor git knows about would otherwise end up
+    // confusing the checks below, or worse, in CVS.
+    let mut strays = verify::strays(&state, &cvs_repo, &checkout, &opt.keep_stray)?;
+    strays.retain(|path| {
+        !kept
+            .iter()
+            .any(|kept| Path::new(&opt.target).join(kept) == *path)
+    });
+    if !strays.is_empty() {
+        let list = strays
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if opt.clean_strays {
+            for path in strays.iter() {
+                fs::remove_file(checkout.join(path))?;
+            }
+            log::debug!("removed stray files: {}", list);
+        } else {
+            log::warn!(
+                "found {} stray files; pass --clean-strays to remove them: {}",
+                strays.len(),
+                list
+            );
+        }
+    }
+
+    if resuming {
+        let mut generated = opt.generated_files();
+        generated.extend(kept.iter().map(PathBuf::as_path));
+
+        // Something may have changed the working copy since the last export.
+        let rewritten = verify::check_tracked(
+            &state,
+            &repo,
+            &detector,
+            if opt.paranoid {
+                None
+            } else {
+                Some(opt.verify_sample)
+            },
+            opt.dirty_workdir,
+            &generated,
+        )?
+        .into_iter()
+        .filter(|discrepancy| !matches!(discrepancy, verify::Discrepancy::Extra(_)))
+        .count();
+        if rewritten > 0 {
+            log::warn!(
+                "rewrote {} files that had changed in the working copy",
+                rewritten
+            );
+        }
+    }
+
+    let exporter = Exporter {
+        walker: Walker {
+            repo: &repo,
+            detector: &detector,
+            submodules: submodules.as_ref(),
+            skip_broken_submodules: opt.continue_on_error,
+            status: Some(&status),
+            symlinks: opt.symlinks,
+            submodule_policy: opt.submodules,
+        },
+        cvs_repo: &cvs_repo,
+        authors: &authors,
+        opt,
+        overrides_applied: Cell::new(0),
+        keyword_fixups: Cell::new(0),
+        empty_commits: Cell::new(0),
+        latest_time: Cell::new(db.latest_commit_time(opt.branch())?),
+        timestamps_clamped: Cell::new(0),
+        symlinks: RefCell::new(BTreeMap::new()),
+        mode_changes: RefCell::new(BTreeMap::new()),
+    };
+    let mut skipped: HashMap<_, _> = db
+        .skipped_commits()?
+        .into_iter()
+        .map(|skipped| (skipped.oid, skipped.reason))
+        .collect();
+    for oid in skip_commits {
+        skipped.entry(oid).or_insert(None);
+    }
+
+    let mut budget = opt.max_runtime.map(budget::Budget::new);
+    let mut outcome = Outcome::Finished;
+    let mut remaining = 0;
+    let (mut exported, mut written, mut removed) = (0, 0, 0);
+    for (i, oid) in plan.commits().enumerate() {
+        let commit = &read_commit(&oid)?;
+        let progress = events::Progress {
+            index: i + 1,
+            total: plan.len(),
+        };
+        if i >= plan.pending.len() {
+            db.append_commits(opt.branch(), std::iter::once(Rc::as_ref(commit)))?;
+        }
+
+        if matches!(opt.max_commits, Some(max) if exported >= max) {
+            log::warn!(
+                "stopping after {} of {} commits because of --max-commits; push with --resume to continue",
+                i,
+                plan.len()
+            );
+            db.record_exception(
+                opt.branch(),
+                &commit.id(),
+                ExceptionKind::Interrupted,
+                &format!(
+                    "stopped by --max-commits after {} of {} commits",
+                    i,
+                    plan.len()
+                ),
+            )?;
+            outcome = Outcome::Stopped;
+            remaining = plan.len() - i;
+            break;
+        }
+
+        if let Some(budget) = &budget {
+            if !budget.allows_next() {
+                log::warn!(
+                    "stopping after {} of {} commits to stay within --max-runtime; push with --resume to continue",
+                    i,
+                    plan.len()
+                );
+                db.record_exception(
+                    opt.branch(),
+                    &commit.id(),
+                    ExceptionKind::Interrupted,
+                    &format!(
+                        "stopped by --max-runtime after {} of {} commits",
+                        i,
+                        plan.len()
+                    ),
+                )?;
+                outcome = Outcome::Stopped;
+                remaining = plan.len() - i;
+                break;
+            }
+        }
+
+        if watch::stop_requested(opt.stop_file.as_deref()) {
+            log::warn!("stopping after {} of {} commits by request", i, plan.len());
+            db.record_exception(
+                opt.branch(),
+                &commit.id(),
+                ExceptionKind::Interrupted,
+                &format!("stopped by request after {} of {} commits", i, plan.len()),
+            )?;
+            outcome = Outcome::Stopped;
+            remaining = plan.len() - i;
+            break;
+        }
+
+        if let Some(reason) = skipped.get(&commit.id()) {
+            // The state still reflects the last exported commit, so whatever
+            // this commit changed will be picked up by the next one.
+            let reason = reason.as_deref().unwrap_or("skipped by request");
+            db.record_exception(
+                opt.branch(),
+                &commit.id(),
+                ExceptionKind::SkippedCommit,
+                reason,
+            )?;
+            db.clear_retryable_exceptions(opt.branch(), &commit.id())?;
+            db.mark_exported(opt.branch(), &commit.id(), &ExportStats::default())?;
+            listeners.event(progress, &Event::Skipped { commit, reason });
+            continue;
+        }
+
+        status.start(progress, commit);
+        let start = Instant::now();
+        let before = opt.continue_on_error.then(|| state.clone());
+        let result = exporter.export_commit(commit, &mut state, &mut db);
+        if let Some(budget) = &mut budget {
+            budget.record(start.elapsed());
+        }
+
+        match result {
+            Ok(stats) => {
+                exported += 1;
+                written += stats.files_added + stats.files_modified;
+                removed += stats.files_removed;
+                db.clear_retryable_exceptions(opt.branch(), &commit.id())?;
+                listeners.event(
+                    progress,
+                    &Event::Exported {
+                        commit,
+                        stats: &stats,
+                    },
+                );
+            }
+            Err(e) => {
+                db.record_exception(
+                    opt.branch(),
+                    &commit.id(),
+                    ExceptionKind::Failed,
+                    &format!("{:#}", e),
+                )?;
+                listeners.event(
+                    progress,
+                    &Event::Failed {
+                        commit,
+                        error: &e,
+                        duration: start.elapsed(),
+                    },
+                );
+                let before = match before {
+                    Some(before) => before,
+                    None => return Err(e),
+                };
+
+                // The next commit starts from the last one that made it, so
+                // whatever this one changed will be committed along with it.
+                state.roll_back(before);
+                log::error!("cannot export commit {}: {:?}", commit.id(), e);
+            }
+        }
+
+        log::trace!("commit {}/{}: {}", i + 1, plan.len(), commit.id());
+    }
+
+    db.write_file_state(
+        opt.branch(),
+        state
+            .iter_files()
+            .map(|(file, oid)| (file.git_path().to_path_buf(), *oid)),
+    )?;
+
+    log::info!(
+        "{} {} commits, writing {} files and removing {}",
+        if opt.dry_run {
+            "would have exported"
+        } else {
+            "exported"
+        },
+        exported,
+        written,
+        removed
+    );
+    if remaining > 0 {
+        log::info!(
+            "{} commits remain to be exported on {}",
+            remaining,
+            opt.branch()
+        );
+    }
+    if opt.dry_run {
+        log::info!(
+            "dry run: skipped {} cvs commands; the database is unchanged",
+            cvs_ctx.planned().len()
+        );
+    }
+    log::info!(
+        "applied {} commit message overrides",
+        exporter.overrides_applied.get()
+    );
+    log::info!(
+        "changed the keyword mode of {} re-added files",
+        exporter.keyword_fixups.get()
+    );
+    if !opt.allow_empty {
+        log::info!(
+            "skipped cvs commit for {} commits that changed nothing in CVS",
+            exporter.empty_commits.get()
+        );
+    }
+    if opt.clamp_timestamps {
+        log::info!(
+            "clamped the timestamps of {} commits",
+            exporter.timestamps_clamped.get()
+        );
+    }
+    log::info!(
+        "{} {} stray files from the working copy",
+        if opt.clean_strays { "removed" } else { "found" },
+        strays.len()
+    );
+    let (dereferenced, skipped): (Vec<_>, Vec<_>) = exporter
+        .symlinks
+        .into_inner()
+        .into_iter()
+        .partition(|(_, dereferenced)| *dereferenced);
+    for (verb, symlinks) in [("dereferenced", dereferenced), ("skipped", skipped)] {
+        if !symlinks.is_empty() {
+            log::info!(
+                "{} {} symlinks with --symlinks {}: {}",
+                verb,
+                symlinks.len(),
+                opt.symlinks,
+                symlinks
+                    .iter()
+                    .map(|(path, _)| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+    let mode_changes = exporter.mode_changes.into_inner();
+    if !mode_changes.is_empty() {
+        log::info!(
+            "changed the executable bit of {} files, which CVS may not keep: {}",
+            mode_changes.len(),
+            mode_changes
+                .keys()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    let (deduplicated, bytes_deduplicated) = state.deduplication_stats();
+    log::info!(
+        "copied {} files from identical blobs, saving {} bytes of git reads",
+        deduplicated,
+        bytes_deduplicated
+    );
+    log::info!(
+        "object cache: commits {}; trees {}",
+        repo.commit_cache_stats(),
+        repo.tree_cache_stats()
+    );
+
+    Ok(outcome)
+}
+
+/// The things needed to export each commit that don't change during a push.
+struct Exporter<'a> {
+    walker: Walker<'a>,
+    cvs_repo: &'a cvs::Repository,
+    authors: &'a AuthorMap,
+    opt: &'a PushOpt,
+    overrides_applied: Cell<usize>,
+    keyword_fixups: Cell<usize>,
+    empty_commits: Cell<usize>,
+    latest_time: Cell<Option<i64>>,
+    timestamps_clamped: Cell<usize>,
+    /// The symlinks found, and whether each was dereferenced.
+    symlinks: RefCell<BTreeMap<PathBuf, bool>>,
+    /// The files whose executable bit changed, and whether each ended up
+    /// executable.
+    mode_changes: RefCell<BTreeMap<PathBuf, bool>>,
+}
+
+impl Exporter<'_> {
+    fn export_commit(
+        &self,
+        commit: &Commit,
+        state: &mut state::Global,
+        db: &mut Database,
+    ) -> anyhow::Result<ExportStats> {
+        let Exporter { cvs_repo, opt, .. } = *self;
+        let start = Instant::now();
+        let mut commit_state = state::Commit::new();
+
+        let time = self.commit_time(commit);
+        let message = self.message(commit, db)?;
+        let cvs_user = self.cvs_user(commit);
+        let sha = if opt.sha_trailer() {
+            Some(commit.id())
+        } else {
+            None
+        };
+
+        let paths = commit_paths(&self.walker, state, commit)?;
+        self.resolve_cvs_dirs(commit, state, db, &paths)?;
+        self.resolve_case_collisions(commit, state, db, &paths)?;
+        let mut files_removed =
+            self.empty_replaced_directories(commit, state, db, &message, cvs_user, sha)?;
+        walk_commit(commit, time, state, &mut commit_state, &self.walker)?;
+        state.check_path_map()?;
+        if let Some(status) = self.walker.status {
+            status.clear();
+        }
+        if let Some(manifest) = &opt.permissions_manifest {
+            permissions::update(manifest, time, state, &mut commit_state)?;
+        }
+        if opt.write_cvswrappers {
+            let patterns: Vec<_> = self
+                .walker
+                .detector
+                .binary_patterns(self.walker.repo, &commit.tree_id())?
+                .iter()
+                .filter_map(|glob| glob.file_name_pattern())
+                .filter(|pattern| cvswrappers::is_valid(pattern))
+                .map(String::from)
+                .collect();
+            cvswrappers::update(&patterns, time, state, &mut commit_state)?;
+        }
+        opt.path_limits().check(
+            commit_state
+                .iter_new_files()
+                .map(|(file, _)| file.cvs_relative_path()),
+        )?;
+
+        log::debug!(
+            "excluded {} paths from commit {}",
+            commit_state.paths_excluded(),
+            commit.id()
+        );
+
+        // Remove files that have been removed, remembering their keyword
+        // modes in case they come back.
+        let removed = state.remove_files(&commit_state);
+        let removed_modes =
+            cvs_repo.keyword_modes(removed.iter().map(|file| file.cvs_relative_path()))?;
+        delete_files(&removed)?;
+        files_removed += remove_from_cvs(cvs_repo, &removed)?;
+        db.record_removed_modes(opt.branch(), &removed_modes)?;
+
+        // Add files that have been added, one batch per keyword mode, after
+        // any directories that only the path map needs.
+        cvs_repo.add_multiple(
+            commit_state.iter_new_directories(),
+            cvs::KeywordMode::Default,
+        )?;
+        for mode in cvs::KeywordMode::ALL {
+            cvs_repo.add_multiple(
+                commit_state
+                    .iter_new_files_with_mode(*mode)
+                    .map(|file| file.cvs_relative_path()),
+                *mode,
+            )?;
+        }
+
+        // CVS resurrects re-added files with the keyword mode they had when
+        // they were removed, which may not be what we just asked for. A
+        // directory that took a removed file's place has no keyword mode.
+        let fixups = keyword_fixups(
+            db,
+            opt.branch(),
+            commit_state
+                .iter_new_files()
+                .filter(|(file, _)| !file.absolute_path().is_dir())
+                .map(|(file, mode)| (file.cvs_relative_path(), mode)),
+        )?;
+
+        // Actually commit.
+        let empty = commit_state.iter_written_files().next().is_none() && files_removed == 0;
+        if empty && !opt.allow_empty {
+            // This happens when the commit only reverts skipped commits, for
+            // instance, only touches excluded paths or paths outside the
+            // prefix, or only changes modes CVS doesn't keep; there's nothing
+            // for CVS to do.
+            match self.walker.repo.prefix() {
+                Some(prefix) => log::info!(
+                    "skipping commit {}, which changes nothing under {}",
+                    commit.id(),
+                    prefix.display()
+                ),
+                None => log::info!(
+                    "skipping commit {}, which changes nothing in CVS",
+                    commit.id()
+                ),
+            }
+            self.empty_commits.set(self.empty_commits.get() + 1);
+        } else {
+            cvs_repo.commit(&message, cvs_user, sha)?;
+        }
+
+        for (path, mode) in fixups.iter() {
+            log::info!(
+                "changing keyword mode of re-added file {} to -k{}",
+                path.display(),
+                mode
+            );
+            cvs_repo.set_keyword_mode(path, mode)?;
+        }
+        self.keyword_fixups
+            .set(self.keyword_fixups.get() + fixups.len());
+        db.forget_removed(
+            opt.branch(),
+            commit_state
+                .iter_new_files()
+                .map(|(file, _)| file.cvs_relative_path()),
+        )?;
+
+        self.symlinks.borrow_mut().extend(
+            commit_state
+                .iter_symlinks()
+                .map(|(path, dereferenced)| (path.to_path_buf(), dereferenced)),
+        );
+
+        // CVS takes the mode of the RCS file from the working file when it's
+        // first added, so later changes generally don't make it to other
+        // checkouts.
+        for (path, executable) in commit_state.iter_mode_changes() {
+            log::warn!(
+                "{} {} executable in commit {}, which CVS may not keep",
+                path.display(),
+                if executable { "became" } else { "is no longer" },
+                commit.id()
+            );
+            self.mode_changes
+                .borrow_mut()
+                .insert(path.to_path_buf(), executable);
+        }
+
+        for (path, reason) in commit_state.iter_skipped_entries() {
+            db.record_exception(
+                opt.branch(),
+                &commit.id(),
+                ExceptionKind::SkippedEntry,
+                &format!("{}: {}", path.display(), reason),
+            )?;
+        }
+
+        // Record the CVS revisions we just created, so that they can be traced
+        // back to this commit later.
+        db.record_revisions(
+            opt.branch(),
+            &commit.id(),
+            &cvs_repo.revisions(
+                commit_state
+                    .iter_written_files()
+                    .map(|file| file.cvs_relative_path()),
+            )?,
+            &commit_state.written_sizes(),
+        )?;
+        let (lines_added, lines_removed) = self.walker.repo.line_stats(commit)?;
+        let stats = ExportStats {
+            duration: start.elapsed(),
+            files_added: commit_state.files_added(),
+            files_modified: commit_state.files_modified(),
+            files_removed,
+            bytes_written: commit_state.bytes_written(),
+            commit_time: Some(time),
+            lines_added,
+            lines_removed,
+            empty,
+        };
+        let files: Vec<_> = commit_state
+            .iter_written_files()
+            .map(|file| (file.git_path().to_path_buf(), state.get_oid(file).copied()))
+            .chain(
+                removed
+                    .iter()
+                    .map(|file| (file.git_path().to_path_buf(), None)),
+            )
+            .collect();
+        db.mark_pushed(opt.branch(), &commit.id(), &stats, &files)?;
+
+        Ok(stats)
+    }
+
+    /// Returns the time to use for a commit. With --clamp-timestamps, commits
+    /// that are older than a commit already exported on the branch are moved
+    /// to just after it.
+    fn commit_time(&self, commit: &Commit) -> i64 {
+        let time = commit.time().seconds();
+        let latest = self.latest_time.get();
+
+        let time = if self.opt.clamp_timestamps {
+            let clamped = clamp_time(time, latest);
+            if clamped != time {
+                log::debug!(
+                    "clamping time of commit {} from {} to {}",
+                    commit.id(),
+                    time,
+                    clamped
+                );
+                self.timestamps_clamped
+                    .set(self.timestamps_clamped.get() + 1);
+            }
+            clamped
+        } else {
+            time
+        };
+
+        self.latest_time
+            .set(Some(latest.map_or(time, |latest| latest.max(time))));
+        time
+    }
+
+    /// Looks for paths in a commit that CVS would take for the directories it
+    /// keeps its metadata in, and either fails or sends them somewhere else
+    /// in CVS. Renames are recorded, so later commits put the path in the same
+    /// place.
+    fn resolve_cvs_dirs(
+        &self,
+        commit: &Commit,
+        state: &state::Global,
+        db: &Database,
+        paths: &BTreeSet<PathBuf>,
+    ) -> anyhow::Result<()> {
+        let opt = self.opt;
+        let found = state.cvs_dirs(paths);
+        if found.is_empty() {
+            return Ok(());
+        }
+
+        if !opt.rename_cvs_dirs {
+            let found: Vec<_> = found
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            anyhow::bail!(
+                "commit {} has paths that CVS would take for its own CVS directories (--rename-cvs-dirs would rename them): {}",
+                commit.id(),
+                found.join(", ")
+            );
+        }
+
+        let mut renames = stored_cvs_dir_renames(db, opt.branch())?;
+        for path in found {
+            let renamed = state
+                .rename_cvs_dir(&path, &opt.cvs_dir_substitute, paths)
+                .with_context(|| format!("cannot export commit {}", commit.id()))?;
+            log::warn!(
+                "{} would be taken for a CVS directory, so it's exported as {} from commit {}",
+                path.display(),
+                renamed.display(),
+                commit.id()
+            );
+            renames.insert(path, renamed);
+        }
+        Ok(db.set_cvs_dir_renames(opt.branch(), Some(&renames.to_string()))?)
+    }
+
+    /// Looks for paths in a commit that a checkout on a case-insensitive file
+    /// system couldn't tell apart, and either fails or sends one of each pair
+    /// somewhere else in CVS. Renames are recorded, so later commits put the
+    /// path in the same place.
+    fn resolve_case_collisions(
+        &self,
+        commit: &Commit,
+        state: &state::Global,
+        db: &Database,
+        paths: &BTreeSet<PathBuf>,
+    ) -> anyhow::Result<()> {
+        let opt = self.opt;
+        loop {
+            let collisions = state.case_collisions(paths);
+            let (existing, colliding) = match collisions.first() {
+                Some(collision) => collision.clone(),
+                None => return Ok(()),
+            };
+
+            if opt.case_collisions == state::CaseCollisions::Error {
+                let pairs: Vec<_> = collisions
+                    .iter()
+                    .map(|(a, b)| format!("{} and {}", a.display(), b.display()))
+                    .collect();
+                anyhow::bail!(
+                    "commit {} has paths that differ only in case, which a case-insensitive checkout can't hold (--case-collisions rename would rename them): {}",
+                    commit.id(),
+                    pairs.join(", ")
+                );
+            }
+
+            let renamed = state.rename_for_case(&colliding, paths);
+            log::warn!(
+                "{} differs from {} only in case, so it's exported as {} from commit {}",
+                colliding.display(),
+                existing.display(),
+                renamed.display(),
+                commit.id()
+            );
+            let mut renames = stored_case_renames(db, opt.branch())?;
+            renames.insert(colliding, renamed);
+            db.set_case_renames(opt.branch(), Some(&renames.to_string()))?;
+        }
+    }
+
+    /// CVS can't commit removals in a directory that's no longer in the
+    /// working copy, or have a file where a directory is, so the files in
+    /// directories that the commit turns into files are removed in a CVS
+    /// commit of their own first. The directories are then dropped from the
+    /// working copy, leaving the walk to write the files in their place.
+    /// Returns the number of files removed.
+    fn empty_replaced_directories(
+        &self,
+        commit: &Commit,
+        state: &mut state::Global,
+        db: &mut Database,
+        message: &[u8],
+        cvs_user: Option<&str>,
+        sha: Option<git2::Oid>,
+    ) -> anyhow::Result<usize> {
+        let Exporter { cvs_repo, opt, .. } = *self;
+        let dirs = replaced_directories(&self.walker, state, commit)?;
+        if dirs.is_empty() {
+            return Ok(0);
+        }
+
+        let mut removal = state::Commit::new();
+        for dir in dirs.iter() {
+            log::info!(
+                "{} becomes a file in commit {}, so its files are removed from CVS first",
+                dir.display(),
+                commit.id()
+            );
+            for file in state.unseen_files(&removal, dir) {
+                removal.removed_file(file);
+            }
+        }
+        let removed = state.remove_files(&removal);
+        let removed_modes =
+            cvs_repo.keyword_modes(removed.iter().map(|file| file.cvs_relative_path()))?;
+        delete_files(&removed)?;
+        let files_removed = remove_from_cvs(cvs_repo, &removed)?;
+        if files_removed > 0 {
+            cvs_repo.commit(message, cvs_user, sha)?;
+        }
+        db.record_removed_modes(opt.branch(), &removed_modes)?;
+
+        for dir in dirs.iter() {
+            cvs_repo.forget_directory(state.file(dir)?.cvs_relative_path())?;
+        }
+        Ok(files_removed)
+    }
+
+    /// The CVS user to commit as, when authors are preserved.
+    fn cvs_user(&self, commit: &Commit) -> Option<&str> {
+        let opt = self.opt;
+        if opt.preserve_authors {
+            match (self.authors.get(commit.author_email()), &opt.default_author) {
+                (Some(author), _) => {
+                    log::debug!(
+                        "committing {} by {} as CVS user {}",
+                        commit.id(),
+                        commit.author_email(),
+                        author.cvs_user
+                    );
+                    Some(author.cvs_user.as_str())
+                }
+                (None, Some(default)) => {
+                    log::info!(
+                        "author {} of commit {} is not mapped; committing as {}",
+                        commit.author_email(),
+                        commit.id(),
+                        default
+                    );
+                    Some(default.as_str())
+                }
+                (None, None) => {
+                    log::warn!(
+                        "author {} of commit {} is not mapped; committing as the current user",
+                        commit.author_email(),
+                        commit.id()
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Builds the CVS log message for a commit. Overrides replace the git
+    /// message before anything else is applied.
+    fn message(&self, commit: &Commit, db: &Database) -> anyhow::Result<Vec<u8>> {
+        let (message, overridden) = commit_message(
+            commit,
+            db,
+            self.authors,
+            self.opt.annotate_authors,
+            self.opt
+                .message_template
+                .as_ref()
+                .map(|template| (template, self.opt.branch())),
+        )?;
+        if overridden {
+            self.overrides_applied.set(self.overrides_applied.get() + 1);
+        }
+        Ok(message)
+    }
+}
+
+/// Builds the CVS commit message for a commit: the replacement recorded in the
+/// database, if any, rendered through the template for the given branch if
+/// there is one, and then the author annotation. Also returns whether the
+/// message was replaced.
+pub fn commit_message(
+    commit: &Commit,
+    db: &Database,
+    authors: &AuthorMap,
+    annotate_authors: bool,
+    template: Option<(&template::Template, &str)>,
+) -> anyhow::Result<(Vec<u8>, bool)> {
+    let (mut message, overridden) = match db.message_override(&commit.id())? {
+        Some(message) => (message.into_bytes(), true),
+        None => (commit.message_raw_bytes().to_vec(), false),
+    };
+
+    if let Some((template, branch)) = template {
+        message = template::render_message(template, commit, branch, &message);
+    }
+
+    if annotate_authors {
+        message = authors.annotate(commit, message);
+    }
+
+    Ok((message, overridden))
+}
+
+/// Copies a file within the working copy. std uses copy_file_range where the
+/// kernel supports it, and falls back to copying in userspace. Like fs::write,
+/// this keeps the permissions of an existing destination.
+fn copy_file(from: &Path, to: &Path) -> anyhow::Result<u64> {
+    let mut source = fs::File::open(from)?;
+    let mut destination = fs::File::create(to)?;
+    Ok(io::copy(&mut source, &mut destination)?)
+}
+
+/// Sets or clears all the executable bits of a file in the working copy.
+fn set_executable(path: &Path, executable: bool) -> anyhow::Result<()> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    let wanted = if executable {
+        mode | 0o111
+    } else {
+        mode & !0o111
+    };
+    if wanted != mode {
+        fs::set_permissions(path, Permissions::from_mode(wanted))?;
+    }
+
+    Ok(())
+}
+
+/// Deletes files that have been removed from the working copy, since cvs
+/// remove won't schedule a file that's still there. Files that are already
+/// gone are fine.
+fn delete_files(files: &[state::File]) -> anyhow::Result<()> {
+    let mut listings = HashMap::new();
+    for file in files {
+        // A directory may have taken the file's place already, or, on a
+        // case-insensitive file system, a file whose name only differs in
+        // case.
+        let path = file.absolute_path();
+        if path.is_dir() || !has_exact_name(&path, &mut listings)? {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("cannot delete {}", path.display())),
+        }
+    }
+
+    Ok(())
+}
+
+/// Schedules files that have been removed from the working copy for removal
+/// from CVS, returning how many there were. The state had them as exported,
+/// so one that CVS doesn't have on the branch means the two disagree, and
+/// forgetting it would hide that.
+fn remove_from_cvs(cvs_repo: &cvs::Repository, files: &[state::File]) -> anyhow::Result<usize> {
+    let skipped = cvs_repo.remove_multiple(files.iter().map(|file| file.cvs_relative_path()))?;
+    if !skipped.is_empty() {
+        anyhow::bail!(
+            "cannot remove files that were exported but that CVS doesn't have on the branch: {}",
+            skipped
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(files.len())
+}
+
+/// Returns whether there's a directory entry with exactly the path's name,
+/// rather than one that a case-insensitive file system only considers the
+/// same. The entries of each directory are listed once.
+fn has_exact_name(
+    path: &Path,
+    listings: &mut HashMap<PathBuf, HashSet<OsString>>,
+) -> anyhow::Result<bool> {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Ok(false),
+    };
+    if !listings.contains_key(dir) {
+        let names = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<io::Result<_>>()
+                .with_context(|| format!("cannot list {}", dir.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e).with_context(|| format!("cannot list {}", dir.display())),
+        };
+        listings.insert(dir.to_path_buf(), names);
+    }
+    Ok(listings[dir].contains(name))
+}
+
+/// The commits a push works through, oldest first: those that an earlier push
+/// recorded without exporting, then any new ones on the branch. The branch's
+/// history is walked once, when the plan is made, but commits are only read as
+/// they're needed.
+struct Plan {
+    pending: Vec<git2::Oid>,
+    new: Vec<git2::Oid>,
+}
+
+impl Plan {
+    fn new(pending: Vec<git2::Oid>) -> Self {
+        Self {
+            pending,
+            new: Vec::new(),
+        }
+    }
+
+    /// Adds the commits on the branch after the first skip of its history,
+    /// which starts from since if given.
+    fn with_history(
+        self,
+        branch: &git::Branch,
+        since: Option<git2::Oid>,
+        skip: usize,
+    ) -> anyhow::Result<Self> {
+        let history = match since {
+            Some(since) => branch.history_since(since)?,
+            None => branch.history()?,
+        };
+        Ok(self.with_new(history.skip(skip).collect::<error::Result<_>>()?))
+    }
+
+    /// Adds commits that haven't been recorded yet.
+    fn with_new(mut self, new: Vec<git2::Oid>) -> Self {
+        self.new = new;
+        self
+    }
+
+    fn len(&self) -> usize {
+        self.pending.len() + self.new.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Leaves out the first count commits.
+    fn skip(&mut self, count: usize) {
+        let pending = count.min(self.pending.len());
+        self.pending.drain(..pending);
+        let new = (count - pending).min(self.new.len());
+        self.new.drain(..new);
+    }
+
+    fn commits(&self) -> impl Iterator<Item = git2::Oid> + '_ {
+        self.pending.iter().chain(self.new.iter()).copied()
+    }
+
+    fn first(&self) -> Option<git2::Oid> {
+        self.commits().next()
+    }
+
+    fn last(&self) -> Option<git2::Oid> {
+        self.new.last().or_else(|| self.pending.last()).copied()
+    }
+}
+
+/// Where a new branch forked from a branch that's already been exported.
+struct Root {
+    /// The exported branch.
+    branch: String,
+    oid: git2::Oid,
+    /// The position of the fork point in the new branch's history.
+    index: usize,
+    /// The position of the fork point in the exported branch's history.
+    root_index: i64,
+}
+
+/// Finds the last commit that a new branch shares with the trunk or another
+/// exported branch, preferring whichever it forked from most recently. Fails
+/// if that commit hasn't been exported yet, since there's nothing in CVS to
+/// root the branch at.
+fn fork_point(
+    repo: &Repository,
+    db: &Database,
+    opt: &PushOpt,
+    commits: &[git2::Oid],
+) -> anyhow::Result<Option<Root>> {
+    let tip = match commits.last() {
+        Some(tip) => *tip,
+        None => return Ok(None),
+    };
+
+    let mut candidates: Vec<_> = db
+        .branch_summaries()?
+        .into_iter()
+        .filter(|summary| !summary.archived)
+        .map(|summary| summary.git_branch)
+        .collect();
+    if let Some(trunk) = &opt.trunk {
+        if !candidates.contains(trunk) {
+            candidates.push(trunk.clone());
+        }
+    }
+
+    let mut best: Option<(usize, String)> = None;
+    for candidate in candidates {
+        if candidate == opt.branch() {
+            continue;
+        }
+        // A branch that hasn't been exported is only a candidate so that we
+        // can say it needs to be.
+        let history: Vec<_> = match db.branch_tip(&candidate)? {
+            Some(_) => db.branch_commits(&candidate)?,
+            None => match repo.branch(&candidate, opt.remote)? {
+                Some(branch) => branch.linear_history()?.into_iter().collect(),
+                None => continue,
+            },
+        };
+        let base = match history.last() {
+            Some(candidate_tip) => match repo.merge_base(&tip, candidate_tip)? {
+                Some(base) => base,
+                None => continue,
+            },
+            None => continue,
+        };
+
+        // Only first parents are exported, so after a merge the merge base
+        // may not be on both branches; the last commit they both have is.
+        let shared: HashSet<_> = history.into_iter().collect();
+        let index = match commits.iter().position(|oid| *oid == base) {
+            Some(index) if shared.contains(&base) => Some(index),
+            _ => commits.iter().rposition(|oid| shared.contains(oid)),
+        };
+        if let Some(index) = index {
+            match &best {
+                Some((best, _)) if *best >= index => {}
+                _ => best = Some((index, candidate)),
+            }
+        }
+    }
+
+    let (index, branch) = match best {
+        Some(best) => best,
+        None => return Ok(None),
+    };
+    let oid = commits[index];
+    let last_exported = match db.last_exported_commit(&branch)? {
+        Some(last) => db.commit_index(&last, &branch)?,
+        None => None,
+    };
+    match db.commit_index(&oid, &branch)? {
+        Some(root_index) if matches!(last_exported, Some(last) if last >= root_index) => {
+            Ok(Some(Root {
+                branch,
+                oid,
+                index,
+                root_index,
+            }))
+        }
+        _ => anyhow::bail!(
+            "branch {} forked from {} at commit {}, which has not been exported yet; push {} first",
+            opt.branch(),
+            branch,
+            oid,
+            branch
+        ),
+    }
+}
+
+/// Returns the CVS revision of each file in the fork point on the branch it
+/// was exported on. The git paths of the files that don't have one, and so
+/// can't be put on the new CVS branch, are added to missing.
+fn root_revisions(
+    db: &Database,
+    root: &Root,
+    commit: &Commit,
+    target: &OsStr,
+    filter: state::Filter,
+    walker: &Walker,
+    missing: &mut Vec<PathBuf>,
+) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let mut state =
+        state::Global::new("", target, filter).with_path_map(exported_path_map(db, &root.branch)?);
+    seed_state(commit, &mut state, walker)?;
+
+    let files: Vec<_> = state
+        .iter_files()
+        .map(|(file, _)| (file.git_path().to_path_buf(), file.cvs_relative_path()))
+        .collect();
+    let mut revisions = db.revisions_at(
+        &root.branch,
+        Some(root.root_index),
+        files.iter().map(|(_, path)| path),
+    )?;
+
+    let mut found = Vec::new();
+    for (git_path, path) in files {
+        match revisions.remove(&path) {
+            Some(revision) => found.push((path, revision)),
+            None => {
+                log::warn!(
+                    "{} has no CVS revision at commit {}, so it won't be on the new branch",
+                    path.display(),
+                    root.oid
+                );
+                missing.push(git_path);
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Returns the path map a branch was exported with, which is empty if it
+/// wasn't given one.
+fn stored_path_map(db: &Database, git_branch: &str) -> anyhow::Result<PathMap> {
+    parse_stored_map(db.path_map(git_branch)?, "path map", git_branch)
+}
+
+/// Returns the paths a branch renamed to avoid case collisions, as a path map.
+fn stored_case_renames(db: &Database, git_branch: &str) -> anyhow::Result<PathMap> {
+    parse_stored_map(db.case_renames(git_branch)?, "case renames", git_branch)
+}
+
+/// Returns the paths a branch renamed so they wouldn't be taken for CVS
+/// directories, as a path map.
+fn stored_cvs_dir_renames(db: &Database, git_branch: &str) -> anyhow::Result<PathMap> {
+    parse_stored_map(
+        db.cvs_dir_renames(git_branch)?,
+        "CVS directory renames",
+        git_branch,
+    )
+}
+
+fn parse_stored_map(
+    stored: Option<String>,
+    what: &str,
+    git_branch: &str,
+) -> anyhow::Result<PathMap> {
+    match stored {
+        Some(map) => map
+            .parse()
+            .with_context(|| format!("cannot parse the {} stored for {}", what, git_branch)),
+        None => Ok(PathMap::default()),
+    }
+}
+
+/// Returns where a branch's files were actually exported to: its path map,
+/// along with any renames.
+pub fn exported_path_map(db: &Database, git_branch: &str) -> anyhow::Result<PathMap> {
+    let mut path_map = stored_path_map(db, git_branch)?;
+    path_map.extend(&stored_cvs_dir_renames(db, git_branch)?);
+    path_map.extend(&stored_case_renames(db, git_branch)?);
+    Ok(path_map)
+}
+
+/// Works out the CVS branch tag for a branch that's being exported for the
+/// first time, which is the one asked for with --cvs-branch if there is one,
+/// refusing tags that another git branch already has.
+pub fn cvs_branch_name(
+    db: &Database,
+    git_branch: &str,
+    requested: Option<&str>,
+    sanitise: cvs::Sanitise,
+) -> anyhow::Result<String> {
+    let cvs_branch = match requested {
+        Some(requested) => {
+            cvs::check_tag_name(requested).context("invalid --cvs-branch")?;
+            requested.to_string()
+        }
+        None => sanitise.apply(git_branch)?,
+    };
+    match db.lookup_tag(&cvs_branch)? {
+        Some(tag) if tag.git_branch != git_branch => anyhow::bail!(
+            "CVS branch tag {} for {} is already used by git branch {}",
+            cvs_branch,
+            git_branch,
+            tag.git_branch
+        ),
+        _ => Ok(cvs_branch),
+    }
+}
+
+/// Fails if a branch is being continued with a different sanitisation scheme
+/// than it was first exported with.
+fn check_sanitise_scheme(
+    db: &Database,
+    git_branch: &str,
+    sanitise: cvs::Sanitise,
+) -> anyhow::Result<()> {
+    let recorded = db
+        .sanitise_scheme(git_branch)?
+        .unwrap_or_else(|| cvs::Sanitise::Escape.as_str().to_string());
+    if recorded != sanitise.as_str() {
+        anyhow::bail!(
+            "branch {} was exported with --sanitise {}, not {}",
+            git_branch,
+            recorded,
+            sanitise.as_str()
+        );
+    }
+    Ok(())
+}
+
+/// Moves a time that's earlier than the latest time used so far to one second
+/// after it.
+fn clamp_time(time: i64, latest: Option<i64>) -> i64 {
+    match latest {
+        Some(latest) if time < latest => latest + 1,
+        _ => time,
+    }
+}
+
+/// Returns the re-added files whose keyword mode differs from the mode they had
+/// when they were removed, along with the mode they should now have.
+fn keyword_fixups<I>(
+    db: &Database,
+    git_branch: &str,
+    added: I,
+) -> anyhow::Result<Vec<(PathBuf, &'static str)>>
+where
+    I: Iterator<Item = (PathBuf, cvs::KeywordMode)>,
+{
+    let mut fixups = Vec::new();
+    for (path, mode) in added {
+        let mode = mode.as_str();
+        match db.removed_mode(git_branch, &path)? {
+            Some(previous) if previous != mode => fixups.push((path, mode)),
+            _ => {}
+        }
+    }
+
+    Ok(fixups)
+}
+
+/// The number of existing files to look for before refusing to export into a
+/// CVS module, and how many of them are shown.
+const EXISTING_CONTENT_LIMIT: usize = 100;
+const EXISTING_CONTENT_SAMPLE: usize = 5;
+
+/// Refuses to export into a target directory that CVS already has files in.
+/// Files elsewhere in the module are never touched, so they don't matter. The
+/// files that the first commit would overwrite are listed separately.
+fn check_empty(
+    cvs_repo: &cvs::Repository,
+    target: &Path,
+    repo: &Repository,
+    first: Option<&Commit>,
+) -> anyhow::Result<()> {
+    if !cvs_repo.cwd().join(target).is_dir() {
+        return Ok(());
+    }
+    let existing = cvs_repo.tracked_files(target, Some(EXISTING_CONTENT_LIMIT))?;
+    if existing.is_empty() {
+        return Ok(());
+    }
+
+    let list = |paths: &[&PathBuf]| {
+        paths
+            .iter()
+            .take(EXISTING_CONTENT_SAMPLE)
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut conflicts = Vec::new();
+    if let Some(first) = first {
+        for path in existing.iter() {
+            let git_path = path.strip_prefix(target).unwrap_or(path);
+            if repo.blob_at(&first.tree_id(), git_path)?.is_some() {
+                conflicts.push(path);
+            }
+        }
+    }
+
+    anyhow::bail!(
+        "{} in the CVS module already contains {}{} files, including {}{}; pass --merge-existing to keep the ones that aren't in git, or --allow-existing-content to replace them all",
+        target.display(),
+        if existing.len() >= EXISTING_CONTENT_LIMIT {
+            "at least "
+        } else {
+            ""
+        },
+        existing.len(),
+        list(&existing.iter().collect::<Vec<_>>()),
+        if conflicts.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "; {} of them are also in git and would be overwritten, including {}",
+                conflicts.len(),
+                list(&conflicts)
+            )
+        }
+    )
+}
+
+/// Tracks the files that CVS already has in the target directory without
+/// knowing their content, so that the first commit rewrites the ones that are
+/// in git and removes the rest.
+///
+/// If a tree to merge with is given, only the files that are also in it are
+/// tracked, so that the first commit modifies them rather than adding them,
+/// and the rest are left alone.
+fn seed_existing(
+    cvs_repo: &cvs::Repository,
+    target: &Path,
+    state: &mut state::Global,
+    merge_with: Option<(&Repository, git2::Oid)>,
+) -> anyhow::Result<()> {
+    let mut kept = Vec::new();
+    for path in cvs_repo.tracked_files(target, None)? {
+        let git_path = path.strip_prefix(target).unwrap_or(&path);
+        if state.scope(git_path) != state::Scope::Inside {
+            continue;
+        }
+        if let Some((repo, tree)) = merge_with {
+            if repo.blob_at(&tree, git_path)?.is_none() {
+                kept.push(path.display().to_string());
+                continue;
+            }
+        }
+        state.save_oid(state.file(git_path)?, &git2::Oid::zero());
+    }
+
+    if !kept.is_empty() {
+        log::info!(
+            "keeping {} files that are in CVS but not git: {}",
+            kept.len(),
+            kept.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Returns the git paths of the files that CVS has in the target directory
+/// but the state doesn't know about, such as those kept by --merge-existing.
+fn untracked_in_cvs(
+    cvs_repo: &cvs::Repository,
+    target: &Path,
+    state: &state::Global,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut untracked = Vec::new();
+    for path in cvs_repo.tracked_files(target, None)? {
+        let git_path = path.strip_prefix(target).unwrap_or(&path);
+        if state.scope(git_path) != state::Scope::Inside
+            || state.get_oid(&state.file(git_path)?).is_none()
+        {
+            untracked.push(git_path.to_path_buf());
+        }
+    }
+
+    Ok(untracked)
+}
+
+/// The things needed to write the trees of commits to the working copy.
+pub struct Walker<'a> {
+    pub repo: &'a Repository,
+    pub detector: &'a detect::Detector,
+    /// Set if gitlinks should be followed into submodules.
+    pub submodules: Option<&'a git::Submodules<'a>>,
+    /// Skip submodules that can't be read instead of failing.
+    pub skip_broken_submodules: bool,
+    /// Set if files written should be counted on the status line.
+    pub status: Option<&'a progress::StatusLine>,
+    pub symlinks: git::Symlinks,
+    /// What to do with submodules when they aren't followed.
+    pub submodule_policy: git::SubmodulePolicy,
+}
+
+impl Walker<'_> {
+    /// Loads the submodule at the given path, returning false if it's broken
+    /// and should be skipped.
+    fn load_submodule(&self, git_path: &Path, oid: &git2::Oid) -> anyhow::Result<bool> {
+        let submodules = match self.submodules {
+            Some(submodules) => submodules,
+            None => return Ok(false),
+        };
+
+        match submodules.load(git_path, oid) {
+            Ok(()) => Ok(true),
+            Err(e) if self.skip_broken_submodules => {
+                log::warn!("skipping submodule: {:#}", e);
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Corrects a state seeded from the last exported commit with what CVS/Entries
+/// and the database say is actually in the working copy: files CVS doesn't
+/// know about will be added again, and files last written from a different
+/// blob will be rewritten.
+fn apply_entries(
+    state: &mut state::Global,
+    checkout: &Path,
+    target: &OsStr,
+    stored: &HashMap<PathBuf, git2::Oid>,
+    filter: state::Filter,
+) -> anyhow::Result<()> {
+    let entries = cvs::scan_entries(&state.target_path())?;
+    if entries.is_empty() {
+        // Without CVS/Entries there's nothing to check against, which only
+        // happens when the working copy isn't a CVS checkout at all.
+        return Ok(());
+    }
+    let (from_entries, discrepancies) = state::Global::from_entries(
+        checkout,
+        target,
+        filter,
+        state.path_map().clone(),
+        &entries,
+        stored,
+    )?;
+    for discrepancy in &discrepancies {
+        log::warn!("{}", discrepancy);
+    }
+
+    let seeded: Vec<_> = state.iter_files().map(|(file, _)| file.clone()).collect();
+    for file in seeded {
+        if from_entries
+            .get_oid(&from_entries.file(file.git_path())?)
+            .is_none()
+        {
+            log::debug!("{:?} is not in CVS, so it will be added", file.git_path());
+            state.forget(&file);
+        }
+    }
+    for (file, oid) in from_entries.iter_files() {
+        let file = state.file(file.git_path())?;
+        if !oid.is_zero() && matches!(state.get_oid(&file), Some(seeded) if seeded != oid) {
+            log::debug!("{:?} was last written from {}", file.git_path(), oid);
+            state.save_oid(file, oid);
+        }
+    }
+
+    Ok(())
+}
+
+/// Populates the state with the files in a commit that has already been
+/// exported, without writing anything to the working copy.
+pub fn seed_state(
+    commit: &Commit,
+    state: &mut state::Global,
+    walker: &Walker,
+) -> anyhow::Result<()> {
+    seed_tree(&commit.tree_id(), Path::new(""), state, walker)?;
+    state.set_base(Some(commit.id()));
+    Ok(())
+}
+
+fn seed_tree(
+    tree: &git2::Oid,
+    prefix: &Path,
+    state: &mut state::Global,
+    walker: &Walker,
+) -> anyhow::Result<()> {
+    walker.repo.walk_tree(tree, |path, entry| {
+        let git_path = prefix.join(path).join(entry.file_name()?);
+
+        match state.scope(&git_path) {
+            state::Scope::Outside => return Ok(TreeWalkResult::Skip),
+            state::Scope::Inside
+                if entry.kind() == Some(ObjectType::Blob)
+                    && entry.filemode() == i32::from(git2::FileMode::Link) =>
+            {
+                // Only dereferenced symlinks were exported as files.
+                let resolved = match walker.symlinks {
+                    git::Symlinks::Dereference => walker
+                        .repo
+                        .resolve_symlink(tree, git_path.strip_prefix(prefix)?)?,
+                    _ => None,
+                };
+                if let Some((oid, mode)) = resolved {
+                    let file = state.file(git_path)?;
+                    state.set_mode(&file, mode);
+                    state.save_oid(file, &oid);
+                }
+            }
+            state::Scope::Inside if entry.kind() == Some(ObjectType::Blob) => {
+                let file = state.file(git_path)?;
+                state.set_mode(&file, entry.filemode());
+                state.save_oid(file, &entry.id());
+            }
+            state::Scope::Inside
+                if entry.kind() == Some(ObjectType::Commit)
+                    && walker.submodules.is_none()
+                    && walker.submodule_policy == git::SubmodulePolicy::Placeholder =>
+            {
+                let content = placeholder(walker.repo, (tree, prefix), &git_path, &entry.id())?;
+                let file = state.file(git_path)?;
+                state.set_mode(&file, i32::from(git2::FileMode::Blob));
+                state.save_oid(file, &git2::Oid::hash_object(ObjectType::Blob, &content)?);
+            }
+            _ if entry.kind() == Some(ObjectType::Commit)
+                && walker.load_submodule(&git_path, &entry.id())? =>
+            {
+                let tree = walker.repo.commit(&entry.id())?.tree_id();
+                seed_tree(&tree, &git_path, state, walker)?;
+            }
+            _ => {}
+        }
+
+        Ok(TreeWalkResult::Ok)
+    })
+}
+
+/// Writes a commit to the working copy. If the state reflects an earlier
+/// commit, only the paths that differ from it are touched; otherwise, the
+/// whole tree is walked.
+fn walk_commit(
+    commit: &Commit,
+    time: i64,
+    state: &mut state::Global,
+    commit_state: &mut state::Commit,
+    walker: &Walker,
+) -> anyhow::Result<()> {
+    // If the walk fails part way through, the state no longer reflects any
+    // one commit, so the next walk has to look at everything.
+    let result = match walk_base(walker, state) {
+        Some(base) => {
+            state.set_base(None);
+            walker
+                .repo
+                .commit(&base)
+                .map_err(anyhow::Error::from)
+                .and_then(|base| walk_diff(&base, commit, time, state, commit_state, walker))
+        }
+        None => walk_tree(
+            &commit.tree_id(),
+            Path::new(""),
+            time,
+            state,
+            commit_state,
+            walker,
+        )
+        .map(|()| commit_state.walked_whole_tree()),
+    };
+    result.with_context(|| format!("cannot export commit {}", commit.id()))?;
+
+    if walker.submodule_policy == git::SubmodulePolicy::Error {
+        let submodules: Vec<_> = commit_state
+            .iter_submodules()
+            .map(|path| path.display().to_string())
+            .collect();
+        if !submodules.is_empty() {
+            anyhow::bail!(
+                "commit {} has submodules, which --submodules error refuses: {}",
+                commit.id(),
+                submodules.join(", ")
+            );
+        }
+    }
+
+    state.set_base(Some(commit.id()));
+    Ok(())
+}
+
+/// Returns the commit the state reflects, if only what changed since then
+/// needs to be walked.
+fn walk_base(walker: &Walker, state: &state::Global) -> Option<git2::Oid> {
+    // A dereferenced symlink's target can change without the link changing, so
+    // only walking what changed could miss it.
+    match walker.symlinks {
+        git::Symlinks::Dereference => None,
+        _ => state.base(),
+    }
+}
+
+/// Brings the working copy from one commit to another, only touching the
+/// paths that differ between them.
+fn walk_diff(
+    base: &Commit,
+    commit: &Commit,
+    time: i64,
+    state: &mut state::Global,
+    commit_state: &mut state::Commit,
+    walker: &Walker,
+) -> anyhow::Result<()> {
+    for change in walker.repo.diff(Some(base), commit)? {
+        let path = &change.path;
+        if state.scope(path) == state::Scope::Outside {
+            if state.excludes(path) {
+                commit_state.excluded_path();
+            }
+            continue;
+        }
+
+        match (&change.old, &change.new) {
+            (Some(old), None) if old.kind() == Some(ObjectType::Commit) => {
+                // Everything exported from a submodule goes with it.
+                for file in state.unseen_files(commit_state, path) {
+                    commit_state.removed_file(file);
+                }
+            }
+            (Some(_), None) => {
+                let file = state.file(path)?;
+                if state.get_oid(&file).is_some() {
+                    commit_state.removed_file(file);
+                }
+            }
+            (_, Some(new)) => {
+                let parent = path.parent().unwrap_or_else(|| Path::new(""));
+                for dir in parent.ancestors().collect::<Vec<_>>().into_iter().rev() {
+                    if !dir.as_os_str().is_empty() {
+                        create_directory(state.file(dir)?, state, commit_state)?;
+                    }
+                }
+
+                let root = (&commit.tree_id(), Path::new(""));
+                walk_tree_entry(parent, new, root, time, state, commit_state, walker)
+                    .with_context(|| {
+                        format!(
+                            "cannot export {}",
+                            git::escape_bytes(path.as_os_str().as_bytes())
+                        )
+                    })?;
+
+                // A submodule's tree is walked in full, so anything it no
+                // longer has wasn't seen.
+                if new.kind() == Some(ObjectType::Commit) && walker.submodules.is_some() {
+                    for file in state.unseen_files(commit_state, path) {
+                        commit_state.removed_file(file);
+                    }
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the directories in the working copy that the commit turns into
+/// files, as git paths.
+fn replaced_directories(
+    walker: &Walker,
+    state: &state::Global,
+    commit: &Commit,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let repo = walker.repo;
+    let base = walk_base(walker, state);
+
+    // Only the paths that became files can have been directories before.
+    // Without a base to compare against, that means any directory that
+    // holds a file we wrote.
+    let candidates: BTreeSet<PathBuf> = match base {
+        Some(base) => repo
+            .diff(Some(&*repo.commit(&base)?), commit)?
+            .into_iter()
+            .filter(|change| {
+                change
+                    .new
+                    .as_ref()
+                    .is_some_and(|new| new.kind() == Some(ObjectType::Blob))
+            })
+            .map(|change| change.path)
+            .collect(),
+        None => {
+            let mut dirs = BTreeSet::new();
+            for (file, _) in state.iter_files() {
+                for dir in file.git_path().ancestors().skip(1) {
+                    if dir.as_os_str().is_empty() || !dirs.insert(dir.to_path_buf()) {
+                        break;
+                    }
+                }
+            }
+            let tree = commit.tree_id();
+            let mut files = BTreeSet::new();
+            for dir in dirs {
+                if repo.blob_at(&tree, &dir)?.is_some() {
+                    files.insert(dir);
+                }
+            }
+            files
+        }
+    };
+
+    let mut replaced = Vec::new();
+    for path in candidates {
+        if state.scope(&path) != state::Scope::Outside
+            && state.file(&path)?.absolute_path().is_dir()
+        {
+            replaced.push(path);
+        }
+    }
+    Ok(replaced)
+}
+
+/// Returns the git paths in scope that a commit has: its files, and its
+/// submodules as a whole.
+fn commit_paths(
+    walker: &Walker,
+    state: &state::Global,
+    commit: &Commit,
+) -> anyhow::Result<BTreeSet<PathBuf>> {
+    let repo = walker.repo;
+    let mut paths = BTreeSet::new();
+    match walk_base(walker, state) {
+        Some(base) => {
+            paths.extend(
+                state
+                    .iter_files()
+                    .map(|(file, _)| file.git_path().to_path_buf()),
+            );
+            for change in repo.diff(Some(&*repo.commit(&base)?), commit)? {
+                if state.scope(&change.path) == state::Scope::Outside {
+                    continue;
+                }
+                match (&change.old, &change.new) {
+                    (Some(old), None) if old.kind() == Some(ObjectType::Commit) => {
+                        paths.retain(|path| !path.starts_with(&change.path));
+                    }
+                    (Some(_), None) => {
+                        paths.remove(&change.path);
+                    }
+                    (_, Some(_)) => {
+                        paths.insert(change.path);
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+        None => {
+            repo.walk_tree(&commit.tree_id(), |path, entry| -> anyhow::Result<_> {
+                let git_path = path.join(entry.file_name()?);
+                match state.scope(&git_path) {
+                    state::Scope::Outside => return Ok(TreeWalkResult::Skip),
+                    state::Scope::Inside
+                        if matches!(
+                            entry.kind(),
+                            Some(ObjectType::Blob) | Some(ObjectType::Commit)
+                        ) =>
+                    {
+                        paths.insert(git_path);
+                    }
+                    _ => {}
+                }
+                Ok(TreeWalkResult::Ok)
+            })?;
+        }
+    }
+    Ok(paths)
+}
+
+/// Creates a directory in the working copy if it isn't already there. New
+/// directories have to be added to CVS along with the files in them.
+fn create_directory(
+    dir: state::File,
+    state: &state::Global,
+    commit_state: &mut state::Commit,
+) -> anyhow::Result<()> {
+    create_parents(&dir, state, commit_state)?;
+    let absolute = dir.absolute_path();
+    match fs::symlink_metadata(&absolute) {
+        Ok(metadata) if metadata.is_dir() => return Ok(()),
+        Ok(_) => {
+            // A file that the commit turns into a directory has to go first.
+            // It's removed from CVS along with the rest of the commit's
+            // removals, before the directory is added.
+            if state.get_oid(&dir).is_none() {
+                anyhow::bail!(
+                    "cannot create directory {}: there's a file in the way that git2cvs didn't write",
+                    absolute.display()
+                );
+            }
+            fs::remove_file(&absolute)
+                .with_context(|| format!("cannot delete {}", absolute.display()))?;
+            commit_state.removed_file(dir.clone());
+        }
+        Err(_) => {}
+    }
+
+    fs::create_dir_all(absolute)?;
+    commit_state.new_file(dir, cvs::KeywordMode::Default);
+    Ok(())
+}
+
+/// Creates the directories a mapped path needs in the working copy that git
+/// doesn't have, such as the new parent of a moved directory.
+fn create_parents(
+    file: &state::File,
+    state: &state::Global,
+    commit_state: &mut state::Commit,
+) -> anyhow::Result<()> {
+    if state.path_map().is_empty() {
+        return Ok(());
+    }
+
+    for (absolute, relative) in file.parents() {
+        if fs::metadata(&absolute).is_err() {
+            fs::create_dir(&absolute)?;
+            commit_state.new_directory(relative);
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a tree to the working copy, with its paths placed under prefix.
+fn walk_tree(
+    tree: &git2::Oid,
+    prefix: &Path,
+    time: i64,
+    state: &mut state::Global,
+    commit_state: &mut state::Commit,
+    walker: &Walker,
+) -> anyhow::Result<()> {
+    walker.repo.walk_tree(tree, |path, entry| {
+        let path = prefix.join(path);
+        walk_tree_entry(
+            &path,
+            entry,
+            (tree, prefix),
+            time,
+            state,
+            commit_state,
+            walker,
+        )
+        .with_context(|| {
+            let mut full = path.as_os_str().as_bytes().to_vec();
+            if !full.is_empty() && !full.ends_with(b"/") {
+                full.push(b'/');
+            }
+            full.extend_from_slice(entry.name_bytes());
+
+            format!("cannot export {}", git::escape_bytes(&full))
+        })
+    })
+}
+
+/// Writes a tree entry to the working copy. Symlinks are resolved within root,
+/// which is the tree being walked and the path it's placed under.
+fn walk_tree_entry(
+    path: &Path,
+    entry: &TreeEntry,
+    root: (&git2::Oid, &Path),
+    time: i64,
+    state: &mut state::Global,
+    commit_state: &mut state::Commit,
+    walker: &Walker,
+) -> anyhow::Result<TreeWalkResult> {
+    let Walker { repo, detector, .. } = *walker;
+    let git_path = path.join(entry.file_name()?);
+
+    // Only directories leading to an included path are of interest outside of
+    // the filter.
+    match (state.scope(&git_path), entry.kind()) {
+        (state::Scope::Outside, _) => {
+            if state.excludes(&git_path) {
+                commit_state.excluded_path();
+            }
+            return Ok(TreeWalkResult::Skip);
+        }
+        (state::Scope::Ancestor, kind)
+            if kind != Some(ObjectType::Tree) && kind != Some(ObjectType::Commit) =>
+        {
+            return Ok(TreeWalkResult::Ok)
+        }
+        _ => {}
+    }
+
+    // Anything not already renamed before the walk, such as a submodule's
+    // content, can only be refused.
+    if state.is_cvs_dir(&git_path) {
+        anyhow::bail!("CVS would take this for its own CVS directory");
+    }
+
+    // Gitlinks are either followed into the submodule's tree, or skipped below.
+    if entry.kind() == Some(ObjectType::Commit) && walker.submodules.is_some() {
+        if !walker.load_submodule(&git_path, &entry.id())? {
+            // Keep whatever we exported from the submodule last time.
+            let kept: Vec<_> = state
+                .iter_files()
+                .map(|(file, _)| file)
+                .filter(|file| file.git_path().starts_with(&git_path))
+                .cloned()
+                .collect();
+            for file in kept {
+                commit_state.seen_file(file);
+            }
+            commit_state.skipped_entry(
+                state.file(&git_path)?,
+                format!("cannot read submodule at {}", entry.id()),
+            );
+            return Ok(TreeWalkResult::Skip);
+        }
+
+        create_directory(state.file(&git_path)?, state, commit_state)?;
+
+        let tree = repo.commit(&entry.id())?.tree_id();
+        walk_tree(&tree, &git_path, time, state, commit_state, walker)?;
+        return Ok(TreeWalkResult::Skip);
+    }
+
+    let file = state.file(git_path)?;
+    let absolute = file.absolute_path();
+
+    match entry.kind() {
+        Some(ObjectType::Blob) => {
+            let (oid, filemode) = if entry.filemode() == i32::from(git2::FileMode::Link) {
+                match symlink(&file, entry, root, state, commit_state, walker)? {
+                    Some(target) => target,
+                    None => return Ok(TreeWalkResult::Ok),
+                }
+            } else {
+                (entry.id(), entry.filemode())
+            };
+
+            // Figure out if we need to write this: does the blob OID match the
+            // previously written OID for this file?
+            let maybe_oid = state.get_oid(&file).copied();
+            let executable = (filemode & 0o111) != 0;
+            let mode_changed = maybe_oid.is_some() && executable != state.is_executable(&file);
+            match maybe_oid {
+                Some(last_oid) if oid == last_oid => {
+                    // It does match, so we only need to fix the permissions if
+                    // the mode changed.
+                    if mode_changed {
+                        set_executable(&absolute, executable)?;
+                    }
+                }
+                _ => {
+                    // We need to write the file, either because it doesn't
+                    // exist or has new content. If we've already written the
+                    // same blob elsewhere, we can copy that rather than going
+                    // back to git.
+                    if maybe_oid.is_none() {
+                        create_parents(&file, state, commit_state)?;
+                    }
+                    let attributes = detector.detect_path(
+                        repo,
+                        Some(root),
+                        file.git_path(),
+                        &file.cvs_relative_path(),
+                    )?;
+                    let expands_keywords = |detection: &detect::Detection| {
+                        detector
+                            .keyword_mode(&file.cvs_relative_path(), detection)
+                            .expands_keywords()
+                    };
+                    let detection = match state.duplicate_of(&oid, attributes) {
+                        Some((source, detection, has_keywords)) => {
+                            let bytes = copy_file(&source.absolute_path(), &absolute)?;
+                            state.deduplicated(bytes);
+                            state.wrote_blob(
+                                &oid,
+                                file.clone(),
+                                detection,
+                                attributes,
+                                has_keywords,
+                                expands_keywords(&detection),
+                            );
+                            commit_state.written_file(
+                                file.clone(),
+                                bytes as usize,
+                                maybe_oid.is_none(),
+                                detection.binary,
+                            );
+                            detection
+                        }
+                        None => {
+                            let blob = repo.blob(&oid)?;
+                            let detection =
+                                attributes.unwrap_or_else(|| detector.detect_content(&blob));
+                            let content = detector.content(&detection, blob.content());
+                            fs::write(&absolute, &content)?;
+                            commit_state.written_file(
+                                file.clone(),
+                                content.len(),
+                                maybe_oid.is_none(),
+                                detection.binary,
+                            );
+                            state.wrote_blob(
+                                &oid,
+                                file.clone(),
+                                detection,
+                                attributes,
+                                content.contains(&b'$'),
+                                expands_keywords(&detection),
+                            );
+                            detection
+                        }
+                    };
+                    if let Some(status) = walker.status {
+                        status.file_written();
+                    }
+
+                    // CVS uses the modification time, so let's set
+                    // that.
+                    let time = FileTime::from_unix_time(time, 0);
+                    filetime::set_file_times(&absolute, time, time)?;
+
+                    // The file may be executable, or may have stopped being
+                    // executable, so let's check.
+                    set_executable(&absolute, executable)?;
+
+                    // If it's a new file, we need to inform CVS.
+                    if maybe_oid.is_none() {
+                        let cvs_path = file.cvs_relative_path();
+                        let mode = detector.keyword_mode(&cvs_path, &detection);
+                        log::debug!(
+                            "adding {:?} as {} ({}) with {}",
+                            cvs_path,
+                            if detection.binary { "binary" } else { "text" },
+                            detection.reason,
+                            mode
+                        );
+                        commit_state.new_file(file.clone(), mode);
+                    }
+
+                    // Finally, we'll store the OID that we just wrote to the
+                    // filesystem.
+                    state.save_oid(file.clone(), &oid);
+                }
+            };
+
+            if mode_changed {
+                commit_state.mode_changed(file.clone(), executable);
+            }
+            state.set_mode(&file, filemode);
+            commit_state.seen_file(file);
+            Ok(TreeWalkResult::Ok)
+        }
+        Some(ObjectType::Tree) => {
+            create_directory(file, state, commit_state)?;
+            Ok(TreeWalkResult::Ok)
+        }
+        Some(ObjectType::Commit) => {
+            submodule(&file, entry, root, time, state, commit_state, walker)?;
+            Ok(TreeWalkResult::Skip)
+        }
+        _ => {
+            log::trace!("unknown kind: {:?}", entry.kind());
+            commit_state.skipped_entry(file, format!("unsupported entry kind {:?}", entry.kind()));
+            Ok(TreeWalkResult::Skip)
+        }
+    }
+}
+
+/// Handles a submodule that isn't being followed according to --submodules.
+/// With --submodules error, the submodule is only noted here, so that the
+/// commit can fail with every submodule in it listed.
+fn submodule(
+    file: &state::File,
+    entry: &TreeEntry,
+    root: (&git2::Oid, &Path),
+    time: i64,
+    state: &mut state::Global,
+    commit_state: &mut state::Commit,
+    walker: &Walker,
+) -> anyhow::Result<()> {
+    commit_state.submodule(file.clone());
+    match walker.submodule_policy {
+        git::SubmodulePolicy::Skip => {
+            log::warn!(
+                "skipping submodule {} at {}",
+                file.git_path().display(),
+                entry.id()
+            );
+            commit_state.skipped_entry(file.clone(), format!("submodule at {}", entry.id()));
+            if state.get_oid(file).is_some() {
+                commit_state.removed_file(file.clone());
+            }
+        }
+        git::SubmodulePolicy::Error => {}
+        git::SubmodulePolicy::Placeholder => {
+            let content = placeholder(walker.repo, root, file.git_path(), &entry.id())?;
+            let oid = git2::Oid::hash_object(ObjectType::Blob, &content)?;
+            let previous = state.get_oid(file).copied();
+            if previous != Some(oid) {
+                let absolute = file.absolute_path();
+                fs::write(&absolute, &content)?;
+                let time = FileTime::from_unix_time(time, 0);
+                filetime::set_file_times(&absolute, time, time)?;
+
+                log::debug!(
+                    "writing placeholder for submodule {:?}",
+                    file.cvs_relative_path()
+                );
+                commit_state.written_file(file.clone(), content.len(), previous.is_none(), false);
+                if previous.is_none() {
+                    commit_state.new_file(file.clone(), cvs::KeywordMode::Default);
+                }
+                state.save_oid(file.clone(), &oid);
+                if let Some(status) = walker.status {
+                    status.file_written();
+                }
+            }
+
+            state.set_mode(file, i32::from(git2::FileMode::Blob));
+            commit_state.seen_file(file.clone());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the content of the file exported in place of a submodule with
+/// --submodules placeholder. The URL comes from .gitmodules in root.
+fn placeholder(
+    repo: &Repository,
+    root: (&git2::Oid, &Path),
+    git_path: &Path,
+    oid: &git2::Oid,
+) -> anyhow::Result<Vec<u8>> {
+    let url = repo.submodule_url(root.0, git_path.strip_prefix(root.1)?)?;
+    Ok(format!(
+        "This is a placeholder for the git submodule at {}, which is not exported to CVS.\n\nURL: {}\nCommit: {}\n",
+        git_path.display(),
+        url.as_deref().unwrap_or("unknown"),
+        oid
+    )
+    .into_bytes())
+}
+
+/// Handles a symlink according to --symlinks, returning the blob and mode to
+/// write in its place, or None if it's left out. A file that was exported at
+/// the same path before is removed.
+fn symlink(
+    file: &state::File,
+    entry: &TreeEntry,
+    root: (&git2::Oid, &Path),
+    state: &state::Global,
+    commit_state: &mut state::Commit,
+    walker: &Walker,
+) -> anyhow::Result<Option<(git2::Oid, i32)>> {
+    let target = walker.repo.blob(&entry.id())?.content().to_vec();
+    let escaped = git::escape_bytes(&target);
+
+    let resolved = match walker.symlinks {
+        git::Symlinks::Skip => None,
+        git::Symlinks::Dereference => {
+            let path = file.git_path().strip_prefix(root.1)?;
+            walker.repo.resolve_symlink(root.0, path)?
+        }
+        git::Symlinks::Materialise => {
+            let absolute = file.absolute_path();
+            if fs::symlink_metadata(&absolute).is_ok() {
+                fs::remove_file(&absolute)?;
+            }
+            std::os::unix::fs::symlink(OsStr::from_bytes(&target), &absolute)?;
+            anyhow::bail!(
+                "CVS cannot store symlinks; created {} -> {} in the working copy, but pass --symlinks skip or --symlinks dereference to export it",
+                file.git_path().display(),
+                escaped
+            );
+        }
+    };
+
+    commit_state.symlink(file.clone(), resolved.is_some());
+    if resolved.is_none() {
+        log::warn!(
+            "skipping symlink {} -> {}",
+            file.git_path().display(),
+            escaped
+        );
+        commit_state.skipped_entry(
+            file.clone(),
+            match walker.symlinks {
+                git::Symlinks::Skip => format!("symlink to {}", escaped),
+                _ => format!("symlink to {} does not lead to a file", escaped),
+            },
+        );
+        if state.get_oid(file).is_some() {
+            commit_state.removed_file(file.clone());
+        }
+    }
+
+    Ok(resolved)
+}
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+    use crate::{
+        parse_args,
+        test_support::{entries_cvs, fake_push_opt, summary_logging_cvs, CvsFixture, GitBuilder},
+        Opt,
+    };
+
+    /// Commits the given files, replacing the entire tree, on top of HEAD.
+    fn commit_files(git: &mut GitBuilder, files: &[(&str, &[u8])]) -> git2::Oid {
+        git.clear();
+        for (path, content) in files {
+            git.file(path, content);
+        }
+        git.commit("commit")
+    }
+
+    struct Walked {
+        changes: Vec<(Vec<PathBuf>, Vec<PathBuf>)>,
+        content: Vec<(PathBuf, Vec<u8>)>,
+        hits: u64,
+        deduplicated: (usize, u64),
+    }
+
+    /// Walks every commit in order, returning the files added and removed by
+    /// each commit and the final content of the working copy.
+    fn export_walk(git_dir: &Path, commits: &[git2::Oid], cache: usize) -> Walked {
+        export_walk_with(git_dir, commits, cache, false)
+    }
+
+    fn export_walk_with(
+        git_dir: &Path,
+        commits: &[git2::Oid],
+        cache: usize,
+        recurse_submodules: bool,
+    ) -> Walked {
+        let repo = Repository::open(git_dir, cache).unwrap();
+        let cvs_dir = tempdir().unwrap();
+        fs::create_dir_all(cvs_dir.path().join("src")).unwrap();
+        let mut state = state::Global::new(cvs_dir.path(), "src", state::Filter::default());
+        let detector = detect::Detector::default();
+        let submodules = git::Submodules::new(&repo);
+        let walker = Walker {
+            repo: &repo,
+            detector: &detector,
+            submodules: if recurse_submodules {
+                Some(&submodules)
+            } else {
+                None
+            },
+            skip_broken_submodules: false,
+            status: None,
+            symlinks: git::Symlinks::Skip,
+            submodule_policy: git::SubmodulePolicy::Skip,
+        };
+
+        let mut changes = Vec::new();
+        for oid in commits {
+            let commit = repo.commit(oid).unwrap();
+            let mut commit_state = state::Commit::new();
+            walk_commit(
+                &commit,
+                commit.time().seconds(),
+                &mut state,
+                &mut commit_state,
+                &walker,
+            )
+            .unwrap();
+
+            let removed = state.remove_files(&commit_state);
+            delete_files(&removed).unwrap();
+            let removed = removed
+                .iter()
+                .map(|file| file.cvs_relative_path())
+                .collect();
+            let added = commit_state
+                .iter_new_files()
+                .map(|(file, _)| file.cvs_relative_path())
+                .collect();
+            changes.push((added, removed));
+        }
+
+        let mut content = Vec::new();
+        let mut dirs = vec![cvs_dir.path().to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    let relative = path.strip_prefix(cvs_dir.path()).unwrap().to_path_buf();
+                    content.push((relative, fs::read(&path).unwrap()));
+                }
+            }
+        }
+        content.sort();
+
+        Walked {
+            changes,
+            content,
+            hits: repo.commit_cache_stats().hits + repo.tree_cache_stats().hits,
+            deduplicated: state.deduplication_stats(),
+        }
+    }
+
+    #[test]
+    fn test_exclude() {
+        let mut git = GitBuilder::new();
+        let files: &[(&str, &[u8])] = &[
+            ("third_party/lib/x.c", b"x\n"),
+            ("third_party.txt", b"t\n"),
+            ("gen.c", b"g\n"),
+            ("src/a.c", b"a\n"),
+            ("src/gen.c", b"g\n"),
+        ];
+        let first = commit_files(&mut git, files);
+        let second = commit_files(
+            &mut git,
+            &[
+                ("third_party/lib/x.c", b"x2\n"),
+                ("third_party.txt", b"t\n"),
+                ("gen.c", b"g2\n"),
+                ("src/a.c", b"a2\n"),
+                ("src/gen.c", b"g\n"),
+            ],
+        );
+
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let cvs_dir = tempdir().unwrap();
+        let excludes: Vec<_> = ["third_party/", "/gen.c", "nothing*"]
+            .iter()
+            .map(|glob| state::parse_exclude(glob).unwrap())
+            .collect();
+        let mut state = state::Global::new(
+            cvs_dir.path(),
+            ".",
+            state::Filter::default().with_excludes(&excludes),
+        );
+        let walker = Walker {
+            repo: &repo,
+            detector: &detect::Detector::default(),
+            submodules: None,
+            skip_broken_submodules: false,
+            status: None,
+            symlinks: git::Symlinks::Skip,
+            submodule_policy: git::SubmodulePolicy::Skip,
+        };
+
+        // The whole tree is walked for the first commit, so the excluded
+        // directory is skipped as a whole.
+        let mut commit_state = state::Commit::new();
+        walk_commit(
+            &repo.commit(&first).unwrap(),
+            0,
+            &mut state,
+            &mut commit_state,
+            &walker,
+        )
+        .unwrap();
+        assert_eq!(2, commit_state.paths_excluded());
+        let mut known: Vec<_> = state
+            .iter_files()
+            .map(|(file, _)| file.git_path().to_path_buf())
+            .collect();
+        known.sort();
+        assert_eq!(
+            vec![
+                PathBuf::from("src/a.c"),
+                "src/gen.c".into(),
+                "third_party.txt".into()
+            ],
+            known
+        );
+        assert!(!cvs_dir.path().join("third_party").exists());
+        assert!(!cvs_dir.path().join("gen.c").exists());
+
+        // Only what changed is walked for the next one.
+        let mut commit_state = state::Commit::new();
+        walk_commit(
+            &repo.commit(&second).unwrap(),
+            0,
+            &mut state,
+            &mut commit_state,
+            &walker,
+        )
+        .unwrap();
+        assert_eq!(2, commit_state.paths_excluded());
+        assert_eq!(
+            vec![Path::new("src/a.c")],
+            commit_state
+                .iter_written_files()
+                .map(|file| file.git_path())
+                .collect::<Vec<_>>()
+        );
+        assert!(state.remove_files(&commit_state).is_empty());
+    }
+
+    #[test]
+    fn test_cache_equivalence() {
+        let mut git = GitBuilder::new();
+
+        let commits = vec![
+            commit_files(&mut git, &[("a.txt", b"a\n"), ("shared/b.txt", b"b\n")]),
+            commit_files(
+                &mut git,
+                &[
+                    ("a.txt", b"a2\n"),
+                    ("shared/b.txt", b"b\n"),
+                    ("c/d.txt", b"d\n"),
+                ],
+            ),
+            commit_files(&mut git, &[("shared/b.txt", b"b\n"), ("c/d.txt", b"d\n")]),
+            commit_files(&mut git, &[("a.txt", b"a3\n"), ("shared/b.txt", b"b\n")]),
+        ];
+
+        let uncached = export_walk(git.path(), &commits, 0);
+        let cached = export_walk(git.path(), &commits, 16);
+
+        assert_eq!(uncached.changes, cached.changes);
+        assert_eq!(uncached.content, cached.content);
+        assert_eq!(0, uncached.hits);
+        assert!(cached.hits > 0);
+    }
+
+    /// Renders the cvs add and remove commands that would be run for each
+    /// commit.
+    fn commands(walked: &Walked) -> Vec<u8> {
+        let mut commands = Vec::new();
+        for (added, removed) in &walked.changes {
+            for (command, paths) in &[("add", added), ("remove", removed)] {
+                commands.extend_from_slice(command.as_bytes());
+                for path in paths.iter() {
+                    commands.push(b' ');
+                    commands.extend_from_slice(path.as_os_str().as_bytes());
+                }
+                commands.push(b'\n');
+            }
+        }
+        commands
+    }
+
+    #[test]
+    fn test_skipped_commit_folded() {
+        let mut git = GitBuilder::new();
+        let base = commit_files(&mut git, &[("a.txt", b"a\n")]);
+        let _dataset = commit_files(&mut git, &[("a.txt", b"a\n"), ("data.bin", b"huge")]);
+        let revert = commit_files(&mut git, &[("a.txt", b"a\n")]);
+        let after = commit_files(&mut git, &[("a.txt", b"a\n"), ("b.txt", b"b\n")]);
+
+        // Skipping a commit means the next commit is walked against the state
+        // of the last exported one, so a revert of a skipped commit is empty.
+        let walked = export_walk(git.path(), &[base, revert, after], 0);
+        assert_eq!(
+            vec![
+                (vec![PathBuf::from("src/a.txt")], vec![]),
+                (vec![], vec![]),
+                (vec![PathBuf::from("src/b.txt")], vec![]),
+            ],
+            walked.changes
+        );
+        assert!(walked
+            .content
+            .iter()
+            .all(|(path, _)| !path.ends_with("data.bin")));
+    }
+
+    #[test]
+    fn test_remove_tree() {
+        let mut git = GitBuilder::new();
+        let base = commit_files(
+            &mut git,
+            &[
+                ("a/b/c/deep.txt", b"deep\n"),
+                ("a/b/c/deeper.txt", b"deeper\n"),
+                ("a/b/middle.txt", b"middle\n"),
+                ("a/top.txt", b"top\n"),
+                ("a-file.txt", b"beside\n"),
+                ("z.txt", b"z\n"),
+            ],
+        );
+        let removed = commit_files(&mut git, &[("z.txt", b"z\n")]);
+
+        // The tree goes from the bottom up, with each level in path order.
+        let walked = export_walk(git.path(), &[base, removed], 0);
+        assert_eq!(
+            vec![
+                PathBuf::from("src/a/b/c/deep.txt"),
+                PathBuf::from("src/a/b/c/deeper.txt"),
+                PathBuf::from("src/a/b/middle.txt"),
+                PathBuf::from("src/a/top.txt"),
+                PathBuf::from("src/a-file.txt"),
+            ],
+            walked.changes[1].1
+        );
+        assert_eq!(
+            vec![(PathBuf::from("src/z.txt"), b"z\n".to_vec())],
+            walked.content
+        );
+    }
+
+    #[test]
+    fn test_deterministic_batches() {
+        let mut git = GitBuilder::new();
+
+        let names: Vec<_> = (0..64)
+            .map(|i| format!("{}/file-{}.txt", ["a", "a-b", "b/c"][i % 3], i))
+            .collect();
+        let all: Vec<_> = names
+            .iter()
+            .map(|name| (name.as_str(), &b"content\n"[..]))
+            .collect();
+        let commits = vec![
+            commit_files(&mut git, &all),
+            commit_files(&mut git, &all[..all.len() / 4]),
+        ];
+
+        let first = export_walk(git.path(), &commits, 0);
+        let second = export_walk(git.path(), &commits, 0);
+        assert_eq!(commands(&first), commands(&second));
+
+        let (added, removed) = &first.changes[0];
+        assert!(removed.is_empty());
+        assert_eq!(
+            Path::new("src/a"),
+            added[0],
+            "directories must be added before their files"
+        );
+        let mut sorted = added.clone();
+        sorted.sort();
+        assert_eq!(&sorted, added);
+
+        let (added, removed) = &first.changes[1];
+        assert!(added.is_empty());
+        assert_eq!(48, removed.len());
+        let mut sorted = removed.clone();
+        sorted.sort_by_key(|path| (std::cmp::Reverse(path.components().count()), path.clone()));
+        assert_eq!(&sorted, removed, "removals must go deepest first");
+    }
+
+    #[test]
+    fn test_non_utf8_names() {
+        let mut git = GitBuilder::new();
+
+        // Latin-1 names, as an old repository converted from elsewhere might
+        // have.
+        let commit = git
+            .file(&b"r\xe9sum\xe9/caf\xe9.txt"[..], "bonjour\n")
+            .file("plain.txt", "bonjour\n")
+            .commit("latin-1");
+
+        let walked = export_walk(git.path(), &[commit], 0);
+        assert_eq!(
+            vec![
+                PathBuf::from("src/plain.txt"),
+                PathBuf::from(OsStr::from_bytes(b"src/r\xe9sum\xe9")),
+                PathBuf::from(OsStr::from_bytes(b"src/r\xe9sum\xe9/caf\xe9.txt")),
+            ],
+            walked.changes[0].0
+        );
+        assert_eq!(
+            vec![
+                (PathBuf::from("src/plain.txt"), b"bonjour\n".to_vec()),
+                (
+                    PathBuf::from(OsStr::from_bytes(b"src/r\xe9sum\xe9/caf\xe9.txt")),
+                    b"bonjour\n".to_vec()
+                ),
+            ],
+            walked.content
+        );
+    }
+
+    #[test]
+    fn test_deduplication() {
+        let mut git = GitBuilder::new();
+        let vendored = &b"vendored\n"[..];
+        // CVS expands the keyword in the working copy on commit, so id.c is
+        // always written from git.
+        let keyword = &b"/* $Id$ */\n"[..];
+        let commits = vec![
+            commit_files(
+                &mut git,
+                &[
+                    ("a/id.c", keyword),
+                    ("a/lib.c", vendored),
+                    ("b/id.c", keyword),
+                    ("b/lib.c", vendored),
+                    ("c.c", b"c\n"),
+                ],
+            ),
+            // a/lib.c no longer has the blob, so d/lib.c has to come from
+            // b/lib.c.
+            commit_files(
+                &mut git,
+                &[
+                    ("a/id.c", keyword),
+                    ("a/lib.c", b"patched\n"),
+                    ("b/id.c", keyword),
+                    ("b/lib.c", vendored),
+                    ("c.c", b"c\n"),
+                    ("d/id.c", keyword),
+                    ("d/lib.c", vendored),
+                ],
+            ),
+        ];
+
+        let walked = export_walk(git.path(), &commits, 0);
+        assert_eq!((2, 2 * vendored.len() as u64), walked.deduplicated);
+        let mut content = walked.content;
+        content.sort();
+        assert_eq!(
+            vec![
+                (PathBuf::from("src/a/id.c"), keyword.to_vec()),
+                (PathBuf::from("src/a/lib.c"), b"patched\n".to_vec()),
+                (PathBuf::from("src/b/id.c"), keyword.to_vec()),
+                (PathBuf::from("src/b/lib.c"), vendored.to_vec()),
+                (PathBuf::from("src/c.c"), b"c\n".to_vec()),
+                (PathBuf::from("src/d/id.c"), keyword.to_vec()),
+                (PathBuf::from("src/d/lib.c"), vendored.to_vec()),
+            ],
+            content
+        );
+    }
+
+    /// Commits a tree with a gitlink to the given submodule commit at lib, or
+    /// no submodule at all, on top of HEAD.
+    fn commit_gitlink(raw: &git2::Repository, submodule: Option<git2::Oid>) -> git2::Oid {
+        let mut tree = raw.treebuilder(None).unwrap();
+        tree.insert("top.txt", raw.blob(b"top\n").unwrap(), 0o100644)
+            .unwrap();
+        if let Some(oid) = submodule {
+            let gitmodules = b"[submodule \"lib\"]\n\tpath = lib\n\turl = ./lib\n";
+            tree.insert(".gitmodules", raw.blob(gitmodules).unwrap(), 0o100644)
+                .unwrap();
+            tree.insert("lib", oid, 0o160000).unwrap();
+        }
+        let tree = raw.find_tree(tree.write().unwrap()).unwrap();
+
+        let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+        let parent = raw.head().ok().map(|head| head.peel_to_commit().unwrap());
+        raw.commit(
+            Some("HEAD"),
+            &sig,
+            &sig,
+            "gitlink",
+            &tree,
+            parent.iter().collect::<Vec<_>>().as_slice(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_submodules() {
+        let git = GitBuilder::new();
+        let mut sub = GitBuilder::init(git.path().join("lib"));
+        fs::write(
+            git.path().join(".gitmodules"),
+            "[submodule \"lib\"]\n\tpath = lib\n\turl = ./lib\n",
+        )
+        .unwrap();
+
+        let first = commit_files(&mut sub, &[("a.txt", b"a\n")]);
+        let second = commit_files(&mut sub, &[("a.txt", b"a2\n"), ("b/c.txt", b"c\n")]);
+        let commits = vec![
+            commit_gitlink(git.raw(), Some(first)),
+            commit_gitlink(git.raw(), Some(second)),
+            commit_gitlink(git.raw(), None),
+        ];
+
+        let walked = export_walk_with(git.path(), &commits, 0, true);
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    paths(&["src/.gitmodules", "src/lib", "src/lib/a.txt", "src/top.txt"]),
+                    vec![]
+                ),
+                (paths(&["src/lib/b", "src/lib/b/c.txt"]), vec![]),
+                (
+                    vec![],
+                    paths(&["src/lib/b/c.txt", "src/lib/a.txt", "src/.gitmodules"])
+                ),
+            ],
+            walked.changes
+        );
+
+        // Without recursion, the gitlink is skipped as before.
+        let walked = export_walk(git.path(), &commits[..1], 0);
+        assert_eq!(
+            paths(&["src/.gitmodules", "src/top.txt"]),
+            walked.changes[0].0
+        );
+
+        // A pinned commit that the submodule doesn't have is an error that
+        // names both.
+        let missing = git2::Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+        let commit = commit_gitlink(git.raw(), Some(missing));
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let cvs_dir = tempdir().unwrap();
+        let mut state = state::Global::new(cvs_dir.path(), ".", state::Filter::default());
+        let submodules = git::Submodules::new(&repo);
+        let mut walker = Walker {
+            repo: &repo,
+            detector: &detect::Detector::default(),
+            submodules: Some(&submodules),
+            skip_broken_submodules: false,
+            status: None,
+            symlinks: git::Symlinks::Skip,
+            submodule_policy: git::SubmodulePolicy::Skip,
+        };
+        let commit = repo.commit(&commit).unwrap();
+        let message = format!(
+            "{:#}",
+            walk_commit(&commit, 0, &mut state, &mut state::Commit::new(), &walker).unwrap_err()
+        );
+        assert!(message.contains("lib"), "{}", message);
+        assert!(message.contains(&missing.to_string()), "{}", message);
+
+        walker.skip_broken_submodules = true;
+        let mut commit_state = state::Commit::new();
+        walk_commit(&commit, 0, &mut state, &mut commit_state, &walker).unwrap();
+        assert_eq!(1, commit_state.iter_skipped_entries().count());
+    }
+
+    #[test]
+    fn test_submodule_policies() {
+        let git = GitBuilder::new();
+        let mut sub = GitBuilder::init(git.path().join("lib"));
+        let first = commit_files(&mut sub, &[("a.txt", b"a\n")]);
+        let second = commit_files(&mut sub, &[("a.txt", b"a2\n")]);
+        let commits = [
+            commit_gitlink(git.raw(), Some(first)),
+            commit_gitlink(git.raw(), Some(second)),
+            commit_gitlink(git.raw(), None),
+        ];
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let detector = detect::Detector::default();
+
+        let cvs_dir = tempdir().unwrap();
+        let mut state = state::Global::new(cvs_dir.path(), ".", state::Filter::default());
+        let mut walker = Walker {
+            repo: &repo,
+            detector: &detector,
+            submodules: None,
+            skip_broken_submodules: false,
+            status: None,
+            symlinks: git::Symlinks::Skip,
+            submodule_policy: git::SubmodulePolicy::Error,
+        };
+        let commit = repo.commit(&commits[0]).unwrap();
+        let message = format!(
+            "{:#}",
+            walk_commit(&commit, 0, &mut state, &mut state::Commit::new(), &walker).unwrap_err()
+        );
+        assert!(
+            message.contains("--submodules error refuses: lib"),
+            "{}",
+            message
+        );
+
+        walker.submodule_policy = git::SubmodulePolicy::Skip;
+        let mut commit_state = state::Commit::new();
+        walk_commit(&commit, 0, &mut state, &mut commit_state, &walker).unwrap();
+        assert_eq!(
+            vec![(Path::new("lib"), format!("submodule at {}", first).as_str())],
+            commit_state.iter_skipped_entries().collect::<Vec<_>>()
+        );
+        assert!(!cvs_dir.path().join("lib").exists());
+
+        // Placeholders are added, rewritten when the pinned commit changes, and
+        // removed along with the submodule.
+        let cvs_dir = tempdir().unwrap();
+        let mut state = state::Global::new(cvs_dir.path(), ".", state::Filter::default());
+        walker.submodule_policy = git::SubmodulePolicy::Placeholder;
+        let mut changes = Vec::new();
+        for oid in commits.iter() {
+            let commit = repo.commit(oid).unwrap();
+            let mut commit_state = state::Commit::new();
+            walk_commit(&commit, 0, &mut state, &mut commit_state, &walker).unwrap();
+            let removed = state.remove_files(&commit_state);
+            delete_files(&removed).unwrap();
+            changes.push((
+                commit_state.files_added(),
+                commit_state.files_modified(),
+                removed.len(),
+            ));
+            if oid == &commits[1] {
+                let placeholder = fs::read_to_string(cvs_dir.path().join("lib")).unwrap();
+                assert!(placeholder.contains("URL: ./lib\n"), "{}", placeholder);
+                assert!(
+                    placeholder.contains(&format!("Commit: {}\n", second)),
+                    "{}",
+                    placeholder
+                );
+            }
+        }
+        // The first commit also adds .gitmodules and top.txt, and the last
+        // removes .gitmodules.
+        assert_eq!(vec![(3, 0, 0), (0, 1, 0), (0, 0, 2)], changes);
+        assert!(!cvs_dir.path().join("lib").exists());
+    }
+
+    #[test]
+    fn test_attributes_from_tree() {
+        let mut git = GitBuilder::new();
+        git.file(
+            ".gitattributes",
+            "*.pdf binary\n*.dat -text\nforce.txt text\n",
+        )
+        .file("a.pdf", "%PDF-1.4 looks like text")
+        .file("b.dat", "plain ascii")
+        .binary("force.txt", "has a NUL");
+        let first = git.commit("with attributes");
+        git.remove(".gitattributes").file("c.pdf", "%PDF-1.4 again");
+        let second = git.commit("without attributes");
+
+        // The working directory never has a .gitattributes, so these can only
+        // come from the trees.
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let cvs_dir = tempdir().unwrap();
+        let mut state = state::Global::new(cvs_dir.path(), ".", state::Filter::default());
+        let walker = Walker {
+            repo: &repo,
+            detector: &detect::Detector::default(),
+            submodules: None,
+            skip_broken_submodules: false,
+            status: None,
+            symlinks: git::Symlinks::Skip,
+            submodule_policy: git::SubmodulePolicy::Skip,
+        };
+        let mut new_files = Vec::new();
+        for oid in [first, second] {
+            let commit = repo.commit(&oid).unwrap();
+            let mut commit_state = state::Commit::new();
+            walk_commit(&commit, 0, &mut state, &mut commit_state, &walker).unwrap();
+            let paths = |files: &mut dyn Iterator<Item = &state::File>| {
+                files
+                    .map(|file| file.git_path().to_path_buf())
+                    .collect::<Vec<_>>()
+            };
+            new_files.push((
+                paths(&mut commit_state.iter_new_files_with_mode(cvs::KeywordMode::Binary)),
+                paths(&mut commit_state.iter_new_files_with_mode(cvs::KeywordMode::Default)),
+            ));
+        }
+
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    paths(&["a.pdf", "b.dat"]),
+                    paths(&[".gitattributes", "force.txt"])
+                ),
+                (vec![], paths(&["c.pdf"])),
+            ],
+            new_files
+        );
+    }
+
+    #[test]
+    fn test_symlinks() {
+        let mut git = GitBuilder::new();
+        git.file("a.txt", "hello")
+            .symlink("link", "a.txt")
+            .symlink("dir/up", "../a.txt")
+            .symlink("dangling", "missing.txt");
+        let first = git.commit("first");
+        let second = git.file("a.txt", "world").commit("second");
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let detector = detect::Detector::default();
+
+        let walk = |symlinks| {
+            let cvs_dir = tempdir().unwrap();
+            fs::create_dir_all(cvs_dir.path().join("src")).unwrap();
+            let mut state = state::Global::new(cvs_dir.path(), "src", state::Filter::default());
+            let walker = Walker {
+                repo: &repo,
+                detector: &detector,
+                submodules: None,
+                skip_broken_submodules: false,
+                status: None,
+                symlinks,
+                submodule_policy: git::SubmodulePolicy::Skip,
+            };
+
+            let mut results = Vec::new();
+            for oid in [first, second] {
+                let commit = repo.commit(&oid).unwrap();
+                let mut commit_state = state::Commit::new();
+                let result = walk_commit(&commit, 0, &mut state, &mut commit_state, &walker);
+                let symlinks: Vec<_> = commit_state
+                    .iter_symlinks()
+                    .map(|(path, dereferenced)| (path.to_path_buf(), dereferenced))
+                    .collect();
+                results.push((
+                    result.map(|()| symlinks),
+                    commit_state.iter_skipped_entries().count(),
+                ));
+                if results.last().unwrap().0.is_err() {
+                    break;
+                }
+            }
+            (cvs_dir, results)
+        };
+        let read =
+            |dir: &tempfile::TempDir, path: &str| fs::read(dir.path().join("src").join(path)).ok();
+
+        // Skipped symlinks aren't written at all.
+        let (dir, results) = walk(git::Symlinks::Skip);
+        let (symlinks, skipped) = &results[0];
+        assert_eq!(
+            3,
+            symlinks
+                .as_ref()
+                .unwrap()
+                .iter()
+                .filter(|(_, d)| !d)
+                .count()
+        );
+        assert_eq!(3, *skipped);
+        assert_eq!(Some(b"world".to_vec()), read(&dir, "a.txt"));
+        assert_eq!(None, read(&dir, "link"));
+        assert_eq!(None, read(&dir, "dir/up"));
+
+        // Dereferenced symlinks follow their target, even when only the target
+        // changes; the dangling one is still skipped.
+        let (dir, results) = walk(git::Symlinks::Dereference);
+        assert_eq!(
+            vec![
+                (PathBuf::from("dangling"), false),
+                (PathBuf::from("dir/up"), true),
+                (PathBuf::from("link"), true),
+            ],
+            {
+                let mut symlinks = results[0].0.as_ref().unwrap().clone();
+                symlinks.sort();
+                symlinks
+            }
+        );
+        assert_eq!(1, results[0].1);
+        assert_eq!(Some(b"world".to_vec()), read(&dir, "link"));
+        assert_eq!(Some(b"world".to_vec()), read(&dir, "dir/up"));
+        assert_eq!(None, read(&dir, "dangling"));
+
+        // Materialised symlinks are created, but fail the commit.
+        let (dir, results) = walk(git::Symlinks::Materialise);
+        assert_eq!(1, results.len());
+        let message = format!("{:#}", results[0].0.as_ref().unwrap_err());
+        assert!(message.contains("CVS cannot store symlinks"), "{}", message);
+        // Tree entries are sorted, so the dangling link is the first one.
+        assert!(fs::symlink_metadata(dir.path().join("src/dangling"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    }
+
+    #[test]
+    fn test_clamp_time() {
+        assert_eq!(100, clamp_time(100, None));
+        assert_eq!(100, clamp_time(100, Some(50)));
+        assert_eq!(100, clamp_time(100, Some(100)));
+        assert_eq!(101, clamp_time(40, Some(100)));
+    }
+
+    #[test]
+    fn test_keyword_fixups() {
+        let mut db = Database::open(":memory:").unwrap();
+        let path = PathBuf::from("src/file");
+        let fixups = |db: &Database, mode| {
+            keyword_fixups(db, "main", std::iter::once((path.clone(), mode))).unwrap()
+        };
+
+        // A new file needs nothing.
+        assert!(fixups(&db, cvs::KeywordMode::Default).is_empty());
+
+        // A text file that comes back as binary.
+        db.record_removed_modes("main", &[(path.clone(), "kv".into())])
+            .unwrap();
+        assert_eq!(
+            vec![(path.clone(), "b")],
+            fixups(&db, cvs::KeywordMode::Binary)
+        );
+        db.forget_removed("main", std::iter::once(&path)).unwrap();
+
+        // Then goes away again, and comes back as text.
+        db.record_removed_modes("main", &[(path.clone(), "b".into())])
+            .unwrap();
+        assert_eq!(
+            vec![(path.clone(), "kv")],
+            fixups(&db, cvs::KeywordMode::Default)
+        );
+        db.forget_removed("main", std::iter::once(&path)).unwrap();
+
+        // A file removed as -ko comes back with -ko.
+        db.record_removed_modes("main", &[(path.clone(), "o".into())])
+            .unwrap();
+        assert!(fixups(&db, cvs::KeywordMode::NoExpand).is_empty());
+        assert_eq!(
+            vec![(path.clone(), "kv")],
+            fixups(&db, cvs::KeywordMode::Default)
+        );
+        db.forget_removed("main", std::iter::once(&path)).unwrap();
+
+        // Modes that match need no fix up, and other branches are unaffected.
+        db.record_removed_modes("main", &[(path.clone(), "b".into())])
+            .unwrap();
+        assert!(fixups(&db, cvs::KeywordMode::Binary).is_empty());
+        assert!(keyword_fixups(
+            &db,
+            "other",
+            std::iter::once((path.clone(), cvs::KeywordMode::Default))
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[test]
+    fn test_cvs_branch_name() {
+        let mut db = Database::open(":memory:").unwrap();
+        let mut export = |git_branch: &str, sanitise: cvs::Sanitise| {
+            let cvs_branch = cvs_branch_name(&db, git_branch, None, sanitise)?;
+            db.write_branch(git_branch, &cvs_branch, std::iter::empty::<&Commit>())
+                .unwrap();
+            db.set_sanitise_scheme(git_branch, sanitise.as_str())
+                .unwrap();
+            Ok::<_, anyhow::Error>(cvs_branch)
+        };
+
+        assert_eq!(
+            "feature_x",
+            export("feature/x", cvs::Sanitise::Replace).unwrap()
+        );
+        // Escaping can't collide, but replacing can.
+        assert_eq!(
+            "feature__u00002ex",
+            export("feature.x", cvs::Sanitise::Escape).unwrap()
+        );
+        // Even a branch named like that escape gets a tag of its own.
+        assert_eq!(
+            "feature__u00005f_u00002ex",
+            export("feature__u00002ex", cvs::Sanitise::Escape).unwrap()
+        );
+        let message = format!(
+            "{:#}",
+            export("feature-x", cvs::Sanitise::Replace)
+                .and_then(|_| export("feature.x", cvs::Sanitise::Replace))
+                .unwrap_err()
+        );
+        assert!(
+            message.contains("already used by git branch feature/x"),
+            "{}",
+            message
+        );
+        // Re-exporting the same branch is fine.
+        assert_eq!(
+            "feature_x",
+            export("feature/x", cvs::Sanitise::Replace).unwrap()
+        );
+
+        // A requested name skips sanitising, but has to be a valid tag that
+        // isn't taken.
+        let requested =
+            |name| cvs_branch_name(&db, "release/1.2", Some(name), cvs::Sanitise::Escape);
+        assert_eq!("REL_1_2_BRANCH", requested("REL_1_2_BRANCH").unwrap());
+        assert!(format!("{:#}", requested("REL_1.2").unwrap_err()).contains("'.'"));
+        assert!(format!("{:#}", requested("feature_x").unwrap_err())
+            .contains("already used by git branch feature/x"));
+
+        assert!(check_sanitise_scheme(&db, "feature/x", cvs::Sanitise::Replace).is_ok());
+        assert!(check_sanitise_scheme(&db, "feature/x", cvs::Sanitise::Escape).is_err());
+
+        // Branches from before the scheme was recorded were escaped.
+        db.write_branch("old", "old", std::iter::empty::<&Commit>())
+            .unwrap();
+        assert!(check_sanitise_scheme(&db, "old", cvs::Sanitise::Escape).is_ok());
+        assert!(check_sanitise_scheme(&db, "old", cvs::Sanitise::Strict).is_err());
+    }
+
+    #[test]
+    fn test_missing_blob_context() {
+        let mut git = GitBuilder::new();
+
+        let commit_oid = git.file("dir/file.txt", "hello\n").commit("initial");
+        let blob_oid = git.raw().blob(b"hello\n").unwrap();
+
+        // Corrupt the repository by removing the loose blob object.
+        let hex = blob_oid.to_string();
+        fs::remove_file(
+            git.path()
+                .join(".git/objects")
+                .join(&hex[..2])
+                .join(&hex[2..]),
+        )
+        .unwrap();
+
+        let repo = Repository::open(git.path(), 16).unwrap();
+        let commit = repo.commit(&commit_oid).unwrap();
+
+        let cvs_dir = tempdir().unwrap();
+        fs::create_dir_all(cvs_dir.path().join("src")).unwrap();
+        let mut state = state::Global::new(cvs_dir.path(), "src", state::Filter::default());
+        let mut commit_state = state::Commit::new();
+
+        let err = walk_commit(
+            &commit,
+            commit.time().seconds(),
+            &mut state,
+            &mut commit_state,
+            &Walker {
+                repo: &repo,
+                detector: &detect::Detector::default(),
+                submodules: None,
+                skip_broken_submodules: false,
+                status: None,
+                symlinks: git::Symlinks::Skip,
+                submodule_policy: git::SubmodulePolicy::Skip,
+            },
+        )
+        .unwrap_err();
+        let message = format!("{:#}", err);
+
+        assert!(message.contains(&commit_oid.to_string()), "{}", message);
+        assert!(message.contains("dir/file.txt"), "{}", message);
+        assert!(message.contains(&hex), "{}", message);
+    }
+
+    #[test]
+    fn test_unchanged_files_untouched() {
+        let mut git = GitBuilder::new();
+        let first = git
+            .file("big/unchanged.txt", "unchanged\n")
+            .file("changed.txt", "one\n")
+            .file("gone.txt", "gone\n")
+            .commit("first");
+        let second = git
+            .file("changed.txt", "two\n")
+            .remove("gone.txt")
+            .commit("second");
+
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let cvs_dir = tempdir().unwrap();
+        fs::create_dir_all(cvs_dir.path().join("src")).unwrap();
+        let mut state = state::Global::new(cvs_dir.path(), "src", state::Filter::default());
+        let detector = detect::Detector::default();
+        let walker = Walker {
+            repo: &repo,
+            detector: &detector,
+            submodules: None,
+            skip_broken_submodules: false,
+            status: None,
+            symlinks: git::Symlinks::Skip,
+            submodule_policy: git::SubmodulePolicy::Skip,
+        };
+        let first = repo.commit(&first).unwrap();
+        walk_commit(&first, 0, &mut state, &mut state::Commit::new(), &walker).unwrap();
+        assert_eq!(Some(first.id()), state.base());
+
+        // The second commit never looks at the unchanged file, so it doesn't
+        // matter that its blob has gone, and it isn't rewritten.
+        let unchanged = git.raw().blob(b"unchanged\n").unwrap().to_string();
+        fs::remove_file(
+            git.path()
+                .join(".git/objects")
+                .join(&unchanged[..2])
+                .join(&unchanged[2..]),
+        )
+        .unwrap();
+        let unchanged_path = cvs_dir.path().join("src/big/unchanged.txt");
+        let mtime = FileTime::from_unix_time(12345, 0);
+        filetime::set_file_times(&unchanged_path, mtime, mtime).unwrap();
+
+        let second = repo.commit(&second).unwrap();
+        let mut commit_state = state::Commit::new();
+        walk_commit(&second, 60, &mut state, &mut commit_state, &walker).unwrap();
+        assert_eq!(
+            vec![Path::new("changed.txt")],
+            commit_state
+                .iter_written_files()
+                .map(state::File::git_path)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![Path::new("gone.txt")],
+            state
+                .remove_files(&commit_state)
+                .iter()
+                .map(state::File::git_path)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            mtime,
+            FileTime::from_last_modification_time(&fs::metadata(&unchanged_path).unwrap())
+        );
+
+        // A walk that fails leaves the state without a base, so the next walk
+        // looks at the whole tree again.
+        let third = repo
+            .commit(&git.file("big/new.txt", "new\n").commit("third"))
+            .unwrap();
+        let new = git.raw().blob(b"new\n").unwrap().to_string();
+        fs::remove_file(
+            git.path()
+                .join(".git/objects")
+                .join(&new[..2])
+                .join(&new[2..]),
+        )
+        .unwrap();
+        assert!(walk_commit(&third, 120, &mut state, &mut state::Commit::new(), &walker).is_err());
+        assert_eq!(None, state.base());
+    }
+
+    #[test]
+    fn test_push_cvs_failure() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let dir = tempfile::tempdir().unwrap();
+        let cvs = dir.path().join("cvs");
+        fs::write(&cvs, "#!/bin/sh\nexit 1\n").unwrap();
+        fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir_all(dir.path().join("root/CVSROOT")).unwrap();
+
+        let opt = match parse_args(vec![
+            OsString::from("git2cvs"),
+            "push".into(),
+            "-g".into(),
+            git.path().into(),
+            "--cvs".into(),
+            cvs.into(),
+            "-c".into(),
+            format!(":local:{}", dir.path().join("root").display()).into(),
+            "-d".into(),
+            dir.path().join("git2cvs.db").into(),
+        ])
+        .unwrap()
+        {
+            Opt::Push(opt) => opt,
+            _ => unreachable!(),
+        };
+        let message = format!("{:#}", run(opt, false).unwrap_err());
+        assert!(
+            message.contains("checkout . failed with exit code 1"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_dry_run() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        git.file("b.c", "b\n").remove("a.c").commit("second");
+        let dir = tempfile::tempdir().unwrap();
+        let cvs = dir.path().join("cvs");
+        fs::write(
+            &cvs,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {}\nif [ \"$3\" = checkout ]; then mkdir -p \"$5\"; fi\n",
+                dir.path().join("log").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir_all(dir.path().join("root/CVSROOT")).unwrap();
+
+        let mut opt = match parse_args(vec![
+            OsString::from("git2cvs"),
+            "push".into(),
+            "-g".into(),
+            git.path().into(),
+            "--cvs".into(),
+            cvs.into(),
+            "-c".into(),
+            format!(":local:{}", dir.path().join("root").display()).into(),
+            "-d".into(),
+            dir.path().join("git2cvs.db").into(),
+            "--dry-run".into(),
+        ])
+        .unwrap()
+        {
+            Opt::Push(opt) => opt,
+            _ => unreachable!(),
+        };
+        assert_eq!(Outcome::Finished, export(&mut opt, Mode::New).unwrap());
+
+        // Only the checkout ran, and the database wasn't created.
+        let log = fs::read_to_string(dir.path().join("log")).unwrap();
+        assert_eq!(1, log.lines().count(), "{}", log);
+        assert!(log.contains("checkout"), "{}", log);
+        assert!(!dir.path().join("git2cvs.db").exists());
+    }
+
+    #[test]
+    fn test_type_changes() {
+        let mut git = GitBuilder::new();
+        git.file("config", "file\n").commit("file");
+        git.remove("config")
+            .file("config/a.txt", "a\n")
+            .file("config/sub/b.txt", "b\n")
+            .commit("directory");
+        git.remove("config/a.txt")
+            .remove("config/sub/b.txt")
+            .file("config", "file again\n")
+            .commit("file again");
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+
+        // A file is removed in the same CVS commit that adds the directory
+        // replacing it, but a directory has to be emptied in a CVS commit of
+        // its own before the file replacing it can be added.
+        assert_eq!(
+            Outcome::Finished,
+            run(fake_push_opt(git.path(), dir.path(), &[]), false).unwrap()
+        );
+        assert_eq!(
+            "add src\n\
+             add src/config\n\
+             commit file\n\
+             remove src/config\n\
+             add src/config\n\
+             add src/config/a.txt\n\
+             add src/config/sub\n\
+             add src/config/sub/b.txt\n\
+             commit directory\n\
+             remove src/config/sub/b.txt\n\
+             remove src/config/a.txt\n\
+             commit file again\n\
+             add src/config\n\
+             commit file again\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_case_collisions() {
+        let mut git = GitBuilder::new();
+        git.file("Makefile", "all:\n")
+            .file("Docs/a.txt", "a\n")
+            .commit("first");
+        git.file("makefile", "all: b\n")
+            .file("docs/b.txt", "b\n")
+            .commit("second");
+        git.file("makefile", "all: c\n").commit("third");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+        let message = format!(
+            "{:#}",
+            run(fake_push_opt(git.path(), dir.path(), &[]), false).unwrap_err()
+        );
+        assert!(
+            message.contains("Docs and docs, Makefile and makefile"),
+            "{}",
+            message
+        );
+
+        // The paths that are new to CVS are the ones renamed, and they stay
+        // renamed.
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+        let opt = fake_push_opt(git.path(), dir.path(), &["--case-collisions", "rename"]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "add src\n\
+             add src/Docs\n\
+             add src/Docs/a.txt\n\
+             add src/Makefile\n\
+             commit first\n\
+             add src/docs~1\n\
+             add src/docs~1/b.txt\n\
+             add src/makefile~1\n\
+             commit second\n\
+             commit third\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert_eq!(
+            Some("docs=docs~1\nmakefile=makefile~1\n".to_string()),
+            db.case_renames(&branch).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cvs_dirs() {
+        let mut git = GitBuilder::new();
+        git.file("foo/CVS/bar.c", "bar\n")
+            .file("lib/CVS", "not a directory\n")
+            .file("docs/cvs/notes.txt", "notes\n")
+            .commit("vendor");
+        git.file("foo/CVS/baz.c", "baz\n").commit("more");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+        let message = format!(
+            "{:#}",
+            run(fake_push_opt(git.path(), dir.path(), &[]), false).unwrap_err()
+        );
+        assert!(message.contains(": foo/CVS, lib/CVS"), "{}", message);
+
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+        let opt = fake_push_opt(git.path(), dir.path(), &["--cvs-dirs-ignore-case"]);
+        let message = format!("{:#}", run(opt, false).unwrap_err());
+        assert!(
+            message.contains(": docs/cvs, foo/CVS, lib/CVS"),
+            "{}",
+            message
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+        let opt = fake_push_opt(git.path(), dir.path(), &["--rename-cvs-dirs"]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "add src\n\
+             add src/docs\n\
+             add src/docs/cvs\n\
+             add src/docs/cvs/notes.txt\n\
+             add src/foo\n\
+             add src/foo/CVS_\n\
+             add src/foo/CVS_/bar.c\n\
+             add src/lib\n\
+             add src/lib/CVS_\n\
+             commit vendor\n\
+             add src/foo/CVS_/baz.c\n\
+             commit more\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert_eq!(
+            Some("foo/CVS=foo/CVS_\nlib/CVS=lib/CVS_\n".to_string()),
+            db.cvs_dir_renames(&branch).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tree_traversal() {
+        let mut git = GitBuilder::new();
+        let first = git.file("a.txt", "a\n").commit("first");
+        let raw = git.raw();
+        let mut inner = raw.treebuilder(None).unwrap();
+        inner
+            .insert("escaped.txt", raw.blob(b"gotcha\n").unwrap(), 0o100644)
+            .unwrap();
+        let inner = inner.write().unwrap();
+        // TreeBuilder refuses a .. entry, but nothing stops anyone writing
+        // the tree object themselves.
+        let mut outer = b"40000 ..\0".to_vec();
+        outer.extend_from_slice(inner.as_bytes());
+        let outer = raw
+            .odb()
+            .unwrap()
+            .write(git2::ObjectType::Tree, &outer)
+            .unwrap();
+        let tree = raw.find_tree(outer).unwrap();
+        let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+        let commit = raw
+            .commit(
+                Some("HEAD"),
+                &sig,
+                &sig,
+                "hostile",
+                &tree,
+                &[&raw.find_commit(first).unwrap()],
+            )
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+        let message = format!(
+            "{:#}",
+            run(fake_push_opt(git.path(), dir.path(), &[]), false).unwrap_err()
+        );
+        assert!(
+            message.contains(&format!("cannot export commit {}", commit)),
+            "{}",
+            message
+        );
+        assert!(message.contains("\"..\""), "{}", message);
+
+        // The tip is checked before anything is checked out, so not even the
+        // first commit makes it to CVS.
+        assert!(!dir.path().join("log").exists());
+    }
+
+    #[test]
+    fn test_case_only_rename() {
+        let mut git = GitBuilder::new();
+        git.file("README", "readme\n").commit("first");
+        git.rename("README", "readme").commit("rename");
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+
+        assert_eq!(
+            Outcome::Finished,
+            run(fake_push_opt(git.path(), dir.path(), &[]), false).unwrap()
+        );
+        assert_eq!(
+            "add src\n\
+             add src/README\n\
+             commit first\n\
+             remove src/README\n\
+             add src/readme\n\
+             commit rename\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        git.file("b.c", "b\n").commit("second");
+        git.file("a.c", "c\n").commit("third");
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+        let log = dir.path().join("log");
+        let fail = dir.path().join("fail");
+        fs::write(&fail, "").unwrap();
+
+        let push_opt = |resume: bool| {
+            fake_push_opt(
+                git.path(),
+                dir.path(),
+                if resume { &["--resume"] } else { &[] },
+            )
+        };
+        assert!(run(push_opt(false), false).is_err());
+        fs::remove_file(&fail).unwrap();
+
+        // Pushing again without --resume would look like nothing happened.
+        let message = format!("{:#}", run(push_opt(false), false).unwrap_err());
+        assert!(message.contains("pass --resume"), "{}", message);
+
+        assert_eq!(Outcome::Finished, run(push_opt(true), false).unwrap());
+        assert_eq!("first\nsecond\nthird\n", fs::read_to_string(&log).unwrap());
+
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert!(db.unexported_commits(&branch).unwrap().is_empty());
+        assert_eq!(2, db.file_state(&branch).unwrap().len());
+    }
+
+    #[test]
+    fn test_since_commit() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let second = git.file("b.c", "b\n").commit("second");
+        git.file("a.c", "c\n").commit("third");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+        let log = || fs::read_to_string(dir.path().join("log")).unwrap();
+
+        let opt = fake_push_opt(git.path(), dir.path(), &["--since-commit", "HEAD~1"]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!("second\nthird\n", log());
+
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert_eq!(Some(second), db.since_commit(&branch).unwrap());
+        assert_eq!(2, db.branch_commits(&branch).unwrap().len());
+
+        // Updating carries on from the recorded commit, with or without the
+        // option, but not from a different one.
+        git.file("a.c", "d\n").commit("fourth");
+        let opt = fake_push_opt(git.path(), dir.path(), &[]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!("second\nthird\nfourth\n", log());
+        let opt = fake_push_opt(git.path(), dir.path(), &["--since-commit", "HEAD~3"]);
+        let message = format!("{:#}", run(opt, false).unwrap_err());
+        assert!(message.starts_with(&format!("branch {} was exported from {}", branch, second)));
+    }
+
+    #[test]
+    fn test_prefix() {
+        let mut git = GitBuilder::new();
+        git.file("services/legacy/foo.c", "a\n").commit("first");
+        git.file("README", "b\n").commit("second");
+        git.file("services/legacy/foo.c", "c\n").commit("third");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+        let log = || fs::read_to_string(dir.path().join("log")).unwrap();
+
+        // The commit that only touches the README has nothing to commit.
+        let opt = fake_push_opt(git.path(), dir.path(), &["--prefix", "services/legacy/"]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!("first\nthird\n", log());
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert_eq!(
+            Some(PathBuf::from("services/legacy")),
+            db.branch_prefix(&branch).unwrap()
+        );
+        assert!(db.unexported_commits(&branch).unwrap().is_empty());
+
+        git.file("services/legacy/foo.c", "d\n").commit("fourth");
+        let opt = fake_push_opt(git.path(), dir.path(), &[]);
+        let message = format!("{:#}", run(opt, false).unwrap_err());
+        assert_eq!(
+            format!("branch {} was exported with a different --prefix", branch),
+            message
+        );
+    }
+
+    #[test]
+    fn test_cvs_branch_override() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &["--cvs-branch", "REL_1_2_BRANCH", "-b", "x", "-b", "y"],
+        );
+        assert_eq!(
+            "--cvs-branch can only be given when pushing one branch",
+            format!("{:#}", run(opt, false).unwrap_err())
+        );
+
+        // Once exported, a branch can't be moved to another CVS branch.
+        let opt = fake_push_opt(git.path(), dir.path(), &[]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        git.file("a.c", "b\n").commit("second");
+        let opt = fake_push_opt(git.path(), dir.path(), &["--cvs-branch", "REL_1_2_BRANCH"]);
+        assert_eq!(
+            format!(
+                "branch {} was exported to CVS branch {}, not REL_1_2_BRANCH",
+                branch, branch
+            ),
+            format!("{:#}", run(opt, false).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn test_cvs_branch_created() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+
+        // The fake cvs only has the branch if there's a tags file, and logs
+        // the commands that pick the branch.
+        let export = |existing: bool| {
+            let dir = tempfile::tempdir().unwrap();
+            let cvs = dir.path().join("cvs");
+            fs::write(
+                &cvs,
+                format!(
+                    r#"#!/bin/sh
+if [ "$1" = -d ]; then shift 2; fi
+case "$1" in
+rlog) if [ -e {tags} ]; then printf 'symbolic names:\n\tREL_1_2_BRANCH: 1.1.0.2\n'; fi ;;
+checkout) mkdir -p "$3"; shift 3; echo "checkout $*" >> {log} ;;
+tag|update) echo "$*" >> {log} ;;
+esac
+"#,
+                    tags = dir.path().join("tags").display(),
+                    log = dir.path().join("log").display()
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+            fs::create_dir_all(dir.path().join("root/CVSROOT")).unwrap();
+            if existing {
+                fs::write(dir.path().join("tags"), "").unwrap();
+            }
+
+            let opt = fake_push_opt(git.path(), dir.path(), &["--cvs-branch", "REL_1_2_BRANCH"]);
+            assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        };
+
+        // An existing branch is checked out, and a new one is created from
+        // the trunk.
+        assert_eq!("checkout -r REL_1_2_BRANCH -R .\n", export(true));
+        assert_eq!(
+            "checkout -R .\ntag -b REL_1_2_BRANCH\nupdate -r REL_1_2_BRANCH\n",
+            export(false)
+        );
+    }
+
+    #[test]
+    fn test_executable_bit() {
+        let mut git = GitBuilder::new();
+        let commits = [
+            git.executable("run.sh", "a\n").commit("first"),
+            git.file("run.sh", "a\n").commit("second"),
+            git.executable("run.sh", "a\n").commit("third"),
+            git.file("run.sh", "b\n").commit("fourth"),
+        ];
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let cvs_dir = tempdir().unwrap();
+        let mut state = state::Global::new(cvs_dir.path(), ".", state::Filter::default());
+        let walker = Walker {
+            repo: &repo,
+            detector: &detect::Detector::default(),
+            submodules: None,
+            skip_broken_submodules: false,
+            status: None,
+            symlinks: git::Symlinks::Skip,
+            submodule_policy: git::SubmodulePolicy::Skip,
+        };
+
+        // The bits follow git whether or not the content changes, and changes
+        // to files that were already there are noted.
+        let path = cvs_dir.path().join("run.sh");
+        let mut walked = Vec::new();
+        for oid in commits.iter() {
+            let mut commit_state = state::Commit::new();
+            let commit = repo.commit(oid).unwrap();
+            walk_commit(&commit, 0, &mut state, &mut commit_state, &walker).unwrap();
+            walked.push((
+                fs::metadata(&path).unwrap().permissions().mode() & 0o111,
+                commit_state
+                    .iter_mode_changes()
+                    .map(|(path, executable)| (path.to_path_buf(), executable))
+                    .collect::<Vec<_>>(),
+            ));
+        }
+        let run = || PathBuf::from("run.sh");
+        assert_eq!(
+            vec![
+                (0o111, vec![]),
+                (0, vec![(run(), false)]),
+                (0o111, vec![(run(), true)]),
+                (0, vec![(run(), false)]),
+            ],
+            walked
+        );
+    }
+
+    #[test]
+    fn test_empty_commits() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let second = git.file("build.log", "b\n").commit("second");
+        let third = git.file("a.c", "c\n").commit("third");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+
+        // The commit that only touches an excluded file is pushed without a
+        // cvs commit, and recorded as such.
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+        let opt = fake_push_opt(git.path(), dir.path(), &["--exclude", "*.log"]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "first\nthird\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert!(db.unexported_commits(&branch).unwrap().is_empty());
+        assert!(db.exported_empty(&branch, &second).unwrap());
+        assert!(!db.exported_empty(&branch, &third).unwrap());
+
+        // --allow-empty commits it anyway.
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &["--exclude", "*.log", "--allow-empty"],
+        );
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "first\nsecond\nthird\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_path_map() {
+        let mut git = GitBuilder::new();
+        let first = git
+            .file("headers/a.h", "a\n")
+            .file("lib/b.c", "b\n")
+            .commit("first");
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let cvs_dir = tempdir().unwrap();
+        fs::create_dir_all(cvs_dir.path().join("src")).unwrap();
+        let map: PathMap = "headers=vendor/include\n".parse().unwrap();
+        let mut state = state::Global::new(cvs_dir.path(), "src", state::Filter::default())
+            .with_path_map(map.clone());
+        let walker = Walker {
+            repo: &repo,
+            detector: &detect::Detector::default(),
+            submodules: None,
+            skip_broken_submodules: false,
+            status: None,
+            symlinks: git::Symlinks::Skip,
+            submodule_policy: git::SubmodulePolicy::Skip,
+        };
+
+        // The git path is still the key, but the file is written and added
+        // where the map puts it, along with a directory git doesn't have.
+        let mut commit_state = state::Commit::new();
+        let commit = repo.commit(&first).unwrap();
+        walk_commit(&commit, 0, &mut state, &mut commit_state, &walker).unwrap();
+        assert_eq!(
+            "a\n",
+            fs::read_to_string(cvs_dir.path().join("src/vendor/include/a.h")).unwrap()
+        );
+        assert!(!cvs_dir.path().join("src/headers").exists());
+        assert_eq!(
+            vec![Path::new("src/vendor")],
+            commit_state.iter_new_directories().collect::<Vec<_>>()
+        );
+        let mut added: Vec<_> = commit_state
+            .iter_new_files()
+            .map(|(file, _)| file.cvs_relative_path())
+            .collect();
+        added.sort();
+        assert_eq!(
+            vec![
+                PathBuf::from("src/lib"),
+                PathBuf::from("src/lib/b.c"),
+                PathBuf::from("src/vendor/include"),
+                PathBuf::from("src/vendor/include/a.h"),
+            ],
+            added
+        );
+        assert!(state.get_oid(&state.file("headers/a.h").unwrap()).is_some());
+
+        // A push records the map, so later pushes can leave it off but can't
+        // change it.
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+        let map_file = dir.path().join("paths");
+        fs::write(&map_file, "headers=vendor/include\n").unwrap();
+        let map_arg = map_file.to_str().unwrap();
+        let opt = fake_push_opt(git.path(), dir.path(), &["--path-map", map_arg]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert_eq!(Some(map.to_string()), db.path_map(&branch).unwrap());
+
+        git.file("headers/c.h", "c\n").commit("second");
+        let opt = fake_push_opt(git.path(), dir.path(), &[]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "first\nsecond\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+
+        git.file("headers/d.h", "d\n").commit("third");
+        fs::write(&map_file, "headers=include\n").unwrap();
+        let opt = fake_push_opt(git.path(), dir.path(), &["--path-map", map_arg]);
+        assert_eq!(
+            format!("branch {} was exported with a different --path-map", branch),
+            format!("{:#}", run(opt, false).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn test_path_limits_with_path_map() {
+        let mut git = GitBuilder::new();
+        git.file("generated_sources/a.c", "a\n").commit("first");
+
+        // The limits apply to where the path map puts each file.
+        let push_with = |mapped: bool| {
+            let dir = tempfile::tempdir().unwrap();
+            summary_logging_cvs(dir.path());
+            let map_file = dir.path().join("paths");
+            fs::write(&map_file, "generated_sources=gen\n").unwrap();
+            let mut args = vec!["--max-component-length", "10"];
+            if mapped {
+                args.extend(&["--path-map", map_file.to_str().unwrap()]);
+            }
+            run(fake_push_opt(git.path(), dir.path(), &args), false)
+        };
+        assert!(format!("{:#}", push_with(false).unwrap_err()).contains(
+            "1 paths are too long for CVS (a --path-map rule can move them): src/generated_sources/a.c has a component of 17 bytes"
+        ));
+        assert_eq!(Outcome::Finished, push_with(true).unwrap());
+    }
+
+    #[test]
+    fn test_path_map_collision() {
+        let mut git = GitBuilder::new();
+        let first = git
+            .file("headers/a.h", "a\n")
+            .file("include/a.h", "b\n")
+            .commit("first");
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+        let map_file = dir.path().join("paths");
+        fs::write(&map_file, "headers=include\n").unwrap();
+
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &["--path-map", map_file.to_str().unwrap()],
+        );
+        assert_eq!(
+            format!(
+                "cannot export commit {}: headers/a.h and include/a.h would both be exported to include/a.h",
+                first
+            ),
+            format!("{:#}", run(opt, false).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn test_since_commit_off_branch() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let trunk = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        git.branch("feature").checkout("feature");
+        let feature = git.file("b.c", "b\n").commit("feature");
+        git.checkout(&trunk);
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &["--since-commit", &feature.to_string()],
+        );
+        assert_eq!(
+            format!(
+                "commit {} is not on the first-parent history of branch {}",
+                feature, trunk
+            ),
+            format!("{:#}", run(opt, false).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn test_max_commits() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        git.file("b.c", "b\n").commit("second");
+        git.file("a.c", "c\n").commit("third");
+        git.file("b.c", "d\n").commit("fourth");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+        let log = || fs::read_to_string(dir.path().join("log")).unwrap();
+
+        let opt = fake_push_opt(git.path(), dir.path(), &["--max-commits", "2"]);
+        assert_eq!(Outcome::Stopped, run(opt, false).unwrap());
+        assert_eq!("first\nsecond\n", log());
+
+        // Commits are only recorded once the push gets to them.
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert_eq!(3, db.branch_commits(&branch).unwrap().len());
+        assert_eq!(1, db.unexported_commits(&branch).unwrap().len());
+        drop(db);
+
+        let opt = fake_push_opt(git.path(), dir.path(), &["--max-commits", "2", "--resume"]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!("first\nsecond\nthird\nfourth\n", log());
+    }
+
+    #[test]
+    fn test_remove_unknown_to_cvs() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").file("b.c", "b\n").commit("first");
+        git.remove("a.c").commit("second");
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+
+        // The fake cvs doesn't keep CVS/Entries, so as far as the export can
+        // tell, CVS doesn't have the file that the second commit removes.
+        let opt = fake_push_opt(git.path(), dir.path(), &[]);
+        assert_eq!(
+            "cannot remove files that were exported but that CVS doesn't have on the branch: src/a.c",
+            format!("{:#}", run(opt, false).unwrap_err())
+        );
+        assert_eq!(
+            "first\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_thin_bundle() {
+        let mut git = GitBuilder::new();
+        let first = git.file("a.c", "a\n").commit("first");
+        git.file("a.c", "b\n").commit("second");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+
+        let bundle = dir.path().join("thin.bundle");
+        let status = subprocess::Exec::cmd("git")
+            .arg("bundle")
+            .arg("create")
+            .arg(&bundle)
+            .arg(format!("{}..{}", first, branch))
+            .cwd(git.path())
+            .join()
+            .unwrap();
+        assert!(status.success());
+
+        let opt = fake_push_opt(&bundle, dir.path(), &["-b", &branch]);
+        assert_eq!(
+            format!(
+                "{} is a thin bundle that requires commit {}; create one with the whole history of the branch instead",
+                bundle.display(),
+                first
+            ),
+            format!("{:#}", run(opt, false).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn test_shallow_clone() {
+        let mut git = GitBuilder::new();
+        let first = git.file("a.c", "a\n").commit("first");
+        let second = git.file("b.c", "b\n").commit("second");
+        git.file("a.c", "c\n").commit("third");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let hex = first.to_string();
+        fs::remove_file(
+            git.raw()
+                .path()
+                .join("objects")
+                .join(&hex[..2])
+                .join(&hex[2..]),
+        )
+        .unwrap();
+        fs::write(git.raw().path().join("shallow"), format!("{}\n", second)).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+
+        let opt = fake_push_opt(git.path(), dir.path(), &[]);
+        assert!(matches!(
+            run(opt, false).unwrap_err().downcast_ref::<error::Error>(),
+            Some(error::Error::ShallowClone { .. })
+        ));
+
+        // The export starts where the clone does.
+        let opt = fake_push_opt(git.path(), dir.path(), &["--allow-shallow"]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "second\nthird\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert_eq!(Some(second), db.since_commit(&branch).unwrap());
+    }
+
+    #[test]
+    fn test_push_discovers_repository() {
+        let mut git = GitBuilder::new();
+        git.file("src/a.c", "a\n").commit("first");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let common = git.path().join(".git").canonicalize().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+
+        fs::create_dir_all(git.path().join("src")).unwrap();
+        let opt = fake_push_opt(&git.path().join("src"), dir.path(), &[]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert_eq!(
+            Some(common.to_string_lossy().into_owned()),
+            db.git_repository(&branch).unwrap()
+        );
+        drop(db);
+
+        // A linked worktree pushes the branch it has checked out, and is
+        // recorded as the repository it belongs to.
+        git.branch("feature").checkout("feature");
+        git.file("src/b.c", "b\n").commit("second");
+        git.checkout(&branch);
+        let worktrees = tempfile::tempdir().unwrap();
+        let feature = git
+            .raw()
+            .find_branch("feature", git2::BranchType::Local)
+            .unwrap();
+        let mut add = git2::WorktreeAddOptions::new();
+        add.reference(Some(feature.get()));
+        git.raw()
+            .worktree("feature", &worktrees.path().join("feature"), Some(&add))
+            .unwrap();
+        let opt = fake_push_opt(&worktrees.path().join("feature"), dir.path(), &[]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert_eq!(
+            Some(git.raw().revparse_single("feature").unwrap().id()),
+            db.last_exported_commit("feature").unwrap()
+        );
+        assert_eq!(
+            Some(common.to_string_lossy().into_owned()),
+            db.git_repository("feature").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_push_mailmap() {
+        let mut git = GitBuilder::new();
+        git.file(
+            ".mailmap",
+            "Real Name <real@example.com> <test@example.com>\n",
+        )
+        .commit("first");
+        let template = "{author_name} <{author_email}>: {message}";
+
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+        let opt = fake_push_opt(git.path(), dir.path(), &["--message-template", template]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "Real Name <real@example.com>: first\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &["--message-template", template, "--no-mailmap"],
+        );
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "Test <test@example.com>: first\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_push_skip_short_sha() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let second = git.file("b.c", "b\n").commit("second");
+        git.file("a.c", "c\n").commit("third");
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+
+        let opt = fake_push_opt(git.path(), dir.path(), &["--skip-commit", "HEAD~3"]);
+        assert!(run(opt, false).is_err());
+
+        let short = &second.to_string()[..7];
+        let opt = fake_push_opt(git.path(), dir.path(), &["--skip-commit", short]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "first\nthird\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_push_dereferenced_symlink_twice() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n")
+            .symlink("link.c", "a.c")
+            .commit("first");
+        git.file("a.c", "b\n").commit("second");
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &["--symlinks", "dereference", "--max-commits", "1"],
+        );
+        assert_eq!(Outcome::Stopped, run(opt, false).unwrap());
+
+        // The next push has to know the link was exported as a file, so that
+        // the change to its target modifies it rather than adding it again.
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &["--symlinks", "dereference", "--resume"],
+        );
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        let log = fs::read_to_string(dir.path().join("log")).unwrap();
+        assert_eq!(1, log.matches("add src/link.c\n").count(), "{}", log);
+        assert!(log.contains("commit second\n"), "{}", log);
+    }
+
+    #[test]
+    fn test_push_submodule_placeholder_twice() {
+        let git = GitBuilder::new();
+        let mut sub = GitBuilder::init(git.path().join("lib"));
+        let first = commit_files(&mut sub, &[("a.txt", b"a\n")]);
+        let second = commit_files(&mut sub, &[("a.txt", b"a2\n")]);
+        commit_gitlink(git.raw(), Some(first));
+        commit_gitlink(git.raw(), Some(second));
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &["--submodules", "placeholder", "--max-commits", "1"],
+        );
+        assert_eq!(Outcome::Stopped, run(opt, false).unwrap());
+
+        // The next push has to know the placeholder was exported, so that the
+        // new pinned commit modifies it rather than adding it again.
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &["--submodules", "placeholder", "--resume"],
+        );
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        let log = fs::read_to_string(dir.path().join("log")).unwrap();
+        assert_eq!(1, log.matches("add src/lib\n").count(), "{}", log);
+        assert_eq!(2, log.matches("commit gitlink\n").count(), "{}", log);
+    }
+
+    #[test]
+    fn test_continue_on_error() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let broken = git.file("b.c", "b\n").commit("broken");
+        git.file("c.c", "c\n").commit("third");
+        let dir = tempfile::tempdir().unwrap();
+        let inner = entries_cvs(dir.path());
+        fs::rename(&inner, dir.path().join("cvs-inner")).unwrap();
+        fs::write(
+            &inner,
+            format!(
+                "#!/bin/sh\nif [ \"$1\" = commit ] && grep -q ^broken \"$3\"; then exit 1; fi\nexec {} \"$@\"\n",
+                dir.path().join("cvs-inner").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&inner, fs::Permissions::from_mode(0o755)).unwrap();
+
+        // The failed commit's file has to be added again with the next one.
+        let opt = fake_push_opt(git.path(), dir.path(), &["--continue-on-error"]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "add src\nadd src/a.c\ncommit first\nadd src/b.c\nadd src/b.c\nadd src/c.c\ncommit third\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+
+        // Retrying finds the failure, but there's nothing left to export.
+        let opt = fake_push_opt(git.path(), dir.path(), &[]);
+        assert_eq!(Outcome::Finished, run(opt, true).unwrap());
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        let exceptions = db.exceptions(None, None).unwrap();
+        assert_eq!(1, exceptions.len());
+        assert_eq!(ExceptionKind::SkippedCommit, exceptions[0].kind);
+        assert_eq!(broken.to_string(), exceptions[0].oid);
+    }
+
+    #[test]
+    fn test_push_head_branch() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let second = git.file("b.c", "b\n").commit("second");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+
+        let opt = fake_push_opt(git.path(), dir.path(), &[]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert_eq!(Some(second), db.last_exported_commit(&branch).unwrap());
+        drop(db);
+
+        // Naming the branch carries on from the same mapping.
+        git.file("a.c", "c\n").commit("third");
+        let opt = fake_push_opt(git.path(), dir.path(), &["-b", &branch]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "first\nsecond\nthird\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+
+        git.raw().set_head_detached(second).unwrap();
+        let opt = fake_push_opt(git.path(), dir.path(), &[]);
+        assert_eq!(
+            "HEAD is not a symbolic reference; pass --branch or --rev",
+            format!("{:#}", run(opt, false).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn test_expand_branches() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let trunk = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        git.branch("release/y").branch("other");
+        git.file("a.c", "b\n").commit("second");
+        git.branch("release/x");
+        git.checkout("release/x").file("x.c", "x\n").commit("x");
+        let dir = tempfile::tempdir().unwrap();
+
+        // The branch that forked earlier comes first, whatever its name.
+        let opt = fake_push_opt(git.path(), dir.path(), &["-b", &trunk, "-b", "release/*"]);
+        assert!(opt.several_branches());
+        assert_eq!(
+            vec![trunk.clone(), "release/y".into(), "release/x".into()],
+            expand_branches(&opt).unwrap()
+        );
+
+        let opt = fake_push_opt(git.path(), dir.path(), &["-b", "feature/*"]);
+        assert!(opt.several_branches());
+        assert_eq!(
+            "no branches match feature/*",
+            format!("{:#}", expand_branches(&opt).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn test_push_branch_glob() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let trunk = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        for (branch, message) in [
+            ("release/1", "one"),
+            ("release/2", "second"),
+            ("release/3", "three"),
+        ] {
+            git.checkout(&trunk).branch(branch);
+            git.checkout(branch).file("b.c", message).commit(message);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+        fs::write(dir.path().join("fail"), "").unwrap();
+
+        // The second branch fails, but the third is still pushed.
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &["-b", "release/*", "--allow-existing-content"],
+        );
+        assert_eq!(
+            "1 of 3 branches failed",
+            format!("{:#}", run(opt, false).unwrap_err())
+        );
+        let log = fs::read_to_string(dir.path().join("log")).unwrap();
+        assert!(log.contains("one\n"), "{}", log);
+        assert!(log.contains("three\n"), "{}", log);
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        for branch in ["release/1", "release/3"] {
+            assert_eq!(
+                Some(git.raw().revparse_single(branch).unwrap().id()),
+                db.last_exported_commit(branch).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_push_rev() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let second = git.file("b.c", "b\n").commit("second");
+        git.file("a.c", "c\n").commit("third");
+        git.raw()
+            .tag_lightweight("v1", &git.raw().find_object(second, None).unwrap(), false)
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        summary_logging_cvs(dir.path());
+
+        let opt = fake_push_opt(git.path(), dir.path(), &["--rev", "v1"]);
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+        assert_eq!(
+            "first\nsecond\n",
+            fs::read_to_string(dir.path().join("log")).unwrap()
+        );
+        let db = Database::open(dir.path().join("git2cvs.db")).unwrap();
+        assert_eq!(Some(second), db.resolved_rev("v1").unwrap());
+        assert_eq!(Some("v1".to_string()), db.get_cvs_branch("v1").unwrap());
+        drop(db);
+
+        let opt = fake_push_opt(git.path(), dir.path(), &["-b", "v1"]);
+        assert_eq!(
+            "v1 was exported as a revision; pass --rev v1 to push it",
+            format!("{:#}", run(opt, false).unwrap_err())
+        );
+
+        let args = ["git2cvs", "push", "-b", "main", "--rev", "v1"];
+        assert!(parse_args(args).is_err());
+    }
+
+    #[test]
+    fn test_unexported_fork_point() {
+        let mut git = GitBuilder::new();
+        let first = git.file("a.c", "a\n").commit("first");
+        let trunk = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        git.branch("feature").checkout("feature");
+        git.file("a.c", "feature\n").commit("feature");
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("root/CVSROOT")).unwrap();
+
+        // The trunk hasn't been exported, so the feature branch has nowhere
+        // to be rooted.
+        let opt = match parse_args(vec![
+            OsString::from("git2cvs"),
+            "push".into(),
+            "-g".into(),
+            git.path().into(),
+            "-b".into(),
+            "feature".into(),
+            "--trunk".into(),
+            trunk.clone().into(),
+            "--cvs".into(),
+            "false".into(),
+            "-c".into(),
+            format!(":local:{}", dir.path().join("root").display()).into(),
+            "-d".into(),
+            dir.path().join("git2cvs.db").into(),
+        ])
+        .unwrap()
+        {
+            Opt::Push(opt) => opt,
+            _ => unreachable!(),
+        };
+        let message = format!("{:#}", run(opt, false).unwrap_err());
+        assert_eq!(
+            format!(
+                "branch feature forked from {} at commit {}, which has not been exported yet; push {} first",
+                trunk, first, trunk
+            ),
+            message
+        );
+    }
+
+    #[test]
+    fn test_update_rewritten_history() {
+        let mut git = GitBuilder::new();
+        let first = git.file("a.c", "a\n").commit("first");
+        git.file("a.c", "b\n").commit("second");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+
+        // The database thinks the second commit on the branch is one that
+        // isn't on it any more.
+        let mut other = GitBuilder::new();
+        let gone = other.file("a.c", "gone\n").commit("gone");
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let other_repo = Repository::open(other.path(), 0).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("git2cvs.db");
+        let mut db = Database::open(&database).unwrap();
+        db.write_branch(
+            &branch,
+            &branch,
+            [
+                repo.commit(&first).unwrap(),
+                other_repo.commit(&gone).unwrap(),
+            ]
+            .iter()
+            .map(Rc::as_ref),
+        )
+        .unwrap();
+        drop(db);
+
+        let mut opt = match parse_args(vec![
+            OsString::from("git2cvs"),
+            "push".into(),
+            "-g".into(),
+            git.path().into(),
+            "-b".into(),
+            branch.clone().into(),
+            "-c".into(),
+            ":local:/nonexistent".into(),
+            "-d".into(),
+            database.into(),
+        ])
+        .unwrap()
+        {
+            Opt::Push(opt) => opt,
+            _ => unreachable!(),
+        };
+        let message = format!("{:#}", export(&mut opt, Mode::New).unwrap_err());
+        assert!(
+            message.contains(&format!("commit {} is no longer at position 1", gone)),
+            "{}",
+            message
+        );
+    }
+
+    /// Exports a history with every kind of change into a real CVS repository
+    /// and checks it out again, so this is ignored unless a cvs binary is
+    /// available: run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_push_with_cvs() {
+        let mut git = GitBuilder::new();
+        git.file("README", "readme\n")
+            .file("lib/old.c", "old\n")
+            .executable("build.sh", "#!/bin/sh\n")
+            .binary("logo.png", "png")
+            .commit("initial");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+
+        git.branch("feature").checkout("feature");
+        let feature = git.file("feature.c", "feature\n").commit("feature");
+        git.checkout(&branch);
+        git.rename("lib/old.c", "lib/new.c")
+            .remove("README")
+            .binary("logo.png", "png2")
+            .commit("rename and remove");
+        git.file("feature.c", "feature\n");
+        let tip = git.merge("merge feature", feature);
+
+        let cvs = CvsFixture::init();
+        cvs.module("module");
+        let database = cvs.scratch("git2cvs.db");
+        let args = vec![
+            OsString::from("git2cvs"),
+            "push".into(),
+            "-g".into(),
+            git.path().into(),
+            "-b".into(),
+            branch.clone().into(),
+            "-c".into(),
+            cvs.root().to_string().into(),
+            "-d".into(),
+            database.clone().into(),
+            "-m".into(),
+            "module".into(),
+        ];
+        let opt = match parse_args(args).unwrap() {
+            Opt::Push(opt) => opt,
+            _ => unreachable!(),
+        };
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+
+        let db = Database::open(&database).unwrap();
+        assert_eq!(Some(tip), db.last_exported_commit(&branch).unwrap());
+
+        let checkout = cvs.checkout("module", "verify", None);
+        let src = cvs.scratch("verify/src");
+        assert_eq!("new\n", fs::read_to_string(src.join("lib/new.c")).unwrap());
+        assert_eq!(
+            "feature\n",
+            fs::read_to_string(src.join("feature.c")).unwrap()
+        );
+        assert_eq!(b"\0png2".to_vec(), fs::read(src.join("logo.png")).unwrap());
+        assert!(src.join("build.sh").exists());
+        assert!(!src.join("README").exists());
+        assert!(!src.join("lib/old.c").exists());
+        assert_eq!(
+            vec![(PathBuf::from("src/logo.png"), "b".to_string())],
+            checkout
+                .keyword_modes(std::iter::once("src/logo.png"))
+                .unwrap()
+        );
+    }
+
+    /// Exports into a module whose target directory already has files, once
+    /// without and then with --merge-existing, so this is ignored unless a cvs
+    /// binary is available: run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_existing_target_with_cvs() {
+        let mut git = GitBuilder::new();
+        git.file("shared.c", "from git\n")
+            .file("new.c", "new\n")
+            .commit("first");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+
+        let cvs = CvsFixture::init();
+        cvs.import(
+            "module",
+            &[
+                ("src/shared.c", "from cvs\n"),
+                ("src/local.c", "local\n"),
+                ("doc/README", "readme\n"),
+            ],
+        );
+        let database = cvs.scratch("git2cvs.db");
+        let git_path = git.path().to_path_buf();
+        let opt = |extra: &[&str]| {
+            let mut args = vec![
+                OsString::from("git2cvs"),
+                "push".into(),
+                "-g".into(),
+                git_path.clone().into(),
+                "-b".into(),
+                branch.clone().into(),
+                "-c".into(),
+                cvs.root().to_string().into(),
+                "-d".into(),
+                database.clone().into(),
+                "-m".into(),
+                "module".into(),
+            ];
+            args.extend(extra.iter().map(OsString::from));
+            match parse_args(args).unwrap() {
+                Opt::Push(opt) => opt,
+                _ => unreachable!(),
+            }
+        };
+
+        let message = format!("{:#}", run(opt(&[]), false).unwrap_err());
+        assert!(message.contains("--merge-existing"), "{}", message);
+        assert!(
+            message.contains(
+                "1 of them are also in git and would be overwritten, including src/shared.c"
+            ),
+            "{}",
+            message
+        );
+
+        assert_eq!(
+            Outcome::Finished,
+            run(opt(&["--merge-existing"]), false).unwrap()
+        );
+        // The later push keeps the files without being told again.
+        git.file("new.c", "newer\n").commit("second");
+        assert_eq!(
+            Outcome::Finished,
+            run(opt(&["--dirty-workdir", "fail", "--clean-strays"]), false).unwrap()
+        );
+
+        let checkout = cvs.checkout("module", "verify", None);
+        let verify = cvs.scratch("verify");
+        assert_eq!(
+            "from git\n",
+            fs::read_to_string(verify.join("src/shared.c")).unwrap()
+        );
+        assert_eq!(
+            "newer\n",
+            fs::read_to_string(verify.join("src/new.c")).unwrap()
+        );
+        assert_eq!(
+            "local\n",
+            fs::read_to_string(verify.join("src/local.c")).unwrap()
+        );
+        assert!(verify.join("doc/README").exists());
+        assert_eq!(
+            vec![(PathBuf::from("src/shared.c"), "1.2".to_string())],
+            checkout.revisions(std::iter::once("src/shared.c")).unwrap()
+        );
+    }
+
+    /// Exports a history that deletes a file and checks that CVS has moved it
+    /// to the Attic, so this is ignored unless a cvs binary is available: run
+    /// with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_removed_files_with_cvs() {
+        let mut git = GitBuilder::new();
+        git.file("keep.c", "keep\n")
+            .file("lib/gone.c", "gone\n")
+            .commit("add");
+        git.remove("lib/gone.c").commit("remove");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+
+        let cvs = CvsFixture::init();
+        cvs.module("module");
+        let opt = match parse_args(vec![
+            OsString::from("git2cvs"),
+            "push".into(),
+            "-g".into(),
+            git.path().into(),
+            "-b".into(),
+            branch.into(),
+            "-c".into(),
+            cvs.root().to_string().into(),
+            "-d".into(),
+            cvs.scratch("git2cvs.db").into(),
+            "-m".into(),
+            "module".into(),
+        ])
+        .unwrap()
+        {
+            Opt::Push(opt) => opt,
+            _ => unreachable!(),
+        };
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+
+        let module = Path::new(cvs.root().path()).join("module/src");
+        assert!(module.join("keep.c,v").exists());
+        assert!(module.join("lib/Attic/gone.c,v").exists());
+        assert!(!module.join("lib/gone.c,v").exists());
+
+        cvs.checkout("module", "verify", None);
+        assert!(cvs.scratch("verify/src/keep.c").exists());
+        assert!(!cvs.scratch("verify/src/lib/gone.c").exists());
+    }
+
+    /// Exports a branch, then the commits added to it afterwards, so this is
+    /// ignored unless a cvs binary is available: run with
+    /// `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_update_with_cvs() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+
+        let cvs = CvsFixture::init();
+        cvs.module("module");
+        let database = cvs.scratch("git2cvs.db");
+        let mut opt = match parse_args(vec![
+            OsString::from("git2cvs"),
+            "push".into(),
+            "-g".into(),
+            git.path().into(),
+            "-b".into(),
+            branch.clone().into(),
+            "-c".into(),
+            cvs.root().to_string().into(),
+            "-d".into(),
+            database.clone().into(),
+            "-m".into(),
+            "module".into(),
+        ])
+        .unwrap()
+        {
+            Opt::Push(opt) => opt,
+            _ => unreachable!(),
+        };
+        assert_eq!(Outcome::Finished, export(&mut opt, Mode::New).unwrap());
+
+        // Pushing again with nothing new does nothing, and new commits are
+        // appended and exported on top of the old ones.
+        assert_eq!(Outcome::Finished, export(&mut opt, Mode::New).unwrap());
+        let second = git.file("a.c", "a2\n").commit("second");
+        let tip = git.file("b.c", "b\n").commit("third");
+        assert_eq!(Outcome::Finished, export(&mut opt, Mode::New).unwrap());
+
+        let db = Database::open(&database).unwrap();
+        assert_eq!(Some(tip), db.last_exported_commit(&branch).unwrap());
+        assert_eq!(3, db.branch_commits(&branch).unwrap().len());
+        assert_eq!(Some(1), db.commit_index(&second, &branch).unwrap());
+        cvs.checkout("module", "verify", None);
+        let src = cvs.scratch("verify/src");
+        assert_eq!("a2\n", fs::read_to_string(src.join("a.c")).unwrap());
+        assert_eq!("b\n", fs::read_to_string(src.join("b.c")).unwrap());
+    }
+
+    /// Exports two branches into the same CVS module, so this is ignored
+    /// unless a cvs binary is available: run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_trunk_with_cvs() {
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let trunk = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        git.branch("feature").checkout("feature");
+        git.file("a.c", "feature\n").commit("feature");
+        git.checkout(&trunk);
+        git.file("a.c", "trunk\n").commit("trunk");
+
+        let cvs = CvsFixture::init();
+        cvs.module("module");
+        let database = cvs.scratch("git2cvs.db");
+        for branch in &[trunk.as_str(), "feature"] {
+            let mut opt = match parse_args(vec![
+                OsString::from("git2cvs"),
+                "push".into(),
+                "-g".into(),
+                git.path().into(),
+                "-b".into(),
+                (*branch).into(),
+                "-c".into(),
+                cvs.root().to_string().into(),
+                "-d".into(),
+                database.clone().into(),
+                "-m".into(),
+                "module".into(),
+                "--trunk".into(),
+                trunk.clone().into(),
+            ])
+            .unwrap()
+            {
+                Opt::Push(opt) => opt,
+                _ => unreachable!(),
+            };
+            assert_eq!(Outcome::Finished, export(&mut opt, Mode::New).unwrap());
+        }
+
+        let db = Database::open(&database).unwrap();
+        assert!(!db.branch_tagged(&trunk).unwrap());
+        assert!(db.branch_tagged("feature").unwrap());
+
+        cvs.checkout("module", "trunk", None);
+        let src = cvs.scratch("trunk/src");
+        assert_eq!("trunk\n", fs::read_to_string(src.join("a.c")).unwrap());
+        cvs.checkout("module", "feature", Some("feature"));
+        let src = cvs.scratch("feature/src");
+        assert_eq!("feature\n", fs::read_to_string(src.join("a.c")).unwrap());
+
+        let log = cvs.output(cvs.scratch("trunk"), &["log", "src/a.c"]);
+        assert!(log.contains("\tfeature: 1."), "{}", log);
+    }
+
+    /// Exports branches that share history into the same CVS module, so this
+    /// is ignored unless a cvs binary is available: run with
+    /// `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_fork_with_cvs() {
+        let mut git = GitBuilder::new();
+        let first = git.file("a.c", "a\n").file("b.c", "b\n").commit("first");
+        let trunk = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        git.branch("feature").checkout("feature");
+        git.file("b.c", "feature\n").commit("feature");
+        git.checkout(&trunk);
+        git.file("a.c", "a2\n").commit("second");
+        git.file("c.c", "c\n").commit("third");
+
+        let cvs = CvsFixture::init();
+        cvs.module("module");
+        let database = cvs.scratch("git2cvs.db");
+        let opt = match parse_args(vec![
+            OsString::from("git2cvs"),
+            "push".into(),
+            "-g".into(),
+            git.path().into(),
+            "-b".into(),
+            trunk.clone().into(),
+            "-b".into(),
+            "feature".into(),
+            "--trunk".into(),
+            trunk.clone().into(),
+            "-c".into(),
+            cvs.root().to_string().into(),
+            "-d".into(),
+            database.clone().into(),
+            "-m".into(),
+            "module".into(),
+        ])
+        .unwrap()
+        {
+            Opt::Push(opt) => opt,
+            _ => unreachable!(),
+        };
+        assert_eq!(Outcome::Finished, run(opt, false).unwrap());
+
+        // The shared commit was only exported once, on the trunk.
+        let db = Database::open(&database).unwrap();
+        assert_eq!(
+            Some((trunk.clone(), first)),
+            db.branch_root("feature").unwrap()
+        );
+        assert_eq!(2, db.commit_revisions(&trunk, &first).unwrap().len());
+        assert!(db.commit_revisions("feature", &first).unwrap().is_empty());
+
+        // The CVS branch has the files as they were at the fork point, plus
+        // its own change, and none of the trunk's later ones.
+        cvs.checkout("module", "feature", Some("feature"));
+        let src = cvs.scratch("feature/src");
+        assert_eq!("a\n", fs::read_to_string(src.join("a.c")).unwrap());
+        assert_eq!("feature\n", fs::read_to_string(src.join("b.c")).unwrap());
+        assert!(!src.join("c.c").exists());
+
+        let log = cvs.output(cvs.scratch("feature"), &["log", "src/a.c", "src/b.c"]);
+        assert_eq!(2, log.matches("\tfeature: 1.1.0.2").count(), "{}", log);
+        assert!(log.contains("revision 1.1.2.1"), "{}", log);
+    }
+
+    #[test]
+    fn test_sha_trailer_flags() {
+        let sha_trailer = |flags: &[&str]| {
+            let mut args: Vec<OsString> = vec![
+                "git2cvs",
+                "push",
+                "-g",
+                ".",
+                "-c",
+                ":local:/cvsroot",
+                "-d",
+                "db",
+            ]
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+            args.extend(flags.iter().map(OsString::from));
+            match parse_args(args).unwrap() {
+                Opt::Push(opt) => opt.sha_trailer(),
+                _ => unreachable!(),
+            }
+        };
+
+        assert!(sha_trailer(&[]));
+        assert!(sha_trailer(&["--sha-trailer"]));
+        assert!(!sha_trailer(&["--no-sha-trailer"]));
+        assert!(!sha_trailer(&["--sha-trailer", "--no-sha-trailer"]));
+        assert!(sha_trailer(&["--no-sha-trailer", "--sha-trailer"]));
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use git2::{ObjectType, Oid};
+
+use crate::{
+    cvs,
+    database::Database,
+    git::{Commit, Repository},
+    state::{self, Scope},
+};
+
+/// Brings the state in line with a commit that was partly or entirely
+/// committed to CVS by a push that died before it could mark the commit
+/// exported, returning the number of files that had already landed.
+///
+/// The state must hold the last exported commit, and the working copy must be
+/// a fresh checkout. Files that landed are recorded against the pending
+/// commit, so that exporting it afterwards only commits what's left.
+pub fn reconcile(
+    last: &Commit,
+    pending: &Commit,
+    repo: &Repository,
+    cvs_repo: &cvs::Repository,
+    db: &mut Database,
+    git_branch: &str,
+    state: &mut state::Global,
+) -> anyhow::Result<usize> {
+    let mut changes = Vec::new();
+    for change in repo.changed_blobs(Some(&last.tree_id()), &pending.tree_id())? {
+        if state.scope(&change.path) == Scope::Inside {
+            changes.push((state.file(&change.path)?, change.new));
+        }
+    }
+    if changes.is_empty() {
+        return Ok(0);
+    }
+
+    // CVS/Entries tells us cheaply whether anything might have landed: a file
+    // the commit adds or modifies with a revision newer than the one we
+    // recorded, or a file the commit removes that's gone. A file without a
+    // recorded revision, such as one adopted or recorded before revisions
+    // were, has only landed if it holds the blob, and is exported again
+    // otherwise.
+    let entries: HashMap<PathBuf, String> = cvs_repo
+        .revisions(changes.iter().map(|(file, _)| file.cvs_relative_path()))?
+        .into_iter()
+        .collect();
+    let recorded = db.latest_revisions(
+        git_branch,
+        changes.iter().map(|(file, _)| file.cvs_relative_path()),
+    )?;
+    let landed_in =
+        |file: &state::File, revision: Option<&String>, new: Option<Oid>| match (revision, new) {
+            (Some(revision), Some(new)) => match recorded.get(&file.cvs_relative_path()) {
+                Some(recorded) => cvs::newer_revision(revision, recorded),
+                None => holds_blob(&file.absolute_path(), &new),
+            },
+            (None, None) => true,
+            _ => false,
+        };
+
+    let candidates: Vec<_> = changes
+        .iter()
+        .filter(|(file, new)| landed_in(file, entries.get(&file.cvs_relative_path()), *new))
+        .collect();
+    if candidates.is_empty() {
+        return Ok(0);
+    }
+
+    // The server has the final say.
+    let statuses = cvs_repo.status(candidates.iter().map(|(file, _)| file.cvs_relative_path()))?;
+    let mut revisions = Vec::new();
+    let mut landed = 0;
+    for (file, new) in candidates {
+        let path = file.cvs_relative_path();
+        let status = statuses.get(&path);
+        let revision = status
+            .filter(|status| status.working_file)
+            .and_then(|status| status.repository_revision.as_ref());
+        if !landed_in(file, revision, *new) {
+            continue;
+        }
+
+        landed += 1;
+        match (new, revision) {
+            (Some(new), Some(revision)) => {
+                log::debug!("{} already has revision {}", path.display(), revision);
+                state.save_oid(file.clone(), new);
+                revisions.push((path, revision.clone()));
+            }
+            _ => {
+                log::debug!("{} has already been removed", path.display());
+                state.forget(file);
+            }
+        }
+    }
+
+    db.record_revisions(git_branch, &pending.id(), &revisions, &HashMap::new())?;
+    Ok(landed)
+}
+
+/// Whether the working copy of a file has the same contents as a blob. A file
+/// that can't be read doesn't.
+fn holds_blob(path: &Path, blob: &Oid) -> bool {
+    match fs::read(path) {
+        Ok(content) => Oid::hash_object(ObjectType::Blob, &content).ok().as_ref() == Some(blob),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+    use git2::Oid;
+
+    use super::*;
+    use crate::database::ExportStats;
+
+    struct Fixture {
+        dir: tempfile::TempDir,
+        repo: Repository,
+        last: Oid,
+        pending: Oid,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let raw = git2::Repository::init(dir.path().join("git")).unwrap();
+            let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+            let commit = |files: &[(&str, &str)], parents: &[&git2::Commit]| {
+                let mut builder = raw.treebuilder(None).unwrap();
+                for (name, content) in files {
+                    builder
+                        .insert(name, raw.blob(content.as_bytes()).unwrap(), 0o100644)
+                        .unwrap();
+                }
+                let tree = raw.find_tree(builder.write().unwrap()).unwrap();
+                raw.commit(None, &sig, &sig, "commit", &tree, parents)
+                    .unwrap()
+            };
+
+            let last = commit(&[("a.c", "a\n"), ("b.c", "b\n"), ("gone.c", "gone\n")], &[]);
+            let pending = commit(
+                &[("a.c", "a2\n"), ("b.c", "b2\n"), ("new.c", "new\n")],
+                &[&raw.find_commit(last).unwrap()],
+            );
+
+            let repo = Repository::open(dir.path().join("git"), 0).unwrap();
+            Self {
+                dir,
+                repo,
+                last,
+                pending,
+            }
+        }
+
+        /// Runs reconcile against a working copy with the given CVS/Entries and
+        /// files, where cvs status prints the given output, returning the
+        /// number of files that landed, the state, and the database.
+        fn reconcile(
+            &self,
+            entries: &str,
+            files: &[(&str, &str)],
+            status: &str,
+        ) -> (usize, state::Global, Database) {
+            let checkout = self.dir.path().join("checkout");
+            fs::create_dir_all(checkout.join("target/CVS")).unwrap();
+            fs::write(checkout.join("target/CVS/Entries"), entries).unwrap();
+            for (name, content) in files {
+                fs::write(checkout.join("target").join(name), content).unwrap();
+            }
+            fs::write(self.dir.path().join("status"), status).unwrap();
+
+            let cvs = self.dir.path().join("cvs");
+            fs::write(
+                &cvs,
+                format!(
+                    "#!/bin/sh\nif [ \"$1\" = status ]; then cat {}; fi\n",
+                    self.dir.path().join("status").display()
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+            let cvs_repo = cvs::Context::new(cvs.as_os_str())
+                .checkout(
+                    &":local:/cvsroot".parse().unwrap(),
+                    "module",
+                    &checkout,
+                    None,
+                )
+                .unwrap();
+
+            let last = self.repo.commit(&self.last).unwrap();
+            let pending = self.repo.commit(&self.pending).unwrap();
+            let mut db = Database::open(":memory:").unwrap();
+            db.write_branch("main", "main", [&last, &pending].iter().map(|c| c.as_ref()))
+                .unwrap();
+            db.record_revisions(
+                "main",
+                &self.last,
+                &[
+                    (PathBuf::from("target/a.c"), "1.1".into()),
+                    (PathBuf::from("target/b.c"), "1.1".into()),
+                    (PathBuf::from("target/gone.c"), "1.1".into()),
+                ],
+                &HashMap::new(),
+            )
+            .unwrap();
+            db.mark_exported("main", &self.last, &ExportStats::default())
+                .unwrap();
+
+            let mut state = state::Global::new(&checkout, "target", state::Filter::default());
+            self.repo
+                .walk_tree(&last.tree_id(), |_, entry| -> anyhow::Result<_> {
+                    state.save_oid(state.file(entry.file_name()?)?, &entry.id());
+                    Ok(git2::TreeWalkResult::Ok)
+                })
+                .unwrap();
+
+            let landed = reconcile(
+                &last, &pending, &self.repo, &cvs_repo, &mut db, "main", &mut state,
+            )
+            .unwrap();
+            (landed, state, db)
+        }
+
+        fn blob(&self, commit: &Oid, name: &str) -> Oid {
+            let raw = git2::Repository::open(self.dir.path().join("git")).unwrap();
+            let tree = raw.find_commit(*commit).unwrap().tree().unwrap();
+            let oid = tree.get_name(name).unwrap().id();
+            oid
+        }
+    }
+
+    fn status(name: &str, revision: &str) -> String {
+        format!(
+            "File: {}\tStatus: Up-to-date\n\n   Working revision:\t{}\n   Repository revision:\t{}\t/cvsroot/module/target/{},v\n\n",
+            name, revision, revision, name
+        )
+    }
+
+    fn removed_status(name: &str) -> String {
+        format!(
+            "File: no file {}\tStatus: Up-to-date\n\n   Working revision:\tNo entry for {}\n   Repository revision:\t1.2\t/cvsroot/module/target/Attic/{},v\n\n",
+            name, name, name
+        )
+    }
+
+    #[test]
+    fn test_nothing_landed() {
+        let fixture = Fixture::new();
+        let (landed, state, db) = fixture.reconcile(
+            "/a.c/1.1/dummy//\n/b.c/1.1/dummy//\n/gone.c/1.1/dummy//\n",
+            &[],
+            // cvs status shouldn't even be needed.
+            "",
+        );
+
+        assert_eq!(0, landed);
+        let a = state.file("a.c").unwrap();
+        assert_eq!(Some(&fixture.blob(&fixture.last, "a.c")), state.get_oid(&a));
+        assert!(state.get_oid(&state.file("gone.c").unwrap()).is_some());
+        assert_eq!(
+            "1.1",
+            db.latest_revisions("main", std::iter::once("target/a.c"))
+                .unwrap()[Path::new("target/a.c")]
+        );
+    }
+
+    #[test]
+    fn test_partly_landed() {
+        let fixture = Fixture::new();
+        // a.c and the removal of gone.c made it; b.c and new.c didn't.
+        let (landed, state, db) = fixture.reconcile(
+            "/a.c/1.2/dummy//\n/b.c/1.1/dummy//\n",
+            &[],
+            &format!("{}{}", status("a.c", "1.2"), removed_status("gone.c")),
+        );
+
+        assert_eq!(2, landed);
+        assert_eq!(
+            Some(&fixture.blob(&fixture.pending, "a.c")),
+            state.get_oid(&state.file("a.c").unwrap())
+        );
+        assert_eq!(
+            Some(&fixture.blob(&fixture.last, "b.c")),
+            state.get_oid(&state.file("b.c").unwrap())
+        );
+        assert_eq!(None, state.get_oid(&state.file("gone.c").unwrap()));
+        assert_eq!(None, state.get_oid(&state.file("new.c").unwrap()));
+        assert_eq!(
+            fixture.pending.to_string(),
+            db.lookup_revision("target/a.c", "1.2")
+                .unwrap()
+                .unwrap()
+                .oid
+        );
+    }
+
+    #[test]
+    fn test_all_landed() {
+        let fixture = Fixture::new();
+        let (landed, state, _) = fixture.reconcile(
+            "/a.c/1.2/dummy//\n/b.c/1.2/dummy//\n/new.c/1.1/dummy//\n",
+            &[("new.c", "new\n")],
+            &format!(
+                "{}{}{}{}",
+                status("a.c", "1.2"),
+                status("b.c", "1.2"),
+                removed_status("gone.c"),
+                status("new.c", "1.1")
+            ),
+        );
+
+        assert_eq!(4, landed);
+        for name in &["a.c", "b.c", "new.c"] {
+            assert_eq!(
+                Some(&fixture.blob(&fixture.pending, name)),
+                state.get_oid(&state.file(name).unwrap())
+            );
+        }
+        assert_eq!(None, state.get_oid(&state.file("gone.c").unwrap()));
+    }
+
+    #[test]
+    fn test_entries_disagree_with_server() {
+        let fixture = Fixture::new();
+        // The working copy claims a.c changed, but the server doesn't.
+        let (landed, state, _) = fixture.reconcile(
+            "/a.c/1.2/dummy//\n/b.c/1.1/dummy//\n/gone.c/1.1/dummy//\n",
+            &[],
+            &status("a.c", "1.1"),
+        );
+
+        assert_eq!(0, landed);
+        assert_eq!(
+            Some(&fixture.blob(&fixture.last, "a.c")),
+            state.get_oid(&state.file("a.c").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_unrecorded_revision() {
+        let fixture = Fixture::new();
+        // new.c has no recorded revision to compare against, so a newer one in
+        // CVS doesn't mean the pending commit put it there.
+        let (landed, state, _) = fixture.reconcile(
+            "/a.c/1.1/dummy//\n/b.c/1.1/dummy//\n/gone.c/1.1/dummy//\n/new.c/1.1/dummy//\n",
+            &[("new.c", "old\n")],
+            &status("new.c", "1.1"),
+        );
+
+        assert_eq!(0, landed);
+        assert_eq!(None, state.get_oid(&state.file("new.c").unwrap()));
+    }
+}
@@ -0,0 +1,330 @@
+use std::ffi::OsString;
+
+use structopt::StructOpt;
+
+use crate::{
+    database::{Database, Grouping, ReportRow, Summary},
+    json::Value,
+    output::{self, Format},
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(short, long, help = "only report on this git branch")]
+    branch: Option<String>,
+
+    #[structopt(
+        long,
+        default_value = "month",
+        possible_values = Grouping::VARIANTS,
+        help = "group exported commits by export month or author"
+    )]
+    group_by: Grouping,
+
+    #[structopt(
+        long,
+        default_value = "table",
+        possible_values = Format::VARIANTS,
+        help = "output format"
+    )]
+    format: Format,
+
+    #[structopt(
+        long,
+        help = "show a one-page summary of history, authors, and files instead"
+    )]
+    summary: bool,
+
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "number of authors and files to list in the summary"
+    )]
+    limit: usize,
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    print!("{}", report(&opt)?);
+    Ok(())
+}
+
+/// Renders the report or summary in the requested format.
+fn report(opt: &Opt) -> anyhow::Result<String> {
+    let db = Database::open_readonly(&opt.database)?;
+    if opt.summary {
+        let summary = db.summary(opt.branch.as_deref(), opt.limit)?;
+        return Ok(match opt.format {
+            Format::Table => summary_table(&summary),
+            Format::Json => format!("{}\n", summary_json(&summary)),
+        });
+    }
+
+    let rows = db.report(opt.group_by, opt.branch.as_deref())?;
+
+    Ok(match opt.format {
+        Format::Table => table(opt.group_by, &rows),
+        Format::Json => format!("{}\n", json(&rows)),
+    })
+}
+
+fn table(grouping: Grouping, rows: &[ReportRow]) -> String {
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            vec![
+                row.key.clone(),
+                row.commits.to_string(),
+                format!("{:.1}", row.duration_ms as f64 / 1000.0),
+                row.files_added.to_string(),
+                row.files_modified.to_string(),
+                row.files_removed.to_string(),
+                row.bytes_written.to_string(),
+            ]
+        })
+        .collect();
+
+    output::table(
+        &[
+            match grouping {
+                Grouping::Month => "month",
+                Grouping::Author => "author",
+            },
+            "commits",
+            "seconds",
+            "added",
+            "modified",
+            "removed",
+            "bytes",
+        ],
+        &rows,
+    )
+}
+
+fn json(rows: &[ReportRow]) -> Value {
+    Value::Array(
+        rows.iter()
+            .map(|row| {
+                Value::object(vec![
+                    ("key", row.key.as_str().into()),
+                    ("commits", row.commits.into()),
+                    ("duration_ms", row.duration_ms.into()),
+                    ("files_added", row.files_added.into()),
+                    ("files_modified", row.files_modified.into()),
+                    ("files_removed", row.files_removed.into()),
+                    ("bytes_written", row.bytes_written.into()),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn summary_table(summary: &Summary) -> String {
+    let mut out = output::table(
+        &["commits", "bytes", "binary files"],
+        &[vec![
+            summary.commits.to_string(),
+            summary.bytes_written.to_string(),
+            summary.binary_files.to_string(),
+        ]],
+    );
+
+    let months: Vec<Vec<String>> = summary
+        .months
+        .iter()
+        .map(|(month, commits)| vec![month.clone(), commits.to_string()])
+        .collect();
+    out.push('\n');
+    out.push_str(&output::table(&["month", "commits"], &months));
+
+    let authors: Vec<Vec<String>> = summary
+        .authors
+        .iter()
+        .map(|author| {
+            vec![
+                author.author.clone(),
+                author.commits.to_string(),
+                author.lines_added.to_string(),
+                author.lines_removed.to_string(),
+            ]
+        })
+        .collect();
+    out.push('\n');
+    out.push_str(&output::table(
+        &["author", "commits", "lines added", "lines removed"],
+        &authors,
+    ));
+
+    let files: Vec<Vec<String>> = summary
+        .largest_files
+        .iter()
+        .map(|(path, size)| vec![path.clone(), size.to_string()])
+        .collect();
+    out.push('\n');
+    out.push_str(&output::table(&["file", "bytes"], &files));
+
+    out
+}
+
+fn summary_json(summary: &Summary) -> Value {
+    Value::object(vec![
+        ("commits", summary.commits.into()),
+        ("bytes_written", summary.bytes_written.into()),
+        ("binary_files", summary.binary_files.into()),
+        (
+            "months",
+            Value::Array(
+                summary
+                    .months
+                    .iter()
+                    .map(|(month, commits)| {
+                        Value::object(vec![
+                            ("month", month.as_str().into()),
+                            ("commits", (*commits).into()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+        (
+            "authors",
+            Value::Array(
+                summary
+                    .authors
+                    .iter()
+                    .map(|author| {
+                        Value::object(vec![
+                            ("author", author.author.as_str().into()),
+                            ("commits", author.commits.into()),
+                            ("lines_added", author.lines_added.into()),
+                            ("lines_removed", author.lines_removed.into()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+        (
+            "largest_files",
+            Value::Array(
+                summary
+                    .largest_files
+                    .iter()
+                    .map(|(path, size)| {
+                        Value::object(vec![
+                            ("path", path.as_str().into()),
+                            ("bytes", (*size).into()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{database::ExportStats, git::Repository, test_support::GitBuilder};
+
+    fn stats(bytes_written: u64) -> ExportStats {
+        ExportStats {
+            duration: Duration::from_millis(1500),
+            files_added: 1,
+            files_modified: 0,
+            files_removed: 0,
+            bytes_written,
+            commit_time: None,
+            lines_added: 2,
+            lines_removed: 0,
+            empty: false,
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let mut git = GitBuilder::new();
+        let first = git.file("a.txt", "a\n").commit("first");
+        let second = git.file("b.txt", "b\n").commit("second");
+        git.file("c.txt", "c\n").commit("unexported");
+        let repo = Repository::open(git.path(), 0).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("git2cvs.db");
+        let mut db = Database::open(&database).unwrap();
+        db.write_branch(
+            "main",
+            "main",
+            [first, second].iter().map(|oid| repo.commit(oid).unwrap()),
+        )
+        .unwrap();
+        db.write_branch(
+            "other",
+            "other",
+            std::iter::once(repo.commit(&first).unwrap()),
+        )
+        .unwrap();
+        db.mark_exported("main", &first, &stats(100)).unwrap();
+        db.mark_exported("main", &second, &stats(200)).unwrap();
+        db.mark_exported("other", &first, &stats(50)).unwrap();
+        drop(db);
+
+        let report = |args: &[&str]| {
+            let mut opt =
+                Opt::from_iter_safe(std::iter::once(&"report").chain(args.iter())).unwrap();
+            opt.database = database.clone().into();
+            report(&opt).unwrap()
+        };
+
+        assert_eq!(
+            "author                   commits  seconds  added  modified  removed  bytes\n\
+             Test <test@example.com>  3        4.5      3      0         0        350\n",
+            report(&["--group-by", "author"])
+        );
+        assert_eq!(
+            "author                   commits  seconds  added  modified  removed  bytes\n\
+             Test <test@example.com>  1        1.5      1      0         0        50\n",
+            report(&["--group-by", "author", "-b", "other"])
+        );
+        assert_eq!(
+            "[{\"key\":\"Test <test@example.com>\",\"commits\":2,\"duration_ms\":3000,\"files_added\":2,\"files_modified\":0,\"files_removed\":0,\"bytes_written\":300}]\n",
+            report(&["--group-by", "author", "-b", "main", "--format", "json"])
+        );
+        assert_eq!(
+            "author  commits  seconds  added  modified  removed  bytes\n",
+            report(&["--group-by", "author", "-b", "missing"])
+        );
+
+        // The summary is filtered by branch in the same way.
+        let summary = |commits: i64, bytes: i64| {
+            format!(
+                "commits  bytes  binary files\n\
+                 {:<7}  {:<5}  0\n\
+                 \n\
+                 month    commits\n\
+                 2001-09  {}\n\
+                 \n\
+                 author                   commits  lines added  lines removed\n\
+                 Test <test@example.com>  {:<7}  {:<11}  0\n\
+                 \n\
+                 file  bytes\n",
+                commits,
+                bytes,
+                commits,
+                commits,
+                commits * 2
+            )
+        };
+        assert_eq!(summary(3, 350), report(&["--summary"]));
+        assert_eq!(summary(2, 300), report(&["--summary", "-b", "main"]));
+        assert_eq!(summary(1, 50), report(&["--summary", "-b", "other"]));
+        assert_eq!(
+            "{\"commits\":0,\"bytes_written\":0,\"binary_files\":0,\"months\":[],\"authors\":[],\"largest_files\":[]}\n",
+            report(&["--summary", "-b", "missing", "--format", "json"])
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::ffi::OsString;
+
+use structopt::StructOpt;
+
+use crate::{database::Database, git::Repository, output};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(short, long, default_value = ".", help = "git repository")]
+    git: OsString,
+
+    #[structopt(
+        short,
+        long,
+        help = "the branch the commit is on; defaults to the branch HEAD refers to"
+    )]
+    branch: Option<String>,
+
+    #[structopt(subcommand)]
+    action: Action,
+}
+
+#[derive(Debug, StructOpt)]
+enum Action {
+    /// Leaves a commit out of future exports. Its changes are included in the
+    /// next commit that is exported. The commit can be abbreviated, but has to
+    /// be on the branch.
+    Add {
+        oid: String,
+
+        #[structopt(long, help = "why the commit is skipped")]
+        reason: Option<String>,
+    },
+
+    /// Lists the commits that are skipped.
+    List,
+
+    /// Stops skipping a commit.
+    Remove { oid: String },
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    let db = match opt.action {
+        Action::List => Database::open_readonly(&opt.database)?,
+        _ => Database::open(&opt.database)?,
+    };
+
+    match opt.action {
+        Action::Add { oid, reason } => {
+            let repo = Repository::discover(&opt.git, 0)?;
+            let branch = match &opt.branch {
+                Some(branch) => branch.clone(),
+                None => repo.head_branch(false)?,
+            };
+            let oid = repo.require_branch(&branch, false)?.resolve(&oid)?;
+            db.skip_commit(&oid, reason.as_deref())?;
+        }
+        Action::List => print!(
+            "{}",
+            output::table(
+                &["oid", "reason"],
+                &db.skipped_commits()?
+                    .into_iter()
+                    .map(|skipped| vec![
+                        skipped.oid.to_string(),
+                        skipped.reason.unwrap_or_default()
+                    ])
+                    .collect::<Vec<_>>(),
+            )
+        ),
+        Action::Remove { oid } => {
+            let resolved = Repository::discover(&opt.git, 0)?.resolve_commit(&oid)?;
+            if !db.unskip_commit(&resolved)? {
+                anyhow::bail!("commit {} is not skipped", oid);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::GitBuilder;
+
+    fn run_skip(git: &GitBuilder, database: &std::path::Path, args: &[&str]) -> anyhow::Result<()> {
+        let mut argv = vec!["skip", "-g", git.path().to_str().unwrap()];
+        argv.extend(args);
+        let mut opt = Opt::from_iter_safe(argv)?;
+        opt.database = database.into();
+        run(opt)
+    }
+
+    #[test]
+    fn test_short_sha() {
+        let mut git = GitBuilder::new();
+        git.file("a.txt", "a\n").commit("first");
+        let dataset = git.file("data.bin", "huge").commit("dataset");
+        let trunk = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        git.branch("other").checkout("other");
+        let other = git.file("b.txt", "b\n").commit("other");
+        git.checkout(&trunk);
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("git2cvs.db");
+
+        let short = dataset.to_string()[..7].to_string();
+        run_skip(&git, &database, &["add", &short, "--reason", "dataset"]).unwrap();
+        let db = Database::open(&database).unwrap();
+        assert_eq!(
+            vec![dataset],
+            db.skipped_commits()
+                .unwrap()
+                .into_iter()
+                .map(|skipped| skipped.oid)
+                .collect::<Vec<_>>()
+        );
+        drop(db);
+
+        // A commit that isn't on the branch would never be skipped.
+        assert!(run_skip(&git, &database, &["add", &other.to_string()[..7]]).is_err());
+        run_skip(
+            &git,
+            &database,
+            &["-b", "other", "add", &other.to_string()[..7]],
+        )
+        .unwrap();
+
+        run_skip(&git, &database, &["remove", &short]).unwrap();
+        assert_eq!(
+            format!("commit {} is not skipped", short),
+            run_skip(&git, &database, &["remove", &short])
+                .unwrap_err()
+                .to_string()
+        );
+    }
+}
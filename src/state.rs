@@ -1,41 +1,636 @@
 use std::{
-    collections::{HashMap, HashSet},
+    cell::{Ref, RefCell},
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
     ffi::OsStr,
+    fmt,
     hash::Hash,
-    path::PathBuf,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
     rc::Rc,
+    str::FromStr,
 };
 
-use git2::Oid;
+use git2::{ObjectType, Oid};
+
+use crate::{
+    attributes::Glob,
+    cvs::{Entry, KeywordMode},
+    detect::Detection,
+    error::Error,
+    pathmap::PathMap,
+};
 
 #[derive(Debug)]
 struct Environment {
     absolute_base: PathBuf,
     cvs_base: PathBuf,
+    filter: Filter,
+    // Case renames are added to the map as commits are exported.
+    path_map: RefCell<PathMap>,
 }
 
-#[derive(Debug)]
+/// The set of git paths being exported. An empty filter includes everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    includes: Vec<PathBuf>,
+    // Paths matching these, or within directories matching them, are left
+    // out even if they're included.
+    excludes: Vec<Glob>,
+}
+
+/// Where a path is relative to a filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// The path is, or is within, an included path.
+    Inside,
+    /// The path is a directory leading to an included path.
+    Ancestor,
+    Outside,
+}
+
+/// What to do with paths in a commit that differ only in case, which a
+/// checkout on a case-insensitive file system can't hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseCollisions {
+    /// Fail the commit, listing the paths.
+    Error,
+    /// Export the newer path under a name with a numbered suffix.
+    Rename,
+}
+
+impl CaseCollisions {
+    pub const VARIANTS: &'static [&'static str] = &["error", "rename"];
+}
+
+impl FromStr for CaseCollisions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(CaseCollisions::Error),
+            "rename" => Ok(CaseCollisions::Rename),
+            _ => anyhow::bail!("unknown case collision policy {}", s),
+        }
+    }
+}
+
+/// The name of the directory CVS keeps its metadata in within a checkout.
+const CVS_DIR: &str = "CVS";
+
+/// Parses a --cvs-dir-substitute name, which has to be a single path
+/// component that isn't itself taken for a CVS directory.
+pub fn parse_cvs_dir_substitute(s: &str) -> anyhow::Result<String> {
+    if s.is_empty() || s == "." || s == ".." || s.contains('/') {
+        anyhow::bail!("{:?} is not a file name", s);
+    }
+    if s.eq_ignore_ascii_case(CVS_DIR) {
+        anyhow::bail!("{} would still be taken for a CVS directory", s);
+    }
+    Ok(s.to_string())
+}
+
+/// Parses an --exclude glob. A trailing slash is allowed, as in .gitignore,
+/// though it matches files as well as directories.
+pub fn parse_exclude(s: &str) -> anyhow::Result<Glob> {
+    match s.strip_suffix('/') {
+        Some(dir) if !dir.ends_with('/') => dir.parse(),
+        _ => s.parse(),
+    }
+}
+
+// How a path compares on a case-insensitive file system.
+fn fold_case(path: &Path) -> Vec<String> {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy().to_lowercase())
+        .collect()
+}
+
+impl Filter {
+    pub fn new<I, P>(includes: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        // Collecting the components normalises away trailing and doubled
+        // slashes, so equivalent filters have the same fingerprint.
+        let mut includes: Vec<PathBuf> = includes
+            .into_iter()
+            .map(|path| path.as_ref().components().collect())
+            .collect();
+        includes.sort();
+        includes.dedup();
+
+        Self {
+            includes,
+            excludes: Vec::new(),
+        }
+    }
+
+    /// Leaves out the paths matching any of the given globs. Unlike includes,
+    /// excludes aren't part of the fingerprint: a path that's no longer
+    /// excluded is simply added by the next commit.
+    pub fn with_excludes(mut self, excludes: &[Glob]) -> Self {
+        self.excludes = excludes.to_vec();
+        self
+    }
+
+    /// Whether the path, or a directory containing it, is excluded.
+    pub fn excludes(&self, path: &Path) -> bool {
+        !self.excludes.is_empty()
+            && path
+                .ancestors()
+                .filter(|path| !path.as_os_str().is_empty())
+                .any(|path| self.excludes.iter().any(|glob| glob.matches(path)))
+    }
+
+    pub fn scope(&self, path: &Path) -> Scope {
+        if self.excludes(path) {
+            return Scope::Outside;
+        }
+        if self.includes.is_empty() {
+            return Scope::Inside;
+        }
+
+        let mut scope = Scope::Outside;
+        for include in self.includes.iter() {
+            if path.starts_with(include) {
+                return Scope::Inside;
+            } else if include.starts_with(path) {
+                scope = Scope::Ancestor;
+            }
+        }
+        scope
+    }
+
+    /// Identifies the filter, so that we can tell whether a branch is being
+    /// exported with the same filter as last time. Returns None if everything
+    /// is included.
+    pub fn fingerprint(&self) -> Option<String> {
+        if self.includes.is_empty() {
+            return None;
+        }
+
+        let mut canonical = Vec::new();
+        for include in self.includes.iter() {
+            canonical.extend_from_slice(include.as_os_str().as_bytes());
+            canonical.push(b'\n');
+        }
+        Oid::hash_object(ObjectType::Blob, &canonical)
+            .ok()
+            .map(|oid| oid.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Global {
     environment: Rc<Environment>,
     known_files: HashMap<File, Oid>,
+    // Every known file and every directory leading to one, with the number of
+    // known files at or within it and where it goes in CVS folded for case,
+    // kept up to date as files come and go so that looking for case
+    // collisions doesn't mean going through every known file.
+    known_prefixes: HashMap<PathBuf, (usize, Vec<String>)>,
+    // The known prefixes by where they go in CVS, folded for case.
+    folded_prefixes: HashMap<Vec<String>, BTreeSet<PathBuf>>,
+    // The permission bits of the known files that are executable.
+    executables: HashMap<File, u32>,
+    // The commit that the known files were last brought up to date with.
+    base: Option<Oid>,
+    // Whether paths named like CVS's metadata directories in any case are
+    // treated as clashing with them.
+    cvs_dirs_ignore_case: bool,
+
+    // The files each blob was written to during this run, so that other files
+    // with the same blob can be copied from them.
+    written_blobs: HashMap<Oid, Vec<WrittenBlob>>,
+    deduplicated: usize,
+    bytes_deduplicated: u64,
+}
+
+#[derive(Debug, Clone)]
+struct WrittenBlob {
+    file: File,
+    detection: Detection,
+    // The detection based on the blob's content alone, if that's what was
+    // used.
+    content_detection: Option<Detection>,
+    // Whether the blob has a $ that might start a keyword, and whether CVS
+    // expands keywords in the file. If both are true, CVS may rewrite the
+    // file when it's committed, so that it no longer holds the blob.
+    has_keywords: bool,
+    expands_keywords: bool,
 }
 
 impl Global {
-    pub fn new<P: Into<PathBuf>, OS: AsRef<OsStr>>(tempdir: P, cvs_base: OS) -> Self {
+    pub fn new<P: Into<PathBuf>, OS: AsRef<OsStr>>(
+        tempdir: P,
+        cvs_base: OS,
+        filter: Filter,
+    ) -> Self {
         Self {
             environment: Rc::new(Environment {
                 absolute_base: tempdir.into(),
                 cvs_base: cvs_base.as_ref().into(),
+                filter,
+                path_map: RefCell::new(PathMap::default()),
             }),
             known_files: HashMap::new(),
+            known_prefixes: HashMap::new(),
+            folded_prefixes: HashMap::new(),
+            executables: HashMap::new(),
+            base: None,
+            cvs_dirs_ignore_case: false,
+            written_blobs: HashMap::new(),
+            deduplicated: 0,
+            bytes_deduplicated: 0,
         }
     }
 
-    pub fn file<P: AsRef<OsStr>>(&self, path: P) -> File {
-        File {
-            environment: self.environment.clone(),
-            relative_path: path.as_ref().into(),
+    /// Builds the state of a working copy that's already checked out from
+    /// what CVS has in its Entries files, with paths relative to the target,
+    /// and the blobs the database says were last written to each path.
+    ///
+    /// Files CVS has that the database doesn't are still tracked, but with a
+    /// zero OID so that they're rewritten the next time they're seen, unless
+    /// the path map means there's no telling which git path they came from.
+    /// Files the database has that CVS doesn't aren't tracked, so they'll be
+    /// added again. Either way, they're returned as discrepancies.
+    pub fn from_entries<P: Into<PathBuf>, OS: AsRef<OsStr>>(
+        tempdir: P,
+        cvs_base: OS,
+        filter: Filter,
+        path_map: PathMap,
+        entries: &[(PathBuf, Entry)],
+        stored: &HashMap<PathBuf, Oid>,
+    ) -> anyhow::Result<(Self, Vec<EntryDiscrepancy>)> {
+        let mut state = Self::new(tempdir, cvs_base, filter).with_path_map(path_map);
+        let mut discrepancies = Vec::new();
+
+        // Entries are where files are in CVS, but the database has git paths.
+        let path_map = state.environment.path_map.borrow();
+        let git_paths: HashMap<_, _> = stored
+            .keys()
+            .map(|path| (path_map.map(path).into_owned(), path.clone()))
+            .collect();
+        drop(path_map);
+
+        for (path, entry) in entries {
+            if entry.is_removed() {
+                continue;
+            }
+
+            let git_path = git_paths.get(path).unwrap_or(path);
+            if state.scope(git_path) != Scope::Inside {
+                continue;
+            }
+
+            let oid = match stored.get(git_path) {
+                Some(oid) => *oid,
+                None => {
+                    discrepancies.push(EntryDiscrepancy::NotInDatabase(path.clone()));
+                    if state.environment.path_map.borrow().map(path) != path.as_path() {
+                        continue;
+                    }
+                    Oid::zero()
+                }
+            };
+            state.save_oid(state.file(git_path)?, &oid);
         }
+
+        let mut missing: Vec<_> = stored
+            .keys()
+            .filter(|path| {
+                state
+                    .file(path)
+                    .map_or(true, |file| !state.known_files.contains_key(&file))
+            })
+            .cloned()
+            .collect();
+        missing.sort();
+        discrepancies.extend(missing.into_iter().map(EntryDiscrepancy::NotInEntries));
+
+        Ok((state, discrepancies))
+    }
+
+    pub fn path_map(&self) -> Ref<'_, PathMap> {
+        self.environment.path_map.borrow()
+    }
+
+    /// Places files in CVS according to the map. This has to be done before
+    /// any files are created.
+    pub fn with_path_map(mut self, path_map: PathMap) -> Self {
+        debug_assert!(self.known_files.is_empty());
+        self.environment = Rc::new(Environment {
+            absolute_base: self.environment.absolute_base.clone(),
+            cvs_base: self.environment.cvs_base.clone(),
+            filter: self.environment.filter.clone(),
+            path_map: RefCell::new(path_map),
+        });
+        self
+    }
+
+    /// Fails if the path map sends two of the known files to the same place
+    /// in CVS.
+    pub fn check_path_map(&self) -> anyhow::Result<()> {
+        let path_map = self.environment.path_map.borrow();
+        if let Some((a, b)) = path_map.collision(self.known_files.keys().map(File::git_path)) {
+            return Err(Error::PathConflict {
+                target: path_map.map(a).into_owned(),
+                paths: vec![a.into(), b.into()],
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Returns the pairs of git paths, or of the directories leading to them,
+    /// that would be exported to places in CVS that differ only in case. Each
+    /// pair is given once, with the path that's already in CVS first where
+    /// there is one; pairs that are both already in CVS are left alone.
+    pub fn case_collisions(&self, paths: &BTreeSet<PathBuf>) -> Vec<(PathBuf, PathBuf)> {
+        let path_map = self.environment.path_map.borrow();
+
+        // The known files were checked when they were added, so only the new
+        // paths need checking, against each other and the known files that
+        // are staying.
+        let staying = |prefix: &Path| {
+            paths
+                .range(prefix.to_path_buf()..)
+                .next()
+                .is_some_and(|path| path.starts_with(prefix))
+        };
+
+        let mut seen: HashMap<Vec<String>, (PathBuf, &Path)> = HashMap::new();
+        let mut collisions = BTreeSet::new();
+        for path in paths
+            .iter()
+            .filter(|path| !self.known_prefixes.contains_key(path.as_path()))
+        {
+            let mut prefixes: Vec<_> = path
+                .ancestors()
+                .filter(|prefix| !prefix.as_os_str().is_empty())
+                .filter(|prefix| !self.known_prefixes.contains_key(*prefix))
+                .collect();
+            prefixes.reverse();
+
+            for prefix in prefixes {
+                let mapped = path_map.map(prefix).into_owned();
+                let folded = fold_case(&mapped);
+                let known: Vec<_> = self
+                    .folded_prefixes
+                    .get(&folded)
+                    .into_iter()
+                    .flatten()
+                    .filter(|known| staying(known))
+                    .collect();
+                if let Some(first) = known.first() {
+                    if !known.iter().any(|known| path_map.map(known) == mapped) {
+                        collisions.insert(((*first).clone(), prefix.to_path_buf()));
+                        break;
+                    }
+                    continue;
+                }
+
+                match seen.get(&folded) {
+                    None => {
+                        seen.insert(folded, (mapped, prefix));
+                    }
+                    Some((other, _)) if *other == mapped => {}
+                    Some((_, other)) => {
+                        collisions.insert((other.to_path_buf(), prefix.to_path_buf()));
+                        break;
+                    }
+                }
+            }
+        }
+        collisions.into_iter().collect()
+    }
+
+    /// Sends a git path, or a directory leading to one, to a place in CVS
+    /// that differs from where it would go by a numbered suffix, choosing the
+    /// first that doesn't collide with the known files or the given paths.
+    /// Returns the new path, relative to the target.
+    pub fn rename_for_case<'a, I>(&self, git_path: &Path, paths: I) -> PathBuf
+    where
+        I: IntoIterator<Item = &'a PathBuf>,
+    {
+        let taken = self.taken_paths(paths);
+        let mapped = self
+            .environment
+            .path_map
+            .borrow()
+            .map(git_path)
+            .into_owned();
+        let stem = mapped.file_stem().unwrap_or_default().to_os_string();
+        let renamed = (1..)
+            .map(|n| {
+                let mut name = stem.clone();
+                name.push(format!("~{}", n));
+                if let Some(extension) = mapped.extension() {
+                    name.push(".");
+                    name.push(extension);
+                }
+                mapped.with_file_name(name)
+            })
+            .find(|renamed| !taken.contains(&fold_case(renamed)))
+            .expect("suffixes run out");
+
+        self.environment
+            .path_map
+            .borrow_mut()
+            .insert(git_path.to_path_buf(), renamed.clone());
+        renamed
+    }
+
+    // Where the known files, the given paths and the directories leading to
+    // them all go in CVS, folded for case.
+    fn taken_paths<'a, I>(&self, paths: I) -> HashSet<Vec<String>>
+    where
+        I: IntoIterator<Item = &'a PathBuf>,
+    {
+        let path_map = self.environment.path_map.borrow();
+        self.folded_prefixes
+            .keys()
+            .cloned()
+            .chain(
+                paths
+                    .into_iter()
+                    .flat_map(|path| path.ancestors())
+                    .map(|prefix| fold_case(&path_map.map(prefix))),
+            )
+            .collect()
+    }
+
+    // Counts a newly known file in the prefixes leading to it.
+    fn add_prefixes(&mut self, git_path: &Path) {
+        let path_map = self.environment.path_map.borrow();
+        for prefix in git_path
+            .ancestors()
+            .filter(|prefix| !prefix.as_os_str().is_empty())
+        {
+            let (count, folded) = self
+                .known_prefixes
+                .entry(prefix.to_path_buf())
+                .or_insert_with(|| (0, fold_case(&path_map.map(prefix))));
+            if *count == 0 {
+                self.folded_prefixes
+                    .entry(folded.clone())
+                    .or_default()
+                    .insert(prefix.to_path_buf());
+            }
+            *count += 1;
+        }
+    }
+
+    // Drops a file that's no longer known from the prefixes leading to it.
+    fn remove_prefixes(&mut self, git_path: &Path) {
+        for prefix in git_path
+            .ancestors()
+            .filter(|prefix| !prefix.as_os_str().is_empty())
+        {
+            let (count, _) = match self.known_prefixes.get_mut(prefix) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            *count -= 1;
+            if *count > 0 {
+                continue;
+            }
+
+            if let Some((_, folded)) = self.known_prefixes.remove(prefix) {
+                if let Some(known) = self.folded_prefixes.get_mut(&folded) {
+                    known.remove(prefix);
+                    if known.is_empty() {
+                        self.folded_prefixes.remove(&folded);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Treats paths named like CVS's metadata directories in any case as
+    /// clashing with them, as a case-insensitive checkout would.
+    pub fn with_cvs_dirs_ignoring_case(mut self, ignore_case: bool) -> Self {
+        self.cvs_dirs_ignore_case = ignore_case;
+        self
+    }
+
+    /// Returns whether a git path would be exported to a place in CVS with
+    /// the name of CVS's own metadata directories.
+    pub fn is_cvs_dir(&self, git_path: &Path) -> bool {
+        let mapped = self
+            .environment
+            .path_map
+            .borrow()
+            .map(git_path)
+            .into_owned();
+        match mapped.file_name().and_then(OsStr::to_str) {
+            Some(name) if self.cvs_dirs_ignore_case => name.eq_ignore_ascii_case(CVS_DIR),
+            Some(name) => name == CVS_DIR,
+            None => false,
+        }
+    }
+
+    /// Returns the git paths, or directories leading to them, that
+    /// is_cvs_dir is true of. Only the outermost is given for each path.
+    pub fn cvs_dirs<'a, I>(&self, paths: I) -> Vec<PathBuf>
+    where
+        I: IntoIterator<Item = &'a PathBuf>,
+    {
+        let mut found = BTreeSet::new();
+        for path in paths {
+            let mut prefixes: Vec<_> = path
+                .ancestors()
+                .filter(|prefix| !prefix.as_os_str().is_empty())
+                .collect();
+            prefixes.reverse();
+            if let Some(prefix) = prefixes.into_iter().find(|prefix| self.is_cvs_dir(prefix)) {
+                found.insert(prefix.to_path_buf());
+            }
+        }
+        found.into_iter().collect()
+    }
+
+    /// Sends a git path, or a directory leading to one, to a place in CVS
+    /// with the given name instead, unless the known files or the given paths
+    /// already have something there. Returns the new path, relative to the
+    /// target.
+    pub fn rename_cvs_dir<'a, I>(
+        &self,
+        git_path: &Path,
+        substitute: &str,
+        paths: I,
+    ) -> anyhow::Result<PathBuf>
+    where
+        I: IntoIterator<Item = &'a PathBuf>,
+    {
+        let mapped = self
+            .environment
+            .path_map
+            .borrow()
+            .map(git_path)
+            .into_owned();
+        let renamed = mapped.with_file_name(substitute);
+        if self.taken_paths(paths).contains(&fold_case(&renamed)) {
+            anyhow::bail!(
+                "cannot export {} as {}, which is already taken",
+                git_path.display(),
+                renamed.display()
+            );
+        }
+
+        self.environment
+            .path_map
+            .borrow_mut()
+            .insert(git_path.to_path_buf(), renamed.clone());
+        Ok(renamed)
+    }
+
+    pub fn scope<P: AsRef<Path>>(&self, path: P) -> Scope {
+        self.environment.filter.scope(path.as_ref())
+    }
+
+    pub fn excludes<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.environment.filter.excludes(path.as_ref())
+    }
+
+    /// Returns the file at the given git path, which must not be outside the
+    /// filter, or lead anywhere but down from the top of the tree.
+    pub fn file<P: AsRef<OsStr>>(&self, path: P) -> anyhow::Result<File> {
+        // A crafted tree could otherwise have files written anywhere the
+        // working copy's path can be joined to.
+        let bytes = path.as_ref().as_bytes();
+        if bytes
+            .split(|byte| *byte == b'/')
+            .any(|component| matches!(component, b"" | b"." | b".."))
+        {
+            anyhow::bail!(
+                "{} is not a path within the tree",
+                crate::git::escape_bytes(bytes)
+            );
+        }
+
+        let relative_path = PathBuf::from(path.as_ref());
+        if self.scope(&relative_path) == Scope::Outside {
+            anyhow::bail!("{} is outside the exported paths", relative_path.display());
+        }
+
+        Ok(File {
+            environment: self.environment.clone(),
+            relative_path,
+        })
+    }
+
+    /// The directory within the working copy that files are written to.
+    pub fn target_path(&self) -> PathBuf {
+        self.environment
+            .absolute_base
+            .join(&self.environment.cvs_base)
+    }
+
+    pub fn iter_files(&self) -> impl Iterator<Item = (&File, &Oid)> {
+        self.known_files.iter()
     }
 
     pub fn get_oid(&self, file: &File) -> Option<&Oid> {
@@ -43,68 +638,401 @@ impl Global {
     }
 
     pub fn save_oid(&mut self, file: File, oid: &Oid) {
-        self.known_files.insert(file, oid.clone());
+        debug_assert_eq!(
+            Scope::Inside,
+            self.scope(&file.relative_path),
+            "tracking {:?}",
+            &file.relative_path
+        );
+        let git_path = file.relative_path.clone();
+        if self.known_files.insert(file, *oid).is_none() {
+            self.add_prefixes(&git_path);
+        }
     }
 
-    pub fn remove_files_unseen_in_commit(&mut self, commit: &Commit) -> HashSet<File> {
-        // This would be _much_ cleaner (and wouldn't require the clone) with
-        // drain_filter(), but that's currently unstable.
-        let mut removed = HashSet::new();
-        self.known_files.retain(|file, _| {
-            if !commit.seen.contains(file) {
-                removed.insert(file.clone());
-                false
-            } else {
-                true
+    /// Goes back to a copy of the state taken before a commit that failed
+    /// part way through. Files the commit added are forgotten, so that the
+    /// next commit adds them again, and files it got as far as writing or
+    /// deleting are kept with a null blob, so that the next commit writes or
+    /// removes them whatever it has for them.
+    pub fn roll_back(&mut self, mut before: Global) {
+        #[allow(clippy::mutable_key_type)]
+        let known_files = &self.known_files;
+        for (file, oid) in before.known_files.iter_mut() {
+            if known_files.get(file) != Some(oid) {
+                *oid = Oid::zero();
             }
+        }
+
+        // What was written is still there, as far as it goes.
+        before.written_blobs = std::mem::take(&mut self.written_blobs);
+        before.deduplicated = self.deduplicated;
+        before.bytes_deduplicated = self.bytes_deduplicated;
+        *self = before;
+    }
+
+    /// Stops tracking a file that's no longer in the working copy.
+    pub fn forget(&mut self, file: &File) {
+        if self.known_files.remove(file).is_some() {
+            self.remove_prefixes(file.git_path());
+        }
+        self.executables.remove(file);
+    }
+
+    pub fn is_executable(&self, file: &File) -> bool {
+        self.executables.contains_key(file)
+    }
+
+    /// Records a file's git mode, which only matters if it's executable.
+    pub fn set_mode(&mut self, file: &File, filemode: i32) {
+        if (filemode & 0o111) != 0 {
+            self.executables
+                .insert(file.clone(), filemode as u32 & 0o777);
+        } else {
+            self.executables.remove(file);
+        }
+    }
+
+    /// Returns the git path and permission bits of each executable file.
+    pub fn iter_executable_files(&self) -> impl Iterator<Item = (&Path, u32)> {
+        self.executables
+            .iter()
+            .map(|(file, mode)| (file.git_path(), *mode))
+    }
+
+    /// The commit that the known files reflect, if they reflect a whole
+    /// commit, so that the next commit only needs to be compared with it.
+    pub fn base(&self) -> Option<Oid> {
+        self.base
+    }
+
+    pub fn set_base(&mut self, commit: Option<Oid>) {
+        self.base = commit;
+    }
+
+    /// Returns the known files at or within the given git path that weren't
+    /// seen in the commit, sorted by path.
+    pub fn unseen_files<P: AsRef<Path>>(&self, commit: &Commit, prefix: P) -> Vec<File> {
+        let mut unseen: Vec<_> = self
+            .known_files
+            .keys()
+            .filter(|file| file.git_path().starts_with(prefix.as_ref()))
+            .filter(|file| !commit.seen.contains(*file))
+            .cloned()
+            .collect();
+        unseen.sort();
+        unseen
+    }
+
+    /// Remembers that a blob was written to a file with the given detection
+    /// result. attributes is the gitattributes detection for the file, if
+    /// any. has_keywords is whether the blob might have keywords in it, and
+    /// expands_keywords whether CVS expands them in the file.
+    pub fn wrote_blob(
+        &mut self,
+        oid: &Oid,
+        file: File,
+        detection: Detection,
+        attributes: Option<Detection>,
+        has_keywords: bool,
+        expands_keywords: bool,
+    ) {
+        // Files only hash and compare their paths, so the path map changing
+        // underneath them doesn't matter.
+        #[allow(clippy::mutable_key_type)]
+        let known_files = &self.known_files;
+        let written = self.written_blobs.entry(*oid).or_default();
+
+        // Files that have been overwritten since are no use as sources.
+        written.retain(|written| known_files.get(&written.file) == Some(oid));
+        written.push(WrittenBlob {
+            file,
+            detection,
+            content_detection: match attributes {
+                Some(_) => None,
+                None => Some(detection),
+            },
+            has_keywords,
+            expands_keywords,
         });
+    }
+
+    /// Returns a file in the working copy that already holds the blob, written
+    /// with the same detection result that a file with the given gitattributes
+    /// detection would get, along with that detection result and whether the
+    /// blob might have keywords in it.
+    pub fn duplicate_of(
+        &self,
+        oid: &Oid,
+        attributes: Option<Detection>,
+    ) -> Option<(File, Detection, bool)> {
+        self.written_blobs.get(oid)?.iter().find_map(|written| {
+            // The file may have changed since the blob was written to it,
+            // either in git or by CVS expanding keywords in it.
+            if self.known_files.get(&written.file) != Some(oid)
+                || (written.has_keywords && written.expands_keywords)
+            {
+                return None;
+            }
+
+            let detection = attributes.or(written.content_detection)?;
+            if detection == written.detection {
+                Some((written.file.clone(), detection, written.has_keywords))
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn deduplicated(&mut self, bytes: u64) {
+        self.deduplicated += 1;
+        self.bytes_deduplicated += bytes;
+    }
+
+    /// Returns how many files were copied from another file with the same
+    /// blob, and how many bytes that saved reading from git.
+    pub fn deduplication_stats(&self) -> (usize, u64) {
+        (self.deduplicated, self.bytes_deduplicated)
+    }
 
+    /// Forgets the files that were removed in the commit, returning them
+    /// deepest first in CVS, then sorted by path, so that a directory's
+    /// contents always come before anything beside it. After a walk of the
+    /// whole tree, that's every file that wasn't seen.
+    pub fn remove_files(&mut self, commit: &Commit) -> Vec<File> {
+        let mut removed: Vec<_> = commit
+            .removed
+            .iter()
+            .filter(|file| self.known_files.contains_key(*file))
+            .cloned()
+            .collect();
+        if commit.complete {
+            removed.extend(self.unseen_files(commit, ""));
+        }
+        removed.sort();
+        removed.dedup();
+        removed.sort_by_key(|file| Reverse(file.mapped_path().components().count()));
+
+        for file in removed.iter() {
+            self.forget(file);
+        }
         removed
     }
 }
 
+/// A difference between what CVS/Entries and the database say is in the
+/// working copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryDiscrepancy {
+    NotInDatabase(PathBuf),
+    NotInEntries(PathBuf),
+}
+
+impl fmt::Display for EntryDiscrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotInDatabase(path) => write!(
+                f,
+                "{} is in CVS/Entries, but the database has no record of it",
+                path.display()
+            ),
+            Self::NotInEntries(path) => write!(
+                f,
+                "{} is in the database, but not in CVS/Entries",
+                path.display()
+            ),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Commit {
-    // These are Vecs because order matters here: we walk the Git tree in
-    // pre-order, which is important because we need directories before files
-    // within their directories when running cvs add.
-    binary: Vec<File>,
-    non_binary: Vec<File>,
-
-    // seen, however, is just used to figure out which files were removed in the
-    // commit, and ordering is unimportant here. We do need to be able to easily
-    // access individual elements, though, so a set is appropriate.
+    // We need directories before files within their directories when running
+    // cvs add. These are iterated sorted by path, which guarantees that, and
+    // also keeps the batches the same from run to run.
+    new: Vec<(File, KeywordMode)>,
+
+    // seen, however, is just used to figure out which files were removed when
+    // a whole tree is walked, and ordering is unimportant here. We do need to
+    // be able to easily access individual elements, though, so a set is
+    // appropriate.
     seen: HashSet<File>,
+    // Whether every file in the commit was seen, rather than just the ones
+    // that changed.
+    complete: bool,
+    removed: Vec<File>,
+
+    // Files written to the working copy in this commit, and counters for the
+    // export statistics recorded in the database.
+    written: Vec<(File, u64, bool)>,
+    skipped: Vec<(File, String)>,
+    // Symlinks found, and whether each was dereferenced rather than skipped.
+    symlinks: Vec<(File, bool)>,
+    // Submodules found that weren't followed.
+    submodules: Vec<File>,
+    // Files whose executable bit changed, and whether they're now executable.
+    mode_changes: Vec<(File, bool)>,
+    // Directories created for files that a path map puts where git has no
+    // directory, as CVS relative paths.
+    new_dirs: Vec<PathBuf>,
+    // Paths left out by --exclude.
+    excluded: usize,
+    added: usize,
+    modified: usize,
+    bytes_written: u64,
 }
 
 impl Commit {
     pub fn new() -> Self {
         Self {
-            binary: Vec::new(),
-            non_binary: Vec::new(),
+            new: Vec::new(),
             seen: HashSet::new(),
+            complete: false,
+            removed: Vec::new(),
+            written: Vec::new(),
+            skipped: Vec::new(),
+            symlinks: Vec::new(),
+            mode_changes: Vec::new(),
+            submodules: Vec::new(),
+            new_dirs: Vec::new(),
+            excluded: 0,
+            added: 0,
+            modified: 0,
+            bytes_written: 0,
         }
     }
 
-    pub fn iter_new_binary_files(&self) -> impl Iterator<Item = &File> {
-        self.binary.iter()
+    pub fn files_added(&self) -> usize {
+        self.added
     }
 
-    pub fn iter_new_non_binary_files(&self) -> impl Iterator<Item = &File> {
-        self.non_binary.iter()
+    pub fn files_modified(&self) -> usize {
+        self.modified
     }
 
-    pub fn new_file(&mut self, file: File, binary: bool) {
-        if binary {
-            self.binary.push(file);
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn iter_new_files(&self) -> impl Iterator<Item = (&File, KeywordMode)> {
+        let mut files: Vec<_> = self.new.iter().map(|(file, mode)| (file, *mode)).collect();
+        files.sort();
+        files.into_iter()
+    }
+
+    pub fn iter_new_files_with_mode(&self, mode: KeywordMode) -> impl Iterator<Item = &File> {
+        self.iter_new_files()
+            .filter(move |(_, file_mode)| *file_mode == mode)
+            .map(|(file, _)| file)
+    }
+
+    pub fn new_file(&mut self, file: File, mode: KeywordMode) {
+        self.new.push((file, mode));
+    }
+
+    pub fn iter_written_files(&self) -> impl Iterator<Item = &File> {
+        self.written.iter().map(|(file, _, _)| file)
+    }
+
+    /// Returns the size of each file written in the commit, and whether it's
+    /// binary, keyed by CVS relative path.
+    pub fn written_sizes(&self) -> HashMap<PathBuf, (u64, bool)> {
+        self.written
+            .iter()
+            .map(|(file, bytes, binary)| (file.cvs_relative_path(), (*bytes, *binary)))
+            .collect()
+    }
+
+    pub fn iter_written_binary_files(&self) -> impl Iterator<Item = &File> {
+        self.written
+            .iter()
+            .filter(|(_, _, binary)| *binary)
+            .map(|(file, _, _)| file)
+    }
+
+    pub fn written_file(&mut self, file: File, bytes: usize, new: bool, binary: bool) {
+        self.written.push((file, bytes as u64, binary));
+        if new {
+            self.added += 1;
         } else {
-            self.non_binary.push(file);
+            self.modified += 1;
         }
+        self.bytes_written += bytes as u64;
+    }
+
+    /// Returns the directories that only exist in CVS because of the path
+    /// map, outermost first.
+    pub fn iter_new_directories(&self) -> impl Iterator<Item = &Path> {
+        self.new_dirs.iter().map(PathBuf::as_path)
+    }
+
+    pub fn new_directory(&mut self, cvs_path: PathBuf) {
+        self.new_dirs.push(cvs_path);
+    }
+
+    pub fn paths_excluded(&self) -> usize {
+        self.excluded
+    }
+
+    pub fn excluded_path(&mut self) {
+        self.excluded += 1;
+    }
+
+    pub fn iter_skipped_entries(&self) -> impl Iterator<Item = (&Path, &str)> {
+        self.skipped
+            .iter()
+            .map(|(file, reason)| (file.git_path(), reason.as_str()))
+    }
+
+    pub fn skipped_entry(&mut self, file: File, reason: String) {
+        self.skipped.push((file, reason));
+    }
+
+    pub fn iter_symlinks(&self) -> impl Iterator<Item = (&Path, bool)> {
+        self.symlinks
+            .iter()
+            .map(|(file, dereferenced)| (file.git_path(), *dereferenced))
+    }
+
+    pub fn symlink(&mut self, file: File, dereferenced: bool) {
+        self.symlinks.push((file, dereferenced));
+    }
+
+    pub fn iter_mode_changes(&self) -> impl Iterator<Item = (&Path, bool)> {
+        self.mode_changes
+            .iter()
+            .map(|(file, executable)| (file.git_path(), *executable))
+    }
+
+    pub fn mode_changed(&mut self, file: File, executable: bool) {
+        self.mode_changes.push((file, executable));
+    }
+
+    pub fn iter_submodules(&self) -> impl Iterator<Item = &Path> {
+        self.submodules.iter().map(File::git_path)
+    }
+
+    pub fn submodule(&mut self, file: File) {
+        self.submodules.push(file);
     }
 
     pub fn seen_file(&mut self, file: File) {
         self.seen.insert(file);
     }
+
+    pub fn has_seen(&self, file: &File) -> bool {
+        self.seen.contains(file)
+    }
+
+    /// Marks the commit as having had its whole tree walked, so that any
+    /// known file that wasn't seen has been removed.
+    pub fn walked_whole_tree(&mut self) {
+        self.complete = true;
+    }
+
+    pub fn removed_file(&mut self, file: File) {
+        self.removed.push(file);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -115,19 +1043,44 @@ pub struct File {
 
 impl File {
     pub fn absolute_path(&self) -> PathBuf {
-        [
-            &self.environment.absolute_base,
-            &self.environment.cvs_base,
-            &self.relative_path,
-        ]
-        .iter()
-        .collect()
+        self.environment
+            .absolute_base
+            .join(&self.environment.cvs_base)
+            .join(self.mapped_path())
+    }
+
+    pub fn git_path(&self) -> &Path {
+        &self.relative_path
     }
 
     pub fn cvs_relative_path(&self) -> PathBuf {
-        [&self.environment.cvs_base, &self.relative_path]
-            .iter()
-            .collect()
+        self.environment.cvs_base.join(self.mapped_path())
+    }
+
+    /// The directories within the target that contain the file in CVS,
+    /// outermost first, as absolute and CVS relative paths.
+    pub fn parents(&self) -> Vec<(PathBuf, PathBuf)> {
+        let mapped = self.mapped_path();
+        let mut parents: Vec<_> = mapped
+            .ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(|dir| {
+                let relative = self.environment.cvs_base.join(dir);
+                (self.environment.absolute_base.join(&relative), relative)
+            })
+            .collect();
+        parents.reverse();
+        parents
+    }
+
+    // Where the file goes within the target.
+    fn mapped_path(&self) -> PathBuf {
+        self.environment
+            .path_map
+            .borrow()
+            .map(&self.relative_path)
+            .into_owned()
     }
 }
 
@@ -144,3 +1097,220 @@ impl PartialEq for File {
 }
 
 impl Eq for File {}
+
+// Paths compare component by component, so a directory always sorts before
+// the files within it.
+impl Ord for File {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.relative_path.cmp(&other.relative_path)
+    }
+}
+
+impl PartialOrd for File {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_entries() {
+        let entry = |revision: &str| Entry {
+            revision: revision.into(),
+            timestamp: String::new(),
+            options: String::new(),
+        };
+        let entries = vec![
+            (PathBuf::from("a.c"), entry("1.2")),
+            (PathBuf::from("lib/b.c"), entry("1.1")),
+            (PathBuf::from("lib/new.c"), entry("0")),
+            (PathBuf::from("removed.c"), entry("-1.1")),
+            (PathBuf::from("other/c.c"), entry("1.1")),
+        ];
+        let oid = |n: u8| Oid::from_bytes(&[n; 20]).unwrap();
+        let stored: HashMap<_, _> = vec![
+            (PathBuf::from("a.c"), oid(1)),
+            (PathBuf::from("lib/b.c"), oid(2)),
+            (PathBuf::from("removed.c"), oid(3)),
+        ]
+        .into_iter()
+        .collect();
+
+        let (state, discrepancies) = Global::from_entries(
+            "/tmp",
+            "src",
+            Filter::new(["a.c", "lib", "removed.c"]),
+            PathMap::default(),
+            &entries,
+            &stored,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                EntryDiscrepancy::NotInDatabase("lib/new.c".into()),
+                EntryDiscrepancy::NotInEntries("removed.c".into()),
+            ],
+            discrepancies
+        );
+        assert_eq!(Some(&oid(1)), state.get_oid(&state.file("a.c").unwrap()));
+        assert_eq!(
+            Some(&Oid::zero()),
+            state.get_oid(&state.file("lib/new.c").unwrap())
+        );
+        assert_eq!(None, state.get_oid(&state.file("removed.c").unwrap()));
+        assert_eq!(3, state.iter_files().count());
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::new(["lib/foo/", "docs"]);
+        assert_eq!(Scope::Inside, filter.scope(Path::new("lib/foo")));
+        assert_eq!(Scope::Inside, filter.scope(Path::new("lib/foo/bar.c")));
+        assert_eq!(Scope::Inside, filter.scope(Path::new("docs/index.md")));
+        assert_eq!(Scope::Ancestor, filter.scope(Path::new("lib")));
+        assert_eq!(Scope::Outside, filter.scope(Path::new("lib/foobar")));
+        assert_eq!(Scope::Outside, filter.scope(Path::new("src/main.c")));
+
+        assert_eq!(
+            filter.fingerprint(),
+            Filter::new(["docs", "lib//foo", "docs"]).fingerprint()
+        );
+        assert_ne!(filter.fingerprint(), Filter::new(["docs"]).fingerprint());
+        assert_eq!(None, Filter::default().fingerprint());
+        assert_eq!(Scope::Inside, Filter::default().scope(Path::new("a/b")));
+
+        let state = Global::new("/tmp", "src", filter);
+        assert!(state.file("lib/foo/bar.c").is_ok());
+        assert!(state.file("lib").is_ok());
+        assert!(state.file("src/main.c").is_err());
+    }
+
+    #[test]
+    fn test_filter_excludes() {
+        let globs = |globs: &[&str]| -> Vec<Glob> {
+            globs
+                .iter()
+                .map(|glob| parse_exclude(glob).unwrap())
+                .collect()
+        };
+        let filter = Filter::default().with_excludes(&globs(&["third_party/", "/gen.c"]));
+        assert_eq!(Scope::Outside, filter.scope(Path::new("third_party")));
+        assert_eq!(Scope::Outside, filter.scope(Path::new("third_party/z/z.c")));
+        assert_eq!(
+            Scope::Outside,
+            filter.scope(Path::new("lib/third_party/a.c"))
+        );
+        assert_eq!(Scope::Outside, filter.scope(Path::new("gen.c")));
+        assert_eq!(Scope::Inside, filter.scope(Path::new("lib/gen.c")));
+        assert_eq!(Scope::Inside, filter.scope(Path::new("third_party.c")));
+
+        // Excludes win over includes, and don't change the fingerprint.
+        let filter = Filter::new(["lib"]).with_excludes(&globs(&["*.o"]));
+        assert_eq!(Scope::Outside, filter.scope(Path::new("lib/a.o")));
+        assert_eq!(Scope::Inside, filter.scope(Path::new("lib/a.c")));
+        assert_eq!(Filter::new(["lib"]).fingerprint(), filter.fingerprint());
+
+        let filter = Filter::default().with_excludes(&globs(&["nothing*"]));
+        assert_eq!(Scope::Inside, filter.scope(Path::new("lib/a.c")));
+        assert!(parse_exclude("/").is_err());
+    }
+
+    #[test]
+    fn test_file_traversal() {
+        let state = Global::new("/tmp", "src", Filter::default());
+        assert!(state.file("lib/a.c").is_ok());
+        for path in [
+            "..",
+            "../a.c",
+            "lib/../../a.c",
+            "/etc/passwd",
+            "lib//a.c",
+            "./a.c",
+        ] {
+            assert!(state.file(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_parse_cvs_dir_substitute() {
+        assert_eq!("CVS_", parse_cvs_dir_substitute("CVS_").unwrap());
+        assert!(parse_cvs_dir_substitute("cvs").is_err());
+        assert!(parse_cvs_dir_substitute("a/b").is_err());
+        assert!(parse_cvs_dir_substitute("").is_err());
+    }
+
+    #[test]
+    fn test_case_collisions() {
+        let oid = Oid::from_bytes(&[1; 20]).unwrap();
+        let mut state = Global::new("/tmp", "src", Filter::default())
+            .with_path_map("headers=include".parse().unwrap());
+        for path in ["Makefile", "Include/a.h", "a.c", "A.c"] {
+            state.save_oid(state.file(path).unwrap(), &oid);
+        }
+
+        // Files already in CVS are left alone, and a directory is only given
+        // once, however many files are in it.
+        let mut paths: BTreeSet<PathBuf> = [
+            "Makefile",
+            "makefile",
+            "Include/a.h",
+            "headers/b.h",
+            "headers/c.h",
+            "a.c",
+            "A.c",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(
+            vec![
+                (PathBuf::from("Include"), PathBuf::from("headers")),
+                (PathBuf::from("Makefile"), PathBuf::from("makefile")),
+            ],
+            state.case_collisions(&paths)
+        );
+
+        assert_eq!(
+            PathBuf::from("include~1"),
+            state.rename_for_case(Path::new("headers"), &paths)
+        );
+        paths.insert("makefile~1".into());
+        assert_eq!(
+            PathBuf::from("makefile~2"),
+            state.rename_for_case(Path::new("makefile"), &paths)
+        );
+        assert!(state.case_collisions(&paths).is_empty());
+        assert_eq!(
+            PathBuf::from("src/include~1/b.h"),
+            state.file("headers/b.h").unwrap().cvs_relative_path()
+        );
+
+        // Known files that the commit removes don't collide, and forgotten
+        // ones are no longer known.
+        let docs = state.file("Docs/a.txt").unwrap();
+        state.save_oid(docs.clone(), &oid);
+        let paths: BTreeSet<PathBuf> = ["Docs/a.txt", "docs/b.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(
+            vec![(PathBuf::from("Docs"), PathBuf::from("docs"))],
+            state.case_collisions(&paths)
+        );
+        let paths: BTreeSet<PathBuf> = ["docs/b.txt"].iter().map(PathBuf::from).collect();
+        assert!(state.case_collisions(&paths).is_empty());
+        state.forget(&docs);
+        let paths: BTreeSet<PathBuf> = ["Docs/a.txt", "docs/b.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(
+            vec![(PathBuf::from("Docs"), PathBuf::from("docs"))],
+            state.case_collisions(&paths)
+        );
+        assert!(!state.known_prefixes.contains_key(Path::new("Docs")));
+    }
+}
@@ -0,0 +1,254 @@
+use std::ffi::OsString;
+
+use structopt::StructOpt;
+
+use crate::{
+    database::{Database, ExceptionKind},
+    git::Repository,
+    json::Value,
+    output::{self, Format},
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(
+        long,
+        default_value = "table",
+        possible_values = Format::VARIANTS,
+        help = "output format"
+    )]
+    format: Format,
+
+    #[structopt(long, help = "include archived branches")]
+    archived: bool,
+
+    #[structopt(
+        short,
+        long,
+        help = "git repository, to count the commits that haven't been recorded yet"
+    )]
+    git: Option<OsString>,
+
+    #[structopt(short, long, help = "look for new commits on remote branches")]
+    remote: bool,
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    print!("{}", status(&opt)?);
+    Ok(())
+}
+
+/// Renders the status of each branch in the requested format.
+fn status(opt: &Opt) -> anyhow::Result<String> {
+    let db = Database::open_readonly(&opt.database)?;
+    let repo = match &opt.git {
+        Some(git) => Some(Repository::discover(git, 0)?),
+        None => None,
+    };
+
+    let mut rows = Vec::new();
+    let mut json = Vec::new();
+    for branch in db.branch_summaries()? {
+        if branch.archived && !opt.archived {
+            continue;
+        }
+
+        let counts = db.exception_counts(&branch.git_branch)?;
+        let count = |kind| counts.get(kind).copied().unwrap_or_default();
+        let last_export = db.last_export(&branch.git_branch)?;
+        let new = match &repo {
+            Some(repo) => Some(new_commits(&db, repo, &branch.git_branch, opt.remote)?),
+            None => None,
+        };
+
+        let mut row = vec![
+            branch.git_branch.clone(),
+            branch.cvs_branch.clone(),
+            branch.commits.to_string(),
+            branch.exported.to_string(),
+            last_export
+                .map(|(oid, _)| oid.to_string())
+                .unwrap_or_default(),
+            last_export
+                .map(|(_, exported_at)| output::date(exported_at))
+                .unwrap_or_default(),
+        ];
+        if let Some(new) = &new {
+            row.push(match new {
+                Some(new) => new.to_string(),
+                None => "?".into(),
+            });
+        }
+        row.extend(
+            ExceptionKind::ALL
+                .iter()
+                .map(|kind| count(kind).to_string()),
+        );
+        rows.push(row);
+
+        let mut fields = vec![
+            ("git_branch", branch.git_branch.into()),
+            ("cvs_branch", branch.cvs_branch.into()),
+            ("commits", branch.commits.into()),
+            ("exported", branch.exported.into()),
+            (
+                "last_exported",
+                last_export.map(|(oid, _)| oid.to_string()).into(),
+            ),
+            (
+                "last_exported_at",
+                last_export.map(|(_, exported_at)| exported_at).into(),
+            ),
+        ];
+        if let Some(new) = new {
+            fields.push(("new", new.map(|new| new as i64).into()));
+        }
+        fields.push((
+            "exceptions",
+            Value::object(
+                ExceptionKind::ALL
+                    .iter()
+                    .map(|kind| (kind.as_str(), count(kind).into())),
+            ),
+        ));
+        json.push(Value::object(fields));
+    }
+
+    match opt.format {
+        Format::Table => {
+            let mut headers = vec![
+                "git branch",
+                "cvs branch",
+                "commits",
+                "exported",
+                "last exported",
+                "exported at",
+            ];
+            if repo.is_some() {
+                headers.push("new");
+            }
+            headers.extend(ExceptionKind::ALL.iter().map(|kind| kind.as_str()));
+            Ok(output::table(&headers, &rows))
+        }
+        Format::Json => Ok(format!("{}\n", Value::Array(json))),
+    }
+}
+
+/// Returns how many commits the git branch has beyond the last one recorded,
+/// or None if the branch is gone or no longer leads on from that commit.
+fn new_commits(
+    db: &Database,
+    repo: &Repository,
+    git_branch: &str,
+    remote: bool,
+) -> anyhow::Result<Option<usize>> {
+    // A revision is looked up again, in case it's moved on.
+    let branch = match db.resolved_rev(git_branch)? {
+        Some(_) => match repo.revision(git_branch) {
+            Ok(revision) => revision,
+            Err(_) => return Ok(None),
+        },
+        None => match repo.branch(git_branch, remote)? {
+            Some(branch) => branch,
+            None => return Ok(None),
+        },
+    };
+    match db.branch_tip(git_branch)? {
+        Some(tip) => Ok(branch.commits_since(tip)?),
+        None => Ok(Some(branch.history_len(None)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::GitBuilder;
+
+    #[test]
+    fn test_status() {
+        let mut git = GitBuilder::new();
+        git.checkout("main");
+        let first = git.file("a.txt", "a\n").commit("first");
+        let second = git.file("a.txt", "b\n").commit("second");
+        git.file("a.txt", "c\n").commit("third");
+
+        // main has one of its two recorded commits exported, and a third
+        // that hasn't been recorded yet. gone is no longer in git.
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("git2cvs.db");
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let mut db = Database::open(&database).unwrap();
+        db.write_branch(
+            "main",
+            "main",
+            [first, second].iter().map(|oid| repo.commit(oid).unwrap()),
+        )
+        .unwrap();
+        db.mark_exported_through("main", 0).unwrap();
+        db.write_branch(
+            "gone",
+            "GONE",
+            std::iter::once(repo.commit(&first).unwrap()),
+        )
+        .unwrap();
+        drop(db);
+
+        let status = |args: &[&str]| {
+            let mut opt =
+                Opt::from_iter_safe(std::iter::once(&"status").chain(args.iter())).unwrap();
+            opt.database = database.clone().into();
+            status(&opt).unwrap()
+        };
+        let cells = |table: String| -> Vec<Vec<String>> {
+            table
+                .lines()
+                .map(|line| line.split_whitespace().map(String::from).collect())
+                .collect()
+        };
+        let exceptions = vec!["0".to_string(); ExceptionKind::ALL.len()];
+
+        let rows = cells(status(&["-g", git.path().to_str().unwrap()]));
+        assert_eq!(
+            ["git", "branch", "cvs", "branch", "commits", "exported"],
+            rows[0][..6]
+        );
+        assert!(rows[0].contains(&"new".to_string()));
+        // The branch that's gone has no last export, and an unknown number of
+        // new commits.
+        assert_eq!(["gone", "GONE", "1", "0", "?"], rows[1][..5]);
+        assert_eq!(exceptions, rows[1][5..]);
+        assert_eq!(["main", "main", "2", "1"], rows[2][..4]);
+        assert_eq!(first.to_string(), rows[2][4]);
+        assert_eq!("1", rows[2][6]);
+        assert_eq!(exceptions, rows[2][7..]);
+        assert_eq!(3, rows.len());
+
+        // Without a repository, there's no new column.
+        let rows = cells(status(&[]));
+        assert!(!rows[0].contains(&"new".to_string()));
+        assert_eq!(["gone", "GONE", "1", "0"], rows[1][..4]);
+        assert_eq!(exceptions, rows[1][4..]);
+
+        let json = status(&["-g", git.path().to_str().unwrap(), "--format", "json"]);
+        assert!(
+            json.starts_with(
+                "[{\"git_branch\":\"gone\",\"cvs_branch\":\"GONE\",\"commits\":1,\"exported\":0,\"last_exported\":null,\"last_exported_at\":null,\"new\":null,"
+            ),
+            "{}",
+            json
+        );
+        assert!(
+            json.contains(&format!(
+                "{{\"git_branch\":\"main\",\"cvs_branch\":\"main\",\"commits\":2,\"exported\":1,\"last_exported\":\"{}\",",
+                first
+            )),
+            "{}",
+            json
+        );
+        assert!(json.contains(",\"new\":1,"), "{}", json);
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use structopt::StructOpt;
+
+use crate::{database::Database, git::Repository, lock::RunLock, output};
+
+const COMMAND: &str = "sync-branches";
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub database: PathBuf,
+
+    #[structopt(short, long, default_value = ".", help = "git repository")]
+    git: OsString,
+
+    #[structopt(short, long, help = "compare against remote branches")]
+    remote: bool,
+
+    #[structopt(long, help = "mark missing branches as archived")]
+    archive: bool,
+
+    #[structopt(
+        long,
+        conflicts_with = "archive",
+        help = "delete missing branches and everything recorded against them"
+    )]
+    delete: bool,
+
+    #[structopt(
+        long,
+        help = "update the mapping of missing branches whose tip matches exactly one unrecorded branch"
+    )]
+    rename: bool,
+
+    #[structopt(short, long, help = "don't ask for confirmation")]
+    yes: bool,
+}
+
+/// A recorded branch that no longer exists in the git repository.
+#[derive(Debug)]
+struct Missing {
+    git_branch: String,
+    cvs_branch: String,
+    archived: bool,
+    renamed_to: Vec<String>,
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    let mutating = opt.archive || opt.delete || opt.rename;
+    let _lock = if mutating {
+        Some(RunLock::acquire(&opt.database)?)
+    } else {
+        None
+    };
+    let mut db = if mutating {
+        Database::open(&opt.database)?
+    } else {
+        Database::open_readonly(&opt.database)?
+    };
+    let repo = Repository::discover(&opt.git, 0)?;
+
+    let missing = find_missing(&db, &repo.branch_tips(opt.remote)?)?;
+    if missing.is_empty() {
+        println!("every recorded branch exists in the repository");
+        return Ok(());
+    }
+
+    print!(
+        "{}",
+        output::table(
+            &["git branch", "cvs branch", "archived", "renamed to"],
+            &missing
+                .iter()
+                .map(|branch| {
+                    vec![
+                        branch.git_branch.clone(),
+                        branch.cvs_branch.clone(),
+                        if branch.archived { "yes" } else { "no" }.into(),
+                        branch.renamed_to.join(", "),
+                    ]
+                })
+                .collect::<Vec<_>>(),
+        )
+    );
+
+    for branch in missing {
+        let name = &branch.git_branch;
+
+        if opt.rename {
+            if let [to] = branch.renamed_to.as_slice() {
+                if confirm(&format!("rename {} to {}?", name, to), opt.yes)? {
+                    db.rename_branch(name, to)?;
+                    db.record_audit(COMMAND, name, "rename", Some(to))?;
+                    println!("renamed {} to {}", name, to);
+                }
+                continue;
+            }
+        }
+
+        if opt.delete {
+            if confirm(
+                &format!("delete {} and everything recorded against it?", name),
+                opt.yes,
+            )? {
+                db.delete_branch(name)?;
+                db.record_audit(COMMAND, name, "delete", None)?;
+                println!("deleted {}", name);
+            }
+        } else if opt.archive && !branch.archived {
+            db.archive_branch(name)?;
+            db.record_audit(COMMAND, name, "archive", None)?;
+            println!("archived {}", name);
+        }
+    }
+
+    Ok(())
+}
+
+fn find_missing(db: &Database, tips: &[(String, git2::Oid)]) -> anyhow::Result<Vec<Missing>> {
+    let summaries = db.branch_summaries()?;
+    let recorded: HashSet<_> = summaries
+        .iter()
+        .map(|branch| branch.git_branch.as_str())
+        .collect();
+
+    // Only branches that aren't already recorded can be rename targets.
+    let mut unrecorded: HashMap<git2::Oid, Vec<&str>> = HashMap::new();
+    for (name, oid) in tips {
+        if !recorded.contains(name.as_str()) {
+            unrecorded.entry(*oid).or_default().push(name);
+        }
+    }
+    let present: HashSet<_> = tips.iter().map(|(name, _)| name.as_str()).collect();
+
+    let mut missing = Vec::new();
+    for branch in summaries.iter() {
+        // A revision pushed with --rev was never a branch to go missing.
+        if present.contains(branch.git_branch.as_str())
+            || db.resolved_rev(&branch.git_branch)?.is_some()
+        {
+            continue;
+        }
+
+        let mut renamed_to: Vec<String> = match db.branch_tip(&branch.git_branch)? {
+            Some(tip) => unrecorded
+                .get(&tip)
+                .map(|names| names.iter().map(|name| name.to_string()).collect())
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+        renamed_to.sort();
+
+        missing.push(Missing {
+            git_branch: branch.git_branch.clone(),
+            cvs_branch: branch.cvs_branch.clone(),
+            archived: branch.archived,
+            renamed_to,
+        });
+    }
+
+    Ok(missing)
+}
+
+pub fn confirm(prompt: &str, yes: bool) -> anyhow::Result<bool> {
+    if yes {
+        return Ok(true);
+    }
+
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(dir.path()).unwrap();
+        let tree = raw
+            .find_tree(raw.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+        let first = raw
+            .commit(Some("refs/heads/main"), &sig, &sig, "first", &tree, &[])
+            .unwrap();
+        let second = raw
+            .commit(
+                Some("refs/heads/feature"),
+                &sig,
+                &sig,
+                "second",
+                &tree,
+                &[&raw.find_commit(first).unwrap()],
+            )
+            .unwrap();
+
+        let repo = Repository::open(dir.path(), 0).unwrap();
+        let mut db = Database::open(":memory:").unwrap();
+        for branch in &["main", "old-feature", "gone"] {
+            let commits = match *branch {
+                "gone" => vec![repo.commit(&first).unwrap()],
+                _ => vec![repo.commit(&first).unwrap(), repo.commit(&second).unwrap()],
+            };
+            db.write_branch(branch, branch, commits.iter().map(|c| c.as_ref()))
+                .unwrap();
+        }
+        db.write_branch(
+            "v1.0",
+            "v1_0",
+            [repo.commit(&first).unwrap()].iter().map(|c| c.as_ref()),
+        )
+        .unwrap();
+        db.set_resolved_rev("v1.0", &first).unwrap();
+
+        let missing = find_missing(&db, &repo.branch_tips(false).unwrap()).unwrap();
+        assert_eq!(
+            vec![("gone", vec![]), ("old-feature", vec!["feature"])],
+            missing
+                .iter()
+                .map(|branch| (
+                    branch.git_branch.as_str(),
+                    branch
+                        .renamed_to
+                        .iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                ))
+                .collect::<Vec<_>>()
+        );
+
+        db.rename_branch("old-feature", "feature").unwrap();
+        db.archive_branch("gone").unwrap();
+        let missing = find_missing(&db, &repo.branch_tips(false).unwrap()).unwrap();
+        assert_eq!(1, missing.len());
+        assert!(missing[0].archived);
+        assert_eq!(
+            Some(second),
+            db.branch_tip("feature").unwrap(),
+            "rename should carry the recorded commits over"
+        );
+
+        db.delete_branch("gone").unwrap();
+        assert!(find_missing(&db, &repo.branch_tips(false).unwrap())
+            .unwrap()
+            .is_empty());
+    }
+}
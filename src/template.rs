@@ -0,0 +1,180 @@
+use crate::{git::Commit, output};
+
+/// A template for CVS commit messages, such as `PROJ-1: {message}`.
+///
+/// Placeholders are written in braces, and `{{` and `}}` are literal braces.
+/// Unknown placeholders are rejected when the template is parsed, so a typo
+/// fails the push before anything is exported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Message,
+    Sha,
+    ShortSha,
+    AuthorName,
+    AuthorEmail,
+    Date,
+    Branch,
+}
+
+impl Field {
+    const NAMES: &'static [(&'static str, Field)] = &[
+        ("message", Field::Message),
+        ("sha", Field::Sha),
+        ("short_sha", Field::ShortSha),
+        ("author_name", Field::AuthorName),
+        ("author_email", Field::AuthorEmail),
+        ("date", Field::Date),
+        ("branch", Field::Branch),
+    ];
+}
+
+impl std::str::FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let name = match rest.find('}') {
+                        Some(end) => &rest[..end],
+                        None => anyhow::bail!("unclosed placeholder in message template"),
+                    };
+                    let field = match Field::NAMES.iter().find(|(known, _)| *known == name) {
+                        Some((_, field)) => *field,
+                        None => anyhow::bail!(
+                            "unknown placeholder {{{}}} in message template; expected one of {}",
+                            name,
+                            Field::NAMES
+                                .iter()
+                                .map(|(name, _)| format!("{{{}}}", name))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                    };
+                    chars = rest[name.len() + 1..].chars();
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => anyhow::bail!("unmatched }} in message template; use }}}} for a literal }}"),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+}
+
+/// Renders the message for a commit on the given branch. The message is
+/// passed separately from the commit, since it may have been replaced, and is
+/// copied in as bytes so that messages that aren't UTF-8 survive.
+pub fn render_message(
+    template: &Template,
+    commit: &Commit,
+    branch: &str,
+    message: &[u8],
+) -> Vec<u8> {
+    let mut rendered = Vec::new();
+    for part in template.parts.iter() {
+        match part {
+            Part::Literal(literal) => rendered.extend_from_slice(literal.as_bytes()),
+            Part::Field(Field::Message) => rendered.extend_from_slice(message),
+            Part::Field(field) => {
+                let value = match field {
+                    Field::Sha => commit.id().to_string(),
+                    Field::ShortSha => commit.id().to_string()[..8].to_string(),
+                    Field::AuthorName => commit.author_name().to_string(),
+                    Field::AuthorEmail => commit.author_email().to_string(),
+                    Field::Date => output::date(commit.time().seconds()),
+                    Field::Branch => branch.to_string(),
+                    Field::Message => unreachable!(),
+                };
+                rendered.extend_from_slice(value.as_bytes());
+            }
+        }
+    }
+
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{git::Repository, test_support::GitBuilder};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Template {
+                parts: vec![
+                    Part::Literal("[".into()),
+                    Part::Field(Field::Branch),
+                    Part::Literal("] {x} ".into()),
+                    Part::Field(Field::Message),
+                ]
+            },
+            "[{branch}] {{x}} {message}".parse().unwrap()
+        );
+        assert_eq!(Template { parts: vec![] }, "".parse().unwrap());
+
+        for bad in ["{nope}", "{message", "message}", "{}"] {
+            assert!(bad.parse::<Template>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_render_message() {
+        let mut git = GitBuilder::new();
+        let oid = git.file("a", "a").commit("Add a");
+        let repo = Repository::open(git.path(), 0).unwrap();
+        let commit = repo.commit(&oid).unwrap();
+
+        let template: Template =
+            "PROJ-1 {short_sha} {date} {branch}\n{author_name} <{author_email}>\n\n{message}\n{sha}"
+                .parse()
+                .unwrap();
+        let mut expected = format!(
+            "PROJ-1 {} {} main\n{} <{}>\n\ncaf",
+            &oid.to_string()[..8],
+            output::date(commit.time().seconds()),
+            commit.author_name(),
+            commit.author_email(),
+        )
+        .into_bytes();
+        expected.extend_from_slice(b"\xe9\n");
+        expected.extend_from_slice(oid.to_string().as_bytes());
+
+        assert_eq!(
+            expected,
+            render_message(&template, &commit, "main", b"caf\xe9")
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Fixtures for tests: git repositories with a scripted history, throwaway
+//! CVS repositories to export them into, and fake cvs commands for the tests
+//! that only need to see what a push would ask of CVS.
+//!
+//! The git builder lives in the git2cvs-test-support crate, so that the
+//! integration tests can use it too. The CVS fixture stays here, since it's
+//! built on the cvs module.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use subprocess::Exec;
+use tempfile::TempDir;
+
+pub use git2cvs_test_support::GitBuilder;
+
+use crate::{
+    cvs::{self, CvsRoot},
+    push::PushOpt,
+};
+
+/// A local CVS repository in a temporary directory, created with `cvs init`.
+///
+/// This needs a real cvs binary, so tests that use it should be `#[ignore]`.
+pub struct CvsFixture {
+    dir: TempDir,
+    root: CvsRoot,
+}
+
+impl CvsFixture {
+    pub fn init() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let root = format!(":local:{}", dir.path().join("root").display())
+            .parse()
+            .unwrap();
+        let fixture = Self { dir, root };
+        fixture.run(fixture.dir.path(), &["init"]);
+        fixture
+    }
+
+    pub fn root(&self) -> &CvsRoot {
+        &self.root
+    }
+
+    /// A scratch directory next to the repository for checkouts and the like.
+    pub fn scratch<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    /// Runs cvs quietly against the repository, panicking if it fails.
+    pub fn run<P: AsRef<Path>>(&self, cwd: P, args: &[&str]) {
+        let status = Exec::cmd("cvs")
+            .arg("-Q")
+            .arg("-d")
+            .arg(self.root.to_command_arg(false))
+            .args(args)
+            .cwd(cwd)
+            .join()
+            .unwrap();
+        assert!(status.success(), "cvs {:?} failed", args);
+    }
+
+    /// Runs cvs against the repository, returning what it printed.
+    pub fn output<P: AsRef<Path>>(&self, cwd: P, args: &[&str]) -> String {
+        let capture = Exec::cmd("cvs")
+            .arg("-Q")
+            .arg("-d")
+            .arg(self.root.to_command_arg(false))
+            .args(args)
+            .cwd(cwd)
+            .capture()
+            .unwrap();
+        assert!(capture.success(), "cvs {:?} failed", args);
+        capture.stdout_str()
+    }
+
+    /// Creates an empty module.
+    pub fn module(&self, name: &str) {
+        fs::create_dir_all(Path::new(self.root.path()).join(name)).unwrap();
+    }
+
+    /// Imports the given files as a new module.
+    pub fn import(&self, module: &str, files: &[(&str, &str)]) {
+        let import = self.scratch(format!("import-{}", module));
+        for (path, content) in files {
+            let path = import.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        fs::create_dir_all(&import).unwrap();
+        self.run(
+            &import,
+            &["import", "-m", "import", module, "vendor", "start"],
+        );
+    }
+
+    /// Checks out a module into the named scratch directory.
+    pub fn checkout(&self, module: &str, name: &str, branch: Option<&str>) -> cvs::Repository {
+        cvs::Context::new(OsStr::new("cvs"))
+            .checkout(&self.root, module, self.scratch(name), branch)
+            .unwrap()
+    }
+}
+
+/// Parses the options to push with a fake cvs, written to the directory by
+/// one of the functions below, that has a CVSROOT and a database there too.
+pub fn fake_push_opt(git: &Path, dir: &Path, extra: &[&str]) -> PushOpt {
+    let mut args = vec![
+        OsString::from("git2cvs"),
+        "push".into(),
+        "-g".into(),
+        git.into(),
+        "--cvs".into(),
+        dir.join("cvs").into(),
+        "-c".into(),
+        format!(":local:{}", dir.join("root").display()).into(),
+        "-d".into(),
+        dir.join("git2cvs.db").into(),
+    ];
+    args.extend(extra.iter().map(OsString::from));
+    match crate::parse_args(args).unwrap() {
+        crate::Opt::Push(opt) => opt,
+        _ => unreachable!(),
+    }
+}
+
+/// Writes a fake cvs to the directory that logs the summary of each commit
+/// to dir/log, and fails to commit "second" while dir/fail exists.
+pub fn summary_logging_cvs(dir: &Path) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let cvs = dir.join("cvs");
+    fs::write(
+        &cvs,
+        format!(
+            r#"#!/bin/sh
+if [ "$3" = checkout ]; then mkdir -p "$5"; fi
+prev=
+for arg in "$@"; do
+if [ "$prev" = -F ]; then
+    message=$(head -n 1 "$arg")
+    if [ "$message" = second ] && [ -e {fail} ]; then exit 1; fi
+    echo "$message" >> {log}
+fi
+prev=$arg
+done
+"#,
+            fail = dir.join("fail").display(),
+            log = dir.join("log").display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::create_dir_all(dir.join("root/CVSROOT")).unwrap();
+    cvs
+}
+
+/// Writes a fake cvs that keeps CVS/Entries up to date as files and
+/// directories are added and removed, and logs each file it's asked about
+/// and the first line of each commit message. A removed file that's still
+/// in the working copy is logged as present, since cvs would refuse it.
+pub fn entries_cvs(dir: &Path) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let cvs = dir.join("cvs");
+    fs::write(
+        &cvs,
+        format!(
+            r#"#!/bin/sh
+if [ "$3" = checkout ]; then mkdir -p "$5/CVS"; : > "$5/CVS/Entries"; exit 0; fi
+command=$1
+shift
+for path in "$@"; do
+entries="$(dirname "$path")/CVS/Entries"
+name=$(basename "$path")
+case "$command" in
+add)
+    case "$path" in -k*) continue ;; esac
+    if [ -d "$path" ]; then
+        mkdir -p "$path/CVS"
+        : > "$path/CVS/Entries"
+        echo "D/$name////" >> "$entries"
+    else
+        echo "/$name/1.1///" >> "$entries"
+    fi
+    echo "add $path" >> {log}
+    ;;
+remove)
+    grep -v "^/$name/" "$entries" > "$entries.new"
+    mv "$entries.new" "$entries"
+    if [ -e "$path" ]; then echo "remove $path present" >> {log}; else echo "remove $path" >> {log}; fi
+    ;;
+commit)
+    if [ "$path" != -F ]; then echo "commit $(head -n 1 "$path")" >> {log}; fi
+    ;;
+esac
+done
+"#,
+            log = dir.join("log").display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::create_dir_all(dir.join("root/CVSROOT")).unwrap();
+    cvs
+}
@@ -0,0 +1,550 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use git2::Oid;
+use structopt::StructOpt;
+use tempfile::tempdir;
+
+use crate::{
+    attributes::Glob,
+    cvs,
+    database::{Database, ExceptionKind},
+    detect::Detector,
+    git::{Commit, Repository},
+    lock::RunLock,
+    pathmap::PathMap,
+    push,
+    state::{self, Filter, Scope},
+    sync::confirm,
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    #[structopt(short, long, help = "the branch to undo the last exported commit of")]
+    branch: String,
+
+    #[structopt(long, default_value = "cvs", help = "cvs binary to use")]
+    cvs: OsString,
+
+    #[structopt(
+        long,
+        default_value = "capture",
+        possible_values = cvs::Output::VARIANTS,
+        help = "capture cvs output into the log, or let it through to the terminal for interactive debugging"
+    )]
+    cvs_output: cvs::Output,
+
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub cvsroot: Option<cvs::CvsRoot>,
+
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(short, long, default_value = ".", help = "git repository")]
+    git: OsString,
+
+    #[structopt(
+        short,
+        long,
+        default_value = ".",
+        help = "cvs module to check out, if any"
+    )]
+    module: String,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "src",
+        help = "the target directory within the cvs checkout; can be . to use the top level"
+    )]
+    target: PathBuf,
+
+    #[structopt(long, help = "the CVS branch tag the branch was exported onto")]
+    cvs_branch: Option<String>,
+
+    #[structopt(long, help = "the paths the branch was exported with")]
+    include: Vec<PathBuf>,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        parse(try_from_str = state::parse_exclude),
+        help = "the globs the branch was exported without"
+    )]
+    exclude: Vec<Glob>,
+
+    #[structopt(
+        long,
+        help = "the directory of the git repository the branch was exported from"
+    )]
+    prefix: Option<PathBuf>,
+
+    #[structopt(long, help = "transcode UTF-16 text files to UTF-8")]
+    transcode_utf16: bool,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "treat files matching this glob as binary"
+    )]
+    binary_glob: Vec<Glob>,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "treat files matching this glob as text"
+    )]
+    text_glob: Vec<Glob>,
+
+    #[structopt(short, long, help = "don't ask for confirmation")]
+    yes: bool,
+}
+
+/// How a file in CVS is put back the way it was before the undone commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reversal {
+    /// The file gets the content of the given blob again. If readd is true,
+    /// the commit removed it, so it has to be added back to CVS.
+    Restore {
+        path: PathBuf,
+        oid: Oid,
+        readd: bool,
+    },
+    /// The commit added the file, so it's removed.
+    Remove { path: PathBuf },
+}
+
+impl Reversal {
+    fn path(&self) -> &Path {
+        match self {
+            Reversal::Restore { path, .. } | Reversal::Remove { path } => path,
+        }
+    }
+}
+
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    let _lock = RunLock::acquire(&opt.database)?;
+    let mut db = Database::open(&opt.database)?;
+    let repo = Repository::discover(&opt.git, 0)?.with_prefix(opt.prefix.as_ref());
+
+    if db.get_cvs_branch(&opt.branch)?.is_none() {
+        anyhow::bail!("branch {} has not been exported", &opt.branch);
+    }
+    let filter = Filter::new(&opt.include).with_excludes(&opt.exclude);
+    if db.filter_fingerprint(&opt.branch)? != filter.fingerprint() {
+        anyhow::bail!(
+            "branch {} was exported with a different set of --include paths (only --exclude can change between pushes)",
+            &opt.branch
+        );
+    }
+    let path_map = push::exported_path_map(&db, &opt.branch)?;
+
+    let last = match db.last_exported_commit(&opt.branch)? {
+        Some(last) => last,
+        None => anyhow::bail!("nothing has been exported on {}", &opt.branch),
+    };
+    if db
+        .exceptions(Some(&opt.branch), Some(ExceptionKind::SkippedCommit))?
+        .iter()
+        .any(|exception| exception.oid == last.to_string())
+    {
+        anyhow::bail!(
+            "the last commit on {}, {}, was skipped, so there's nothing in CVS to undo",
+            &opt.branch,
+            last
+        );
+    }
+    if db.exported_empty(&opt.branch, &last)? {
+        anyhow::bail!(
+            "the last commit on {}, {}, changed nothing in CVS, so there's nothing to undo",
+            &opt.branch,
+            last
+        );
+    }
+    let index = db.commit_index(&last, &opt.branch)?.unwrap_or_default();
+    let previous = db.previous_exported_commit(&opt.branch, index)?;
+
+    let last_commit = repo.commit(&last)?;
+    let previous_commit = match &previous {
+        Some(oid) => Some(repo.commit(oid)?),
+        None => None,
+    };
+    let reversals = plan(&repo, previous_commit.as_deref(), &last_commit, &filter)?;
+
+    if !confirm(
+        &format!(
+            "undo commit {} ({}) on {}, changing {} files in CVS?",
+            last,
+            last_commit.summary(),
+            &opt.branch,
+            reversals.len()
+        ),
+        opt.yes,
+    )? {
+        println!("nothing undone");
+        return Ok(());
+    }
+
+    // Branches that were exported onto their own CVS branch are undone there.
+    let cvs_branch = match &opt.cvs_branch {
+        Some(cvs_branch) => Some(cvs_branch.clone()),
+        None if db.branch_tagged(&opt.branch)? => db.get_cvs_branch(&opt.branch)?,
+        None => None,
+    };
+    let tempdir = tempdir()?;
+    let cvs_repo = cvs::Context::new(&opt.cvs)
+        .output(opt.cvs_output)
+        .checkout(
+            opt.cvsroot.as_ref().expect("parse_args sets the CVSROOT"),
+            &opt.module,
+            tempdir.path().join("cvs"),
+            cvs_branch.as_deref(),
+        )?;
+    let checkout = cvs_repo.cwd().to_path_buf();
+    check_unmoved(
+        &cvs_repo,
+        &opt.target,
+        &db.commit_revisions(&opt.branch, &last)?,
+        &reversals,
+        &path_map,
+    )?;
+
+    let detector =
+        Detector::new(opt.transcode_utf16).with_globs(&opt.binary_glob, &opt.text_glob)?;
+    let mut restored = Vec::new();
+    let mut removed = Vec::new();
+    let mut readded = (Vec::new(), Vec::new());
+    for reversal in reversals.iter() {
+        let cvs_path = opt.target.join(path_map.map(reversal.path()));
+        let absolute = checkout.join(&cvs_path);
+
+        match reversal {
+            Reversal::Restore { path, oid, readd } => {
+                let blob = repo.blob(oid)?;
+                let detection = detector.detect(&repo, None, path, &cvs_path, &blob)?;
+                if *readd {
+                    if let Some(parent) = cvs_path.parent() {
+                        add_directories(&cvs_repo, &checkout, parent)?;
+                    }
+                    if detection.binary {
+                        readded.1.push(cvs_path.clone());
+                    } else {
+                        readded.0.push(cvs_path.clone());
+                    }
+                }
+                fs::write(&absolute, detector.content(&detection, blob.content()))?;
+                restored.push(cvs_path);
+            }
+            Reversal::Remove { .. } => {
+                fs::remove_file(&absolute)?;
+                removed.push(cvs_path);
+            }
+        }
+    }
+    // A file that CVS doesn't have on the branch is already gone from it,
+    // which is all the undo needs.
+    cvs_repo.remove_multiple(removed.iter())?;
+    cvs_repo.add_multiple(readded.0.iter(), cvs::KeywordMode::Default)?;
+    cvs_repo.add_multiple(readded.1.iter(), cvs::KeywordMode::Binary)?;
+    cvs_repo.commit(
+        format!(
+            "Undo \"{}\"\n\nThis reverts the export of git commit {}.\n",
+            last_commit.summary(),
+            last
+        )
+        .as_bytes(),
+        None,
+        None,
+    )?;
+
+    // The new revisions have the content of the previous commit, and
+    // recording them as such keeps them from looking like a half finished
+    // export of the undone commit.
+    if let Some(previous) = &previous {
+        db.record_revisions(
+            &opt.branch,
+            previous,
+            &cvs_repo.revisions(restored.iter())?,
+            &HashMap::new(),
+        )?;
+    }
+    db.unmark_exported(&opt.branch, &last)?;
+    db.record_exception(
+        &opt.branch,
+        &last,
+        ExceptionKind::Interrupted,
+        "export undone by undo-last",
+    )?;
+    db.record_audit("undo-last", &opt.branch, "undo", Some(&last.to_string()))?;
+
+    println!(
+        "undid the export of {} on {}; run retry-failures to export it again",
+        last, &opt.branch
+    );
+    Ok(())
+}
+
+/// Works out what has to change in CVS to get from the last commit back to
+/// the previous one, or to nothing if there isn't one.
+fn plan(
+    repo: &Repository,
+    previous: Option<&Commit>,
+    last: &Commit,
+    filter: &Filter,
+) -> anyhow::Result<Vec<Reversal>> {
+    let previous = previous.map(Commit::tree_id);
+    let mut reversals = Vec::new();
+
+    for change in repo.changed_blobs(previous.as_ref(), &last.tree_id())? {
+        if filter.scope(&change.path) != Scope::Inside {
+            continue;
+        }
+
+        reversals.push(match change.old {
+            Some(oid) => Reversal::Restore {
+                path: change.path,
+                oid,
+                readd: change.new.is_none(),
+            },
+            None => Reversal::Remove { path: change.path },
+        });
+    }
+
+    Ok(reversals)
+}
+
+/// Checks that nothing has been committed to CVS since the last commit was
+/// exported: the files it wrote must still be at the revisions it created,
+/// and the files it removed must still be gone.
+fn check_unmoved(
+    cvs_repo: &cvs::Repository,
+    target: &Path,
+    recorded: &[(PathBuf, String)],
+    reversals: &[Reversal],
+    path_map: &PathMap,
+) -> anyhow::Result<()> {
+    let current: HashMap<PathBuf, String> = cvs_repo
+        .revisions(
+            recorded.iter().map(|(path, _)| path.clone()).chain(
+                reversals
+                    .iter()
+                    .map(|reversal| target.join(path_map.map(reversal.path()))),
+            ),
+        )?
+        .into_iter()
+        .collect();
+
+    for (path, revision) in recorded {
+        match current.get(path) {
+            Some(current) if current == revision => {}
+            Some(current) => anyhow::bail!(
+                "{} is at revision {} in CVS, but the last export left it at {}",
+                path.display(),
+                current,
+                revision
+            ),
+            None => anyhow::bail!(
+                "{} has been removed from CVS since the last export",
+                path.display()
+            ),
+        }
+    }
+
+    for reversal in reversals {
+        let path = target.join(path_map.map(reversal.path()));
+        if let (Reversal::Restore { readd: true, .. }, Some(current)) =
+            (reversal, current.get(&path))
+        {
+            anyhow::bail!(
+                "{} has been added back to CVS at revision {} since the last export",
+                path.display(),
+                current
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the directories leading to dir to CVS if it doesn't know about them,
+/// which happens if they were pruned.
+fn add_directories(cvs_repo: &cvs::Repository, checkout: &Path, dir: &Path) -> anyhow::Result<()> {
+    let mut ancestors: Vec<_> = dir.ancestors().collect();
+    ancestors.reverse();
+
+    for ancestor in ancestors {
+        if ancestor.as_os_str().is_empty() || checkout.join(ancestor).join("CVS").is_dir() {
+            continue;
+        }
+
+        fs::create_dir_all(checkout.join(ancestor))?;
+        cvs_repo.add(ancestor.as_os_str(), cvs::KeywordMode::Default)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(raw: &git2::Repository, files: &[(&str, &str)], parents: &[Oid]) -> Oid {
+        let mut index = git2::Index::new().unwrap();
+        for (path, content) in files {
+            let oid = raw.blob(content.as_bytes()).unwrap();
+            index
+                .add(&git2::IndexEntry {
+                    ctime: git2::IndexTime::new(0, 0),
+                    mtime: git2::IndexTime::new(0, 0),
+                    dev: 0,
+                    ino: 0,
+                    mode: 0o100644,
+                    uid: 0,
+                    gid: 0,
+                    file_size: 0,
+                    id: oid,
+                    flags: 0,
+                    flags_extended: 0,
+                    path: path.as_bytes().to_vec(),
+                })
+                .unwrap();
+        }
+        let tree = raw.find_tree(index.write_tree_to(raw).unwrap()).unwrap();
+        let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+        let parents: Vec<_> = parents
+            .iter()
+            .map(|oid| raw.find_commit(*oid).unwrap())
+            .collect();
+        raw.commit(
+            None,
+            &sig,
+            &sig,
+            "commit",
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(dir.path()).unwrap();
+        let previous = commit(
+            &raw,
+            &[
+                ("lib/changed.c", "old\n"),
+                ("lib/removed.c", "removed\n"),
+                ("docs/outside.txt", "old\n"),
+            ],
+            &[],
+        );
+        let last = commit(
+            &raw,
+            &[
+                ("lib/changed.c", "new\n"),
+                ("lib/added.c", "added\n"),
+                ("docs/outside.txt", "new\n"),
+            ],
+            &[previous],
+        );
+
+        let repo = Repository::open(dir.path(), 0).unwrap();
+        let previous = repo.commit(&previous).unwrap();
+        let last = repo.commit(&last).unwrap();
+        let filter = Filter::new(["lib"]);
+
+        assert_eq!(
+            vec![
+                Reversal::Remove {
+                    path: "lib/added.c".into()
+                },
+                Reversal::Restore {
+                    path: "lib/changed.c".into(),
+                    oid: raw.blob(b"old\n").unwrap(),
+                    readd: false,
+                },
+                Reversal::Restore {
+                    path: "lib/removed.c".into(),
+                    oid: raw.blob(b"removed\n").unwrap(),
+                    readd: true,
+                },
+            ],
+            plan(&repo, Some(&previous), &last, &filter).unwrap()
+        );
+
+        // Undoing the first commit removes everything.
+        assert_eq!(
+            vec![
+                Reversal::Remove {
+                    path: "lib/changed.c".into()
+                },
+                Reversal::Remove {
+                    path: "lib/removed.c".into()
+                },
+            ],
+            plan(&repo, None, &previous, &filter).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_check_unmoved() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src/CVS")).unwrap();
+        let cvs_repo = cvs::Context::new("true".as_ref())
+            .checkout(&"/cvsroot".parse().unwrap(), "module", dir.path(), None)
+            .unwrap();
+        let entries = |content: &str| {
+            fs::write(dir.path().join("src/CVS/Entries"), content).unwrap();
+        };
+
+        let recorded = vec![
+            (PathBuf::from("src/changed.c"), "1.2".to_string()),
+            (PathBuf::from("src/added.c"), "1.1".to_string()),
+        ];
+        let reversals = vec![
+            Reversal::Remove {
+                path: "added.c".into(),
+            },
+            Reversal::Restore {
+                path: "changed.c".into(),
+                oid: Oid::zero(),
+                readd: false,
+            },
+            Reversal::Restore {
+                path: "removed.c".into(),
+                oid: Oid::zero(),
+                readd: true,
+            },
+        ];
+        let check = || {
+            check_unmoved(
+                &cvs_repo,
+                Path::new("src"),
+                &recorded,
+                &reversals,
+                &PathMap::default(),
+            )
+        };
+
+        entries("/changed.c/1.2/dummy//\n/added.c/1.1/dummy//\n");
+        check().unwrap();
+
+        // Someone committed changed.c again.
+        entries("/changed.c/1.3/dummy//\n/added.c/1.1/dummy//\n");
+        assert!(check().is_err());
+
+        // Someone removed added.c.
+        entries("/changed.c/1.2/dummy//\n");
+        assert!(check().is_err());
+
+        // Someone re-added removed.c.
+        entries("/changed.c/1.2/dummy//\n/added.c/1.1/dummy//\n/removed.c/1.4/dummy//\n");
+        assert!(check().is_err());
+    }
+}
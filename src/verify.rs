@@ -0,0 +1,1078 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsString,
+    fmt, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use git2::{ObjectType, Oid, TreeWalkResult};
+use structopt::StructOpt;
+use tempfile::tempdir;
+
+use crate::{
+    attributes::Glob,
+    cvs, cvswrappers,
+    database::Database,
+    detect::Detector,
+    error::Error,
+    git::{self, Repository},
+    push, state,
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    #[structopt(short, long, help = "the branch to verify")]
+    branch: String,
+
+    // The shared options, which parse_args fills in.
+    #[structopt(skip)]
+    pub cvsroot: Option<cvs::CvsRoot>,
+
+    #[structopt(long, default_value = "cvs", help = "cvs binary to use")]
+    cvs: OsString,
+
+    #[structopt(skip)]
+    pub database: OsString,
+
+    #[structopt(short, long, default_value = ".", help = "git repository")]
+    git: OsString,
+
+    #[structopt(
+        short,
+        long,
+        default_value = ".",
+        help = "cvs module the branch was exported into"
+    )]
+    module: String,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "src",
+        help = "the target directory within the cvs checkout; can be . to use the top level"
+    )]
+    target: PathBuf,
+
+    #[structopt(long, help = "the paths the branch was exported with")]
+    include: Vec<PathBuf>,
+
+    #[structopt(
+        long,
+        number_of_values = 1,
+        parse(try_from_str = state::parse_exclude),
+        help = "the globs the branch was exported without"
+    )]
+    exclude: Vec<Glob>,
+
+    #[structopt(
+        long,
+        help = "the directory of the git repository the branch was exported from"
+    )]
+    prefix: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "an exported commit to verify, checked out at the revisions it created, rather than the last one"
+    )]
+    revision: Option<String>,
+
+    #[structopt(
+        long,
+        help = "ignore differences in expanded RCS keywords such as $Id$"
+    )]
+    tolerate_keyword_diffs: bool,
+
+    #[structopt(
+        long,
+        help = "the permissions manifest within the target, which isn't in the git tree"
+    )]
+    permissions_manifest: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "the target contains a .cvswrappers file written by push --write-cvswrappers"
+    )]
+    write_cvswrappers: bool,
+
+    #[structopt(long, help = "keep the checkout after the run")]
+    keep_workdir: bool,
+}
+
+/// Checks out the CVS module and compares it against an exported commit,
+/// failing if they differ.
+pub fn run(opt: Opt) -> anyhow::Result<()> {
+    let db = Database::open_readonly(&opt.database)?;
+    let repo = Repository::discover(&opt.git, 0)?.with_prefix(opt.prefix.as_ref());
+
+    let cvs_branch = match db.get_cvs_branch(&opt.branch)? {
+        Some(cvs_branch) => cvs_branch,
+        None => anyhow::bail!("branch {} has not been exported", &opt.branch),
+    };
+    let cvs_branch = db.branch_tagged(&opt.branch)?.then_some(cvs_branch);
+    let filter = state::Filter::new(&opt.include).with_excludes(&opt.exclude);
+    if db.filter_fingerprint(&opt.branch)? != filter.fingerprint() {
+        anyhow::bail!(
+            "branch {} was exported with a different set of --include paths (only --exclude can change between pushes)",
+            &opt.branch
+        );
+    }
+
+    // A commit other than the last is compared against the revisions it left
+    // in CVS, rather than the latest ones.
+    let (commit, index) = match &opt.revision {
+        Some(revision) => {
+            let oid = repo.resolve_commit(revision)?;
+            match db.commit_index(&oid, &opt.branch)? {
+                Some(index) if db.exported_at(&opt.branch, &oid)?.is_some() => (oid, Some(index)),
+                _ => anyhow::bail!("commit {} has not been exported on {}", oid, &opt.branch),
+            }
+        }
+        None => match db.last_export(&opt.branch)? {
+            Some((oid, _)) => (oid, None),
+            None => anyhow::bail!("no commits on {} have been exported", &opt.branch),
+        },
+    };
+
+    let workdir = tempdir()?;
+    let ctx = cvs::Context::new(&opt.cvs);
+    let cvs_repo = ctx.checkout(
+        opt.cvsroot.as_ref().expect("parse_args sets the CVSROOT"),
+        &opt.module,
+        workdir.path().join("cvs"),
+        cvs_branch.as_deref(),
+    )?;
+
+    // The export put files where the filters and path map say, so that's
+    // where we expect to find them.
+    let detector = Detector::default();
+    let mut expected = state::Global::new(cvs_repo.cwd(), &opt.target, filter)
+        .with_path_map(push::exported_path_map(&db, &opt.branch)?);
+    push::seed_state(
+        &*repo.commit(&commit)?,
+        &mut expected,
+        &push::Walker {
+            repo: &repo,
+            detector: &detector,
+            submodules: None,
+            skip_broken_submodules: true,
+            status: None,
+            symlinks: git::Symlinks::Skip,
+            submodule_policy: git::SubmodulePolicy::Skip,
+        },
+    )?;
+    if let Some(index) = index {
+        rewind(&db, &opt.branch, index, &cvs_repo, &expected)?;
+    }
+    if opt.keep_workdir {
+        eprintln!(
+            "keeping working directory {}",
+            workdir.into_path().display()
+        );
+    }
+
+    let target = expected.target_path();
+    let mut files = Vec::new();
+    for (file, oid) in expected.iter_files() {
+        files.push(Expected {
+            path: file.absolute_path().strip_prefix(&target)?.to_path_buf(),
+            oid: *oid,
+            executable: expected.is_executable(file),
+        });
+    }
+    let discrepancies = compare_files(
+        &repo,
+        files,
+        &target,
+        &Options {
+            tolerate_keyword_diffs: opt.tolerate_keyword_diffs,
+            generated: opt
+                .permissions_manifest
+                .iter()
+                .cloned()
+                .chain(opt.write_cvswrappers.then(|| cvswrappers::PATH.into()))
+                .collect(),
+            check_modes: true,
+        },
+        |_, _| {},
+    )?;
+    if discrepancies.is_empty() {
+        println!("CVS matches commit {}", commit);
+        return Ok(());
+    }
+
+    for discrepancy in &discrepancies {
+        println!("{}", discrepancy);
+    }
+    anyhow::bail!(
+        "CVS does not match commit {}: {}",
+        commit,
+        summarise(&discrepancies)
+    );
+}
+
+/// Takes a checkout of the latest revisions back to the ones recorded as of
+/// the commit at the given index: files the commit had are updated to their
+/// revisions, and files that only later commits added are deleted.
+fn rewind(
+    db: &Database,
+    git_branch: &str,
+    index: i64,
+    cvs_repo: &cvs::Repository,
+    expected: &state::Global,
+) -> anyhow::Result<()> {
+    let paths: Vec<_> = expected
+        .iter_files()
+        .map(|(file, _)| file.cvs_relative_path())
+        .collect();
+    let mut revisions: Vec<_> = db
+        .revisions_at(git_branch, Some(index), paths.iter())?
+        .into_iter()
+        .collect();
+    for path in paths.iter() {
+        if !revisions.iter().any(|(recorded, _)| recorded == path) {
+            log::warn!(
+                "no CVS revision is recorded for {}, so it is compared as it is now",
+                path.display()
+            );
+        }
+    }
+    revisions.sort();
+    cvs_repo.update_to_revisions(&revisions)?;
+
+    let target = expected.target_path();
+    let unexpected: Vec<_> = files_on_disk(&target)?
+        .into_iter()
+        .filter_map(|path| {
+            target
+                .join(path)
+                .strip_prefix(cvs_repo.cwd())
+                .ok()
+                .map(Path::to_path_buf)
+        })
+        .filter(|path| !paths.contains(path))
+        .collect();
+    let then = db.revisions_at(git_branch, Some(index), unexpected.iter())?;
+    for path in db.latest_revisions(git_branch, unexpected.iter())?.keys() {
+        if !then.contains_key(path) {
+            let absolute = cvs_repo.cwd().join(path);
+            fs::remove_file(&absolute)
+                .with_context(|| format!("cannot delete {}", absolute.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts the discrepancies of each kind, as in "2 missing, 1 different".
+fn summarise(discrepancies: &[Discrepancy]) -> String {
+    let mut counts = BTreeMap::new();
+    for discrepancy in discrepancies {
+        *counts.entry(discrepancy.kind()).or_insert(0) += 1;
+    }
+
+    counts
+        .into_iter()
+        .map(|(kind, count)| format!("{} {}", count, kind))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A difference between a git tree and a directory on disk.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Discrepancy {
+    Missing(PathBuf),
+    Different(PathBuf),
+    Extra(PathBuf),
+    /// The content differs only within expanded RCS keywords.
+    Keywords(PathBuf),
+    /// The content matches, but the executable bit doesn't.
+    Mode(PathBuf),
+}
+
+impl Discrepancy {
+    fn kind(&self) -> &'static str {
+        match self {
+            Discrepancy::Missing(_) => "missing",
+            Discrepancy::Different(_) => "different",
+            Discrepancy::Extra(_) => "extra",
+            Discrepancy::Keywords(_) => "different in keywords only",
+            Discrepancy::Mode(_) => "different in mode",
+        }
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Discrepancy::Missing(path)
+            | Discrepancy::Different(path)
+            | Discrepancy::Extra(path)
+            | Discrepancy::Keywords(path)
+            | Discrepancy::Mode(path) => path,
+        }
+    }
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.path().display())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Treat expanded RCS keywords such as `$Id: foo.c,v 1.2 ... $` as equal
+    /// to their unexpanded forms.
+    pub tolerate_keyword_diffs: bool,
+
+    /// Files generated by the export, such as the permissions manifest, which
+    /// aren't in the git tree and so aren't extra.
+    pub generated: Vec<PathBuf>,
+
+    /// Compare the executable bit of each file as well as its content.
+    pub check_modes: bool,
+}
+
+/// A file that should be in the directory being compared, with its path
+/// relative to the directory.
+struct Expected {
+    path: PathBuf,
+    oid: Oid,
+    executable: bool,
+}
+
+/// Compares every blob in the given tree against the files within dir,
+/// returning the files that differ. CVS administrative directories are
+/// ignored.
+///
+/// The blob OIDs of matching files are passed to the matched callback.
+pub fn compare<F>(
+    repo: &Repository,
+    tree: &Oid,
+    dir: &Path,
+    options: &Options,
+    matched: F,
+) -> anyhow::Result<Vec<Discrepancy>>
+where
+    F: FnMut(&Path, &Oid),
+{
+    let mut files = Vec::new();
+    repo.walk_tree(tree, |root, entry| -> anyhow::Result<_> {
+        if entry.kind() == Some(ObjectType::Blob) {
+            files.push(Expected {
+                path: root.join(entry.file_name()?),
+                oid: entry.id(),
+                executable: is_executable(entry.filemode()),
+            });
+        }
+        Ok(TreeWalkResult::Ok)
+    })?;
+
+    compare_files(repo, files, dir, options, matched)
+}
+
+fn compare_files<F>(
+    repo: &Repository,
+    files: Vec<Expected>,
+    dir: &Path,
+    options: &Options,
+    mut matched: F,
+) -> anyhow::Result<Vec<Discrepancy>>
+where
+    F: FnMut(&Path, &Oid),
+{
+    let mut discrepancies = Vec::new();
+    let mut seen = HashSet::new();
+
+    for Expected {
+        path,
+        oid,
+        executable,
+    } in files
+    {
+        let absolute = dir.join(&path);
+        seen.insert(path.clone());
+
+        let on_disk = match fs::read(&absolute) {
+            Ok(content) => content,
+            Err(_) => {
+                discrepancies.push(Discrepancy::Missing(path));
+                continue;
+            }
+        };
+
+        let blob = repo.blob(&oid)?;
+        if !content_matches(blob.content(), &on_disk, options) {
+            // Keyword expansion is the usual suspect, so it's worth saying
+            // when that's all there is to it.
+            if collapse_keywords(blob.content()) == collapse_keywords(&on_disk) {
+                discrepancies.push(Discrepancy::Keywords(path));
+            } else {
+                discrepancies.push(Discrepancy::Different(path));
+            }
+        } else if options.check_modes
+            && executable != (fs::metadata(&absolute)?.permissions().mode() & 0o111 != 0)
+        {
+            discrepancies.push(Discrepancy::Mode(path));
+        } else {
+            matched(&path, &oid);
+        }
+    }
+
+    for path in files_on_disk(dir)? {
+        if !seen.contains(&path) && !options.generated.contains(&path) {
+            discrepancies.push(Discrepancy::Extra(path));
+        }
+    }
+
+    discrepancies.sort();
+    Ok(discrepancies)
+}
+
+/// What to do about files in the working copy that we aren't tracking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirtyPolicy {
+    Warn,
+    Fail,
+}
+
+impl DirtyPolicy {
+    pub const VARIANTS: &'static [&'static str] = &["warn", "fail"];
+}
+
+impl FromStr for DirtyPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(DirtyPolicy::Warn),
+            "fail" => Ok(DirtyPolicy::Fail),
+            _ => anyhow::bail!("unknown dirty working copy policy {}", s),
+        }
+    }
+}
+
+/// Checks that the tracked files in the working copy still have the content
+/// that the state says they do, rewriting any that don't from git. Only an
+/// evenly spaced sample of the tracked files is hashed, unless sample is None.
+///
+/// Generated files are regenerated with the next commit rather than taken
+/// from git, so they're never rewritten here, nor reported as untracked.
+///
+/// Returns the files that were rewritten, and any untracked files found.
+pub fn check_tracked(
+    state: &state::Global,
+    repo: &Repository,
+    detector: &Detector,
+    sample: Option<usize>,
+    policy: DirtyPolicy,
+    generated: &[&Path],
+) -> anyhow::Result<Vec<Discrepancy>> {
+    let mut tracked: Vec<_> = state.iter_files().collect();
+    tracked.sort();
+
+    let known: HashSet<_> = tracked.iter().map(|(file, _)| file.git_path()).collect();
+    let mut extra: Vec<_> = files_on_disk(&state.target_path())?
+        .into_iter()
+        .filter(|path| !known.contains(path.as_path()) && !generated.contains(&path.as_path()))
+        .collect();
+    extra.sort();
+    if !extra.is_empty() {
+        let list = extra
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        match policy {
+            DirtyPolicy::Warn => log::warn!("untracked files in the working copy: {}", list),
+            DirtyPolicy::Fail => anyhow::bail!("untracked files in the working copy: {}", list),
+        }
+    }
+
+    let mut discrepancies = Vec::new();
+    let candidates: Vec<_> = tracked
+        .into_iter()
+        .filter(|(file, _)| !generated.contains(&file.git_path()))
+        .collect();
+    for (file, oid) in sampled(&candidates, sample) {
+        let absolute = file.absolute_path();
+        let on_disk = fs::read(&absolute).ok();
+
+        // Hashing is enough for most files; we only need to look at the blob
+        // if the content was transformed on the way out, or CVS expanded
+        // keywords on checkout.
+        if let Some(content) = &on_disk {
+            if Oid::hash_object(ObjectType::Blob, content)? == **oid {
+                continue;
+            }
+        }
+
+        // Submodule placeholders aren't in git, so there's nothing to compare
+        // them with; they're written again when the submodule changes.
+        let blob = match repo.blob(oid) {
+            Ok(blob) => blob,
+            Err(Error::ObjectNotFound { .. }) => {
+                log::debug!("not checking placeholder {}", file.git_path().display());
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let detection = detector.detect(
+            repo,
+            None,
+            file.git_path(),
+            &file.cvs_relative_path(),
+            &blob,
+        )?;
+        let matches = match &on_disk {
+            Some(content) => content_matches(
+                &detector.content(&detection, blob.content()),
+                content,
+                &Options {
+                    tolerate_keyword_diffs: true,
+                    ..Options::default()
+                },
+            ),
+            None => false,
+        };
+        if matches {
+            continue;
+        }
+
+        if let Some(parent) = absolute.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&absolute, detector.content(&detection, blob.content()))?;
+
+        let path = file.git_path().to_path_buf();
+        let discrepancy = match on_disk {
+            Some(_) => Discrepancy::Different(path),
+            None => Discrepancy::Missing(path),
+        };
+        log::warn!("rewrote externally modified file: {}", discrepancy);
+        discrepancies.push(discrepancy);
+    }
+
+    discrepancies.extend(extra.into_iter().map(Discrepancy::Extra));
+
+    Ok(discrepancies)
+}
+
+/// Returns the files in the working copy at root that neither CVS nor the
+/// state knows about, relative to root, other than those matching the keep
+/// pathspecs.
+pub fn strays(
+    state: &state::Global,
+    cvs_repo: &cvs::Repository,
+    root: &Path,
+    keep: &[String],
+) -> anyhow::Result<Vec<PathBuf>> {
+    // An empty pathspec matches everything.
+    let keep = match keep {
+        [] => None,
+        keep => Some(git2::Pathspec::new(keep.iter())?),
+    };
+    let tracked: HashSet<_> = state
+        .iter_files()
+        .map(|(file, _)| file.cvs_relative_path())
+        .collect();
+
+    let mut candidates: Vec<_> = files_on_disk(root)?
+        .into_iter()
+        .filter(|path| !tracked.contains(path))
+        .filter(|path| {
+            !matches!(&keep, Some(keep) if keep.matches_path(path, git2::PathspecFlags::DEFAULT))
+        })
+        .collect();
+    let in_cvs: HashSet<_> = cvs_repo
+        .revisions(candidates.iter())?
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    candidates.retain(|path| !in_cvs.contains(path));
+
+    candidates.sort();
+    Ok(candidates)
+}
+
+fn sampled<T>(items: &[T], sample: Option<usize>) -> impl Iterator<Item = &T> {
+    let (step, count) = match sample {
+        Some(sample) if sample < items.len() => (items.len() / sample.max(1), sample),
+        _ => (1, items.len()),
+    };
+    items.iter().step_by(step).take(count)
+}
+
+fn is_executable(filemode: i32) -> bool {
+    filemode == 0o100755
+}
+
+fn content_matches(git: &[u8], on_disk: &[u8], options: &Options) -> bool {
+    git == on_disk
+        || (options.tolerate_keyword_diffs && collapse_keywords(git) == collapse_keywords(on_disk))
+}
+
+fn files_on_disk(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+
+    while let Some(relative) = dirs.pop() {
+        for entry in fs::read_dir(dir.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+
+            if entry.file_type()?.is_dir() {
+                if entry.file_name() != "CVS" {
+                    dirs.push(path);
+                }
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+const KEYWORDS: &[&[u8]] = &[
+    b"Author",
+    b"CVSHeader",
+    b"Date",
+    b"Header",
+    b"Id",
+    b"Locker",
+    b"Log",
+    b"Name",
+    b"RCSfile",
+    b"Revision",
+    b"Source",
+    b"State",
+];
+
+/// Replaces expanded RCS keywords with their unexpanded forms, so `$Id: x $`
+/// becomes `$Id$`.
+pub fn collapse_keywords(content: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.iter().position(|c| *c == b'$') {
+        out.extend_from_slice(&rest[..=start]);
+        rest = &rest[start + 1..];
+
+        let keyword = KEYWORDS
+            .iter()
+            .find(|keyword| rest.starts_with(keyword) && rest.get(keyword.len()) == Some(&b':'));
+        if let Some(keyword) = keyword {
+            let value = &rest[keyword.len()..];
+            if let Some(end) = value
+                .iter()
+                .take_while(|c| **c != b'\n')
+                .position(|c| *c == b'$')
+            {
+                out.extend_from_slice(keyword);
+                rest = &value[end..];
+            }
+        }
+    }
+
+    out.extend_from_slice(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parse_args,
+        push::{self, Outcome},
+        test_support::{entries_cvs, fake_push_opt, GitBuilder},
+        Opt,
+    };
+
+    #[test]
+    fn test_collapse_keywords() {
+        assert_eq!(
+            b"/* $Id$ */\n$Revision$ $Unknown: x $ $Date:\n$".to_vec(),
+            collapse_keywords(
+                b"/* $Id: foo.c,v 1.2 2021/01/01 00:00:00 bob Exp $ */\n$Revision: 1.2 $ $Unknown: x $ $Date:\n$"
+            )
+        );
+        assert_eq!(b"no keywords".to_vec(), collapse_keywords(b"no keywords"));
+    }
+
+    #[test]
+    fn test_compare() {
+        let git_dir = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(git_dir.path()).unwrap();
+        let mut tree = raw.treebuilder(None).unwrap();
+        for (name, content) in &[
+            ("same.c", &b"same\n"[..]),
+            ("keyword.c", b"$Id$\n"),
+            ("changed.c", b"old\n"),
+            ("missing.c", b"gone\n"),
+        ] {
+            tree.insert(name, raw.blob(content).unwrap(), 0o100644)
+                .unwrap();
+        }
+        let tree = tree.write().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("CVS")).unwrap();
+        fs::write(dir.path().join("CVS/Entries"), "").unwrap();
+        fs::write(dir.path().join("same.c"), "same\n").unwrap();
+        fs::write(dir.path().join("keyword.c"), "$Id: keyword.c,v 1.1 $\n").unwrap();
+        fs::write(dir.path().join("changed.c"), "new\n").unwrap();
+        fs::write(dir.path().join("extra.c"), "extra\n").unwrap();
+
+        let repo = Repository::open(git_dir.path(), 0).unwrap();
+        let mut matched = Vec::new();
+        assert_eq!(
+            vec![
+                Discrepancy::Missing("missing.c".into()),
+                Discrepancy::Different("changed.c".into()),
+                Discrepancy::Extra("extra.c".into()),
+                Discrepancy::Keywords("keyword.c".into()),
+            ],
+            compare(&repo, &tree, dir.path(), &Options::default(), |path, _| {
+                matched.push(path.to_path_buf())
+            })
+            .unwrap()
+        );
+        assert_eq!(vec![PathBuf::from("same.c")], matched);
+
+        assert_eq!(
+            vec![
+                Discrepancy::Missing("missing.c".into()),
+                Discrepancy::Different("changed.c".into()),
+                Discrepancy::Extra("extra.c".into()),
+            ],
+            compare(
+                &repo,
+                &tree,
+                dir.path(),
+                &Options {
+                    tolerate_keyword_diffs: true,
+                    ..Options::default()
+                },
+                |_, _| {}
+            )
+            .unwrap()
+        );
+
+        // A file that the export generates isn't extra.
+        assert_eq!(
+            vec![
+                Discrepancy::Missing("missing.c".into()),
+                Discrepancy::Different("changed.c".into()),
+            ],
+            compare(
+                &repo,
+                &tree,
+                dir.path(),
+                &Options {
+                    tolerate_keyword_diffs: true,
+                    generated: vec!["extra.c".into()],
+                    ..Options::default()
+                },
+                |_, _| {}
+            )
+            .unwrap()
+        );
+
+        fs::set_permissions(dir.path().join("same.c"), fs::Permissions::from_mode(0o755)).unwrap();
+        let discrepancies = compare(
+            &repo,
+            &tree,
+            dir.path(),
+            &Options {
+                tolerate_keyword_diffs: true,
+                generated: vec!["extra.c".into()],
+                check_modes: true,
+            },
+            |_, _| {},
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                Discrepancy::Missing("missing.c".into()),
+                Discrepancy::Different("changed.c".into()),
+                Discrepancy::Mode("same.c".into()),
+            ],
+            discrepancies
+        );
+        assert_eq!(
+            "1 different, 1 different in mode, 1 missing",
+            summarise(&discrepancies)
+        );
+    }
+
+    #[test]
+    fn test_check_tracked() {
+        let git_dir = tempfile::tempdir().unwrap();
+        let raw = git2::Repository::init(git_dir.path()).unwrap();
+        let repo = Repository::open(git_dir.path(), 0).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src/CVS")).unwrap();
+        let mut state = state::Global::new(dir.path(), "src", state::Filter::default());
+        for (name, content, on_disk) in &[
+            ("same.c", "same\n", Some("same\n")),
+            ("keyword.c", "$Id$\n", Some("$Id: keyword.c,v 1.1 $\n")),
+            ("changed.c", "old\n", Some("tampered\n")),
+            ("missing.c", "gone\n", None),
+        ] {
+            let oid = raw.blob(content.as_bytes()).unwrap();
+            state.save_oid(state.file(name).unwrap(), &oid);
+            if let Some(on_disk) = on_disk {
+                fs::write(dir.path().join("src").join(name), on_disk).unwrap();
+            }
+        }
+        fs::write(dir.path().join("src/extra.c"), "extra\n").unwrap();
+
+        let detector = Detector::default();
+        assert!(check_tracked(&state, &repo, &detector, None, DirtyPolicy::Fail, &[]).is_err());
+        assert_eq!(
+            vec![
+                Discrepancy::Different("changed.c".into()),
+                Discrepancy::Missing("missing.c".into()),
+                Discrepancy::Extra("extra.c".into()),
+            ],
+            check_tracked(&state, &repo, &detector, None, DirtyPolicy::Warn, &[]).unwrap()
+        );
+        assert_eq!(
+            "old\n",
+            fs::read_to_string(dir.path().join("src/changed.c")).unwrap()
+        );
+        assert_eq!(
+            "gone\n",
+            fs::read_to_string(dir.path().join("src/missing.c")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_strays() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src/CVS")).unwrap();
+        fs::create_dir_all(dir.path().join("src/build")).unwrap();
+        fs::write(
+            dir.path().join("src/CVS/Entries"),
+            "/in-cvs.c/1.1/dummy//\nD/build////\n",
+        )
+        .unwrap();
+        for name in &[
+            "tracked.c",
+            "in-cvs.c",
+            "stray.c~",
+            "notes.keep",
+            "build/out.o",
+        ] {
+            fs::write(dir.path().join("src").join(name), "x\n").unwrap();
+        }
+
+        let mut state = state::Global::new(dir.path(), "src", state::Filter::default());
+        state.save_oid(state.file("tracked.c").unwrap(), &Oid::zero());
+        let cvs_repo = cvs::Context::new("true".as_ref())
+            .checkout(&"/cvsroot".parse().unwrap(), "module", dir.path(), None)
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                PathBuf::from("src/build/out.o"),
+                PathBuf::from("src/notes.keep"),
+                PathBuf::from("src/stray.c~"),
+            ],
+            strays(&state, &cvs_repo, dir.path(), &[]).unwrap()
+        );
+        assert_eq!(
+            vec![PathBuf::from("src/stray.c~")],
+            strays(
+                &state,
+                &cvs_repo,
+                dir.path(),
+                &["*.keep".to_string(), "src/build/".to_string()]
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sampled() {
+        let items: Vec<_> = (0..10).collect();
+        assert_eq!(10, sampled(&items, None).count());
+        assert_eq!(10, sampled(&items, Some(20)).count());
+        assert_eq!(
+            vec![&0, &3, &6],
+            sampled(&items, Some(3)).collect::<Vec<_>>()
+        );
+        assert_eq!(0, sampled(&items, Some(0)).count());
+    }
+
+    #[test]
+    fn test_verify() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut git = GitBuilder::new();
+        let first = git.file("a.c", "a\n").commit("first");
+        git.file("b.c", "$Id$\nb\n").commit("second");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+        let opt = fake_push_opt(git.path(), dir.path(), &[]);
+        assert_eq!(Outcome::Finished, push::run(opt, false).unwrap());
+
+        // A checkout has CVS's latest revisions, with the keyword in the
+        // second expanded. Updating to the first commit's revisions gets
+        // those instead.
+        for (fixture, files) in &[
+            ("old", &[("a.c", "a\n")][..]),
+            (
+                "new",
+                &[("a.c", "a\n"), ("b.c", "$Id: b.c,v 1.1 $\nb\n")][..],
+            ),
+        ] {
+            let src = dir.path().join(fixture).join("src");
+            fs::create_dir_all(src.join("CVS")).unwrap();
+            for (name, content) in *files {
+                fs::write(src.join(name), content).unwrap();
+            }
+        }
+        let cvs = dir.path().join("verify-cvs");
+        fs::write(
+            &cvs,
+            format!(
+                r#"#!/bin/sh
+case "$1" in
+update)
+    echo "$*" >> {dir}/verify-log
+    shift 3
+    for path; do cp {dir}/old/$path $path; done
+    ;;
+*)
+    echo "$3 $6 $7" >> {dir}/verify-log
+    cp -R {dir}/new/. "$5"
+    ;;
+esac
+"#,
+                dir = dir.path().display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let verify = |extra: &[&str]| {
+            let mut args = vec![
+                OsString::from("git2cvs"),
+                "verify".into(),
+                "-b".into(),
+                branch.clone().into(),
+                "-g".into(),
+                git.path().into(),
+                "--cvs".into(),
+                cvs.clone().into(),
+                "-c".into(),
+                "/cvsroot".into(),
+                "-d".into(),
+                dir.path().join("git2cvs.db").into(),
+            ];
+            args.extend(extra.iter().map(OsString::from));
+            match parse_args(args).unwrap() {
+                Opt::Verify(opt) => run(opt),
+                _ => unreachable!(),
+            }
+        };
+
+        let message = format!("{:#}", verify(&[]).unwrap_err());
+        assert!(
+            message.ends_with(": 1 different in keywords only"),
+            "{}",
+            message
+        );
+        verify(&["--tolerate-keyword-diffs"]).unwrap();
+        verify(&["--revision", &first.to_string()]).unwrap();
+        assert!(verify(&["--revision", "HEAD~5"]).is_err());
+
+        let log = fs::read_to_string(dir.path().join("verify-log")).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(4, lines.len(), "{}", log);
+        assert_eq!("checkout -R .", lines[0]);
+        assert_eq!("update -r 1.1 src/a.c", lines[3]);
+    }
+    #[test]
+    fn test_verify_path_map() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut git = GitBuilder::new();
+        git.file("headers/a.h", "a\n")
+            .file("lib/b.c", "b\n")
+            .file("docs/c.txt", "c\n")
+            .commit("first");
+        let branch = git.raw().head().unwrap().shorthand().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        entries_cvs(dir.path());
+        let map_file = dir.path().join("paths");
+        fs::write(&map_file, "headers=include\n").unwrap();
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &[
+                "--path-map",
+                map_file.to_str().unwrap(),
+                "--include",
+                "headers",
+                "--include",
+                "lib",
+            ],
+        );
+        assert_eq!(Outcome::Finished, push::run(opt, false).unwrap());
+
+        // CVS has the mapped paths, and nothing that wasn't included.
+        let src = dir.path().join("checkout/src");
+        for (name, content) in &[("include/a.h", "a\n"), ("lib/b.c", "b\n")] {
+            let path = src.join(name);
+            fs::create_dir_all(path.parent().unwrap().join("CVS")).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        let cvs = dir.path().join("verify-cvs");
+        fs::write(
+            &cvs,
+            format!(
+                "#!/bin/sh\ncp -R {}/checkout/. \"$5\"\n",
+                dir.path().display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&cvs, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let verify = |extra: &[&str]| {
+            let mut args = vec![
+                OsString::from("git2cvs"),
+                "verify".into(),
+                "-b".into(),
+                branch.clone().into(),
+                "-g".into(),
+                git.path().into(),
+                "--cvs".into(),
+                cvs.clone().into(),
+                "-c".into(),
+                "/cvsroot".into(),
+                "-d".into(),
+                dir.path().join("git2cvs.db").into(),
+            ];
+            args.extend(extra.iter().map(OsString::from));
+            match parse_args(args).unwrap() {
+                Opt::Verify(opt) => run(opt),
+                _ => unreachable!(),
+            }
+        };
+
+        verify(&["--include", "headers", "--include", "lib"]).unwrap();
+        assert_eq!(
+            format!(
+                "branch {} was exported with a different set of --include paths (only --exclude can change between pushes)",
+                branch
+            ),
+            format!("{:#}", verify(&[]).unwrap_err())
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    ffi::OsStr,
+    fs, io,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use subprocess::Exec;
+
+use crate::{
+    bundle::Bundle,
+    cvs,
+    database::Database,
+    git::Repository,
+    lock::RunLock,
+    push::{self, Mode, Outcome, PushOpt},
+};
+
+/// The audit log command for watch iterations.
+const COMMAND: &str = "watch";
+
+/// The most the interval is multiplied by after repeated failures.
+const MAX_BACKOFF: u32 = 16;
+
+/// How often a sleeping watch checks whether it should stop.
+const STOP_POLL: Duration = Duration::from_secs(1);
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+/// Returns whether a stop has been requested, either by a signal or by the
+/// stop file. The stop file is removed once it's been seen, so that the next
+/// run isn't stopped too.
+pub fn stop_requested(stop_file: Option<&Path>) -> bool {
+    if let Some(path) = stop_file {
+        if path.exists() {
+            log::info!("found stop file {}", path.display());
+            if let Err(e) = fs::remove_file(path) {
+                log::warn!("cannot remove stop file {}: {}", path.display(), e);
+            }
+            STOP.store(true, Ordering::SeqCst);
+        }
+    }
+    STOP.load(Ordering::SeqCst)
+}
+
+/// Pushes the branch, then keeps exporting new commits every interval until
+/// stopped by SIGINT, SIGTERM, or the stop file, or until too many exports in
+/// a row have failed. The run lock is held throughout, and the CVS checkout is
+/// kept from one export to the next.
+pub fn run(mut opt: PushOpt, mode: Mode, interval: Duration) -> anyhow::Result<Outcome> {
+    for signal in &[libc::SIGINT, libc::SIGTERM] {
+        // The handler only stores to an atomic, which is async-signal-safe.
+        unsafe {
+            libc::signal(
+                *signal,
+                request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+    // Ctrl-C reaches cvs too, and mustn't stop it part way through a commit.
+    cvs::shield_from_stop_signals();
+    let _lock = RunLock::acquire(&opt.database)?;
+
+    // A bundle never gains new commits, so there's nothing to watch.
+    if Bundle::open(&opt.git)?.is_some() {
+        anyhow::bail!("cannot watch a bundle; watch the repository it came from instead");
+    }
+    if opt.branch.is_none() {
+        let branch = match opt.rev.as_ref().or_else(|| opt.branches.first()) {
+            Some(branch) => branch.clone(),
+            None => Repository::discover(&opt.git, 0)?.head_branch(opt.remote)?,
+        };
+        log::info!("watching branch {}", branch);
+        opt.branch = Some(branch);
+    }
+
+    let (workdir, _tempdir) = push::create_workdir(opt.keep_workdir)?;
+    opt.session_workdir = Some(workdir.clone());
+
+    let mut mode = mode;
+    let mut failures = 0;
+    while !stop_requested(opt.stop_file.as_deref()) {
+        let before = last_exported(&opt)?;
+        let result = if opt.fetch {
+            fetch(&opt.git_binary, &opt.git).and_then(|_| push::export(&mut opt, mode))
+        } else {
+            push::export(&mut opt, mode)
+        };
+
+        let db = Database::open(&opt.database)?;
+        let branch = opt.branch.clone().unwrap_or_default();
+        match result {
+            Ok(outcome) => {
+                failures = 0;
+                let after = db.last_exported_commit(&branch)?;
+                match after.filter(|after| Some(*after) != before) {
+                    Some(after) => {
+                        db.record_audit(COMMAND, &branch, "export", Some(&after.to_string()))?
+                    }
+                    None => db.record_audit(COMMAND, &branch, "idle", None)?,
+                }
+                if outcome == Outcome::Stopped && stop_requested(opt.stop_file.as_deref()) {
+                    break;
+                }
+            }
+            Err(e) => {
+                failures += 1;
+                db.record_audit(COMMAND, &branch, "error", Some(&format!("{:#}", e)))?;
+                if opt.watch_max_failures > 0 && failures >= opt.watch_max_failures {
+                    return Err(e.context(format!(
+                        "stopped watching {} after {} failed exports in a row",
+                        branch, failures
+                    )));
+                }
+
+                // The failed export may have left changes in the checkout that
+                // shouldn't go in with the next commit.
+                discard_checkout(&workdir)?;
+                log::error!(
+                    "cannot export {}, retrying in {}s: {:?}",
+                    branch,
+                    backoff(interval, failures).as_secs(),
+                    e
+                );
+            }
+        }
+
+        // Once the branch has been exported, later runs pick up new commits,
+        // along with any this run didn't get to.
+        if exported(&opt)? {
+            mode = Mode::Update;
+            opt.resume = true;
+        }
+        sleep(backoff(interval, failures), opt.stop_file.as_deref());
+    }
+
+    log::info!("stopped watching");
+    Ok(Outcome::Finished)
+}
+
+/// Removes the CVS checkout from a working directory, if there is one.
+pub fn discard_checkout(workdir: &Path) -> anyhow::Result<()> {
+    let checkout = workdir.join("cvs");
+    match fs::remove_dir_all(&checkout) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("cannot remove {}", checkout.display())),
+    }
+}
+
+fn exported(opt: &PushOpt) -> anyhow::Result<bool> {
+    match &opt.branch {
+        Some(branch) => Ok(Database::open(&opt.database)?
+            .get_cvs_branch(branch)?
+            .is_some()),
+        None => Ok(false),
+    }
+}
+
+fn last_exported(opt: &PushOpt) -> anyhow::Result<Option<git2::Oid>> {
+    match &opt.branch {
+        Some(branch) => Ok(Database::open(&opt.database)?.last_exported_commit(branch)?),
+        None => Ok(None),
+    }
+}
+
+fn fetch(git_binary: &OsStr, git: &OsStr) -> anyhow::Result<()> {
+    let status = Exec::cmd(git_binary)
+        .arg("-C")
+        .arg(git)
+        .arg("fetch")
+        .arg("--quiet")
+        .join()?;
+    if !status.success() {
+        anyhow::bail!("git fetch failed: {:?}", status);
+    }
+    Ok(())
+}
+
+/// The time to wait before the next iteration: the interval, doubled for each
+/// consecutive failure up to a limit.
+fn backoff(interval: Duration, failures: u32) -> Duration {
+    interval * 2u32.pow(failures.min(MAX_BACKOFF.trailing_zeros()))
+}
+
+fn sleep(duration: Duration, stop_file: Option<&Path>) {
+    let end = Instant::now() + duration;
+    while !stop_requested(stop_file) {
+        let now = Instant::now();
+        if now >= end {
+            break;
+        }
+        thread::sleep(STOP_POLL.min(end - now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        push,
+        test_support::{entries_cvs, fake_push_opt, GitBuilder},
+    };
+
+    #[test]
+    fn test_backoff() {
+        let interval = Duration::from_secs(60);
+        assert_eq!(interval, backoff(interval, 0));
+        assert_eq!(interval * 2, backoff(interval, 1));
+        assert_eq!(interval * 8, backoff(interval, 3));
+        assert_eq!(interval * 16, backoff(interval, 4));
+        assert_eq!(interval * 16, backoff(interval, 40));
+    }
+
+    #[test]
+    fn test_watch_reuses_checkout() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut git = GitBuilder::new();
+        git.file("a.c", "a\n").commit("first");
+        let dir = tempfile::tempdir().unwrap();
+        let inner = entries_cvs(dir.path());
+        fs::rename(&inner, dir.path().join("cvs-inner")).unwrap();
+
+        // The first CVS commit leaves a new git commit for the next export.
+        // Updating the checkout always fails, and so does checking it out
+        // again afterwards.
+        let log = dir.path().join("log");
+        fs::write(
+            dir.path().join("cvs"),
+            format!(
+                r#"#!/bin/sh
+case "$1 $3" in
+"update "*) echo update >> {log}; exit 1 ;;
+*" checkout") grep -q checkout {log} 2>/dev/null && exit 1; echo checkout >> {log} ;;
+"commit "*) git -C {git} -c user.name=t -c user.email=t@example.com commit -q --allow-empty -m second ;;
+esac
+exec {inner} "$@"
+"#,
+                log = log.display(),
+                git = git.path().display(),
+                inner = dir.path().join("cvs-inner").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(dir.path().join("cvs"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let opt = fake_push_opt(
+            git.path(),
+            dir.path(),
+            &["--watch", "0s", "--watch-max-failures", "2"],
+        );
+        let message = format!("{:#}", push::run(opt, false).unwrap_err());
+        assert!(
+            message.contains("after 2 failed exports in a row"),
+            "{}",
+            message
+        );
+
+        // The second export updates the first one's checkout, and the third
+        // checks out again since the second failed.
+        assert_eq!(
+            "checkout\n\
+             add src\n\
+             add src/a.c\n\
+             commit first\n\
+             update\n",
+            fs::read_to_string(&log).unwrap()
+        );
+    }
+}
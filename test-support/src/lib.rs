@@ -0,0 +1,217 @@
+//! Test fixtures shared by git2cvs's unit tests and the integration tests in
+//! `tests/`: git repositories with a scripted history.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use git2::{Oid, Signature, Time};
+use tempfile::TempDir;
+
+const FILE: i32 = 0o100644;
+const EXECUTABLE: i32 = 0o100755;
+const SYMLINK: i32 = 0o120000;
+
+/// Builds a git repository one commit at a time.
+///
+/// Files are staged with [`file`](Self::file) and friends, and carry over from
+/// one commit to the next until they're removed, so each commit only needs to
+/// describe what changed. Commits are made on whatever branch HEAD refers to,
+/// one minute apart, so that histories are reproducible.
+pub struct GitBuilder {
+    _dir: Option<TempDir>,
+    path: PathBuf,
+    raw: git2::Repository,
+    files: BTreeMap<Vec<u8>, (i32, Vec<u8>)>,
+    time: i64,
+}
+
+impl Default for GitBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GitBuilder {
+    /// Creates a repository in a new temporary directory.
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = Self::init(dir.path());
+        builder._dir = Some(dir);
+        builder
+    }
+
+    /// Creates a repository at the given path, which the caller has to clean
+    /// up.
+    pub fn init<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            _dir: None,
+            path: path.as_ref().to_path_buf(),
+            raw: git2::Repository::init(path).unwrap(),
+            files: BTreeMap::new(),
+            time: 1_000_000_000,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn raw(&self) -> &git2::Repository {
+        &self.raw
+    }
+
+    /// Stages a regular file.
+    pub fn file<P: AsRef<[u8]>, C: AsRef<[u8]>>(&mut self, path: P, content: C) -> &mut Self {
+        self.stage(path, FILE, content)
+    }
+
+    /// Stages an executable file.
+    pub fn executable<P: AsRef<[u8]>, C: AsRef<[u8]>>(&mut self, path: P, content: C) -> &mut Self {
+        self.stage(path, EXECUTABLE, content)
+    }
+
+    /// Stages a symlink to the given target.
+    pub fn symlink<P: AsRef<[u8]>, T: AsRef<[u8]>>(&mut self, path: P, target: T) -> &mut Self {
+        self.stage(path, SYMLINK, target)
+    }
+
+    /// Stages a file that's detected as binary, by prefixing the content with
+    /// a NUL.
+    pub fn binary<P: AsRef<[u8]>, C: AsRef<[u8]>>(&mut self, path: P, content: C) -> &mut Self {
+        let mut bytes = vec![0];
+        bytes.extend_from_slice(content.as_ref());
+        self.stage(path, FILE, bytes)
+    }
+
+    pub fn remove<P: AsRef<[u8]>>(&mut self, path: P) -> &mut Self {
+        self.files
+            .remove(path.as_ref())
+            .expect("removed file should be staged");
+        self
+    }
+
+    /// Moves a file, keeping its mode and content.
+    pub fn rename<F: AsRef<[u8]>, T: AsRef<[u8]>>(&mut self, from: F, to: T) -> &mut Self {
+        let entry = self
+            .files
+            .remove(from.as_ref())
+            .expect("renamed file should be staged");
+        self.files.insert(to.as_ref().to_vec(), entry);
+        self
+    }
+
+    /// Unstages everything, so that the next commit only has the files
+    /// staged after this.
+    pub fn clear(&mut self) -> &mut Self {
+        self.files.clear();
+        self
+    }
+
+    /// Commits the staged files on top of HEAD.
+    pub fn commit(&mut self, message: &str) -> Oid {
+        let parents: Vec<_> = self.head().into_iter().collect();
+        self.commit_with_parents(message, &parents)
+    }
+
+    /// Commits the staged files with HEAD and the given commit as parents.
+    /// Whatever the merge should contain has to be staged first.
+    pub fn merge(&mut self, message: &str, other: Oid) -> Oid {
+        let head = self.head().expect("merge needs a commit on HEAD");
+        self.commit_with_parents(message, &[head, other])
+    }
+
+    /// Creates a branch at HEAD.
+    pub fn branch(&mut self, name: &str) -> &mut Self {
+        {
+            let head = self.raw.head().unwrap().peel_to_commit().unwrap();
+            self.raw.branch(name, &head, false).unwrap();
+        }
+        self
+    }
+
+    /// Points HEAD at the given branch and stages its files, or nothing if it
+    /// doesn't have any commits yet.
+    pub fn checkout(&mut self, name: &str) -> &mut Self {
+        self.raw.set_head(&format!("refs/heads/{}", name)).unwrap();
+        self.files.clear();
+        if let Some(head) = self.head() {
+            let tree = self.raw.find_commit(head).unwrap().tree().unwrap();
+            let mut files = BTreeMap::new();
+            tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+                if entry.kind() == Some(git2::ObjectType::Blob) {
+                    let mut path = dir.as_bytes().to_vec();
+                    path.extend_from_slice(entry.name_bytes());
+                    let blob = self.raw.find_blob(entry.id()).unwrap();
+                    files.insert(path, (entry.filemode(), blob.content().to_vec()));
+                }
+                git2::TreeWalkResult::Ok
+            })
+            .unwrap();
+            self.files = files;
+        }
+        self
+    }
+
+    fn head(&self) -> Option<Oid> {
+        self.raw
+            .head()
+            .ok()
+            .map(|head| head.peel_to_commit().unwrap().id())
+    }
+
+    fn stage<P: AsRef<[u8]>, C: AsRef<[u8]>>(
+        &mut self,
+        path: P,
+        mode: i32,
+        content: C,
+    ) -> &mut Self {
+        self.files
+            .insert(path.as_ref().to_vec(), (mode, content.as_ref().to_vec()));
+        self
+    }
+
+    fn commit_with_parents(&mut self, message: &str, parents: &[Oid]) -> Oid {
+        let mut index = git2::Index::new().unwrap();
+        for (path, (mode, content)) in &self.files {
+            index
+                .add(&git2::IndexEntry {
+                    ctime: git2::IndexTime::new(0, 0),
+                    mtime: git2::IndexTime::new(0, 0),
+                    dev: 0,
+                    ino: 0,
+                    mode: *mode as u32,
+                    uid: 0,
+                    gid: 0,
+                    file_size: 0,
+                    id: self.raw.blob(content).unwrap(),
+                    flags: 0,
+                    flags_extended: 0,
+                    path: path.clone(),
+                })
+                .unwrap();
+        }
+        let tree = self
+            .raw
+            .find_tree(index.write_tree_to(&self.raw).unwrap())
+            .unwrap();
+
+        self.time += 60;
+        let sig = Signature::new("Test", "test@example.com", &Time::new(self.time, 0)).unwrap();
+        let parents = parents
+            .iter()
+            .map(|oid| self.raw.find_commit(*oid).unwrap())
+            .collect::<Vec<_>>();
+        self.raw
+            .commit(
+                Some("HEAD"),
+                &sig,
+                &sig,
+                message,
+                &tree,
+                parents.iter().collect::<Vec<_>>().as_slice(),
+            )
+            .unwrap()
+    }
+}
@@ -0,0 +1,217 @@
+//! Exports git histories into a real CVS repository by running the git2cvs
+//! binary, and checks what CVS ends up with.
+//!
+//! These need a cvs binary on PATH, and are skipped without one.
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use git2cvs_test_support::GitBuilder;
+use tempfile::TempDir;
+
+/// Returns early from a test, with a note, if there's no cvs to run.
+macro_rules! require_cvs {
+    () => {
+        if Command::new("cvs").arg("--version").output().is_err() {
+            eprintln!("skipping: cvs is not on PATH");
+            return;
+        }
+    };
+}
+
+#[test]
+fn test_history() {
+    require_cvs!();
+
+    let fixture = Fixture::new();
+    let mut git = fixture.git();
+    git.file("a.txt", "one\n")
+        .file("dir/b.txt", "b\n")
+        .file("logo.png", b"\0png\x89")
+        .executable("build.sh", "#!/bin/sh\n")
+        .commit("add");
+    git.file("a.txt", "two\n")
+        .file("logo.png", b"\0png\x8a")
+        .commit("modify");
+    git.remove("a.txt").commit("remove");
+    fixture.push();
+
+    assert!(!fixture.checkout("first").join("a.txt").exists());
+    assert_eq!(3, fixture.revisions("a.txt"));
+
+    // Re-adding a removed file revives it in CVS, rather than making a new
+    // file alongside the one in the Attic.
+    git.file("a.txt", "three\n").commit("re-add");
+    git.remove("dir/b.txt").commit("remove dir");
+    fixture.push();
+
+    let checkout = fixture.checkout("second");
+    assert_eq!(
+        "three\n",
+        fs::read_to_string(checkout.join("a.txt")).unwrap()
+    );
+    assert_eq!(
+        b"\0png\x8a",
+        &fs::read(checkout.join("logo.png")).unwrap()[..]
+    );
+    assert!(!checkout.join("dir/b.txt").exists());
+    assert_eq!(
+        0o111,
+        fs::metadata(checkout.join("build.sh"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o111
+    );
+    assert_eq!(
+        0,
+        fs::metadata(checkout.join("a.txt"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o111
+    );
+
+    assert_eq!(4, fixture.revisions("a.txt"));
+    assert_eq!(2, fixture.revisions("dir/b.txt"));
+    assert_eq!(2, fixture.revisions("logo.png"));
+    assert_eq!(1, fixture.revisions("build.sh"));
+
+    assert_eq!("b", fixture.keyword_mode("logo.png"));
+    assert_eq!("kv", fixture.keyword_mode("a.txt"));
+    assert_eq!("kv", fixture.keyword_mode("build.sh"));
+}
+
+#[test]
+fn test_merges_are_squashed() {
+    require_cvs!();
+
+    let fixture = Fixture::new();
+    let mut git = fixture.git();
+    git.file("main.c", "main\n").commit("initial");
+    git.branch("feature").checkout("feature");
+    let feature = git.file("feature.c", "feature\n").commit("feature");
+    git.checkout("main");
+    git.file("main.c", "main 2\n").commit("main");
+    git.file("feature.c", "feature\n").merge("merge", feature);
+    fixture.push();
+
+    // Only the first-parent history reaches CVS, with the merge carrying the
+    // feature branch's changes.
+    let checkout = fixture.checkout("checkout");
+    assert_eq!(
+        "feature\n",
+        fs::read_to_string(checkout.join("feature.c")).unwrap()
+    );
+    assert_eq!(1, fixture.revisions("feature.c"));
+    assert_eq!(2, fixture.revisions("main.c"));
+}
+
+/// A git repository, an empty CVS module to export it into, and a database,
+/// all in one temporary directory.
+struct Fixture {
+    dir: TempDir,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let fixture = Self {
+            dir: tempfile::tempdir().unwrap(),
+        };
+        fixture.cvs(fixture.dir.path(), &["init"]);
+        fs::create_dir_all(fixture.root().join("module")).unwrap();
+        fixture
+    }
+
+    fn root(&self) -> PathBuf {
+        self.dir.path().join("root")
+    }
+
+    /// Creates the git repository, with HEAD on the main branch.
+    fn git(&self) -> GitBuilder {
+        let mut git = GitBuilder::init(self.dir.path().join("git"));
+        git.checkout("main");
+        git
+    }
+
+    /// Pushes the main branch into the module with the binary under test.
+    fn push(&self) {
+        let output = Command::new(env!("CARGO_BIN_EXE_git2cvs"))
+            .arg("push")
+            .arg("-g")
+            .arg(self.dir.path().join("git"))
+            .args(["-b", "main", "-m", "module", "-t", "."])
+            .arg("-c")
+            .arg(format!(":local:{}", self.root().display()))
+            .arg("-d")
+            .arg(self.dir.path().join("git2cvs.db"))
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "push failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// Checks out the module into the named directory, returning its path.
+    fn checkout(&self, name: &str) -> PathBuf {
+        self.cvs(self.dir.path(), &["checkout", "-d", name, "module"]);
+        self.dir.path().join(name)
+    }
+
+    /// Returns the number of revisions CVS has of a file in the module,
+    /// including the dead revisions that record removals.
+    fn revisions(&self, path: &str) -> usize {
+        self.header(path, "total revisions:")
+            .split(';')
+            .next()
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap()
+    }
+
+    /// Returns the default keyword substitution mode of a file in the
+    /// module, such as b for binary files.
+    fn keyword_mode(&self, path: &str) -> String {
+        self.header(path, "keyword substitution:")
+    }
+
+    /// Returns the rest of the line of `cvs rlog -h` output for a file that
+    /// starts with the given field name.
+    fn header(&self, path: &str, field: &str) -> String {
+        let log = self.cvs(
+            self.dir.path(),
+            &["rlog", "-h", &format!("module/{}", path)],
+        );
+        log.lines()
+            .find_map(|line| line.strip_prefix(field))
+            .unwrap_or_else(|| panic!("no {} in the log of {}:\n{}", field, path, log))
+            .trim()
+            .to_string()
+    }
+
+    /// Runs cvs quietly against the repository, returning its output.
+    fn cvs<P: AsRef<Path>>(&self, cwd: P, args: &[&str]) -> String {
+        let output = Command::new("cvs")
+            .arg("-Q")
+            .arg("-d")
+            .arg(format!(":local:{}", self.root().display()))
+            .args(args)
+            .current_dir(cwd)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "cvs {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+}